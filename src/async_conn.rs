@@ -0,0 +1,820 @@
+//! The keyed streams of [`conn`](crate::conn) for tokio based services.
+//!
+//! Same protocol and buffering, but the reader and the writer are tasks
+//! instead of threads and the transport is anything `AsyncRead +
+//! AsyncWrite`, e.g. a `tokio::net::TcpStream`. Sessions don't resume, a
+//! dropped connection fails the keys still open. Closing, rate limits and
+//! idle timeouts need the runtime's time driver.
+
+use std::future::poll_fn;
+use std::io;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::task::{Context, Poll, Waker};
+use std::time::Instant;
+
+use tokio::io::{
+    AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader, BufWriter, ReadBuf,
+};
+use tokio::sync::Notify;
+use tokio::task::JoinHandle;
+
+use crate::conn::{
+    Compression, Config, Frame, Integrity, Metadata, SendOptions, State, CLOSE_TIMED_OUT,
+};
+
+struct Inner {
+    state: State,
+    /// Tasks waiting for data, keys or an error.
+    readable: Vec<Waker>,
+    /// Tasks waiting for buffered data to go out.
+    writable: Vec<Waker>,
+}
+
+#[derive(Clone, Copy)]
+enum Waiting {
+    Readable,
+    Writable,
+}
+
+struct Shared {
+    inner: Mutex<Inner>,
+    /// There may be frames for the writer task.
+    work: Notify,
+}
+
+impl Shared {
+    fn lock(&self) -> MutexGuard<'_, Inner> {
+        self.inner.lock().unwrap()
+    }
+
+    /// Poll the state, waking `cx` on the next change of the `waiting` kind
+    /// if it isn't ready.
+    fn poll<T>(
+        &self,
+        cx: &mut Context<'_>,
+        waiting: Waiting,
+        poll: impl FnOnce(&mut State) -> Poll<T>,
+    ) -> Poll<T> {
+        let mut inner = self.lock();
+        let ready = poll(&mut inner.state);
+        if ready.is_pending() {
+            let wakers = match waiting {
+                Waiting::Readable => &mut inner.readable,
+                Waiting::Writable => &mut inner.writable,
+            };
+            if !wakers.iter().any(|waker| waker.will_wake(cx.waker())) {
+                wakers.push(cx.waker().clone());
+            }
+        }
+        ready
+    }
+
+    fn wake(&self, waiting: Waiting) {
+        let wakers = {
+            let mut inner = self.lock();
+            std::mem::take(match waiting {
+                Waiting::Readable => &mut inner.readable,
+                Waiting::Writable => &mut inner.writable,
+            })
+        };
+        wakers.into_iter().for_each(Waker::wake);
+    }
+
+    fn fail(&self, error: &io::Error) {
+        self.lock().state.fail(error);
+        self.wake_all();
+    }
+
+    fn wake_all(&self) {
+        self.wake(Waiting::Readable);
+        self.wake(Waiting::Writable);
+        self.work.notify_one();
+    }
+}
+
+/// A connection carrying keyed streams, see [`conn`](crate::conn).
+///
+/// Dropping it without [`close`](Conn::close) tears the connection down
+/// at once.
+pub struct Conn {
+    shared: Arc<Shared>,
+    reader: JoinHandle<()>,
+    /// Taken by closing.
+    writer: Mutex<Option<JoinHandle<()>>>,
+}
+
+impl Conn {
+    /// Start the reader and writer tasks on the current tokio runtime.
+    ///
+    /// # Panics
+    ///
+    /// Outside of a runtime.
+    pub fn new<T>(transport: T) -> Self
+    where
+        T: AsyncRead + AsyncWrite + Send + 'static,
+    {
+        Self::with_config(transport, Config::default())
+    }
+
+    /// Like [`new`](Conn::new), but with other than the default settings.
+    pub fn with_config<T>(transport: T, config: Config) -> Self
+    where
+        T: AsyncRead + AsyncWrite + Send + 'static,
+    {
+        let shared = Arc::new(Shared {
+            inner: Mutex::new(Inner {
+                state: State::new(&config),
+                readable: Vec::new(),
+                writable: Vec::new(),
+            }),
+            work: Notify::new(),
+        });
+        let (input, output) = tokio::io::split(transport);
+        let reader = tokio::spawn(read_loop(shared.clone(), BufReader::new(input)));
+        let writer = tokio::spawn(write_loop(shared.clone(), BufWriter::new(output)));
+
+        Self {
+            shared,
+            reader,
+            writer: Mutex::new(Some(writer)),
+        }
+    }
+
+    /// The checks both sides agreed on, `None` until the peer's handshake
+    /// arrived.
+    pub fn integrity(&self) -> Option<Integrity> {
+        self.shared.lock().state.integrity()
+    }
+
+    /// The compressions both sides offer, `None` until the peer's
+    /// handshake arrived.
+    pub fn compressions(&self) -> Option<Vec<Compression>> {
+        self.shared.lock().state.compressions()
+    }
+
+    /// Start sending `key`. The data is complete once the writer is
+    /// [shut down](AsyncWriteExt::shutdown) or dropped.
+    pub fn send(&self, key: &str) -> io::Result<KeyWriter> {
+        self.send_with(key, &SendOptions::default())
+    }
+
+    /// Like [`send`](Conn::send), but with other than the default options.
+    pub fn send_with(&self, key: &str, options: &SendOptions) -> io::Result<KeyWriter> {
+        let stream = self.shared.lock().state.open(key, options)?;
+        self.shared.work.notify_one();
+        Ok(KeyWriter {
+            shared: self.shared.clone(),
+            stream,
+            ended: false,
+        })
+    }
+
+    /// Wait for the next key, `None` once either side closed the
+    /// connection.
+    pub async fn receive(&self) -> io::Result<Option<(String, KeyReader)>> {
+        let accepted =
+            poll_fn(|cx| self.shared.poll(cx, Waiting::Readable, State::poll_accept)).await?;
+        Ok(accepted.map(|(key, metadata, stream)| {
+            let reader = KeyReader {
+                shared: self.shared.clone(),
+                stream,
+                metadata,
+            };
+            (key, reader)
+        }))
+    }
+
+    /// Send the keys written so far, then close the connection. Keys still
+    /// being written end with what their writers wrote, keys still being
+    /// received are cut off. Closing again has no effect. Fails with
+    /// [`TimedOut`](io::ErrorKind::TimedOut) after tearing the connection
+    /// down if the keys don't go out within the
+    /// [close timeout](Config::close_timeout).
+    ///
+    /// What the peer still sends is read and thrown away in the background
+    /// until it closes its side too, see [`crate::conn::Conn::close`].
+    pub async fn close(&self) -> io::Result<()> {
+        let result = {
+            let mut inner = self.shared.lock();
+            let result = if inner.state.closing {
+                Ok(())
+            } else {
+                inner.state.check()
+            };
+            inner.state.close();
+            result
+        };
+        self.shared.wake_all();
+        let writer = self.writer.lock().unwrap().take();
+        if let Some(mut writer) = writer {
+            let timeout = self.shared.lock().state.close_timeout;
+            if tokio::time::timeout(timeout, &mut writer).await.is_err() {
+                writer.abort();
+                self.tear_down(CLOSE_TIMED_OUT);
+                return Err(io::Error::new(io::ErrorKind::TimedOut, CLOSE_TIMED_OUT));
+            }
+        }
+        // the reader task exits at the peer's end of the connection
+
+        // the writer task may have failed while draining
+        result.and(match self.shared.lock().state.error.take() {
+            Some((kind, message)) => Err(io::Error::new(kind, message)),
+            None => Ok(()),
+        })
+    }
+}
+
+impl Conn {
+    /// Tear the connection down at once, like dropping it, see
+    /// [`crate::conn::Conn::abort`].
+    pub fn abort(&self) {
+        self.tear_down("the connection was aborted");
+    }
+
+    fn tear_down(&self, message: &str) {
+        let error = io::Error::new(io::ErrorKind::ConnectionAborted, message);
+        self.shared.lock().state.abort(&error);
+        self.shared.wake_all();
+        if let Some(writer) = self.writer.lock().unwrap().take() {
+            writer.abort();
+        }
+        self.reader.abort();
+    }
+}
+
+impl Drop for Conn {
+    fn drop(&mut self) {
+        if self.writer.get_mut().unwrap().is_some() {
+            self.tear_down("the connection was dropped");
+        }
+    }
+}
+
+/// The next frame, `None` if the input ends before it.
+async fn read_frame<R: AsyncRead + Unpin>(input: &mut R) -> io::Result<Option<Frame>> {
+    let mut kind = [0];
+    if input.read(&mut kind).await? == 0 {
+        return Ok(None);
+    }
+
+    let mut fields = vec![0; Frame::fields_len(kind[0])?];
+    input.read_exact(&mut fields).await?;
+    let (frame, len) = Frame::decode(kind[0], &fields)?;
+    let mut body = vec![0; len];
+    input.read_exact(&mut body).await?;
+    frame.with_body(body).map(Some)
+}
+
+async fn read_loop<R: AsyncRead + Unpin>(shared: Arc<Shared>, mut input: R) {
+    loop {
+        let frame = match read_frame(&mut input).await {
+            Ok(Some(frame)) => frame,
+            Ok(None) => {
+                shared.lock().state.peer_done = true;
+                return shared.wake_all();
+            }
+            Err(e) => return shared.fail(&e),
+        };
+
+        let result = shared.lock().state.receive_frame(frame);
+        if let Err(e) = result {
+            return shared.fail(&e);
+        }
+        shared.wake_all();
+    }
+}
+
+async fn write_loop<W: AsyncWrite + Unpin>(shared: Arc<Shared>, mut output: W) {
+    let mut unflushed = false;
+    let result = loop {
+        let (frame, wake_at) = {
+            let mut inner = shared.lock();
+            if inner.state.error.is_some() {
+                return;
+            }
+            let frame = inner.state.next_frame();
+            (frame, inner.state.wake_at(Instant::now()))
+        };
+
+        let Some(frame) = frame else {
+            if unflushed {
+                // nothing else to batch with, let it out
+                unflushed = false;
+                if let Err(e) = output.flush().await {
+                    break Err(e);
+                }
+            } else if let Some(wake_at) = wake_at {
+                let wake_at = tokio::time::Instant::from_std(wake_at);
+                let _ = tokio::time::timeout_at(wake_at, shared.work.notified()).await;
+            } else {
+                shared.work.notified().await;
+            }
+            continue;
+        };
+        shared.wake(Waiting::Writable);
+
+        let written = async {
+            output.write_all(&frame.header()).await?;
+            output.write_all(&frame.body()).await
+        };
+        if let Err(e) = written.await {
+            break Err(e);
+        }
+        if frame == Frame::GoAway {
+            break output.shutdown().await;
+        }
+        unflushed = true;
+    };
+
+    match result {
+        Ok(()) => shared.lock().state.finished = true,
+        Err(e) => shared.fail(&e),
+    }
+}
+
+/// Writes the data of one key, see [`Conn::send`]. Writes wait while
+/// [`INITIAL_WINDOW`](crate::conn::INITIAL_WINDOW) bytes of the key wait to
+/// be sent; shutting down ends the key and waits for all of it to go out.
+pub struct KeyWriter {
+    shared: Arc<Shared>,
+    stream: u32,
+    ended: bool,
+}
+
+impl KeyWriter {
+    /// Give up on the key: what wasn't sent yet is dropped and the peer's
+    /// reader fails with [`ConnectionAborted`](io::ErrorKind::ConnectionAborted)
+    /// and `reason` after what it got. The connection carries on.
+    pub fn abort(mut self, reason: &str) -> io::Result<()> {
+        self.ended = true;
+        let result = self.shared.lock().state.abort_stream(self.stream, reason);
+        self.shared.work.notify_one();
+        result
+    }
+
+    fn end(&mut self) -> io::Result<()> {
+        self.ended = true;
+        let result = self.shared.lock().state.end(self.stream);
+        self.shared.work.notify_one();
+        result
+    }
+}
+
+impl AsyncWrite for KeyWriter {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let stream = self.stream;
+        let written = self
+            .shared
+            .poll(cx, Waiting::Writable, |state| state.poll_write(stream, buf));
+        if written.is_ready() {
+            self.shared.work.notify_one();
+        }
+        written
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let stream = self.stream;
+        self.shared
+            .poll(cx, Waiting::Writable, |state| state.poll_flush(stream))
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        if !self.ended {
+            self.end()?;
+        }
+        self.poll_flush(cx)
+    }
+}
+
+impl Drop for KeyWriter {
+    fn drop(&mut self) {
+        if !self.ended {
+            let _ = self.end();
+        }
+    }
+}
+
+/// Reads the data of one key, see [`Conn::receive`].
+///
+/// Dropping it early [rejects](KeyReader::reject) the rest of the key.
+pub struct KeyReader {
+    shared: Arc<Shared>,
+    stream: u32,
+    metadata: Metadata,
+}
+
+impl KeyReader {
+    /// What the sender told about the key, empty if nothing.
+    pub fn metadata(&self) -> &Metadata {
+        &self.metadata
+    }
+
+    /// Tell the sender to stop, its writer fails with
+    /// [`ConnectionRefused`](io::ErrorKind::ConnectionRefused) and
+    /// `reason`. The connection carries on.
+    pub fn reject(self, reason: &str) {
+        if self.shared.lock().state.reject(self.stream, reason) {
+            self.shared.work.notify_one();
+        }
+    }
+}
+
+impl AsyncRead for KeyReader {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let stream = self.stream;
+        let read = self.shared.poll(cx, Waiting::Readable, |state| {
+            state.poll_read(stream, buf.initialize_unfilled())
+        });
+        let Poll::Ready(n) = read? else {
+            return Poll::Pending;
+        };
+        buf.advance(n);
+        self.shared.work.notify_one();
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl Drop for KeyReader {
+    fn drop(&mut self) {
+        if self.shared.lock().state.reject(self.stream, "") {
+            self.shared.work.notify_one();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+    use std::future::Future;
+    use std::net::SocketAddr;
+    use std::time::Duration;
+
+    use sha2::{Digest, Sha256};
+    use tokio::net::{TcpListener, TcpStream};
+
+    use crate::conn::tests::{hex, new_random_key, Random};
+
+    /// Accepts one connection and runs `handle` on it.
+    async fn start_server<F, H>(handle: H) -> (SocketAddr, JoinHandle<()>)
+    where
+        H: FnOnce(Conn) -> F + Send + 'static,
+        F: Future<Output = ()> + Send,
+    {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let server = tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            handle(Conn::new(stream)).await;
+        });
+        (address, server)
+    }
+
+    async fn dial(address: SocketAddr) -> Conn {
+        Conn::new(TcpStream::connect(address).await.unwrap())
+    }
+
+    async fn read_random_data(reader: &mut (impl AsyncRead + Unpin)) -> String {
+        let mut hash = Sha256::new();
+        let mut buf = vec![0; 23 << 20];
+        loop {
+            let n = reader.read(&mut buf).await.unwrap();
+            if n == 0 {
+                break;
+            }
+            hash.update(&buf[..n]);
+        }
+        hex(&hash.finalize())
+    }
+
+    async fn write_random_data(
+        writer: &mut (impl AsyncWrite + Unpin),
+        size: usize,
+        buf_size: usize,
+    ) -> String {
+        let mut hash = Sha256::new();
+        let mut random = Random::new();
+        let mut buf = vec![0; buf_size];
+        for _ in 0..size / buf_size {
+            random.fill(&mut buf);
+            hash.update(&buf);
+            writer.write_all(&buf).await.unwrap();
+        }
+        hex(&hash.finalize())
+    }
+
+    #[tokio::test]
+    async fn test_case_0() {
+        const KEY: &str = "Bible";
+        const DATA: &str = "Then I heard the voice of the Lord saying, \u{201c}Whom shall I send? And who will go for us?\u{201d}
+And I said, \u{201c}Here am I. Send me!\u{201d}
+Isaiah 6:8";
+
+        let (address, server) = start_server(|conn| async move {
+            let (key, mut reader) = conn.receive().await.unwrap().unwrap();
+            assert_eq!(key, KEY);
+            let mut data = String::new();
+            reader.read_to_string(&mut data).await.unwrap();
+            assert_eq!(data, DATA);
+
+            // never shut down, closing the connection ends the key
+            let mut writer = conn.send(KEY).unwrap();
+            assert_eq!(writer.write(DATA.as_bytes()).await.unwrap(), DATA.len());
+            conn.close().await.unwrap();
+        })
+        .await;
+
+        let conn = dial(address).await;
+        let mut writer = conn.send(KEY).unwrap();
+        assert_eq!(writer.write(DATA.as_bytes()).await.unwrap(), DATA.len());
+        writer.shutdown().await.unwrap();
+
+        let (key, mut reader) = conn.receive().await.unwrap().unwrap();
+        assert_eq!(key, KEY);
+        let mut data = String::new();
+        reader.read_to_string(&mut data).await.unwrap();
+        assert_eq!(data, DATA);
+        assert!(conn.receive().await.unwrap().is_none());
+        conn.close().await.unwrap();
+        server.await.unwrap();
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_case_1() {
+        const DATA_SIZE: usize = 500 << 20;
+        const BUF_SIZE: usize = 1 << 20;
+
+        let checksums = Arc::new(Mutex::new(HashMap::<String, String>::new()));
+        let (address, server) = start_server({
+            let checksums = checksums.clone();
+            |conn| async move {
+                let (key, mut reader) = conn.receive().await.unwrap().unwrap();
+                let checksum = read_random_data(&mut reader).await;
+                let expected = checksums.lock().unwrap().get(&key).cloned();
+                assert_eq!(Some(checksum), expected, "{}", key);
+
+                for key in [new_random_key(), new_random_key()] {
+                    let mut writer = conn.send(&key).unwrap();
+                    let checksum = write_random_data(&mut writer, DATA_SIZE, BUF_SIZE).await;
+                    checksums.lock().unwrap().insert(key, checksum);
+                    writer.shutdown().await.unwrap();
+                }
+                conn.close().await.unwrap();
+            }
+        })
+        .await;
+
+        let conn = dial(address).await;
+        let key = new_random_key();
+        let mut writer = conn.send(&key).unwrap();
+        let checksum = write_random_data(&mut writer, DATA_SIZE, BUF_SIZE).await;
+        checksums.lock().unwrap().insert(key, checksum);
+        writer.shutdown().await.unwrap();
+
+        let mut keys = 0;
+        while let Some((key, mut reader)) = conn.receive().await.unwrap() {
+            let checksum = read_random_data(&mut reader).await;
+            let expected = checksums.lock().unwrap().get(&key).cloned();
+            assert_eq!(Some(checksum), expected, "{}", key);
+            keys += 1;
+        }
+        assert_eq!(keys, 2);
+        conn.close().await.unwrap();
+        server.await.unwrap();
+    }
+
+    /// Over an in-memory pipe, one key left unread while another and the
+    /// reverse direction carry on.
+    #[tokio::test]
+    async fn test_any_transport() {
+        const SIZE: usize = 4 * crate::conn::INITIAL_WINDOW as usize;
+
+        let (a, b) = tokio::io::duplex(64 << 10);
+        let (a, b) = (Conn::new(a), Conn::new(b));
+
+        let mut slow = a.send("slow").unwrap();
+        let mut fast = a.send("fast").unwrap();
+        let writing = tokio::spawn(async move {
+            let data = vec![1; SIZE];
+            let slow = slow.write_all(&data);
+            let fast = async {
+                fast.write_all(&vec![2; SIZE]).await?;
+                fast.shutdown().await
+            };
+            tokio::join!(slow, fast)
+        });
+
+        let (key, _slow) = b.receive().await.unwrap().unwrap();
+        assert_eq!(key, "slow");
+        let (key, mut fast) = b.receive().await.unwrap().unwrap();
+        assert_eq!(key, "fast");
+        let mut data = Vec::new();
+        fast.read_to_end(&mut data).await.unwrap();
+        assert!(data.len() == SIZE && data.iter().all(|&b| b == 2));
+
+        let mut reply = b.send("reply").unwrap();
+        reply.write_all(b"ok").await.unwrap();
+        reply.shutdown().await.unwrap();
+        let (key, mut reply) = a.receive().await.unwrap().unwrap();
+        assert_eq!(key, "reply");
+        data.clear();
+        reply.read_to_end(&mut data).await.unwrap();
+        assert_eq!(data, b"ok");
+
+        // dropping the slow reader stops its writer
+        drop(_slow);
+        let (slow, fast) = writing.await.unwrap();
+        assert_eq!(slow.unwrap_err().kind(), io::ErrorKind::ConnectionRefused);
+        fast.unwrap();
+        a.close().await.unwrap();
+        assert!(b.receive().await.unwrap().is_none());
+        b.close().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_integrity_is_negotiated() {
+        let (a, b) = tokio::io::duplex(64 << 10);
+        let crc32c_only = Integrity {
+            crc32c: true,
+            sha256: false,
+        };
+        let a = Conn::with_config(a, Config::new().integrity(crc32c_only));
+        let b = Conn::new(b);
+
+        let mut writer = a.send("key").unwrap();
+        writer.write_all(b"checked").await.unwrap();
+        writer.shutdown().await.unwrap();
+        let (_, mut reader) = b.receive().await.unwrap().unwrap();
+        let mut data = Vec::new();
+        reader.read_to_end(&mut data).await.unwrap();
+        assert_eq!(data, b"checked");
+        assert_eq!(a.integrity(), Some(crc32c_only));
+        assert_eq!(b.integrity(), Some(crc32c_only));
+
+        a.close().await.unwrap();
+        b.close().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_metadata_arrives_before_the_data() {
+        let (a, b) = tokio::io::duplex(64 << 10);
+        let (a, b) = (Conn::new(a), Conn::new(b));
+
+        let options = SendOptions::new()
+            .content_type("image/png")
+            .length(4)
+            .header("frame", "7")
+            .compression(Compression::Lz4);
+        let mut writer = a.send_with("screenshot", &options).unwrap();
+        writer.write_all(b"\x89PNG").await.unwrap();
+        writer.shutdown().await.unwrap();
+
+        let (_, mut reader) = b.receive().await.unwrap().unwrap();
+        let expected = Metadata {
+            content_type: Some("image/png".to_string()),
+            length: Some(4),
+            headers: vec![("frame".to_string(), "7".to_string())],
+        };
+        assert_eq!(reader.metadata(), &expected);
+        let mut data = Vec::new();
+        reader.read_to_end(&mut data).await.unwrap();
+        assert_eq!(data, b"\x89PNG");
+
+        a.close().await.unwrap();
+        b.close().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_aborted_key_fails_the_reader() {
+        let (a, b) = tokio::io::duplex(64 << 10);
+        let (a, b) = (Conn::new(a), Conn::new(b));
+
+        let mut writer = a.send("aborted").unwrap();
+        writer.write_all(b"partial").await.unwrap();
+        writer.flush().await.unwrap();
+        writer.abort("the source went away").unwrap();
+        let mut writer = a.send("kept").unwrap();
+        writer.write_all(b"kept").await.unwrap();
+        writer.shutdown().await.unwrap();
+
+        let (_, mut reader) = b.receive().await.unwrap().unwrap();
+        let mut data = Vec::new();
+        let error = reader.read_to_end(&mut data).await.unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::ConnectionAborted);
+        assert_eq!(
+            error.to_string(),
+            "the sender aborted the key: the source went away"
+        );
+        assert_eq!(data, b"partial");
+        let (key, mut reader) = b.receive().await.unwrap().unwrap();
+        assert_eq!(key, "kept");
+        data.clear();
+        reader.read_to_end(&mut data).await.unwrap();
+        assert_eq!(data, b"kept");
+
+        a.close().await.unwrap();
+        b.close().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_rate_limit_holds_the_writer_back() {
+        const MAX_DATA: usize = crate::conn::MAX_DATA;
+
+        let (a, b) = tokio::io::duplex(64 << 10);
+        let a = Conn::with_config(a, Config::new().rate_limit(1 << 20));
+        let b = Conn::new(b);
+
+        let start = Instant::now();
+        let mut writer = a.send("limited").unwrap();
+        writer.write_all(&[0; 10 * MAX_DATA]).await.unwrap();
+        writer.shutdown().await.unwrap();
+        let (_, mut reader) = b.receive().await.unwrap().unwrap();
+        let mut data = Vec::new();
+        reader.read_to_end(&mut data).await.unwrap();
+        assert_eq!(data.len(), 10 * MAX_DATA);
+        // the last frame waits for the nine before it, less a burst
+        assert!(start.elapsed() >= Duration::from_millis(9 * 62 - 50));
+
+        a.close().await.unwrap();
+        b.close().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_close_ends_open_keys() {
+        let (a, b) = tokio::io::duplex(64 << 10);
+        let (a, b) = (Conn::new(a), Conn::new(b));
+
+        let mut ended = a.send("ended").unwrap();
+        ended.write_all(&[1; 1 << 20]).await.unwrap();
+        ended.shutdown().await.unwrap();
+        let mut open = a.send("open").unwrap();
+        open.write_all(b"partial").await.unwrap();
+        let reading = tokio::spawn(async move {
+            let (_, mut reader) = b.receive().await.unwrap().unwrap();
+            let mut data = Vec::new();
+            reader.read_to_end(&mut data).await.unwrap();
+            assert_eq!(data.len(), 1 << 20);
+            let (_, mut reader) = b.receive().await.unwrap().unwrap();
+            data.clear();
+            reader.read_to_end(&mut data).await.unwrap();
+            assert_eq!(data, b"partial");
+            assert!(b.receive().await.unwrap().is_none());
+            b.close().await.unwrap();
+        });
+
+        a.close().await.unwrap();
+        a.close().await.unwrap();
+        let error = open.write_all(b"more").await.unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::NotConnected);
+        assert!(a.receive().await.unwrap().is_none());
+        reading.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_close_gives_up_on_a_peer_not_reading() {
+        const TIMEOUT: Duration = Duration::from_millis(200);
+        const INITIAL_WINDOW: usize = crate::conn::INITIAL_WINDOW as usize;
+
+        let (a, b) = tokio::io::duplex(64 << 10);
+        let a = Conn::with_config(a, Config::new().close_timeout(TIMEOUT));
+        let b = Conn::new(b);
+
+        let mut writer = a.send("unread").unwrap();
+        writer
+            .write_all(&vec![1; 2 * INITIAL_WINDOW])
+            .await
+            .unwrap();
+        let (_, _reader) = b.receive().await.unwrap().unwrap();
+        let started = Instant::now();
+        let error = a.close().await.unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::TimedOut);
+        assert!(started.elapsed() >= TIMEOUT);
+        let error = writer.write_all(&[1]).await.unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::ConnectionAborted);
+    }
+
+    #[tokio::test]
+    async fn test_idle_connection_closes_itself() {
+        const IDLE: Duration = Duration::from_millis(100);
+
+        let (a, b) = tokio::io::duplex(64 << 10);
+        let a = Conn::with_config(a, Config::new().idle_timeout(IDLE));
+        let b = Conn::new(b);
+
+        let start = Instant::now();
+        assert!(b.receive().await.unwrap().is_none());
+        assert!(start.elapsed() >= IDLE);
+        assert!(a.receive().await.unwrap().is_none());
+        a.close().await.unwrap();
+        b.close().await.unwrap();
+    }
+}
@@ -0,0 +1,126 @@
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+
+use crate::error::{Error, Result};
+use crate::frame::{Frame, BYTES_PER_PIXEL};
+use crate::redact;
+
+const FILE_HEADER_SIZE: u32 = 14;
+const INFO_HEADER_SIZE: u32 = 40;
+
+/// Encode `frame` as a top-down 32 bit BMP.
+///
+/// The mandatory redaction configured through [`redact::install`] is applied
+/// before any pixel is written.
+pub fn write_bmp<W: Write>(writer: &mut W, frame: &Frame) -> io::Result<()> {
+    let frame = redact::prepare(frame);
+    let img_buffer_size = frame.width * frame.height * BYTES_PER_PIXEL as u32;
+
+    write_headers(
+        writer,
+        img_buffer_size,
+        frame.width as i32,
+        frame.height as i32,
+    )?;
+    for y in 0..frame.height {
+        writer.write_all(frame.row(y))?;
+    }
+
+    Ok(())
+}
+
+pub fn save_bmp<P: AsRef<Path>>(path: P, frame: &Frame) -> io::Result<()> {
+    let mut writer = BufWriter::new(File::create(path)?);
+    write_bmp(&mut writer, frame)?;
+    writer.flush()
+}
+
+pub fn rgba_to_bmp(
+    filename: &str,
+    img_data: &[u8],
+    img_buffer_size: u32,
+    width: i32,
+    height: i32,
+) -> Result<()> {
+    let img_data = img_data.get(..img_buffer_size as usize).ok_or_else(|| {
+        Error::InvalidGeometry(format!(
+            "{} bytes of image data, expected {}",
+            img_data.len(),
+            img_buffer_size
+        ))
+    })?;
+
+    if redact::mandatory().is_some() {
+        let frame =
+            Frame::from_bgra(width as u32, height as u32, img_data.to_vec()).ok_or_else(|| {
+                Error::InvalidGeometry(format!(
+                    "{} bytes of image data is too short for {}x{}",
+                    img_data.len(),
+                    width,
+                    height
+                ))
+            })?;
+        return Ok(save_bmp(filename, &frame)?);
+    }
+
+    let mut file = File::create(filename)?;
+    write_headers(&mut file, img_buffer_size, width, height)?;
+    file.write_all(img_data)?;
+
+    Ok(())
+}
+
+fn write_headers<W: Write>(
+    writer: &mut W,
+    img_buffer_size: u32,
+    width: i32,
+    height: i32,
+) -> io::Result<()> {
+    let bf_off_bits = FILE_HEADER_SIZE + INFO_HEADER_SIZE;
+
+    // BITMAPFILEHEADER
+    writer.write_all(&0x4D42u16.to_le_bytes())?;
+    writer.write_all(&(bf_off_bits + img_buffer_size).to_le_bytes())?;
+    writer.write_all(&0u16.to_le_bytes())?;
+    writer.write_all(&0u16.to_le_bytes())?;
+    writer.write_all(&bf_off_bits.to_le_bytes())?;
+
+    // BITMAPINFOHEADER, a negative height means the rows are stored top-down.
+    writer.write_all(&INFO_HEADER_SIZE.to_le_bytes())?;
+    writer.write_all(&width.to_le_bytes())?;
+    writer.write_all(&(-height).to_le_bytes())?;
+    writer.write_all(&1u16.to_le_bytes())?;
+    writer.write_all(&32u16.to_le_bytes())?;
+    writer.write_all(&0u32.to_le_bytes())?; // BI_RGB
+    writer.write_all(&img_buffer_size.to_le_bytes())?;
+    writer.write_all(&[0u8; 16])?; // resolution and palette
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_write_bmp() {
+        let mut frame = Frame::new(2, 2);
+        frame.set_pixel(1, 1, [1, 2, 3, 4]);
+
+        let mut buf = Vec::new();
+        write_bmp(&mut buf, &frame).unwrap();
+
+        assert_eq!(&buf[..2], b"BM");
+        assert_eq!(buf.len(), 54 + 16);
+        assert_eq!(u32::from_le_bytes(buf[2..6].try_into().unwrap()), 70);
+        assert_eq!(i32::from_le_bytes(buf[22..26].try_into().unwrap()), -2);
+        assert_eq!(&buf[66..], &[1, 2, 3, 4]);
+    }
+
+    #[test]
+    fn test_rgba_to_bmp_short_data() {
+        let e = rgba_to_bmp("unused.bmp", &[0; 12], 16, 2, 2).unwrap_err();
+        assert!(matches!(e, Error::InvalidGeometry(_)));
+    }
+}
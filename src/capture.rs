@@ -0,0 +1,662 @@
+//! Long running capture on top of a platform [`Backend`].
+//!
+//! Desktop switches, UAC prompts and resolution changes invalidate the
+//! platform capture objects. A [`Session`] notices that, rebuilds the backend
+//! with exponential [`Backoff`] and reports the new output geometry before
+//! handing out frames again.
+//!
+//! [`spawn`] runs a session on its own thread for continuous capture, paced
+//! by a [`Pacer`] and with frame buffers recycled through a [`FramePool`].
+
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, Receiver};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+use crate::error::{Error, Result};
+use crate::frame::{Frame, FrameInfo, Rect};
+use crate::output::Adapter;
+use crate::pool::{FramePool, PooledFrame};
+
+/// A source of desktop frames, e.g. DXGI desktop duplication.
+pub trait Backend {
+    /// Create, or recreate after a failure, everything needed to capture.
+    /// Returns the captured area in desktop coordinates.
+    fn open(&mut self) -> Result<Rect>;
+
+    /// Wait for the next frame. [`Error::AccessLost`] means the backend has
+    /// to be opened again.
+    fn next_frame(&mut self) -> Result<Frame>;
+
+    /// Like [`next_frame`](Self::next_frame), but backends that can should
+    /// store the pixels in `buffer` instead of allocating.
+    fn next_frame_into(&mut self, buffer: Vec<u8>) -> Result<Frame> {
+        drop(buffer);
+        self.next_frame()
+    }
+
+    /// The adapters and outputs this backend can capture.
+    fn adapters(&mut self) -> Result<Vec<Adapter>> {
+        Err(Error::backend("this backend can't enumerate outputs"))
+    }
+}
+
+impl<B: Backend + ?Sized> Backend for Box<B> {
+    fn open(&mut self) -> Result<Rect> {
+        (**self).open()
+    }
+
+    fn next_frame(&mut self) -> Result<Frame> {
+        (**self).next_frame()
+    }
+
+    fn next_frame_into(&mut self, buffer: Vec<u8>) -> Result<Frame> {
+        (**self).next_frame_into(buffer)
+    }
+
+    fn adapters(&mut self) -> Result<Vec<Adapter>> {
+        (**self).adapters()
+    }
+}
+
+/// Delays between attempts to reopen a backend, doubling from `initial` up
+/// to `max`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Backoff {
+    pub initial: Duration,
+    pub max: Duration,
+    /// Give up after this many failed attempts in a row, `None` retries
+    /// forever.
+    pub max_attempts: Option<u32>,
+}
+
+impl Default for Backoff {
+    fn default() -> Self {
+        Self {
+            initial: Duration::from_millis(50),
+            max: Duration::from_secs(2),
+            max_attempts: None,
+        }
+    }
+}
+
+impl Backoff {
+    /// The delay before retrying after `attempt` failures, `None` once the
+    /// attempts are used up.
+    pub fn delay(&self, attempt: u32) -> Option<Duration> {
+        if self.max_attempts.is_some_and(|max| attempt >= max) {
+            return None;
+        }
+
+        let factor = 1u32
+            .checked_shl(attempt.saturating_sub(1))
+            .unwrap_or(u32::MAX);
+        Some(self.initial.saturating_mul(factor).min(self.max))
+    }
+}
+
+/// What a [`Session`] hands to its consumer.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Event<F = Frame> {
+    /// The captured area changed, or was opened for the first time. Frames
+    /// that follow have this size and origin.
+    Geometry(Rect),
+    Frame(F),
+}
+
+/// Supervises a [`Backend`], reopening it whenever access is lost or the
+/// output changes size.
+pub struct Session<B> {
+    backend: B,
+    backoff: Backoff,
+    sleep: Box<dyn FnMut(Duration) + Send>,
+    geometry: Option<Rect>,
+    open: bool,
+    pending: Option<Frame>,
+    pool: FramePool,
+}
+
+impl<B: Backend> Session<B> {
+    pub fn new(backend: B) -> Self {
+        Self {
+            backend,
+            backoff: Backoff::default(),
+            sleep: Box::new(thread::sleep),
+            geometry: None,
+            open: false,
+            pending: None,
+            pool: FramePool::new(4),
+        }
+    }
+
+    /// Where the buffers for new frames come from, see [`Session::pool`].
+    pub fn with_pool(mut self, pool: FramePool) -> Self {
+        self.pool = pool;
+        self
+    }
+
+    pub fn backoff(mut self, backoff: Backoff) -> Self {
+        self.backoff = backoff;
+        self
+    }
+
+    /// Replace [`thread::sleep`] for waiting between attempts, mainly so
+    /// tests don't have to wait.
+    pub fn sleep_with<F: FnMut(Duration) + Send + 'static>(mut self, sleep: F) -> Self {
+        self.sleep = Box::new(sleep);
+        self
+    }
+
+    /// The captured area as of the last [`Event::Geometry`].
+    pub fn geometry(&self) -> Option<Rect> {
+        self.geometry
+    }
+
+    pub fn backend(&self) -> &B {
+        &self.backend
+    }
+
+    /// Frames are captured into buffers from this pool, hand them back with
+    /// [`FramePool::recycle`] or [`FramePool::wrap`] to avoid allocations.
+    pub fn pool(&self) -> &FramePool {
+        &self.pool
+    }
+
+    /// The next event, reopening the backend first if needed.
+    ///
+    /// Errors other than [`Error::AccessLost`] are passed through and leave
+    /// the backend open, so [`Error::Timeout`] can simply be retried.
+    pub fn next_event(&mut self) -> Result<Event> {
+        if let Some(frame) = self.pending.take() {
+            return Ok(Event::Frame(frame));
+        }
+
+        loop {
+            if !self.open {
+                let geometry = self.reopen()?;
+                if self.geometry != Some(geometry) {
+                    self.geometry = Some(geometry);
+                    return Ok(Event::Geometry(geometry));
+                }
+            }
+
+            match self.backend.next_frame_into(self.pool.take()) {
+                Ok(frame) => return Ok(self.check_geometry(frame)),
+                Err(Error::AccessLost) => self.open = false,
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    /// The next frame, skipping geometry events.
+    pub fn next_frame(&mut self) -> Result<Frame> {
+        loop {
+            if let Event::Frame(frame) = self.next_event()? {
+                return Ok(frame);
+            }
+        }
+    }
+
+    fn reopen(&mut self) -> Result<Rect> {
+        let mut attempt = 0;
+        loop {
+            match self.backend.open() {
+                Ok(geometry) => {
+                    self.open = true;
+                    return Ok(geometry);
+                }
+                Err(e) if e.is_recoverable() => {
+                    attempt += 1;
+                    match self.backoff.delay(attempt) {
+                        Some(delay) => (self.sleep)(delay),
+                        None => return Err(e),
+                    }
+                }
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    /// Frames of a different size than announced mean the mode changed
+    /// without the backend noticing, announce the new geometry first.
+    fn check_geometry(&mut self, frame: Frame) -> Event {
+        let geometry = Rect::new(frame.origin.0, frame.origin.1, frame.width, frame.height);
+        if self.geometry == Some(geometry) {
+            return Event::Frame(frame);
+        }
+
+        self.geometry = Some(geometry);
+        self.pending = Some(frame);
+        Event::Geometry(geometry)
+    }
+}
+
+impl<B: Backend> Iterator for Session<B> {
+    type Item = Result<Event>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        Some(self.next_event())
+    }
+}
+
+/// How often a continuously running capture delivers frames.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Pace {
+    /// As fast as the backend produces them.
+    Max,
+    /// At most this many frames per second.
+    Fps(u32),
+    /// Only frames with new desktop content, cursor-only updates and
+    /// repeated presents are dropped.
+    OnChange,
+}
+
+/// Decides when to capture and which frames to deliver for a [`Pace`].
+///
+/// It never reads the clock itself, callers pass the current time in.
+#[derive(Debug, Clone)]
+pub struct Pacer {
+    pace: Pace,
+    next: Option<Instant>,
+    last_present_time: Option<i64>,
+}
+
+impl Pacer {
+    pub fn new(pace: Pace) -> Self {
+        Self {
+            pace,
+            next: None,
+            last_present_time: None,
+        }
+    }
+
+    /// How long to wait at `now` before capturing the next frame.
+    pub fn delay(&self, now: Instant) -> Duration {
+        self.next
+            .map_or(Duration::ZERO, |next| next.saturating_duration_since(now))
+    }
+
+    /// Whether the frame captured at `now` should be delivered.
+    pub fn admit(&mut self, info: &FrameInfo, now: Instant) -> bool {
+        match self.pace {
+            Pace::Max => true,
+            Pace::Fps(fps) => {
+                let interval = Duration::from_secs(1) / fps.max(1);
+                // stay on the schedule unless we fell behind by a whole
+                // interval, then restart from now instead of bursting
+                let base = self
+                    .next
+                    .filter(|&next| next + interval > now)
+                    .unwrap_or(now);
+                self.next = Some(base + interval);
+                true
+            }
+            Pace::OnChange => {
+                if info.accumulated_frames == 0
+                    || self.last_present_time == Some(info.last_present_time)
+                {
+                    return false;
+                }
+                self.last_present_time = Some(info.last_present_time);
+                true
+            }
+        }
+    }
+}
+
+/// Events of a session running on a capture thread, see [`spawn`].
+///
+/// Dropping it stops the thread.
+pub struct Frames {
+    receiver: Option<Receiver<Result<Event<PooledFrame>>>>,
+    stop: Arc<AtomicBool>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl Iterator for Frames {
+    type Item = Result<Event<PooledFrame>>;
+
+    /// Blocks until the next event, `None` once the thread stopped after
+    /// reporting an error.
+    fn next(&mut self) -> Option<Self::Item> {
+        self.receiver.as_ref()?.recv().ok()
+    }
+}
+
+impl Drop for Frames {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::Relaxed);
+        // unblocks a capture thread waiting for room in the channel
+        self.receiver.take();
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+/// Capture continuously on a new thread.
+///
+/// `open` builds the session on that thread, so backends don't have to be
+/// `Send`. At most `depth` events wait in the channel; when the consumer
+/// falls behind the thread blocks instead of capturing more. Timeouts are
+/// skipped, any other error is delivered and ends the capture.
+pub fn spawn<B, F>(open: F, pace: Pace, depth: usize) -> Frames
+where
+    B: Backend,
+    F: FnOnce() -> Session<B> + Send + 'static,
+{
+    let (sender, receiver) = mpsc::sync_channel(depth);
+    let stop = Arc::new(AtomicBool::new(false));
+    let stopped = stop.clone();
+
+    let thread = thread::spawn(move || {
+        run(open(), pace, &stopped, |event| sender.send(event).is_ok());
+    });
+
+    Frames {
+        receiver: Some(receiver),
+        stop,
+        thread: Some(thread),
+    }
+}
+
+/// The loop of a capture thread: capture, pace and hand events to `deliver`
+/// until it returns `false`, `stop` is set or an error was delivered.
+pub(crate) fn run<B: Backend>(
+    mut session: Session<B>,
+    pace: Pace,
+    stop: &AtomicBool,
+    mut deliver: impl FnMut(Result<Event<PooledFrame>>) -> bool,
+) {
+    let pool = session.pool().clone();
+    let mut pacer = Pacer::new(pace);
+
+    while !stop.load(Ordering::Relaxed) {
+        thread::sleep(pacer.delay(Instant::now()));
+
+        let event = match session.next_event() {
+            Ok(Event::Geometry(geometry)) => Ok(Event::Geometry(geometry)),
+            Ok(Event::Frame(frame)) => {
+                if !pacer.admit(&frame.info, Instant::now()) {
+                    pool.recycle(frame.data);
+                    continue;
+                }
+                Ok(Event::Frame(pool.wrap(frame)))
+            }
+            Err(Error::Timeout) => continue,
+            Err(e) => Err(e),
+        };
+
+        let failed = event.is_err();
+        if !deliver(event) || failed {
+            break;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::VecDeque;
+    use std::sync::atomic::AtomicUsize;
+    use std::sync::Mutex;
+
+    /// Replays scripted results of `open` and `next_frame`.
+    #[derive(Default)]
+    struct MockBackend {
+        opens: VecDeque<Result<Rect>>,
+        frames: VecDeque<Result<Frame>>,
+        opened: u32,
+    }
+
+    impl Backend for MockBackend {
+        fn open(&mut self) -> Result<Rect> {
+            self.opened += 1;
+            self.opens.pop_front().expect("unexpected open")
+        }
+
+        fn next_frame(&mut self) -> Result<Frame> {
+            self.frames.pop_front().expect("unexpected next_frame")
+        }
+    }
+
+    fn frame(width: u32, height: u32) -> Result<Frame> {
+        Ok(Frame::new(width, height))
+    }
+
+    fn session(backend: MockBackend) -> (Session<MockBackend>, Arc<Mutex<Vec<Duration>>>) {
+        let slept = Arc::new(Mutex::new(Vec::new()));
+        let log = slept.clone();
+        let session = Session::new(backend)
+            .backoff(Backoff {
+                initial: Duration::from_millis(10),
+                max: Duration::from_millis(25),
+                max_attempts: Some(4),
+            })
+            .sleep_with(move |d| log.lock().unwrap().push(d));
+        (session, slept)
+    }
+
+    #[test]
+    fn test_backoff_delay() {
+        let backoff = Backoff {
+            initial: Duration::from_millis(10),
+            max: Duration::from_millis(35),
+            max_attempts: Some(5),
+        };
+        let delays: Vec<_> = (1..=5).map(|n| backoff.delay(n)).collect();
+        assert_eq!(
+            delays,
+            [10, 20, 35, 35]
+                .map(|ms| Some(Duration::from_millis(ms)))
+                .into_iter()
+                .chain([None])
+                .collect::<Vec<_>>()
+        );
+        assert!(Backoff::default().delay(1_000).is_some());
+    }
+
+    #[test]
+    fn test_recovers_from_access_lost() {
+        let screen = Rect::new(0, 0, 4, 3);
+        let (mut session, slept) = session(MockBackend {
+            opens: [Ok(screen), Err(Error::AccessLost), Ok(screen)].into(),
+            frames: [frame(4, 3), Err(Error::AccessLost), frame(4, 3)].into(),
+            ..Default::default()
+        });
+
+        assert_eq!(session.next_event().unwrap(), Event::Geometry(screen));
+        assert!(matches!(session.next_event().unwrap(), Event::Frame(_)));
+        // same geometry after reopening, so the consumer only sees a frame
+        assert!(matches!(session.next_event().unwrap(), Event::Frame(_)));
+        assert_eq!(session.backend().opened, 3);
+        assert_eq!(*slept.lock().unwrap(), [Duration::from_millis(10)]);
+    }
+
+    #[test]
+    fn test_reports_new_geometry_after_mode_change() {
+        let (mut session, _) = session(MockBackend {
+            opens: [Ok(Rect::new(0, 0, 4, 3)), Ok(Rect::new(0, 0, 8, 6))].into(),
+            frames: [Err(Error::AccessLost), frame(8, 6), frame(2, 2)].into(),
+            ..Default::default()
+        });
+
+        assert!(matches!(session.next_event().unwrap(), Event::Geometry(_)));
+        assert_eq!(
+            session.next_event().unwrap(),
+            Event::Geometry(Rect::new(0, 0, 8, 6))
+        );
+        assert_eq!(session.next_frame().unwrap().width, 8);
+
+        // a size change the backend didn't report is announced before the frame
+        assert_eq!(
+            session.next_event().unwrap(),
+            Event::Geometry(Rect::new(0, 0, 2, 2))
+        );
+        assert_eq!(session.next_frame().unwrap().width, 2);
+        assert_eq!(session.geometry(), Some(Rect::new(0, 0, 2, 2)));
+    }
+
+    #[test]
+    fn test_gives_up_after_max_attempts() {
+        let (mut session, slept) = session(MockBackend {
+            opens: (0..4).map(|_| Err(Error::AccessLost)).collect(),
+            ..Default::default()
+        });
+
+        assert!(matches!(session.next_event(), Err(Error::AccessLost)));
+        assert_eq!(
+            *slept.lock().unwrap(),
+            [10, 20, 25].map(Duration::from_millis)
+        );
+    }
+
+    #[test]
+    fn test_passes_through_other_errors() {
+        let (mut session, slept) = session(MockBackend {
+            opens: [
+                Err(Error::UnsupportedFormat("R16G16B16A16".to_string())),
+                Ok(Rect::new(0, 0, 1, 1)),
+            ]
+            .into(),
+            frames: [Err(Error::Timeout), frame(1, 1)].into(),
+            ..Default::default()
+        });
+
+        assert!(matches!(
+            session.next_event(),
+            Err(Error::UnsupportedFormat(_))
+        ));
+        assert!(matches!(session.next_event().unwrap(), Event::Geometry(_)));
+        assert!(matches!(session.next_event(), Err(Error::Timeout)));
+        assert!(matches!(session.next_event().unwrap(), Event::Frame(_)));
+        assert_eq!(session.backend().opened, 2);
+        assert!(slept.lock().unwrap().is_empty());
+    }
+
+    /// Produces 4x4 frames with a new present time each, counting them.
+    struct FakeBackend {
+        produced: Arc<AtomicUsize>,
+        fail_after: Option<usize>,
+    }
+
+    impl Backend for FakeBackend {
+        fn open(&mut self) -> Result<Rect> {
+            Ok(Rect::new(0, 0, 4, 4))
+        }
+
+        fn next_frame(&mut self) -> Result<Frame> {
+            self.next_frame_into(Vec::new())
+        }
+
+        fn next_frame_into(&mut self, mut buffer: Vec<u8>) -> Result<Frame> {
+            let n = self.produced.fetch_add(1, Ordering::SeqCst);
+            if self.fail_after.is_some_and(|max| n >= max) {
+                return Err(Error::backend("device removed"));
+            }
+
+            buffer.resize(4 * 4 * 4, 0);
+            let info = FrameInfo {
+                accumulated_frames: 1,
+                last_present_time: n as i64,
+                ..Default::default()
+            };
+            Ok(Frame::from_bgra(4, 4, buffer).unwrap().with_info(info))
+        }
+    }
+
+    fn fake(fail_after: Option<usize>) -> (FakeBackend, Arc<AtomicUsize>) {
+        let produced = Arc::new(AtomicUsize::new(0));
+        let backend = FakeBackend {
+            produced: produced.clone(),
+            fail_after,
+        };
+        (backend, produced)
+    }
+
+    #[test]
+    fn test_pacer_fps() {
+        let start = Instant::now();
+        let ms = |n| start + Duration::from_millis(n);
+        let info = FrameInfo::default();
+        let mut pacer = Pacer::new(Pace::Fps(10));
+
+        assert_eq!(pacer.delay(start), Duration::ZERO);
+        assert!(pacer.admit(&info, ms(0)));
+        assert_eq!(pacer.delay(ms(30)), Duration::from_millis(70));
+
+        // a late capture doesn't shift the schedule
+        assert!(pacer.admit(&info, ms(120)));
+        assert_eq!(pacer.delay(ms(120)), Duration::from_millis(80));
+
+        // falling behind by more than an interval restarts it
+        assert!(pacer.admit(&info, ms(450)));
+        assert_eq!(pacer.delay(ms(450)), Duration::from_millis(100));
+    }
+
+    #[test]
+    fn test_pacer_on_change() {
+        let now = Instant::now();
+        let mut pacer = Pacer::new(Pace::OnChange);
+        let presented = |time| FrameInfo {
+            accumulated_frames: 1,
+            last_present_time: time,
+            ..Default::default()
+        };
+
+        assert!(pacer.admit(&presented(5), now));
+        assert!(!pacer.admit(&presented(5), now));
+        assert!(!pacer.admit(&FrameInfo::default(), now));
+        assert!(pacer.admit(&presented(6), now));
+        assert_eq!(pacer.delay(now), Duration::ZERO);
+    }
+
+    #[test]
+    fn test_spawn_reuses_buffers() {
+        let (backend, _) = fake(None);
+        let pool = FramePool::new(4);
+        let session_pool = pool.clone();
+        let mut frames = spawn(
+            move || Session::new(backend).with_pool(session_pool),
+            Pace::Max,
+            1,
+        );
+
+        assert!(matches!(
+            frames.next().unwrap().unwrap(),
+            Event::Geometry(_)
+        ));
+        for _ in 0..20 {
+            match frames.next().unwrap().unwrap() {
+                Event::Frame(frame) => assert_eq!(frame.data.len(), 64),
+                event => panic!("unexpected {:?}", event),
+            }
+        }
+
+        // the consumer, the channel and the capture thread hold one each
+        assert!(pool.allocations() <= 3, "{}", pool.allocations());
+    }
+
+    #[test]
+    fn test_spawn_backpressure() {
+        let (backend, produced) = fake(None);
+        let mut frames = spawn(move || Session::new(backend), Pace::Max, 2);
+
+        frames.next().unwrap().unwrap();
+        thread::sleep(Duration::from_millis(50));
+        // two waiting in the channel, one blocked in send
+        assert!(produced.load(Ordering::SeqCst) <= 3);
+
+        drop(frames);
+    }
+
+    #[test]
+    fn test_spawn_stops_on_error() {
+        let (backend, _) = fake(Some(2));
+        let events: Vec<_> = spawn(move || Session::new(backend), Pace::Max, 4).collect();
+
+        assert_eq!(events.len(), 4);
+        assert!(matches!(events[3], Err(Error::Backend(_))));
+    }
+}
@@ -0,0 +1,4436 @@
+//! Many keyed byte streams over one TCP connection.
+//!
+//! Either side [`send`](Conn::send)s a key and then writes any amount of
+//! data for it; the other side [`receive`](Conn::receive)s the key and reads
+//! the data until EOF. Any number of keys can be open at once in both
+//! directions, and a key nobody reads never holds up the others.
+//!
+//! Each direction is a sequence of frames, a type byte, the sender's stream
+//! number `u32` and the fields of the type:
+//!
+//! - `HELLO` (5): features `u32`, session ID `u64`, always stream 0. The
+//!   first frame of both sides on every connection, offering
+//!   [`Integrity`] checks, bit 0 CRC32C and bit 1 SHA-256, resuming with
+//!   bit 2 and decompressing with bit 3 LZ4 and bit 4 zstd. Each side waits
+//!   for the other's before sending more, from then on the features both
+//!   offered are used. The session ID is 0 unless resuming, the server
+//!   picks it.
+//! - `OPEN` (1): key length `u16`, the key in UTF-8. Starts a key on the
+//!   next stream number, counting from 0. `OPEN_WITH_METADATA` (12)
+//!   instead for keys with [`Metadata`], the same plus its length `u32`
+//!   after the key's, and the metadata before the key.
+//! - `DATA` (2): payload length `u32`, at most [`MAX_DATA`], then the
+//!   payload. `CHECKED_DATA` (6) instead with CRC32C, the same plus the
+//!   CRC32C `u32` of the payload after the length.
+//! - `COMPRESSED_DATA` (10), `CHECKED_COMPRESSED_DATA` (11): `DATA` and
+//!   `CHECKED_DATA` with a compressed payload, followed by its length
+//!   uncompressed `u32` and the [`Compression`] `u8`, 1 for an LZ4 block, 2
+//!   for a zstd frame. The check covers the compressed payload, lengths
+//!   and windows count the data uncompressed. Chunks that shrink by less
+//!   than an eighth go as `DATA`.
+//! - `END` (3): no fields. The key is complete and the number unused.
+//!   `CHECKED_END` (7) instead with SHA-256, followed by the 32 byte
+//!   SHA-256 of all of the key's payloads.
+//! - `WINDOW` (4): increment `u32`. Flows against the data: the receiver
+//!   consumed that much of the key the sender opened under the number. In
+//!   resumable sessions an increment of 0 acknowledges the `END`.
+//! - `RESUME` (8): the number of streams the peer opened as the stream
+//!   number, a count `u32`, then per stream still known: the stream `u32`,
+//!   bytes received `u64`, window left `u32` and whether it ended `u8`.
+//!   Follows `HELLO` on a resumed connection.
+//! - `ABORT` (13): reason length `u16`, the reason in UTF-8. Ends the key
+//!   instead of `END`, the sender gave up on it.
+//! - `REJECT` (14): reason length `u16`, the reason in UTF-8. Flows
+//!   against the data: the receiver doesn't want the rest of the key the
+//!   sender opened under the number. The sender answers with `ABORT`
+//!   unless the key ended already.
+//! - `GOAWAY` (9): no fields, always stream 0. The last frame, the sender
+//!   closes the connection.
+//!
+//! All integers are little endian. A stream may have [`INITIAL_WINDOW`]
+//! bytes of `DATA` beyond what `WINDOW` frames acknowledged in flight;
+//! sending more is a protocol error. Streams with data and window left take
+//! turns, one `DATA` frame each, those of the highest [`Priority`] first.
+//! [Rate limits](Config::rate_limit) of the connection and of single keys
+//! hold streams back without holding up the others. The connection
+//! shutting down means no more keys follow; keys it leaves open are
+//! truncated and fail to read. A key failing a check fails to read after
+//! the data received intact, the rest of it is dropped and the connection
+//! carries on, the same as for keys the sender
+//! [aborts](KeyWriter::abort) or the receiver [rejects](KeyReader::reject).
+//!
+//! [Closing](Conn::close) takes turns like this: no more keys are sent or
+//! received, keys still being written end with what was written so far as
+//! if their writers closed them and keys still arriving are rejected, the
+//! keys go out in full, then `GOAWAY` and the end of the writing side of
+//! the transport. Keys that don't go out within the
+//! [close timeout](Config::close_timeout) are failed like when aborting. The peer receives the keys
+//! before the `GOAWAY`, then `None`. [Aborting](Conn::abort) or dropping a
+//! connection instead tears it down at once, failing the keys in flight on
+//! both sides. With an [idle timeout](Config::idle_timeout) a connection
+//! closes on its own.
+//!
+//! Sessions between [`Conn::dial`] and a [`Listener`] survive the
+//! connection dropping without `GOAWAY`: the client dials again and sends
+//! the session ID, both sides tell in `RESUME` how far they got with each
+//! key and send again what the other missed, kept until `WINDOW` frames
+//! acknowledge it. Keys carry on as if nothing happened unless the client
+//! isn't back within the [resume timeout](Config::resume_timeout).
+//!
+//! A reader thread sorts incoming frames into per-key buffers, a writer
+//! thread schedules outgoing ones, so neither direction waits for the
+//! application on the other. Instead of TCP they can run over any
+//! [`Transport`], TLS with [`Conn::connect_tls`] and [`Conn::accept_tls`]
+//! for example.
+
+use std::borrow::Cow;
+use std::collections::hash_map::RandomState;
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::hash::{BuildHasher, Hasher};
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::net::{Shutdown, SocketAddr, TcpListener, TcpStream, ToSocketAddrs};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{mpsc, Arc, Condvar, Mutex, MutexGuard, Weak};
+use std::task::Poll;
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+use sha2::{Digest, Sha256};
+
+use crate::tls::TlsStream;
+
+const OPEN: u8 = 1;
+const DATA: u8 = 2;
+const END: u8 = 3;
+const WINDOW: u8 = 4;
+const HELLO: u8 = 5;
+const CHECKED_DATA: u8 = 6;
+const CHECKED_END: u8 = 7;
+const RESUME: u8 = 8;
+const GOAWAY: u8 = 9;
+const COMPRESSED_DATA: u8 = 10;
+const CHECKED_COMPRESSED_DATA: u8 = 11;
+const OPEN_WITH_METADATA: u8 = 12;
+const ABORT: u8 = 13;
+const REJECT: u8 = 14;
+
+/// Why keys are rejected when closing.
+const CLOSED: &str = "the connection closed";
+/// Why closing tore the connection down.
+pub(crate) const CLOSE_TIMED_OUT: &str = "the keys didn't go out before the close timeout";
+
+const CRC32C: u32 = 1;
+const SHA256: u32 = 2;
+const RESUMABLE: u32 = 4;
+const LZ4: u32 = 8;
+const ZSTD: u32 = 16;
+const COMPRESSIONS: u32 = LZ4 | ZSTD;
+
+/// Bytes of a stream in a `RESUME` frame.
+const RESUMED_LEN: usize = 17;
+/// Most streams a `RESUME` frame may list.
+const MAX_RESUMED: usize = 1 << 20;
+/// Largest [`Metadata`] of a key, encoded.
+pub const MAX_METADATA: usize = 16 << 10;
+
+/// Largest payload of a `DATA` frame, bigger writes are split. Also how
+/// much a stream sends before the next one gets its turn.
+pub const MAX_DATA: usize = 64 << 10;
+
+/// Bytes of a key in flight before the receiver has to grant more. Also
+/// what a [`KeyWriter`] buffers before `write` blocks.
+pub const INITIAL_WINDOW: u32 = 1 << 20;
+
+/// Checks that make reading a corrupted or truncated key fail instead of
+/// end normally. Each side offers some, a connection uses those both offer.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Integrity {
+    /// A CRC32C of every `DATA` payload, catching corruption where it
+    /// happens.
+    pub crc32c: bool,
+    /// A SHA-256 of the whole key, checked at its end.
+    pub sha256: bool,
+}
+
+impl Integrity {
+    pub const NONE: Integrity = Integrity {
+        crc32c: false,
+        sha256: false,
+    };
+    pub const ALL: Integrity = Integrity {
+        crc32c: true,
+        sha256: true,
+    };
+
+    fn bits(self) -> u32 {
+        (self.crc32c as u32 * CRC32C) | (self.sha256 as u32 * SHA256)
+    }
+
+    fn from_bits(bits: u32) -> Self {
+        Integrity {
+            crc32c: bits & CRC32C != 0,
+            sha256: bits & SHA256 != 0,
+        }
+    }
+}
+
+/// Everything is checked by default.
+impl Default for Integrity {
+    fn default() -> Self {
+        Integrity::ALL
+    }
+}
+
+/// How the data of a key is compressed on the wire, see
+/// [`SendOptions::compression`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Compression {
+    #[default]
+    None,
+    /// Fast, for data that has to go out right away.
+    Lz4,
+    /// Smaller, for logs and the like.
+    Zstd,
+}
+
+impl Compression {
+    fn bit(self) -> u32 {
+        match self {
+            Compression::None => 0,
+            Compression::Lz4 => LZ4,
+            Compression::Zstd => ZSTD,
+        }
+    }
+
+    /// Those of `bits`, in the order of preference.
+    fn from_bits(bits: u32) -> Vec<Compression> {
+        [Compression::Zstd, Compression::Lz4]
+            .into_iter()
+            .filter(|compression| bits & compression.bit() != 0)
+            .collect()
+    }
+
+    fn code(self) -> u8 {
+        match self {
+            Compression::None => 0,
+            Compression::Lz4 => 1,
+            Compression::Zstd => 2,
+        }
+    }
+
+    fn from_code(code: u8) -> io::Result<Self> {
+        match code {
+            1 => Ok(Compression::Lz4),
+            2 => Ok(Compression::Zstd),
+            code => Err(invalid(format!("unknown compression {}", code))),
+        }
+    }
+
+    /// `data` compressed, `None` if that doesn't save an eighth of it.
+    fn compress(self, data: &[u8]) -> Option<Vec<u8>> {
+        let compressed = match self {
+            Compression::None => return None,
+            Compression::Lz4 => lz4_flex::block::compress(data),
+            Compression::Zstd => {
+                zstd::bulk::compress(data, zstd::DEFAULT_COMPRESSION_LEVEL).ok()?
+            }
+        };
+        (compressed.len() <= data.len() - data.len() / 8).then_some(compressed)
+    }
+
+    /// The `len` bytes `data` was compressed from, `None` if it wasn't.
+    fn decompress(self, data: &[u8], len: usize) -> Option<Vec<u8>> {
+        let decompressed = match self {
+            Compression::None => return None,
+            Compression::Lz4 => lz4_flex::block::decompress(data, len).ok()?,
+            Compression::Zstd => zstd::bulk::decompress(data, len).ok()?,
+        };
+        (decompressed.len() == len).then_some(decompressed)
+    }
+}
+
+/// Which keys go first, see [`SendOptions::priority`]. Keys of a class
+/// only get what the keys of higher classes leave of the connection, keys
+/// of the same class take turns.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Default)]
+pub enum Priority {
+    /// Bulk data like logs.
+    Low,
+    #[default]
+    Normal,
+    /// Data someone waits for, like screenshots.
+    High,
+}
+
+/// Lets `DATA` payloads through at a rate, sending running ahead of it by
+/// up to [`BURST`](Limiter::BURST).
+#[derive(Debug, Clone)]
+struct Limiter {
+    /// Bytes per second.
+    rate: f64,
+    /// When what was let through so far would have gone out at the rate.
+    due: Option<Instant>,
+}
+
+impl Limiter {
+    const BURST: Duration = Duration::from_millis(50);
+
+    fn new(rate: u64) -> Self {
+        Limiter {
+            rate: rate as f64,
+            due: None,
+        }
+    }
+
+    /// From when on it lets data through, `None` if right away.
+    fn ready_at(&self) -> Option<Instant> {
+        self.due
+            .map(|due| due.checked_sub(Self::BURST).unwrap_or(due))
+    }
+
+    fn allows(&self, now: Instant) -> bool {
+        self.ready_at().is_none_or(|ready| ready <= now)
+    }
+
+    fn take(&mut self, len: usize, now: Instant) {
+        let due = self.due.map_or(now, |due| due.max(now));
+        self.due = Some(due + Duration::from_secs_f64(len as f64 / self.rate));
+    }
+}
+
+/// Settings of a connection, see [`Conn::with_config`].
+#[derive(Debug, Clone)]
+pub struct Config {
+    integrity: Integrity,
+    compressions: u32,
+    resume_timeout: Duration,
+    rate_limit: Option<u64>,
+    idle_timeout: Option<Duration>,
+    close_timeout: Duration,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            integrity: Integrity::ALL,
+            compressions: COMPRESSIONS,
+            resume_timeout: Duration::from_secs(30),
+            rate_limit: None,
+            idle_timeout: None,
+            close_timeout: Duration::from_secs(30),
+        }
+    }
+}
+
+impl Config {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// How long a resumable session waits for a new connection after one
+    /// dropped before it fails, 30 s by default.
+    pub fn resume_timeout(mut self, timeout: Duration) -> Self {
+        self.resume_timeout = timeout;
+        self
+    }
+
+    /// [Close](Conn::close) the connection once no keys were open either
+    /// way and no frames went either way for `timeout`. Never by default.
+    pub fn idle_timeout(mut self, timeout: Duration) -> Self {
+        self.idle_timeout = Some(timeout);
+        self
+    }
+
+    /// How long [closing](Conn::close) waits for the keys to go out before
+    /// it tears the connection down like [`Conn::abort`], 30 s by default.
+    pub fn close_timeout(mut self, timeout: Duration) -> Self {
+        self.close_timeout = timeout;
+        self
+    }
+
+    /// The checks to offer, [`Integrity::ALL`] by default.
+    pub fn integrity(mut self, integrity: Integrity) -> Self {
+        self.integrity = integrity;
+        self
+    }
+
+    /// The compressions to offer, all of them by default. Keys sent with
+    /// one the peer doesn't offer go uncompressed.
+    pub fn compressions(mut self, offered: &[Compression]) -> Self {
+        self.compressions = offered
+            .iter()
+            .fold(0, |bits, compression| bits | compression.bit());
+        self
+    }
+
+    /// Send no more than `bytes_per_second` of data on average, over all
+    /// keys. Unlimited by default.
+    ///
+    /// # Panics
+    ///
+    /// If `bytes_per_second` is 0.
+    pub fn rate_limit(mut self, bytes_per_second: u64) -> Self {
+        assert!(bytes_per_second > 0, "a rate limit of 0 sends nothing");
+        self.rate_limit = Some(bytes_per_second);
+        self
+    }
+}
+
+/// What the receiver of a key learns before its data, see
+/// [`KeyReader::metadata`].
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Metadata {
+    /// A MIME type, for example.
+    pub content_type: Option<String>,
+    /// Of the data, reading fails if it turns out to be different.
+    pub length: Option<u64>,
+    /// Anything else, in the order they were added.
+    pub headers: Vec<(String, String)>,
+}
+
+impl Metadata {
+    /// The value of the first header called `name`.
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(header, _)| header == name)
+            .map(|(_, value)| value.as_str())
+    }
+
+    /// Flags `u8`, bit 0 for a length and bit 1 for a content type, the
+    /// length `u64`, the content type, the number of headers `u16` and
+    /// their names and values, all strings prefixed with their length
+    /// `u16`.
+    fn encode(&self) -> Vec<u8> {
+        fn put_string(encoded: &mut Vec<u8>, string: &str) {
+            encoded.extend_from_slice(&(string.len() as u16).to_le_bytes());
+            encoded.extend_from_slice(string.as_bytes());
+        }
+
+        let mut encoded =
+            vec![self.length.is_some() as u8 | (self.content_type.is_some() as u8) << 1];
+        if let Some(length) = self.length {
+            encoded.extend_from_slice(&length.to_le_bytes());
+        }
+        if let Some(content_type) = &self.content_type {
+            put_string(&mut encoded, content_type);
+        }
+        encoded.extend_from_slice(&(self.headers.len() as u16).to_le_bytes());
+        for (name, value) in &self.headers {
+            put_string(&mut encoded, name);
+            put_string(&mut encoded, value);
+        }
+        encoded
+    }
+
+    /// The metadata at the start of `encoded` and its encoded length.
+    fn decode(encoded: &[u8]) -> io::Result<(Metadata, usize)> {
+        fn take<'a>(input: &mut &'a [u8], len: usize) -> io::Result<&'a [u8]> {
+            if input.len() < len {
+                return Err(invalid("metadata cut short"));
+            }
+            let (taken, rest) = input.split_at(len);
+            *input = rest;
+            Ok(taken)
+        }
+
+        fn take_string(input: &mut &[u8]) -> io::Result<String> {
+            let len = u16::from_le_bytes(take(input, 2)?.try_into().unwrap());
+            String::from_utf8(take(input, len as usize)?.to_vec())
+                .map_err(|_| invalid("metadata is not UTF-8"))
+        }
+
+        let mut input = encoded;
+        let flags = take(&mut input, 1)?[0];
+        let length = match flags & 1 {
+            0 => None,
+            _ => Some(u64::from_le_bytes(take(&mut input, 8)?.try_into().unwrap())),
+        };
+        let content_type = match flags & 2 {
+            0 => None,
+            _ => Some(take_string(&mut input)?),
+        };
+        let count = u16::from_le_bytes(take(&mut input, 2)?.try_into().unwrap());
+        let headers = (0..count)
+            .map(|_| Ok((take_string(&mut input)?, take_string(&mut input)?)))
+            .collect::<io::Result<_>>()?;
+        let metadata = Metadata {
+            content_type,
+            length,
+            headers,
+        };
+        Ok((metadata, encoded.len() - input.len()))
+    }
+}
+
+/// How to send a key, see [`Conn::send_with`].
+#[derive(Debug, Clone, Default)]
+pub struct SendOptions {
+    compression: Compression,
+    metadata: Metadata,
+    priority: Priority,
+    rate_limit: Option<u64>,
+}
+
+impl SendOptions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Compress the data, [`Compression::None`] by default. Chunks that
+    /// don't get smaller go uncompressed anyway.
+    pub fn compression(mut self, compression: Compression) -> Self {
+        self.compression = compression;
+        self
+    }
+
+    /// Send before keys of lower priority, [`Priority::Normal`] by default.
+    pub fn priority(mut self, priority: Priority) -> Self {
+        self.priority = priority;
+        self
+    }
+
+    /// Send no more than `bytes_per_second` of the key's data on average,
+    /// within the limit of the connection. Unlimited by default.
+    ///
+    /// # Panics
+    ///
+    /// If `bytes_per_second` is 0.
+    pub fn rate_limit(mut self, bytes_per_second: u64) -> Self {
+        assert!(bytes_per_second > 0, "a rate limit of 0 sends nothing");
+        self.rate_limit = Some(bytes_per_second);
+        self
+    }
+
+    /// Tell the receiver what kind of data it is.
+    pub fn content_type(mut self, content_type: &str) -> Self {
+        self.metadata.content_type = Some(content_type.to_string());
+        self
+    }
+
+    /// Tell the receiver how much data there is. Writing more fails, and
+    /// so does closing the writer before all of it was written.
+    pub fn length(mut self, length: u64) -> Self {
+        self.metadata.length = Some(length);
+        self
+    }
+
+    /// Add a header for the receiver.
+    pub fn header(mut self, name: &str, value: &str) -> Self {
+        self.metadata
+            .headers
+            .push((name.to_string(), value.to_string()));
+        self
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) enum Frame {
+    Hello {
+        features: u32,
+        session: u64,
+    },
+    Open {
+        stream: u32,
+        key: String,
+        metadata: Option<Metadata>,
+    },
+    Data {
+        stream: u32,
+        payload: Vec<u8>,
+        crc: Option<u32>,
+        /// How the payload is compressed and its length before.
+        compressed: Option<(Compression, u32)>,
+    },
+    End {
+        stream: u32,
+        digest: Option<[u8; 32]>,
+    },
+    Window {
+        stream: u32,
+        increment: u32,
+    },
+    Resume {
+        opened: u32,
+        streams: Vec<Resumed>,
+    },
+    Abort {
+        stream: u32,
+        reason: String,
+    },
+    Reject {
+        stream: u32,
+        reason: String,
+    },
+    GoAway,
+}
+
+/// How far the receiver got with a stream, as reported in `RESUME`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct Resumed {
+    stream: u32,
+    received: u64,
+    window: u32,
+    ended: bool,
+}
+
+impl Frame {
+    /// The type byte and the fixed fields.
+    pub(crate) fn header(&self) -> Vec<u8> {
+        let (kind, stream) = match self {
+            Frame::Hello { .. } => (HELLO, 0),
+            Frame::Open {
+                stream,
+                metadata: None,
+                ..
+            } => (OPEN, *stream),
+            Frame::Open { stream, .. } => (OPEN_WITH_METADATA, *stream),
+            Frame::Data {
+                stream,
+                crc,
+                compressed,
+                ..
+            } => match (crc, compressed) {
+                (None, None) => (DATA, *stream),
+                (Some(_), None) => (CHECKED_DATA, *stream),
+                (None, Some(_)) => (COMPRESSED_DATA, *stream),
+                (Some(_), Some(_)) => (CHECKED_COMPRESSED_DATA, *stream),
+            },
+            Frame::End {
+                stream,
+                digest: None,
+            } => (END, *stream),
+            Frame::End { stream, .. } => (CHECKED_END, *stream),
+            Frame::Window { stream, .. } => (WINDOW, *stream),
+            Frame::Resume { opened, .. } => (RESUME, *opened),
+            Frame::Abort { stream, .. } => (ABORT, *stream),
+            Frame::Reject { stream, .. } => (REJECT, *stream),
+            Frame::GoAway => (GOAWAY, 0),
+        };
+        let mut header = vec![kind];
+        header.extend_from_slice(&stream.to_le_bytes());
+        match self {
+            Frame::Hello { features, session } => {
+                header.extend_from_slice(&features.to_le_bytes());
+                header.extend_from_slice(&session.to_le_bytes());
+            }
+            Frame::Open { key, metadata, .. } => {
+                header.extend_from_slice(&(key.len() as u16).to_le_bytes());
+                if let Some(metadata) = metadata {
+                    header.extend_from_slice(&(metadata.encode().len() as u32).to_le_bytes());
+                }
+            }
+            Frame::Data {
+                payload,
+                crc,
+                compressed,
+                ..
+            } => {
+                header.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+                if let Some(crc) = crc {
+                    header.extend_from_slice(&crc.to_le_bytes());
+                }
+                if let Some((compression, len)) = compressed {
+                    header.extend_from_slice(&len.to_le_bytes());
+                    header.push(compression.code());
+                }
+            }
+            Frame::Window { increment, .. } => header.extend_from_slice(&increment.to_le_bytes()),
+            Frame::Resume { streams, .. } => {
+                header.extend_from_slice(&(streams.len() as u32).to_le_bytes())
+            }
+            Frame::Abort { reason, .. } | Frame::Reject { reason, .. } => {
+                header.extend_from_slice(&(reason.len() as u16).to_le_bytes())
+            }
+            Frame::End { .. } | Frame::GoAway => {}
+        }
+        header
+    }
+
+    /// What follows the header, the metadata and the key, the payload, the
+    /// digest, the resumed streams or the reason.
+    pub(crate) fn body(&self) -> Cow<'_, [u8]> {
+        match self {
+            Frame::Open {
+                key,
+                metadata: None,
+                ..
+            } => Cow::Borrowed(key.as_bytes()),
+            Frame::Open {
+                key,
+                metadata: Some(metadata),
+                ..
+            } => {
+                let mut body = metadata.encode();
+                body.extend_from_slice(key.as_bytes());
+                Cow::Owned(body)
+            }
+            Frame::Data { payload, .. } => Cow::Borrowed(payload),
+            Frame::End {
+                digest: Some(digest),
+                ..
+            } => Cow::Borrowed(digest),
+            Frame::Resume { streams, .. } => {
+                let mut body = Vec::with_capacity(streams.len() * RESUMED_LEN);
+                for resumed in streams {
+                    body.extend_from_slice(&resumed.stream.to_le_bytes());
+                    body.extend_from_slice(&resumed.received.to_le_bytes());
+                    body.extend_from_slice(&resumed.window.to_le_bytes());
+                    body.push(resumed.ended as u8);
+                }
+                Cow::Owned(body)
+            }
+            Frame::Abort { reason, .. } | Frame::Reject { reason, .. } => {
+                Cow::Borrowed(reason.as_bytes())
+            }
+            Frame::Hello { .. } | Frame::End { .. } | Frame::Window { .. } | Frame::GoAway => {
+                Cow::Borrowed(&[])
+            }
+        }
+    }
+
+    fn write_to<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+        writer.write_all(&self.header())?;
+        writer.write_all(&self.body())
+    }
+
+    /// Length of the fixed fields following type byte `kind`.
+    pub(crate) fn fields_len(kind: u8) -> io::Result<usize> {
+        match kind {
+            OPEN | ABORT | REJECT => Ok(6),
+            DATA | WINDOW | RESUME => Ok(8),
+            CHECKED_DATA => Ok(12),
+            OPEN_WITH_METADATA => Ok(10),
+            COMPRESSED_DATA => Ok(13),
+            HELLO => Ok(16),
+            CHECKED_COMPRESSED_DATA => Ok(17),
+            END | CHECKED_END | GOAWAY => Ok(4),
+            kind => Err(invalid(format!("unknown frame type {}", kind))),
+        }
+    }
+
+    /// The frame with an empty body and the length of the body to read
+    /// into it with [`with_body`](Self::with_body).
+    pub(crate) fn decode(kind: u8, fields: &[u8]) -> io::Result<(Frame, usize)> {
+        let field = |at: usize| u32::from_le_bytes(fields[at..at + 4].try_into().unwrap());
+        let stream = field(0);
+        Ok(match kind {
+            HELLO => {
+                let session = u64::from_le_bytes(fields[8..16].try_into().unwrap());
+                let features = field(4);
+                (Frame::Hello { features, session }, 0)
+            }
+            OPEN => {
+                let len = u16::from_le_bytes(fields[4..6].try_into().unwrap());
+                let key = String::new();
+                let metadata = None;
+                (
+                    Frame::Open {
+                        stream,
+                        key,
+                        metadata,
+                    },
+                    len as usize,
+                )
+            }
+            OPEN_WITH_METADATA => {
+                let len = u16::from_le_bytes(fields[4..6].try_into().unwrap()) as usize;
+                let metadata_len = u32::from_le_bytes(fields[6..10].try_into().unwrap()) as usize;
+                if metadata_len > MAX_METADATA {
+                    return Err(invalid(format!("{} bytes of metadata", metadata_len)));
+                }
+                let key = String::new();
+                let metadata = Some(Metadata::default());
+                (
+                    Frame::Open {
+                        stream,
+                        key,
+                        metadata,
+                    },
+                    metadata_len + len,
+                )
+            }
+            DATA | CHECKED_DATA | COMPRESSED_DATA | CHECKED_COMPRESSED_DATA => {
+                let len = field(4) as usize;
+                let checked = kind == CHECKED_DATA || kind == CHECKED_COMPRESSED_DATA;
+                let crc = checked.then(|| field(8));
+                let at = if checked { 12 } else { 8 };
+                let compressed = match kind {
+                    COMPRESSED_DATA | CHECKED_COMPRESSED_DATA => {
+                        Some((Compression::from_code(fields[at + 4])?, field(at)))
+                    }
+                    _ => None,
+                };
+                let decompressed = compressed.map_or(len, |(_, len)| len as usize);
+                if len.max(decompressed) > MAX_DATA {
+                    return Err(invalid(format!("{} bytes of data in one frame", len)));
+                }
+                let payload = Vec::new();
+                (
+                    Frame::Data {
+                        stream,
+                        payload,
+                        crc,
+                        compressed,
+                    },
+                    len,
+                )
+            }
+            END => (
+                Frame::End {
+                    stream,
+                    digest: None,
+                },
+                0,
+            ),
+            CHECKED_END => (
+                Frame::End {
+                    stream,
+                    digest: Some([0; 32]),
+                },
+                32,
+            ),
+            WINDOW => (
+                Frame::Window {
+                    stream,
+                    increment: field(4),
+                },
+                0,
+            ),
+            RESUME => {
+                let count = field(4) as usize;
+                if count > MAX_RESUMED {
+                    return Err(invalid(format!("{} streams to resume", count)));
+                }
+                let streams = Vec::new();
+                let frame = Frame::Resume {
+                    opened: stream,
+                    streams,
+                };
+                (frame, count * RESUMED_LEN)
+            }
+            ABORT | REJECT => {
+                let len = u16::from_le_bytes(fields[4..6].try_into().unwrap());
+                let reason = String::new();
+                let frame = match kind {
+                    ABORT => Frame::Abort { stream, reason },
+                    _ => Frame::Reject { stream, reason },
+                };
+                (frame, len as usize)
+            }
+            GOAWAY => (Frame::GoAway, 0),
+            kind => return Err(invalid(format!("unknown frame type {}", kind))),
+        })
+    }
+
+    pub(crate) fn with_body(mut self, body: Vec<u8>) -> io::Result<Frame> {
+        match &mut self {
+            Frame::Open { key, metadata, .. } => {
+                let mut body = body;
+                if let Some(metadata) = metadata {
+                    let (decoded, len) = Metadata::decode(&body)?;
+                    *metadata = decoded;
+                    body.drain(..len);
+                }
+                *key = String::from_utf8(body).map_err(|_| invalid("key is not UTF-8"))?
+            }
+            Frame::Data { payload, .. } => *payload = body,
+            Frame::End {
+                digest: Some(digest),
+                ..
+            } => digest.copy_from_slice(&body),
+            Frame::Resume { streams, .. } => {
+                *streams = body
+                    .chunks_exact(RESUMED_LEN)
+                    .map(|entry| Resumed {
+                        stream: u32::from_le_bytes(entry[0..4].try_into().unwrap()),
+                        received: u64::from_le_bytes(entry[4..12].try_into().unwrap()),
+                        window: u32::from_le_bytes(entry[12..16].try_into().unwrap()),
+                        ended: entry[16] != 0,
+                    })
+                    .collect()
+            }
+            Frame::Abort { reason, .. } | Frame::Reject { reason, .. } => {
+                *reason = String::from_utf8(body).map_err(|_| invalid("reason is not UTF-8"))?
+            }
+            Frame::Hello { .. } | Frame::End { .. } | Frame::Window { .. } | Frame::GoAway => {}
+        }
+        Ok(self)
+    }
+
+    /// The next frame, `None` if the input ends before it.
+    fn read_from<R: Read>(reader: &mut R) -> io::Result<Option<Frame>> {
+        let mut kind = [0];
+        loop {
+            match reader.read(&mut kind) {
+                Ok(0) => return Ok(None),
+                Ok(_) => break,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => return Err(e),
+            }
+        }
+
+        let mut fields = vec![0; Frame::fields_len(kind[0])?];
+        reader.read_exact(&mut fields)?;
+        let (frame, len) = Frame::decode(kind[0], &fields)?;
+        let mut body = vec![0; len];
+        reader.read_exact(&mut body)?;
+        frame.with_body(body).map(Some)
+    }
+}
+
+/// A key being sent.
+#[derive(Default)]
+struct Sending {
+    /// For opening it again on a resumed connection.
+    key: String,
+    metadata: Option<Metadata>,
+    compression: Compression,
+    priority: Priority,
+    limiter: Option<Limiter>,
+    /// By the application, to check against the length in the metadata.
+    written: u64,
+    chunks: VecDeque<Vec<u8>>,
+    buffered: usize,
+    /// What the peer still accepts.
+    credit: u32,
+    /// The writer is done, `END` follows the buffered data.
+    ending: bool,
+    /// Offset of the next byte to send.
+    sent: u64,
+    /// Of the data up to `hashed`, sent at least once.
+    hash: Sha256,
+    hashed: u64,
+    digest: Option<[u8; 32]>,
+    /// Sent but maybe not received yet, kept for resuming sessions only.
+    retained: VecDeque<Vec<u8>>,
+    /// Offset of the first retained byte.
+    retained_from: u64,
+    /// `END` went out, the stream stays until the peer acknowledges it.
+    end_sent: bool,
+    /// `ABORT` went out with the reason instead.
+    aborted: Option<String>,
+}
+
+impl Sending {
+    /// Forget the retained data the peer consumed up to `offset`.
+    fn release(&mut self, offset: u64) {
+        while let Some(front) = self.retained.front() {
+            let end = self.retained_from + front.len() as u64;
+            if end > offset {
+                break;
+            }
+            self.retained_from = end;
+            self.retained.pop_front();
+        }
+    }
+
+    /// Send again what the peer didn't receive, everything from `offset`.
+    fn rewind(&mut self, offset: u64) {
+        self.release(offset);
+        if let Some(front) = self.retained.front_mut() {
+            front.drain(..(offset - self.retained_from) as usize);
+            self.retained_from = offset;
+        }
+        for chunk in self.retained.drain(..).rev() {
+            self.buffered += chunk.len();
+            self.chunks.push_front(chunk);
+        }
+        self.sent = offset;
+        self.end_sent = false;
+    }
+}
+
+/// A key being received.
+#[derive(Default)]
+struct Receiving {
+    chunks: VecDeque<Vec<u8>>,
+    /// Read from the front chunk already.
+    offset: usize,
+    /// Bytes of the key that arrived so far.
+    received: u64,
+    /// What the metadata announced.
+    length: Option<u64>,
+    /// What the peer may still send.
+    window: u32,
+    /// Consumed but not granted back yet.
+    unacked: u32,
+    ended: bool,
+    /// The reader was dropped, data is thrown away as it arrives.
+    discarding: bool,
+    /// Failed a check, data is thrown away like when discarding and reads
+    /// fail after what was received intact.
+    failed: Option<String>,
+    /// The sender aborted the key with the reason, reads fail after what
+    /// was received.
+    aborted: Option<String>,
+    /// Rejected with the reason, repeated on resumed connections until the
+    /// key ends.
+    rejected: Option<String>,
+    hash: Sha256,
+}
+
+pub(crate) struct State {
+    offered: u32,
+    /// Our `HELLO` goes out before anything else on a connection.
+    hello_due: bool,
+    /// The peer's `HELLO` arrived on the current connection.
+    connected: bool,
+    /// What both sides offered, once the peer's first `HELLO` arrived.
+    integrity: Option<Integrity>,
+    compressions: u32,
+    /// Picked by the server when both sides offer resuming.
+    session: Option<u64>,
+    /// Our `RESUME`, following the `HELLO` on a resumed connection.
+    resume: Option<Frame>,
+    /// Nothing but `HELLO` and `RESUME` goes out before the peer's
+    /// `RESUME` arrived.
+    peer_resuming: bool,
+    /// When the connection dropped, `None` while connected.
+    pub(crate) disconnected_at: Option<Instant>,
+    /// Counts the connections of a session, frames of earlier ones are
+    /// ignored.
+    pub(crate) generation: u64,
+    next_stream: u32,
+    /// Keys the peer opened so far.
+    peer_streams: u32,
+    sending: BTreeMap<u32, Sending>,
+    /// Keys the peer rejected while they were written, with the reason.
+    rejected: HashMap<u32, String>,
+    receiving: HashMap<u32, Receiving>,
+    /// Keys received but not handed out by [`Conn::receive`] yet.
+    accepted: VecDeque<(String, Metadata, u32)>,
+    /// Frames going out before any data.
+    control: VecDeque<Frame>,
+    /// The stream that sent last, for taking turns.
+    turn: u32,
+    /// Of the whole connection.
+    limiter: Option<Limiter>,
+    idle_timeout: Option<Duration>,
+    pub(crate) close_timeout: Duration,
+    /// When the last frame went either way.
+    active_at: Instant,
+    /// The peer won't open more keys.
+    pub(crate) peer_done: bool,
+    pub(crate) closing: bool,
+    goaway_sent: bool,
+    /// The `GOAWAY` was written, nothing follows.
+    pub(crate) finished: bool,
+    pub(crate) error: Option<(io::ErrorKind, String)>,
+}
+
+impl State {
+    pub(crate) fn new(config: &Config) -> Self {
+        Self {
+            offered: config.integrity.bits() | config.compressions,
+            hello_due: true,
+            connected: false,
+            integrity: None,
+            compressions: 0,
+            session: None,
+            resume: None,
+            peer_resuming: false,
+            disconnected_at: None,
+            generation: 0,
+            next_stream: 0,
+            peer_streams: 0,
+            sending: BTreeMap::new(),
+            rejected: HashMap::new(),
+            receiving: HashMap::new(),
+            accepted: VecDeque::new(),
+            control: VecDeque::new(),
+            turn: 0,
+            limiter: config.rate_limit.map(Limiter::new),
+            idle_timeout: config.idle_timeout,
+            close_timeout: config.close_timeout,
+            active_at: Instant::now(),
+            peer_done: false,
+            closing: false,
+            goaway_sent: false,
+            finished: false,
+            error: None,
+        }
+    }
+
+    /// Offer resuming the session over a new connection when one drops,
+    /// under `session` if the server picked it already.
+    pub(crate) fn resumable(mut self, session: Option<u64>) -> Self {
+        self.offered |= RESUMABLE;
+        self.session = session;
+        self
+    }
+
+    /// The checks in use, `None` before the handshake completed.
+    pub(crate) fn integrity(&self) -> Option<Integrity> {
+        self.integrity
+    }
+
+    /// The compressions both sides offer, `None` before the handshake
+    /// completed.
+    pub(crate) fn compressions(&self) -> Option<Vec<Compression>> {
+        self.integrity
+            .map(|_| Compression::from_bits(self.compressions))
+    }
+
+    pub(crate) fn session(&self) -> Option<u64> {
+        self.session
+    }
+
+    pub(crate) fn check(&self) -> io::Result<()> {
+        match &self.error {
+            Some((kind, message)) => Err(io::Error::new(*kind, message.clone())),
+            None if self.closing => Err(io::Error::new(
+                io::ErrorKind::NotConnected,
+                "the connection is closed",
+            )),
+            None => Ok(()),
+        }
+    }
+
+    pub(crate) fn fail(&mut self, error: &io::Error) {
+        if !self.closing {
+            self.abort(error);
+        }
+    }
+
+    /// Fail even while closing, the connection is gone.
+    pub(crate) fn abort(&mut self, error: &io::Error) {
+        if self.error.is_none() {
+            self.error = Some((error.kind(), error.to_string()));
+        }
+    }
+
+    /// The connection dropped: start over on a new one, telling the peer
+    /// what arrived so it sends the rest.
+    pub(crate) fn reconnect(&mut self) {
+        self.generation += 1;
+        self.hello_due = true;
+        self.connected = false;
+        self.disconnected_at.get_or_insert_with(Instant::now);
+        // what was lost with them is covered by the RESUME frames
+        self.control.clear();
+        self.goaway_sent = false;
+        self.finished = false;
+        let streams = self
+            .receiving
+            .iter()
+            .map(|(&stream, receiving)| Resumed {
+                stream,
+                received: receiving.received,
+                window: receiving.window,
+                ended: receiving.ended,
+            })
+            .collect();
+        self.resume = Some(Frame::Resume {
+            opened: self.peer_streams,
+            streams,
+        });
+        // after the RESUME, in case they were lost
+        for (&stream, receiving) in &self.receiving {
+            if let (Some(reason), false) = (&receiving.rejected, receiving.ended) {
+                self.control.push_back(Frame::Reject {
+                    stream,
+                    reason: reason.clone(),
+                });
+            }
+        }
+        self.peer_resuming = true;
+    }
+
+    /// The next frame to send: control frames first, then the streams that
+    /// can make progress take turns, those of the highest priority first.
+    pub(crate) fn next_frame(&mut self) -> Option<Frame> {
+        self.next_frame_at(Instant::now())
+    }
+
+    /// Like [`next_frame`](Self::next_frame), with rate limits and the idle
+    /// timeout as of `now`.
+    pub(crate) fn next_frame_at(&mut self, now: Instant) -> Option<Frame> {
+        if self.idle_at().is_some_and(|idle_at| idle_at <= now) {
+            self.close();
+        }
+        let frame = self.pick_frame(now)?;
+        self.active_at = now;
+        Some(frame)
+    }
+
+    fn pick_frame(&mut self, now: Instant) -> Option<Frame> {
+        if self.hello_due {
+            self.hello_due = false;
+            return Some(Frame::Hello {
+                features: self.offered,
+                session: self.session.unwrap_or(0),
+            });
+        }
+        // frames depend on what the peer offers and got
+        if !self.connected {
+            return None;
+        }
+        if let Some(resume) = self.resume.take() {
+            return Some(resume);
+        }
+        if self.peer_resuming {
+            return None;
+        }
+        let integrity = self.integrity?;
+        if let Some(frame) = self.control.pop_front() {
+            return Some(frame);
+        }
+
+        let turn = self.turn;
+        let allows =
+            |limiter: &Option<Limiter>| limiter.as_ref().is_none_or(|limiter| limiter.allows(now));
+        let ready = |sending: &Sending| {
+            (sending.buffered > 0
+                && sending.credit > 0
+                && allows(&self.limiter)
+                && allows(&sending.limiter))
+                || (sending.buffered == 0 && sending.ending && !sending.end_sent)
+        };
+        // the first of the highest priority in turn
+        let Some(stream) = self
+            .sending
+            .range(turn + 1..)
+            .chain(self.sending.range(..=turn))
+            .filter(|(_, sending)| ready(sending))
+            .min_by_key(|(_, sending)| std::cmp::Reverse(sending.priority))
+            .map(|(&stream, _)| stream)
+        else {
+            if self.closing && !self.goaway_sent && self.drained() {
+                self.goaway_sent = true;
+                return Some(Frame::GoAway);
+            }
+            return None;
+        };
+        self.turn = stream;
+
+        let resumable = self.session.is_some();
+        let sending = self.sending.get_mut(&stream).unwrap();
+        if sending.buffered == 0 {
+            let hash = &mut sending.hash;
+            let digest = integrity.sha256.then(|| {
+                *sending
+                    .digest
+                    .get_or_insert_with(|| std::mem::take(hash).finalize().into())
+            });
+            if resumable {
+                sending.end_sent = true;
+            } else {
+                self.sending.remove(&stream);
+            }
+            return Some(Frame::End { stream, digest });
+        }
+
+        let credit = sending.credit as usize;
+        let front = sending.chunks.front_mut().unwrap();
+        let payload = if front.len() <= credit {
+            sending.chunks.pop_front().unwrap()
+        } else {
+            let rest = front.split_off(credit);
+            std::mem::replace(front, rest)
+        };
+        sending.buffered -= payload.len();
+        sending.credit -= payload.len() as u32;
+        let offset = sending.sent;
+        sending.sent += payload.len() as u64;
+        // replayed data is hashed already
+        if integrity.sha256 && sending.sent > sending.hashed {
+            sending
+                .hash
+                .update(&payload[(sending.hashed - offset) as usize..]);
+            sending.hashed = sending.sent;
+        }
+        if resumable {
+            sending.retained.push_back(payload.clone());
+        }
+        // uncompressed if the peer can't decompress it or it doesn't pay
+        let compression = sending.compression;
+        let (payload, compressed) = match compression.bit() & self.compressions {
+            0 => (payload, None),
+            _ => match compression.compress(&payload) {
+                Some(packed) => (packed, Some((compression, payload.len() as u32))),
+                None => (payload, None),
+            },
+        };
+        let limiters = sending.limiter.iter_mut().chain(self.limiter.as_mut());
+        for limiter in limiters {
+            limiter.take(payload.len(), now);
+        }
+        let crc = integrity.crc32c.then(|| crc32c::crc32c(&payload));
+        Some(Frame::Data {
+            stream,
+            payload,
+            crc,
+            compressed,
+        })
+    }
+
+    /// When a stream a rate limit holds back as of `now` may send again,
+    /// `None` if none is held back.
+    pub(crate) fn throttled_until(&self, now: Instant) -> Option<Instant> {
+        self.sending
+            .values()
+            .filter(|sending| sending.buffered > 0 && sending.credit > 0)
+            .filter_map(|sending| {
+                [&self.limiter, &sending.limiter]
+                    .into_iter()
+                    .filter_map(|limiter| limiter.as_ref()?.ready_at())
+                    .max()
+            })
+            .filter(|&ready| ready > now)
+            .min()
+    }
+
+    /// When the connection idles out, `None` while keys are open.
+    fn idle_at(&self) -> Option<Instant> {
+        let idle = !self.closing
+            && self.sending.is_empty()
+            && self.receiving.is_empty()
+            && self.accepted.is_empty();
+        let timeout = self.idle_timeout.filter(|_| idle)?;
+        Some(self.active_at + timeout)
+    }
+
+    /// When time alone gives the writer something to do, a rate limit
+    /// letting a stream go again or the connection idling out.
+    pub(crate) fn wake_at(&self, now: Instant) -> Option<Instant> {
+        [self.throttled_until(now), self.idle_at()]
+            .into_iter()
+            .flatten()
+            .min()
+    }
+
+    /// Start closing: keys still being written end with what was written
+    /// so far and keys still arriving are rejected, `GOAWAY` follows the
+    /// rest.
+    pub(crate) fn close(&mut self) {
+        if self.closing {
+            return;
+        }
+        self.closing = true;
+        for sending in self.sending.values_mut() {
+            sending.ending = true;
+        }
+        let arriving: Vec<u32> = self
+            .receiving
+            .iter()
+            .filter(|(_, receiving)| !receiving.ended)
+            .map(|(&stream, _)| stream)
+            .collect();
+        for stream in arriving {
+            self.reject(stream, CLOSED);
+        }
+    }
+
+    /// Everything handed to the writer went out.
+    fn drained(&self) -> bool {
+        self.control.is_empty()
+            && self
+                .sending
+                .values()
+                .all(|sending| sending.buffered == 0 && (!sending.ending || sending.end_sent))
+    }
+
+    pub(crate) fn receive_frame(&mut self, frame: Frame) -> io::Result<()> {
+        self.active_at = Instant::now();
+        match frame {
+            Frame::Hello { features, session } if !self.connected => {
+                return self.receive_hello(features, session)
+            }
+            Frame::Hello { .. } => return Err(invalid("a second hello")),
+            _ if !self.connected => return Err(invalid("no hello from the peer")),
+            Frame::Resume { opened, streams } if self.peer_resuming => {
+                self.peer_resuming = false;
+                return self.resume(opened, streams);
+            }
+            _ if self.peer_resuming => return Err(invalid("no resume from the peer")),
+            _ => {}
+        }
+        let integrity = self.integrity.unwrap();
+
+        match frame {
+            Frame::Hello { .. } => unreachable!(),
+            Frame::Resume { .. } => return Err(invalid("resume on a fresh connection")),
+            Frame::GoAway => self.peer_done = true,
+            Frame::Open {
+                stream,
+                key,
+                metadata,
+            } => {
+                if self.receiving.contains_key(&stream) || stream < self.peer_streams {
+                    return Err(invalid(format!("stream {} opened twice", stream)));
+                }
+                self.peer_streams = stream + 1;
+                let metadata = metadata.unwrap_or_default();
+                self.receiving.insert(
+                    stream,
+                    Receiving {
+                        window: INITIAL_WINDOW,
+                        length: metadata.length,
+                        ..Receiving::default()
+                    },
+                );
+                // nobody receives keys after closing
+                if self.closing {
+                    self.reject(stream, CLOSED);
+                } else {
+                    self.accepted.push_back((key, metadata, stream));
+                }
+            }
+            Frame::Data {
+                stream,
+                payload,
+                crc,
+                compressed,
+            } => {
+                if crc.is_some() != integrity.crc32c {
+                    return Err(invalid("data checked other than agreed"));
+                }
+                if compressed
+                    .is_some_and(|(compression, _)| compression.bit() & self.compressions == 0)
+                {
+                    return Err(invalid("data compressed other than agreed"));
+                }
+                let receiving = self.receiving_mut(stream)?;
+                // windows count the data before compression
+                let len = compressed.map_or(payload.len() as u32, |(_, len)| len);
+                if len > receiving.window {
+                    return Err(invalid(format!("stream {} overran its window", stream)));
+                }
+                receiving.window -= len;
+                receiving.received += len as u64;
+                if let Some(length) = receiving.length {
+                    if receiving.received > length && receiving.failed.is_none() {
+                        receiving.failed =
+                            Some(format!("more than the {} bytes announced", length));
+                    }
+                }
+
+                if crc.is_some_and(|crc| crc != crc32c::crc32c(&payload))
+                    && receiving.failed.is_none()
+                {
+                    receiving.failed = Some("CRC32C mismatch, the key was corrupted".to_string());
+                }
+                let payload = match compressed {
+                    Some(_) if receiving.discarding || receiving.failed.is_some() => payload,
+                    Some((compression, len)) => compression
+                        .decompress(&payload, len as usize)
+                        .unwrap_or_else(|| {
+                            receiving.failed =
+                                Some("can't decompress, the key was corrupted".to_string());
+                            Vec::new()
+                        }),
+                    None => payload,
+                };
+                if receiving.discarding || receiving.failed.is_some() {
+                    receiving.window += len;
+                    self.control.push_back(Frame::Window {
+                        stream,
+                        increment: len,
+                    });
+                } else if len > 0 {
+                    if integrity.sha256 {
+                        receiving.hash.update(&payload);
+                    }
+                    receiving.chunks.push_back(payload);
+                }
+            }
+            Frame::End { stream, digest } => {
+                if digest.is_some() != integrity.sha256 {
+                    return Err(invalid("key checked other than agreed"));
+                }
+                let receiving = self.receiving_mut(stream)?;
+                receiving.ended = true;
+                let hash = std::mem::take(&mut receiving.hash);
+                if digest.is_some_and(|digest| digest != <[u8; 32]>::from(hash.finalize()))
+                    && receiving.failed.is_none()
+                {
+                    receiving.failed = Some("SHA-256 mismatch, the key was corrupted".to_string());
+                }
+                if let Some(length) = receiving.length {
+                    if receiving.received < length && receiving.failed.is_none() {
+                        receiving.failed = Some(format!(
+                            "the key ended after {} of the {} bytes announced",
+                            receiving.received, length
+                        ));
+                    }
+                }
+                if receiving.discarding {
+                    self.receiving.remove(&stream);
+                }
+                if self.session.is_some() {
+                    // the sender can forget the key
+                    self.control.push_back(Frame::Window {
+                        stream,
+                        increment: 0,
+                    });
+                }
+            }
+            Frame::Window { stream, increment } => {
+                // the stream may have ended meanwhile
+                if let Some(sending) = self.sending.get_mut(&stream) {
+                    if increment == 0 && sending.end_sent {
+                        self.sending.remove(&stream);
+                        return Ok(());
+                    }
+                    sending.credit = sending.credit.checked_add(increment).ok_or_else(|| {
+                        invalid(format!("window of stream {} overflowed", stream))
+                    })?;
+                    // what was granted again was consumed
+                    let consumed = sending.sent + sending.credit as u64;
+                    sending.release(consumed.saturating_sub(INITIAL_WINDOW as u64));
+                }
+            }
+            Frame::Abort { stream, reason } => {
+                let receiving = self.receiving_mut(stream)?;
+                receiving.ended = true;
+                receiving.aborted = Some(reason);
+                if receiving.discarding {
+                    self.receiving.remove(&stream);
+                }
+                if self.session.is_some() {
+                    self.control.push_back(Frame::Window {
+                        stream,
+                        increment: 0,
+                    });
+                }
+            }
+            Frame::Reject { stream, reason } => {
+                // the key may have ended meanwhile
+                if let Some(sending) = self.sending.get(&stream).filter(|s| !s.end_sent) {
+                    if !sending.ending {
+                        self.rejected.insert(stream, reason.clone());
+                    }
+                    self.stop(stream, reason);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn receive_hello(&mut self, features: u32, session: u64) -> io::Result<()> {
+        let integrity = Integrity::from_bits(self.offered & features);
+        let compressions = self.offered & features & COMPRESSIONS;
+        match self.integrity {
+            None => {
+                self.integrity = Some(integrity);
+                self.compressions = compressions;
+                if self.offered & features & RESUMABLE != 0 && session != 0 {
+                    self.session.get_or_insert(session);
+                }
+            }
+            Some(agreed) => {
+                if Some(session) != self.session {
+                    return Err(io::Error::new(
+                        io::ErrorKind::NotFound,
+                        "the peer doesn't know the session any more",
+                    ));
+                }
+                if integrity != agreed || compressions != self.compressions {
+                    return Err(invalid("the peer offers other features than before"));
+                }
+            }
+        }
+        self.connected = true;
+        self.disconnected_at = None;
+        Ok(())
+    }
+
+    /// Continue where the peer's `RESUME` says it got to.
+    fn resume(&mut self, opened: u32, streams: Vec<Resumed>) -> io::Result<()> {
+        let streams: HashMap<u32, Resumed> = streams
+            .into_iter()
+            .map(|resumed| (resumed.stream, resumed))
+            .collect();
+        let mut reopened = Vec::new();
+        let mut result = Ok(());
+        let mut aborted = Vec::new();
+        self.sending.retain(|&stream, sending| {
+            if stream >= opened {
+                // the peer never heard of an aborted key
+                if sending.aborted.is_some() {
+                    return false;
+                }
+                // the OPEN got lost, all of it goes again
+                sending.rewind(0);
+                sending.credit = INITIAL_WINDOW;
+                reopened.push(Frame::Open {
+                    stream,
+                    key: sending.key.clone(),
+                    metadata: sending.metadata.clone(),
+                });
+                return true;
+            }
+            match streams.get(&stream) {
+                Some(resumed) if resumed.ended => false,
+                Some(_) if sending.aborted.is_some() => {
+                    aborted.push(Frame::Abort {
+                        stream,
+                        reason: sending.aborted.clone().unwrap(),
+                    });
+                    true
+                }
+                Some(resumed) => {
+                    if resumed.received < sending.retained_from || resumed.received > sending.sent {
+                        result = Err(invalid(format!("stream {} resumes out of range", stream)));
+                    } else {
+                        sending.rewind(resumed.received);
+                        sending.credit = resumed.window;
+                    }
+                    true
+                }
+                // the peer read it all and forgot it
+                None => false,
+            }
+        });
+        self.control.extend(reopened);
+        self.control.extend(aborted);
+        result
+    }
+
+    fn receiving_mut(&mut self, stream: u32) -> io::Result<&mut Receiving> {
+        match self.receiving.get_mut(&stream) {
+            Some(receiving) if !receiving.ended => Ok(receiving),
+            _ => Err(invalid(format!("stream {} is not open", stream))),
+        }
+    }
+
+    /// Give window back for `len` consumed bytes, batched so `WINDOW`
+    /// frames don't outnumber `DATA` frames.
+    fn consumed(&mut self, stream: u32, len: usize) {
+        let receiving = self.receiving.get_mut(&stream).unwrap();
+        receiving.unacked += len as u32;
+        if receiving.unacked >= INITIAL_WINDOW / 2 && !receiving.ended {
+            let increment = std::mem::take(&mut receiving.unacked);
+            receiving.window += increment;
+            self.control.push_back(Frame::Window { stream, increment });
+        }
+    }
+
+    /// Start sending `key`, returning its stream number.
+    pub(crate) fn open(&mut self, key: &str, options: &SendOptions) -> io::Result<u32> {
+        if key.len() > u16::MAX as usize {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("key of {} bytes is too long", key.len()),
+            ));
+        }
+        let metadata = Some(options.metadata.clone()).filter(|m| *m != Metadata::default());
+        let metadata_len = metadata.as_ref().map_or(0, |m| m.encode().len());
+        if metadata_len > MAX_METADATA {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("metadata of {} bytes is too long", metadata_len),
+            ));
+        }
+        self.check()?;
+
+        let stream = self.next_stream;
+        self.next_stream += 1;
+        self.sending.insert(
+            stream,
+            Sending {
+                key: key.to_string(),
+                metadata: metadata.clone(),
+                compression: options.compression,
+                priority: options.priority,
+                limiter: options.rate_limit.map(Limiter::new),
+                credit: INITIAL_WINDOW,
+                ..Sending::default()
+            },
+        );
+        self.control.push_back(Frame::Open {
+            stream,
+            key: key.to_string(),
+            metadata,
+        });
+        Ok(stream)
+    }
+
+    /// The next key received and its stream number, `None` once either
+    /// side closed the connection.
+    pub(crate) fn poll_accept(&mut self) -> Poll<io::Result<Option<(String, Metadata, u32)>>> {
+        if let Some(accepted) = self.accepted.pop_front() {
+            return Poll::Ready(Ok(Some(accepted)));
+        }
+        if self.peer_done || self.closing {
+            return Poll::Ready(Ok(None));
+        }
+        self.check()?;
+        Poll::Pending
+    }
+
+    /// Buffer some of `buf` for `stream`, pending while
+    /// [`INITIAL_WINDOW`] bytes are buffered already.
+    pub(crate) fn poll_write(&mut self, stream: u32, buf: &[u8]) -> Poll<io::Result<usize>> {
+        self.check()?;
+        if let Some(reason) = self.rejected.get(&stream) {
+            return Poll::Ready(Err(rejected(reason)));
+        }
+        let sending = self.sending.get_mut(&stream).unwrap();
+        let length = sending.metadata.as_ref().and_then(|m| m.length);
+        let left = length.map_or(u64::MAX, |length| length - sending.written);
+        if left == 0 && !buf.is_empty() {
+            return Poll::Ready(Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("more than the {} bytes announced", length.unwrap()),
+            )));
+        }
+        let room = (INITIAL_WINDOW as usize).saturating_sub(sending.buffered);
+        if room == 0 {
+            return Poll::Pending;
+        }
+        let len = buf.len().min(room).min(MAX_DATA);
+        let chunk = &buf[..len.min(left.try_into().unwrap_or(usize::MAX))];
+        if !chunk.is_empty() {
+            sending.chunks.push_back(chunk.to_vec());
+            sending.buffered += chunk.len();
+            sending.written += chunk.len() as u64;
+        }
+        Poll::Ready(Ok(chunk.len()))
+    }
+
+    /// Ready once everything buffered for `stream` went out.
+    pub(crate) fn poll_flush(&mut self, stream: u32) -> Poll<io::Result<()>> {
+        self.check()?;
+        if let Some(reason) = self.rejected.get(&stream) {
+            return Poll::Ready(Err(rejected(reason)));
+        }
+        match self.sending.get(&stream) {
+            Some(sending) if sending.buffered > 0 => Poll::Pending,
+            _ => Poll::Ready(Ok(())),
+        }
+    }
+
+    /// `END` follows what is buffered for `stream`.
+    pub(crate) fn end(&mut self, stream: u32) -> io::Result<()> {
+        if let Some(reason) = self.rejected.remove(&stream) {
+            return Err(rejected(&reason));
+        }
+        if let Some(sending) = self.sending.get_mut(&stream) {
+            sending.ending = true;
+            // the receiver fails the key too
+            if let Some(length) = sending.metadata.as_ref().and_then(|m| m.length) {
+                if sending.written < length {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidInput,
+                        format!(
+                            "the key ended after {} of the {} bytes announced",
+                            sending.written, length
+                        ),
+                    ));
+                }
+            }
+        }
+        self.check()
+    }
+
+    /// Give up on `stream`: drop what's buffered and `ABORT` the key
+    /// instead of ending it.
+    pub(crate) fn abort_stream(&mut self, stream: u32, reason: &str) -> io::Result<()> {
+        // nothing left to abort
+        if self.rejected.remove(&stream).is_some() {
+            return Ok(());
+        }
+        if self.sending.contains_key(&stream) {
+            self.stop(stream, reason.to_string());
+        }
+        self.check()
+    }
+
+    /// Send nothing more of `stream` but an `ABORT`.
+    fn stop(&mut self, stream: u32, mut reason: String) {
+        reason.truncate(reason.floor_char_boundary(u16::MAX as usize));
+        let sending = self.sending.get_mut(&stream).unwrap();
+        sending.chunks.clear();
+        sending.buffered = 0;
+        sending.retained.clear();
+        sending.ending = true;
+        sending.end_sent = true;
+        sending.aborted = Some(reason.clone());
+        // resumable sessions keep it until acknowledged like after END
+        if self.session.is_none() {
+            self.sending.remove(&stream);
+        }
+        self.control.push_back(Frame::Abort { stream, reason });
+    }
+
+    /// Read data of `stream`, 0 at its end.
+    pub(crate) fn poll_read(&mut self, stream: u32, buf: &mut [u8]) -> Poll<io::Result<usize>> {
+        if buf.is_empty() {
+            return Poll::Ready(Ok(0));
+        }
+        // rejected when closing, and the rest of the key arrived since
+        let Some(receiving) = self.receiving.get_mut(&stream) else {
+            self.check()?;
+            return Poll::Ready(Err(io::Error::new(io::ErrorKind::NotConnected, CLOSED)));
+        };
+        if let Some(front) = receiving.chunks.front() {
+            let available = &front[receiving.offset..];
+            let n = available.len().min(buf.len());
+            buf[..n].copy_from_slice(&available[..n]);
+            receiving.offset += n;
+            if receiving.offset == front.len() {
+                receiving.chunks.pop_front();
+                receiving.offset = 0;
+            }
+            self.consumed(stream, n);
+            return Poll::Ready(Ok(n));
+        }
+        if let Some(failure) = &receiving.failed {
+            return Poll::Ready(Err(invalid(failure.clone())));
+        }
+        if let Some(reason) = &receiving.aborted {
+            return Poll::Ready(Err(cancelled(
+                io::ErrorKind::ConnectionAborted,
+                "the sender aborted the key",
+                reason,
+            )));
+        }
+        if receiving.ended {
+            return Poll::Ready(Ok(0));
+        }
+        self.check()?;
+        if self.peer_done {
+            return Poll::Ready(Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "the connection closed in the middle of the key",
+            )));
+        }
+        Poll::Pending
+    }
+
+    /// The reader of `stream` is gone: unless the key ended, tell the peer
+    /// to stop sending it and throw away what arrives meanwhile. Returns
+    /// whether a frame was queued.
+    pub(crate) fn reject(&mut self, stream: u32, reason: &str) -> bool {
+        // rejected before
+        let Some(receiving) = self.receiving.get_mut(&stream) else {
+            return false;
+        };
+        if receiving.ended {
+            self.receiving.remove(&stream);
+            return false;
+        }
+        if receiving.rejected.is_some() {
+            return false;
+        }
+
+        let mut reason = reason.to_string();
+        reason.truncate(reason.floor_char_boundary(u16::MAX as usize));
+        receiving.chunks.clear();
+        receiving.offset = 0;
+        receiving.discarding = true;
+        receiving.rejected = Some(reason.clone());
+        self.control.push_back(Frame::Reject { stream, reason });
+        true
+    }
+}
+
+/// A byte stream a [`Conn`] runs over, one thread reading it while another
+/// writes.
+pub trait Transport: Read + Write + Send + 'static {
+    /// Another handle on the same stream, for the other thread.
+    fn try_clone(&self) -> io::Result<Self>
+    where
+        Self: Sized;
+
+    /// Shut down like [`TcpStream::shutdown`], reads blocked in other
+    /// threads return when it's shut down for reading too.
+    fn shutdown(&self, how: Shutdown) -> io::Result<()>;
+}
+
+impl Transport for TcpStream {
+    fn try_clone(&self) -> io::Result<Self> {
+        TcpStream::try_clone(self)
+    }
+
+    fn shutdown(&self, how: Shutdown) -> io::Result<()> {
+        TcpStream::shutdown(self, how)
+    }
+}
+
+#[cfg(unix)]
+impl Transport for std::os::unix::net::UnixStream {
+    fn try_clone(&self) -> io::Result<Self> {
+        std::os::unix::net::UnixStream::try_clone(self)
+    }
+
+    fn shutdown(&self, how: Shutdown) -> io::Result<()> {
+        std::os::unix::net::UnixStream::shutdown(self, how)
+    }
+}
+
+/// The connection a session runs over at the moment.
+#[derive(Default)]
+struct Connection {
+    stream: Option<Box<dyn Transport>>,
+    reader: Option<JoinHandle<()>>,
+    writer: Option<JoinHandle<()>>,
+}
+
+/// Dials the server again, for clients of resumable sessions.
+type Redial = Box<dyn Fn() -> io::Result<TcpStream> + Send + Sync>;
+
+struct Shared {
+    state: Mutex<State>,
+    /// Data, keys or an error arrived.
+    readable: Condvar,
+    /// Buffered data went out.
+    writable: Condvar,
+    /// There may be frames for the writer thread.
+    work: Condvar,
+    /// Locked before `state` when both are.
+    connection: Mutex<Connection>,
+    redial: Option<Redial>,
+    resume_timeout: Duration,
+}
+
+impl Shared {
+    fn new(state: State, redial: Option<Redial>, config: &Config) -> Arc<Self> {
+        Arc::new(Shared {
+            state: Mutex::new(state),
+            readable: Condvar::new(),
+            writable: Condvar::new(),
+            work: Condvar::new(),
+            connection: Mutex::new(Connection::default()),
+            redial,
+            resume_timeout: config.resume_timeout,
+        })
+    }
+
+    fn lock(&self) -> MutexGuard<'_, State> {
+        self.state.lock().unwrap()
+    }
+
+    fn fail(&self, error: &io::Error) {
+        self.lock().fail(error);
+        self.notify_all();
+    }
+
+    /// Repeat `poll` whenever `condvar` is notified until it's ready.
+    fn wait<T>(&self, condvar: &Condvar, mut poll: impl FnMut(&mut State) -> Poll<T>) -> T {
+        let mut state = self.lock();
+        loop {
+            if let Poll::Ready(value) = poll(&mut state) {
+                return value;
+            }
+            state = condvar.wait(state).unwrap();
+        }
+    }
+
+    /// Like [`wait`](Self::wait), `None` if it isn't ready within
+    /// `timeout`.
+    fn wait_timeout<T>(
+        &self,
+        condvar: &Condvar,
+        timeout: Duration,
+        mut poll: impl FnMut(&mut State) -> Poll<T>,
+    ) -> Option<T> {
+        // too far ahead to ever come
+        let deadline = Instant::now().checked_add(timeout);
+        let mut state = self.lock();
+        loop {
+            if let Poll::Ready(value) = poll(&mut state) {
+                return Some(value);
+            }
+            state = match deadline {
+                Some(deadline) => {
+                    let left = deadline.saturating_duration_since(Instant::now());
+                    if left.is_zero() {
+                        return None;
+                    }
+                    condvar.wait_timeout(state, left).unwrap().0
+                }
+                None => condvar.wait(state).unwrap(),
+            };
+        }
+    }
+
+    fn notify_all(&self) {
+        self.readable.notify_all();
+        self.writable.notify_all();
+        self.work.notify_all();
+    }
+}
+
+/// Run connection `generation` of the session over `stream`.
+fn start<T: Transport>(shared: &Arc<Shared>, stream: T, generation: u64) -> io::Result<()> {
+    let input = BufReader::new(stream.try_clone()?);
+    let output = BufWriter::new(stream.try_clone()?);
+
+    let mut connection = shared.connection.lock().unwrap();
+    {
+        let state = shared.lock();
+        if state.generation != generation || state.error.is_some() {
+            return Err(io::Error::new(
+                io::ErrorKind::ConnectionAborted,
+                "the session moved on",
+            ));
+        }
+    }
+    let reader = {
+        let shared = shared.clone();
+        thread::spawn(move || read_loop(&shared, input, generation))
+    };
+    let writer = {
+        let shared = shared.clone();
+        thread::spawn(move || write_loop(&shared, output, generation))
+    };
+    // the threads of an earlier connection exit on their own
+    *connection = Connection {
+        stream: Some(Box::new(stream)),
+        reader: Some(reader),
+        writer: Some(writer),
+    };
+    Ok(())
+}
+
+/// Connection `generation` failed: resume the session over a new one if
+/// it's resumable, otherwise fail it.
+fn disconnect(shared: &Arc<Shared>, generation: u64, error: io::Error) {
+    let mut connection = shared.connection.lock().unwrap();
+    let mut state = shared.lock();
+    if state.generation != generation {
+        return;
+    }
+    if let Some(stream) = connection.stream.take() {
+        let _ = stream.shutdown(Shutdown::Both);
+    }
+    // a broken frame means a broken peer, not a broken connection
+    let resume = state.session.is_some()
+        && state.error.is_none()
+        && !(state.finished && state.peer_done)
+        && error.kind() != io::ErrorKind::InvalidData;
+    if resume {
+        state.reconnect();
+    } else {
+        state.abort(&error);
+    }
+    drop(state);
+    drop(connection);
+    shared.notify_all();
+
+    if resume {
+        let shared = shared.clone();
+        thread::spawn(move || resume_session(&shared, generation + 1, error));
+    }
+}
+
+/// Get connection `generation` going, dialing again on clients and
+/// waiting for the client on servers, failing with `error` if it takes
+/// too long.
+fn resume_session(shared: &Arc<Shared>, generation: u64, error: io::Error) {
+    let mut backoff = Duration::from_millis(10);
+    loop {
+        let state = shared.lock();
+        if state.generation != generation
+            || state.error.is_some()
+            || state.disconnected_at.is_none()
+        {
+            return;
+        }
+        let since = state.disconnected_at.unwrap().elapsed();
+        let Some(left) = shared.resume_timeout.checked_sub(since) else {
+            drop(state);
+            return disconnect_failed(shared, generation, error);
+        };
+
+        match &shared.redial {
+            Some(redial) => {
+                drop(state);
+                // a new connection dropping starts over, one that didn't
+                // start is retried like a failed dial
+                if let Ok(stream) = redial() {
+                    if start(shared, stream, generation).is_ok() {
+                        return;
+                    }
+                }
+                thread::sleep(backoff.min(left));
+                backoff = (backoff * 2).min(Duration::from_secs(1));
+            }
+            // the client dials in again, see Listener
+            None => drop(shared.work.wait_timeout(state, left).unwrap()),
+        }
+    }
+}
+
+/// The session gave up on resuming.
+fn disconnect_failed(shared: &Shared, generation: u64, error: io::Error) {
+    let connection = shared.connection.lock().unwrap();
+    let mut state = shared.lock();
+    if state.generation == generation {
+        if let Some(stream) = &connection.stream {
+            let _ = stream.shutdown(Shutdown::Both);
+        }
+        state.abort(&error);
+    }
+    drop(state);
+    drop(connection);
+    shared.notify_all();
+}
+
+/// A connection carrying keyed streams, see the [module docs](self).
+///
+/// Dropping it without [`close`](Conn::close) tears the connection down
+/// at once.
+pub struct Conn {
+    shared: Arc<Shared>,
+    closed: AtomicBool,
+}
+
+impl Conn {
+    pub fn new(stream: TcpStream) -> io::Result<Self> {
+        Self::with_config(stream, Config::default())
+    }
+
+    /// Like [`new`](Conn::new), but with other than the default settings.
+    pub fn with_config(stream: TcpStream, config: Config) -> io::Result<Self> {
+        stream.set_nodelay(true)?;
+        Self::with_transport(stream, config)
+    }
+
+    /// Run over a stream other than TCP.
+    pub fn with_transport<T: Transport>(transport: T, config: Config) -> io::Result<Self> {
+        let shared = Shared::new(State::new(&config), None, &config);
+        start(&shared, transport, 0)?;
+        Ok(Self {
+            shared,
+            closed: AtomicBool::new(false),
+        })
+    }
+
+    /// Run over TLS as the client, checking the server's certificate is
+    /// for `server_name`. See [`tls`](crate::tls) for configurations with
+    /// a client certificate.
+    pub fn connect_tls(
+        stream: TcpStream,
+        server_name: &str,
+        tls: Arc<rustls::ClientConfig>,
+        config: Config,
+    ) -> io::Result<Self> {
+        stream.set_nodelay(true)?;
+        Self::with_transport(TlsStream::connect(stream, server_name, tls)?, config)
+    }
+
+    /// Run over TLS as the server.
+    pub fn accept_tls(
+        stream: TcpStream,
+        tls: Arc<rustls::ServerConfig>,
+        config: Config,
+    ) -> io::Result<Self> {
+        stream.set_nodelay(true)?;
+        Self::with_transport(TlsStream::accept(stream, tls)?, config)
+    }
+
+    /// Connect to a [`Listener`], resuming the session over a new
+    /// connection whenever one drops.
+    pub fn dial<A: ToSocketAddrs>(address: A, config: Config) -> io::Result<Self> {
+        let addresses: Vec<SocketAddr> = address.to_socket_addrs()?.collect();
+        let redial = move || {
+            let stream = TcpStream::connect(&addresses[..])?;
+            stream.set_nodelay(true)?;
+            Ok(stream)
+        };
+        let stream = redial()?;
+        let state = State::new(&config).resumable(None);
+        let shared = Shared::new(state, Some(Box::new(redial)), &config);
+        start(&shared, stream, 0)?;
+        Ok(Self {
+            shared,
+            closed: AtomicBool::new(false),
+        })
+    }
+
+    /// The checks both sides agreed on, `None` until the peer's handshake
+    /// arrived.
+    pub fn integrity(&self) -> Option<Integrity> {
+        self.shared.lock().integrity()
+    }
+
+    /// The ID the session resumes under, `None` until the handshake or if
+    /// it can't be resumed.
+    pub fn session(&self) -> Option<u64> {
+        self.shared.lock().session()
+    }
+
+    /// The compressions both sides offer, `None` until the peer's
+    /// handshake arrived.
+    pub fn compressions(&self) -> Option<Vec<Compression>> {
+        self.shared.lock().compressions()
+    }
+
+    /// Start sending `key`. The data is complete once the writer is
+    /// [closed](KeyWriter::close) or dropped.
+    pub fn send(&self, key: &str) -> io::Result<KeyWriter> {
+        self.send_with(key, &SendOptions::default())
+    }
+
+    /// Like [`send`](Conn::send), but with other than the default options.
+    pub fn send_with(&self, key: &str, options: &SendOptions) -> io::Result<KeyWriter> {
+        let stream = self.shared.lock().open(key, options)?;
+        self.shared.work.notify_one();
+        Ok(KeyWriter {
+            shared: self.shared.clone(),
+            stream,
+            ended: false,
+        })
+    }
+
+    /// Wait for the next key, `None` once either side closed the
+    /// connection.
+    pub fn receive(&self) -> io::Result<Option<(String, KeyReader)>> {
+        let accepted = self
+            .shared
+            .wait(&self.shared.readable, State::poll_accept)?;
+        Ok(accepted.map(|(key, metadata, stream)| {
+            let reader = KeyReader {
+                shared: self.shared.clone(),
+                stream,
+                metadata,
+            };
+            (key, reader)
+        }))
+    }
+
+    /// Send the keys written so far, then close the connection, see the
+    /// [module docs](self). Keys still being written end with what their
+    /// writers wrote, keys still being received are cut off; writing to the
+    /// former and reading the latter fail with
+    /// [`NotConnected`](io::ErrorKind::NotConnected). Closing again, or
+    /// after the connection idled out, has no effect.
+    ///
+    /// If the keys don't go out within the
+    /// [close timeout](Config::close_timeout), the peer not reading them for
+    /// example, the connection is torn down like with [`abort`](Conn::abort)
+    /// and closing fails with [`TimedOut`](io::ErrorKind::TimedOut).
+    ///
+    /// What the peer still sends, window updates for example, is read and
+    /// thrown away in the background until it closes its side too, so the
+    /// connection isn't reset before the peer read everything.
+    pub fn close(&self) -> io::Result<()> {
+        self.closed.store(true, Ordering::Relaxed);
+        let result = {
+            let mut state = self.shared.lock();
+            let result = if state.closing { Ok(()) } else { state.check() };
+            state.close();
+            result
+        };
+        self.shared.notify_all();
+        // over whichever connection the session is on by then
+        let timeout = self.shared.lock().close_timeout;
+        let drained = self
+            .shared
+            .wait_timeout(&self.shared.writable, timeout, |state| {
+                if state.finished || state.error.is_some() {
+                    Poll::Ready(())
+                } else {
+                    Poll::Pending
+                }
+            });
+        if drained.is_none() {
+            self.tear_down(CLOSE_TIMED_OUT);
+            return Err(io::Error::new(io::ErrorKind::TimedOut, CLOSE_TIMED_OUT));
+        }
+
+        let mut connection = std::mem::take(&mut *self.shared.connection.lock().unwrap());
+        if let Some(writer) = connection.writer.take() {
+            let _ = writer.join();
+        }
+        // the writer thread may have failed while draining
+        let result = result.and(match self.shared.lock().error.take() {
+            Some((kind, message)) => Err(io::Error::new(kind, message)),
+            None => Ok(()),
+        });
+        // the writer thread ended the writing side after the GOAWAY
+        if let (Some(stream), Err(_)) = (&connection.stream, &result) {
+            let _ = stream.shutdown(Shutdown::Both);
+        }
+        // the reader thread exits at the peer's end of the connection
+        result
+    }
+
+    /// Tear the connection down at once, like dropping it. Keys in flight
+    /// fail on both sides, readers and writers of this one with
+    /// [`ConnectionAborted`](io::ErrorKind::ConnectionAborted).
+    pub fn abort(&self) {
+        self.closed.store(true, Ordering::Relaxed);
+        self.tear_down("the connection was aborted");
+    }
+
+    fn tear_down(&self, message: &str) {
+        let error = io::Error::new(io::ErrorKind::ConnectionAborted, message);
+        self.shared.lock().abort(&error);
+        self.shared.notify_all();
+        let connection = std::mem::take(&mut *self.shared.connection.lock().unwrap());
+        if let Some(stream) = &connection.stream {
+            let _ = stream.shutdown(Shutdown::Both);
+        }
+        for thread in [connection.writer, connection.reader].into_iter().flatten() {
+            let _ = thread.join();
+        }
+    }
+}
+
+impl Drop for Conn {
+    fn drop(&mut self) {
+        if !*self.closed.get_mut() {
+            self.tear_down("the connection was dropped");
+        }
+    }
+}
+
+/// Accepts connections from [`Conn::dial`], and from [`Conn::new`] on the
+/// other end too. Clients coming back to resume a session are handed to
+/// it in the background.
+pub struct Listener {
+    address: SocketAddr,
+    accepted: Mutex<mpsc::Receiver<Conn>>,
+    stop: Arc<AtomicBool>,
+    thread: Option<JoinHandle<()>>,
+}
+
+/// How long a new connection has for its `HELLO`.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(5);
+
+impl Listener {
+    pub fn bind<A: ToSocketAddrs>(address: A, config: Config) -> io::Result<Self> {
+        let listener = TcpListener::bind(address)?;
+        let address = listener.local_addr()?;
+        let (sender, accepted) = mpsc::channel();
+        let stop = Arc::new(AtomicBool::new(false));
+        let thread = {
+            let stop = stop.clone();
+            thread::spawn(move || accept_loop(listener, config, sender, &stop))
+        };
+        Ok(Self {
+            address,
+            accepted: Mutex::new(accepted),
+            stop,
+            thread: Some(thread),
+        })
+    }
+
+    pub fn local_addr(&self) -> SocketAddr {
+        self.address
+    }
+
+    /// Wait for the next new session.
+    pub fn accept(&self) -> io::Result<Conn> {
+        self.accepted
+            .lock()
+            .unwrap()
+            .recv()
+            .map_err(|_| io::Error::new(io::ErrorKind::BrokenPipe, "the listener stopped"))
+    }
+}
+
+impl Drop for Listener {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::Relaxed);
+        // unblock the accept
+        let _ = TcpStream::connect(self.address);
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+fn accept_loop(
+    listener: TcpListener,
+    config: Config,
+    accepted: mpsc::Sender<Conn>,
+    stop: &AtomicBool,
+) {
+    let sessions: Arc<Mutex<HashMap<u64, Weak<Shared>>>> = Arc::default();
+    for stream in listener.incoming() {
+        if stop.load(Ordering::Relaxed) {
+            return;
+        }
+        let Ok(stream) = stream else { continue };
+        // a client slow with its HELLO only holds itself up
+        let (config, accepted, sessions) = (config.clone(), accepted.clone(), sessions.clone());
+        thread::spawn(move || handshake(stream, &config, &accepted, &sessions));
+    }
+}
+
+/// Read the `HELLO` of a new connection, then start a session on it or
+/// carry on the one it names.
+fn handshake(
+    mut stream: TcpStream,
+    config: &Config,
+    accepted: &mpsc::Sender<Conn>,
+    sessions: &Mutex<HashMap<u64, Weak<Shared>>>,
+) {
+    if stream.set_nodelay(true).is_err() {
+        return;
+    }
+    let hello = stream
+        .set_read_timeout(Some(HANDSHAKE_TIMEOUT))
+        .and_then(|()| Frame::read_from(&mut stream))
+        .and_then(|hello| {
+            stream.set_read_timeout(None)?;
+            Ok(hello)
+        });
+    let (features, session) = match hello {
+        Ok(Some(Frame::Hello { features, session })) => (features, session),
+        _ => return,
+    };
+    let hello = Frame::Hello { features, session };
+
+    if session != 0 {
+        let shared = {
+            let mut sessions = sessions.lock().unwrap();
+            sessions.retain(|_, shared| shared.strong_count() > 0);
+            sessions.get(&session).and_then(Weak::upgrade)
+        };
+        match shared {
+            Some(shared) => attach(&shared, stream, hello),
+            // the client fails on a session other than its own
+            None => {
+                let _ = Frame::Hello {
+                    features: 0,
+                    session: 0,
+                }
+                .write_to(&mut stream);
+            }
+        }
+        return;
+    }
+
+    let mut state = State::new(config);
+    if features & RESUMABLE != 0 {
+        let session = new_session_id();
+        state = state.resumable(Some(session));
+    }
+    let session = state.session();
+    if state.receive_frame(hello).is_err() {
+        return;
+    }
+    let shared = Shared::new(state, None, config);
+    if start(&shared, stream, 0).is_err() {
+        return;
+    }
+    if let Some(session) = session {
+        sessions
+            .lock()
+            .unwrap()
+            .insert(session, Arc::downgrade(&shared));
+    }
+    let conn = Conn {
+        shared,
+        closed: AtomicBool::new(false),
+    };
+    // the listener is gone when this fails, the connection with it
+    let _ = accepted.send(conn);
+}
+
+/// Carry on the session of `shared` over `stream`, the client dialed in
+/// again and sent `hello`.
+fn attach(shared: &Arc<Shared>, stream: TcpStream, hello: Frame) {
+    // the old connection may not have noticed it's gone yet
+    let generation = shared.lock().generation;
+    if shared.connection.lock().unwrap().stream.is_some() {
+        let error = io::Error::new(io::ErrorKind::ConnectionReset, "the client reconnected");
+        disconnect(shared, generation, error);
+    }
+
+    let (generation, result) = {
+        let mut state = shared.lock();
+        (state.generation, state.receive_frame(hello))
+    };
+    match result {
+        // there's no other connection to fall back on
+        Ok(()) => {
+            if let Err(e) = start(shared, stream, generation) {
+                disconnect_failed(shared, generation, e);
+            }
+        }
+        Err(e) => shared.fail(&e),
+    }
+}
+
+/// Random enough to not be guessed by accident.
+fn new_session_id() -> u64 {
+    let id = RandomState::new().build_hasher().finish();
+    id.max(1)
+}
+
+fn read_loop<R: Read>(shared: &Arc<Shared>, mut input: R, generation: u64) {
+    loop {
+        let frame = match Frame::read_from(&mut input) {
+            Ok(Some(frame)) => frame,
+            Ok(None) => {
+                let mut state = shared.lock();
+                if state.generation != generation {
+                    return;
+                }
+                // without GOAWAY it's a drop only resumable sessions survive
+                if state.peer_done || state.session.is_none() {
+                    state.peer_done = true;
+                    drop(state);
+                    return shared.notify_all();
+                }
+                drop(state);
+                let error = io::Error::new(io::ErrorKind::UnexpectedEof, "the connection dropped");
+                return disconnect(shared, generation, error);
+            }
+            Err(e) => return disconnect(shared, generation, e),
+        };
+
+        let mut state = shared.lock();
+        if state.generation != generation {
+            return;
+        }
+        if let Err(e) = state.receive_frame(frame) {
+            state.fail(&e);
+            drop(state);
+            return shared.notify_all();
+        }
+        drop(state);
+        shared.notify_all();
+    }
+}
+
+fn write_loop<T: Transport>(shared: &Arc<Shared>, mut output: BufWriter<T>, generation: u64) {
+    let mut unflushed = false;
+    loop {
+        let mut state = shared.lock();
+        let frame = loop {
+            if state.error.is_some() || state.generation != generation {
+                return;
+            }
+            if let Some(frame) = state.next_frame() {
+                break frame;
+            }
+            if unflushed {
+                // nothing else to batch with, let it out
+                drop(state);
+                unflushed = false;
+                if let Err(e) = output.flush() {
+                    return disconnect(shared, generation, e);
+                }
+                state = shared.lock();
+            } else {
+                state = match state.wake_at(Instant::now()) {
+                    Some(until) => {
+                        let timeout = until.saturating_duration_since(Instant::now());
+                        shared.work.wait_timeout(state, timeout).unwrap().0
+                    }
+                    None => shared.work.wait(state).unwrap(),
+                };
+            }
+        };
+        drop(state);
+        shared.writable.notify_all();
+
+        // FIN after the GOAWAY
+        let last = frame == Frame::GoAway;
+        let written = frame.write_to(&mut output).and_then(|()| {
+            if last {
+                output
+                    .flush()
+                    .and_then(|()| output.get_ref().shutdown(Shutdown::Write))
+            } else {
+                Ok(())
+            }
+        });
+        if let Err(e) = written {
+            return disconnect(shared, generation, e);
+        }
+        if last {
+            shared.lock().finished = true;
+            return shared.notify_all();
+        }
+        unflushed = true;
+    }
+}
+
+/// Writes the data of one key, see [`Conn::send`]. `write` blocks while
+/// [`INITIAL_WINDOW`] bytes of the key wait to be sent.
+pub struct KeyWriter {
+    shared: Arc<Shared>,
+    stream: u32,
+    ended: bool,
+}
+
+impl KeyWriter {
+    /// Tell the peer all data of the key was written. The data and the end
+    /// are sent in the background.
+    pub fn close(mut self) -> io::Result<()> {
+        self.end()
+    }
+
+    /// Give up on the key: what wasn't sent yet is dropped and the peer's
+    /// reader fails with [`ConnectionAborted`](io::ErrorKind::ConnectionAborted)
+    /// and `reason` after what it got. The connection carries on.
+    pub fn abort(mut self, reason: &str) -> io::Result<()> {
+        self.ended = true;
+        let result = self.shared.lock().abort_stream(self.stream, reason);
+        self.shared.work.notify_one();
+        result
+    }
+
+    fn end(&mut self) -> io::Result<()> {
+        self.ended = true;
+        let result = self.shared.lock().end(self.stream);
+        self.shared.work.notify_one();
+        result
+    }
+}
+
+impl Write for KeyWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = self.shared.wait(&self.shared.writable, |state| {
+            state.poll_write(self.stream, buf)
+        })?;
+        self.shared.work.notify_one();
+        Ok(n)
+    }
+
+    /// Wait until everything written was handed to the connection.
+    fn flush(&mut self) -> io::Result<()> {
+        self.shared
+            .wait(&self.shared.writable, |state| state.poll_flush(self.stream))
+    }
+}
+
+impl Drop for KeyWriter {
+    fn drop(&mut self) {
+        if !self.ended {
+            let _ = self.end();
+        }
+    }
+}
+
+/// Reads the data of one key, see [`Conn::receive`]. Reads return 0 at the
+/// end of the key.
+///
+/// Dropping it early [rejects](KeyReader::reject) the rest of the key.
+pub struct KeyReader {
+    shared: Arc<Shared>,
+    stream: u32,
+    metadata: Metadata,
+}
+
+impl KeyReader {
+    /// What the sender told about the key, empty if nothing.
+    pub fn metadata(&self) -> &Metadata {
+        &self.metadata
+    }
+
+    /// Tell the sender to stop, its writer fails with
+    /// [`ConnectionRefused`](io::ErrorKind::ConnectionRefused) and
+    /// `reason`. The connection carries on.
+    pub fn reject(self, reason: &str) {
+        if self.shared.lock().reject(self.stream, reason) {
+            self.shared.work.notify_one();
+        }
+    }
+}
+
+impl Read for KeyReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.shared.wait(&self.shared.readable, |state| {
+            state.poll_read(self.stream, buf)
+        })?;
+        self.shared.work.notify_one();
+        Ok(n)
+    }
+}
+
+impl Drop for KeyReader {
+    fn drop(&mut self) {
+        if self.shared.lock().reject(self.stream, "") {
+            self.shared.work.notify_one();
+        }
+    }
+}
+
+fn invalid<E: Into<Box<dyn std::error::Error + Send + Sync>>>(error: E) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, error)
+}
+
+/// The error for a key the peer gave up on, `what` happened and why.
+fn cancelled(kind: io::ErrorKind, what: &str, reason: &str) -> io::Error {
+    match reason {
+        "" => io::Error::new(kind, what),
+        reason => io::Error::new(kind, format!("{}: {}", what, reason)),
+    }
+}
+
+fn rejected(reason: &str) -> io::Error {
+    cancelled(
+        io::ErrorKind::ConnectionRefused,
+        "the receiver rejected the key",
+        reason,
+    )
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use std::collections::HashMap;
+    use std::net::{SocketAddr, TcpListener};
+    use std::ops::Range;
+    use std::sync::atomic::{AtomicU64, AtomicUsize};
+    use std::sync::Arc;
+    use std::thread::{self, JoinHandle};
+    use std::time::{SystemTime, UNIX_EPOCH};
+
+    use sha2::{Digest, Sha256};
+
+    /// Accepts one connection and runs `handle` on it.
+    fn start_server<F>(handle: F) -> (SocketAddr, JoinHandle<()>)
+    where
+        F: FnOnce(Conn) + Send + 'static,
+    {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let server = thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            handle(Conn::new(stream).unwrap());
+        });
+        (address, server)
+    }
+
+    fn dial(address: SocketAddr) -> Conn {
+        Conn::new(TcpStream::connect(address).unwrap()).unwrap()
+    }
+
+    /// What [`start_proxy`] does to the bytes on their way to the server.
+    enum Tamper {
+        /// Invert the byte at the offset.
+        Flip(usize),
+        /// Leave the bytes out.
+        Skip(Range<usize>),
+        /// Shut the connection down at the offset.
+        Cut(usize),
+    }
+
+    /// Relays one connection to `upstream`, tampering with the client's
+    /// bytes.
+    fn start_proxy(upstream: SocketAddr, tamper: Tamper) -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        thread::spawn(move || {
+            let (mut client, _) = listener.accept().unwrap();
+            let mut server = TcpStream::connect(upstream).unwrap();
+            {
+                let mut client = client.try_clone().unwrap();
+                let mut server = server.try_clone().unwrap();
+                thread::spawn(move || {
+                    let _ = io::copy(&mut server, &mut client);
+                    let _ = client.shutdown(Shutdown::Write);
+                });
+            }
+
+            let mut offset = 0;
+            let mut buf = vec![0; 64 << 10];
+            let mut cut = false;
+            while let Ok(n @ 1..) = client.read(&mut buf) {
+                // bytes past a cut are swallowed so the client isn't blocked
+                let mut relayed = Vec::with_capacity(n);
+                for (at, &byte) in (offset..).zip(&buf[..n]) {
+                    match tamper {
+                        Tamper::Flip(flip) if at == flip => relayed.push(!byte),
+                        Tamper::Skip(ref skip) if skip.contains(&at) => {}
+                        Tamper::Cut(end) if at >= end => cut = true,
+                        _ => relayed.push(byte),
+                    }
+                }
+                offset += n;
+                if server.write_all(&relayed).is_err() {
+                    break;
+                }
+                if cut {
+                    let _ = server.shutdown(Shutdown::Write);
+                }
+            }
+            let _ = server.shutdown(Shutdown::Write);
+        });
+        address
+    }
+
+    /// Where the first payload of `key` starts when it's the first thing
+    /// the client sends.
+    fn payload_offset(key: &str, integrity: Integrity) -> usize {
+        let hello = 17;
+        let open = 7 + key.len();
+        let data = if integrity.crc32c { 13 } else { 9 };
+        hello + open + data
+    }
+
+    /// A state that already exchanged `HELLO`s offering `integrity`.
+    fn negotiated(integrity: Integrity) -> State {
+        let mut state = State::new(&Config::new().integrity(integrity));
+        let hello = state.next_frame().unwrap();
+        state.receive_frame(hello).unwrap();
+        state
+    }
+
+    /// xorshift64*, random enough and fast in debug builds.
+    pub(crate) struct Random(u64);
+
+    impl Random {
+        pub(crate) fn new() -> Self {
+            static SEQUENCE: AtomicU64 = AtomicU64::new(0);
+            let nanos = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap()
+                .as_nanos() as u64;
+            Random((nanos ^ SEQUENCE.fetch_add(0x9E37_79B9_7F4A_7C15, Ordering::Relaxed)) | 1)
+        }
+
+        pub(crate) fn fill(&mut self, buf: &mut [u8]) {
+            for chunk in buf.chunks_mut(8) {
+                self.0 ^= self.0 >> 12;
+                self.0 ^= self.0 << 25;
+                self.0 ^= self.0 >> 27;
+                let value = self.0.wrapping_mul(0x2545_F491_4F6C_DD1D).to_le_bytes();
+                chunk.copy_from_slice(&value[..chunk.len()]);
+            }
+        }
+    }
+
+    pub(crate) fn new_random_key() -> String {
+        let mut buf = [0; 8];
+        Random::new().fill(&mut buf);
+        hex(&buf)
+    }
+
+    pub(crate) fn hex(bytes: &[u8]) -> String {
+        bytes.iter().map(|b| format!("{:02x}", b)).collect()
+    }
+
+    /// Reads everything with a buffer size the protocol knows nothing
+    /// about, returning the checksum.
+    fn read_random_data(reader: &mut impl Read) -> String {
+        let mut hash = Sha256::new();
+        let mut buf = vec![0; 23 << 20];
+        loop {
+            let n = reader.read(&mut buf).unwrap();
+            if n == 0 {
+                break;
+            }
+            hash.update(&buf[..n]);
+        }
+        hex(&hash.finalize())
+    }
+
+    /// Writes `size` random bytes in writes of `buf_size`, returning the
+    /// checksum.
+    fn write_random_data(writer: &mut impl Write, size: usize, buf_size: usize) -> String {
+        let mut hash = Sha256::new();
+        let mut random = Random::new();
+        let mut buf = vec![0; buf_size];
+        let mut written = 0;
+        for _ in 0..size / buf_size {
+            random.fill(&mut buf);
+            hash.update(&buf);
+            writer.write_all(&buf).unwrap();
+            written += buf.len();
+        }
+        assert_eq!(written, size);
+        hex(&hash.finalize())
+    }
+
+    #[test]
+    fn test_frames_round_trip() {
+        let frames = [
+            Frame::Hello {
+                features: 3,
+                session: 1 << 40,
+            },
+            Frame::Open {
+                stream: 7,
+                key: "Bible".to_string(),
+                metadata: None,
+            },
+            Frame::Open {
+                stream: 8,
+                key: "Psalms".to_string(),
+                metadata: Some(Metadata {
+                    content_type: Some("text/plain".to_string()),
+                    length: Some(5 << 32),
+                    headers: vec![
+                        ("translation".to_string(), "KJV".to_string()),
+                        ("translation".to_string(), String::new()),
+                    ],
+                }),
+            },
+            Frame::Open {
+                stream: 9,
+                key: String::new(),
+                metadata: Some(Metadata::default()),
+            },
+            Frame::Data {
+                stream: 7,
+                payload: vec![1, 2, 3],
+                crc: None,
+                compressed: None,
+            },
+            Frame::Data {
+                stream: 7,
+                payload: vec![4, 5],
+                crc: Some(0xDEAD_BEEF),
+                compressed: None,
+            },
+            Frame::Data {
+                stream: 7,
+                payload: vec![6],
+                crc: None,
+                compressed: Some((Compression::Lz4, 1000)),
+            },
+            Frame::Data {
+                stream: 7,
+                payload: vec![7, 8],
+                crc: Some(0xFEED_FACE),
+                compressed: Some((Compression::Zstd, 2000)),
+            },
+            Frame::Window {
+                stream: 3,
+                increment: 4096,
+            },
+            Frame::End {
+                stream: 7,
+                digest: None,
+            },
+            Frame::End {
+                stream: 8,
+                digest: Some([7; 32]),
+            },
+            Frame::Abort {
+                stream: 9,
+                reason: "the disk is full".to_string(),
+            },
+            Frame::Reject {
+                stream: 4,
+                reason: String::new(),
+            },
+            Frame::Resume {
+                opened: 9,
+                streams: vec![
+                    Resumed {
+                        stream: 7,
+                        received: 5 << 32,
+                        window: 1000,
+                        ended: false,
+                    },
+                    Resumed {
+                        stream: 8,
+                        received: 0,
+                        window: 0,
+                        ended: true,
+                    },
+                ],
+            },
+            Frame::GoAway,
+        ];
+        let mut wire = Vec::new();
+        for frame in &frames {
+            frame.write_to(&mut wire).unwrap();
+        }
+
+        let mut input = &wire[..];
+        for frame in frames {
+            assert_eq!(Frame::read_from(&mut input).unwrap(), Some(frame));
+        }
+        assert_eq!(Frame::read_from(&mut input).unwrap(), None);
+
+        let mut truncated = &wire[..wire.len() - 2];
+        for _ in 0..14 {
+            Frame::read_from(&mut truncated).unwrap().unwrap();
+        }
+        assert_eq!(
+            Frame::read_from(&mut truncated).unwrap_err().kind(),
+            io::ErrorKind::UnexpectedEof
+        );
+        assert!(Frame::read_from(&mut &[42, 0, 0, 0, 0][..]).is_err());
+        let mut unknown = vec![COMPRESSED_DATA, 0, 0, 0, 0, 1, 0, 0, 0, 1, 0, 0, 0, 3, 0];
+        assert!(Frame::read_from(&mut &unknown[..]).is_err());
+        // bigger than a frame may be once decompressed
+        unknown[9..14].copy_from_slice(&[0, 0, 0, 1, 1]);
+        assert!(Frame::read_from(&mut &unknown[..]).is_err());
+        // a header count beyond the metadata
+        let open = [OPEN_WITH_METADATA, 0, 0, 0, 0, 0, 0, 3, 0, 0, 0, 0, 1, 0];
+        assert!(Frame::read_from(&mut &open[..]).is_err());
+    }
+
+    #[test]
+    fn test_streams_take_turns() {
+        let mut state = negotiated(Integrity::NONE);
+        for stream in 0..3 {
+            state.sending.insert(
+                stream,
+                Sending {
+                    chunks: vec![vec![stream as u8; 10]; 2].into(),
+                    buffered: 20,
+                    credit: INITIAL_WINDOW,
+                    ending: stream == 2,
+                    ..Sending::default()
+                },
+            );
+        }
+        state.sending.get_mut(&1).unwrap().credit = 15;
+
+        let mut sent = Vec::new();
+        while let Some(frame) = state.next_frame() {
+            sent.push(match frame {
+                Frame::Data {
+                    stream, payload, ..
+                } => (stream, payload.len()),
+                Frame::End { stream, .. } => (stream, 0),
+                frame => panic!("{:?}", frame),
+            });
+        }
+        // stream 1 runs out of window half way through its second chunk
+        assert_eq!(
+            sent,
+            [(1, 10), (2, 10), (0, 10), (1, 5), (2, 10), (0, 10), (2, 0)]
+        );
+
+        state
+            .receive_frame(Frame::Window {
+                stream: 1,
+                increment: 100,
+            })
+            .unwrap();
+        assert_eq!(
+            state.next_frame(),
+            Some(Frame::Data {
+                stream: 1,
+                payload: vec![1; 5],
+                crc: None,
+                compressed: None,
+            })
+        );
+        assert_eq!(state.next_frame(), None);
+    }
+
+    #[test]
+    fn test_window_is_enforced() {
+        let mut state = negotiated(Integrity::NONE);
+        state
+            .receive_frame(Frame::Open {
+                stream: 0,
+                key: "key".to_string(),
+                metadata: None,
+            })
+            .unwrap();
+        let data = || Frame::Data {
+            stream: 0,
+            payload: vec![0; MAX_DATA],
+            crc: None,
+            compressed: None,
+        };
+        for _ in 0..INITIAL_WINDOW as usize / MAX_DATA {
+            state.receive_frame(data()).unwrap();
+        }
+        assert_eq!(
+            state.receive_frame(data()).unwrap_err().kind(),
+            io::ErrorKind::InvalidData
+        );
+
+        // reading half of it grants that half again
+        state.consumed(0, INITIAL_WINDOW as usize / 2);
+        assert_eq!(
+            state.control.pop_front(),
+            Some(Frame::Window {
+                stream: 0,
+                increment: INITIAL_WINDOW / 2
+            })
+        );
+        state.receive_frame(data()).unwrap();
+    }
+
+    #[test]
+    fn test_integrity_is_negotiated() {
+        let mut state = State::new(&Config::new());
+        assert!(matches!(
+            state.next_frame(),
+            Some(Frame::Hello {
+                features,
+                session: 0
+            }) if features == CRC32C | SHA256 | COMPRESSIONS
+        ));
+        // nothing goes out before the peer's offer arrived
+        state.open("key", &SendOptions::default()).unwrap();
+        assert_eq!(state.next_frame(), None);
+        assert!(!state.drained());
+
+        let open = Frame::Open {
+            stream: 0,
+            key: "key".to_string(),
+            metadata: None,
+        };
+        assert!(state.receive_frame(open).is_err());
+        state
+            .receive_frame(Frame::Hello {
+                features: SHA256 | 8,
+                session: 0,
+            })
+            .unwrap();
+        assert_eq!(
+            state.integrity(),
+            Some(Integrity {
+                crc32c: false,
+                sha256: true
+            })
+        );
+        assert!(matches!(state.next_frame(), Some(Frame::Open { .. })));
+        let hello = Frame::Hello {
+            features: 0,
+            session: 0,
+        };
+        assert!(state.receive_frame(hello).is_err());
+
+        state
+            .receive_frame(Frame::Open {
+                stream: 0,
+                key: "key".to_string(),
+                metadata: None,
+            })
+            .unwrap();
+        let data = Frame::Data {
+            stream: 0,
+            payload: vec![1],
+            crc: Some(crc32c::crc32c(&[1])),
+            compressed: None,
+        };
+        assert!(state.receive_frame(data).is_err());
+        let end = Frame::End {
+            stream: 0,
+            digest: None,
+        };
+        assert!(state.receive_frame(end).is_err());
+    }
+
+    #[test]
+    fn test_corrupted_chunk_fails_the_key() {
+        const KEY: &str = "corrupted";
+        let (address, server) = start_server(|conn| {
+            let (key, mut reader) = conn.receive().unwrap().unwrap();
+            assert_eq!(key, KEY);
+            let mut data = Vec::new();
+            let error = reader.read_to_end(&mut data).unwrap_err();
+            assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+            assert!(error.to_string().contains("CRC32C"), "{}", error);
+            assert!(data.is_empty());
+            drop(reader);
+
+            // the connection carries on
+            let (key, mut reader) = conn.receive().unwrap().unwrap();
+            assert_eq!(key, "intact");
+            let mut data = Vec::new();
+            reader.read_to_end(&mut data).unwrap();
+            assert_eq!(data, [2; 200_000]);
+            conn.close().unwrap();
+        });
+
+        let flip = payload_offset(KEY, Integrity::ALL) + 1000;
+        let conn = dial(start_proxy(address, Tamper::Flip(flip)));
+        for (key, byte) in [(KEY, 1), ("intact", 2)] {
+            let mut writer = conn.send(key).unwrap();
+            writer.write_all(&[byte; 200_000]).unwrap();
+            // out before the next key, which would take turns with it
+            writer.flush().unwrap();
+            writer.close().unwrap();
+        }
+        assert!(conn.receive().unwrap().is_none());
+        assert_eq!(conn.integrity(), Some(Integrity::ALL));
+        conn.close().unwrap();
+        server.join().unwrap();
+    }
+
+    /// Without CRCs corruption only shows at the end of the key, after
+    /// the data was read.
+    #[test]
+    fn test_digest_fails_the_key() {
+        const KEY: &str = "key";
+        const SHA256_ONLY: Integrity = Integrity {
+            crc32c: false,
+            sha256: true,
+        };
+        let start = payload_offset(KEY, SHA256_ONLY);
+
+        // a flipped bit, then the whole second DATA frame missing
+        for (tamper, read) in [
+            (Tamper::Flip(start + 1000), 200_000),
+            (
+                Tamper::Skip(start + MAX_DATA..start + 2 * MAX_DATA + 9),
+                200_000 - MAX_DATA,
+            ),
+        ] {
+            let (address, server) = start_server(move |conn| {
+                let (_, mut reader) = conn.receive().unwrap().unwrap();
+                let mut data = Vec::new();
+                let error = reader.read_to_end(&mut data).unwrap_err();
+                assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+                assert!(error.to_string().contains("SHA-256"), "{}", error);
+                assert_eq!(data.len(), read);
+                conn.close().unwrap();
+            });
+
+            let proxy = start_proxy(address, tamper);
+            let config = Config::new().integrity(SHA256_ONLY);
+            let conn = Conn::with_config(TcpStream::connect(proxy).unwrap(), config).unwrap();
+            let mut writer = conn.send(KEY).unwrap();
+            writer.write_all(&[1; 200_000]).unwrap();
+            writer.close().unwrap();
+            assert!(conn.receive().unwrap().is_none());
+            assert_eq!(conn.integrity(), Some(SHA256_ONLY));
+            conn.close().unwrap();
+            server.join().unwrap();
+        }
+    }
+
+    /// A key the connection ends in the middle of fails to read.
+    #[test]
+    fn test_truncated_key_fails() {
+        const KEY: &str = "truncated";
+        let (address, server) = start_server(|conn| {
+            let (_, mut reader) = conn.receive().unwrap().unwrap();
+            let mut data = Vec::new();
+            let error = reader.read_to_end(&mut data).unwrap_err();
+            assert_eq!(error.kind(), io::ErrorKind::UnexpectedEof);
+            assert_eq!(data.len(), MAX_DATA);
+            // the connection broke with it
+            assert!(conn.receive().is_err());
+            assert!(conn.close().is_err());
+        });
+
+        // into the second frame, a frame is only taken whole
+        let cut = payload_offset(KEY, Integrity::ALL) + MAX_DATA + 5000;
+        let conn = dial(start_proxy(address, Tamper::Cut(cut)));
+        let mut writer = conn.send(KEY).unwrap();
+        writer.write_all(&[1; 200_000]).unwrap();
+        writer.close().unwrap();
+        assert!(conn.receive().unwrap().is_none());
+        conn.close().unwrap();
+        server.join().unwrap();
+    }
+
+    /// Checks only run when both sides offer them.
+    #[test]
+    fn test_unchecked_corruption_goes_unnoticed() {
+        const KEY: &str = "unchecked";
+        let (address, server) = start_server(|conn| {
+            let (_, mut reader) = conn.receive().unwrap().unwrap();
+            let mut data = Vec::new();
+            reader.read_to_end(&mut data).unwrap();
+            assert_eq!(data.len(), 200_000);
+            assert_eq!(data.iter().filter(|&&b| b != 1).count(), 1);
+            assert_eq!(conn.integrity(), Some(Integrity::NONE));
+            conn.close().unwrap();
+        });
+
+        let flip = payload_offset(KEY, Integrity::NONE) + 1000;
+        let proxy = start_proxy(address, Tamper::Flip(flip));
+        let config = Config::new().integrity(Integrity::NONE);
+        let conn = Conn::with_config(TcpStream::connect(proxy).unwrap(), config).unwrap();
+        let mut writer = conn.send(KEY).unwrap();
+        writer.write_all(&[1; 200_000]).unwrap();
+        writer.close().unwrap();
+        assert!(conn.receive().unwrap().is_none());
+        conn.close().unwrap();
+        server.join().unwrap();
+    }
+
+    /// Hand up to `frames` frames from `from` to `to`, returning how many
+    /// there were.
+    fn deliver(from: &mut State, to: &mut State, frames: usize) -> usize {
+        let mut delivered = 0;
+        while delivered < frames {
+            let Some(frame) = from.next_frame() else {
+                break;
+            };
+            to.receive_frame(frame).unwrap();
+            delivered += 1;
+        }
+        delivered
+    }
+
+    /// Deliver both ways until nothing moves.
+    fn settle(a: &mut State, b: &mut State) {
+        while deliver(a, b, usize::MAX) + deliver(b, a, usize::MAX) > 0 {}
+    }
+
+    fn write_to_state(state: &mut State, stream: u32, mut data: &[u8]) {
+        while !data.is_empty() {
+            match state.poll_write(stream, data) {
+                Poll::Ready(Ok(n)) => data = &data[n..],
+                poll => panic!("{:?}", poll),
+            }
+        }
+    }
+
+    fn read_from_state(state: &mut State, stream: u32) -> Vec<u8> {
+        let mut data = Vec::new();
+        let mut buf = [0; 10_000];
+        loop {
+            match state.poll_read(stream, &mut buf) {
+                Poll::Ready(Ok(0)) => return data,
+                Poll::Ready(Ok(n)) => data.extend_from_slice(&buf[..n]),
+                poll => panic!("{:?}", poll),
+            }
+        }
+    }
+
+    #[test]
+    fn test_resume_replays_what_was_lost() {
+        let data: Vec<u8> = (0..300_000u32).map(|i| (i % 251) as u8).collect();
+        let mut client = State::new(&Config::new()).resumable(None);
+        let mut server = State::new(&Config::new()).resumable(Some(7));
+        settle(&mut client, &mut server);
+        assert_eq!(client.session(), Some(7));
+
+        let first = client.open("first", &SendOptions::default()).unwrap();
+        write_to_state(&mut client, first, &data);
+        client.end(first).unwrap();
+        // the OPEN and two DATA frames arrive
+        assert_eq!(deliver(&mut client, &mut server, 3), 3);
+
+        let second = client.open("second", &SendOptions::default()).unwrap();
+        write_to_state(&mut client, second, b"second");
+        client.end(second).unwrap();
+        // the OPEN of the second key and some data of both go down with
+        // the connection
+        for _ in 0..3 {
+            client.next_frame().unwrap();
+        }
+
+        client.reconnect();
+        server.reconnect();
+        settle(&mut client, &mut server);
+        for (key, stream) in [("first", first), ("second", second)] {
+            let accepted = server.poll_accept();
+            assert!(
+                matches!(&accepted, Poll::Ready(Ok(Some((k, _, s)))) if k == key && *s == stream),
+                "{:?}",
+                accepted
+            );
+        }
+        assert_eq!(read_from_state(&mut server, first), data);
+        assert_eq!(read_from_state(&mut server, second), b"second");
+        // the ENDs were acknowledged, the client forgot the keys
+        assert!(client.sending.is_empty());
+    }
+
+    /// Like a service log, compresses well.
+    fn log_lines(len: usize) -> Vec<u8> {
+        let mut log = Vec::with_capacity(len + 100);
+        for i in 0.. {
+            if log.len() >= len {
+                break;
+            }
+            writeln!(
+                log,
+                "2026-10-19T12:{:02}:{:02}Z INFO request {} served in {} ms",
+                i / 60 % 60,
+                i % 60,
+                i,
+                i * 7 % 1000
+            )
+            .unwrap();
+        }
+        log.truncate(len);
+        log
+    }
+
+    #[test]
+    fn test_compression_is_negotiated() {
+        let log = log_lines(200_000);
+        let mut client = State::new(&Config::new());
+        let mut server = State::new(&Config::new().compressions(&[Compression::Lz4]));
+        settle(&mut client, &mut server);
+        assert_eq!(client.compressions(), Some(vec![Compression::Lz4]));
+        assert_eq!(server.compressions(), Some(vec![Compression::Lz4]));
+
+        // the server can't decompress zstd, that key goes uncompressed
+        for (compression, used) in [
+            (Compression::Zstd, None),
+            (Compression::Lz4, Some(Compression::Lz4)),
+        ] {
+            let options = SendOptions::new().compression(compression);
+            let stream = client.open("log", &options).unwrap();
+            write_to_state(&mut client, stream, &log);
+            client.end(stream).unwrap();
+            assert_eq!(deliver(&mut client, &mut server, 1), 1);
+
+            let frame = client.next_frame().unwrap();
+            let Frame::Data {
+                payload,
+                compressed,
+                ..
+            } = &frame
+            else {
+                panic!("{:?}", frame);
+            };
+            assert_eq!(compressed.map(|(compression, _)| compression), used);
+            if used.is_some() {
+                assert_eq!(compressed.unwrap().1, MAX_DATA as u32);
+                assert!(payload.len() < MAX_DATA / 2, "{}", payload.len());
+            }
+            server.receive_frame(frame).unwrap();
+            settle(&mut client, &mut server);
+            assert_eq!(read_from_state(&mut server, stream), log);
+        }
+
+        server
+            .receive_frame(Frame::Open {
+                stream: 2,
+                key: "zstd".to_string(),
+                metadata: None,
+            })
+            .unwrap();
+        let payload = zstd::bulk::compress(&log[..1000], 0).unwrap();
+        let data = Frame::Data {
+            stream: 2,
+            crc: Some(crc32c::crc32c(&payload)),
+            payload,
+            compressed: Some((Compression::Zstd, 1000)),
+        };
+        assert!(server.receive_frame(data).is_err());
+    }
+
+    #[test]
+    fn test_incompressible_chunks_go_uncompressed() {
+        let mut state = negotiated(Integrity::NONE);
+        let mut data = vec![0; 2 * MAX_DATA];
+        Random::new().fill(&mut data[..MAX_DATA]);
+        data[MAX_DATA..].copy_from_slice(&log_lines(MAX_DATA));
+
+        let options = SendOptions::new().compression(Compression::Zstd);
+        let stream = state.open("mixed", &options).unwrap();
+        write_to_state(&mut state, stream, &data);
+        assert!(matches!(state.next_frame(), Some(Frame::Open { .. })));
+        let mut packed = Vec::new();
+        while let Some(Frame::Data { compressed, .. }) = state.next_frame() {
+            packed.push(compressed.map(|(compression, _)| compression));
+        }
+        assert_eq!(packed, [None, Some(Compression::Zstd)]);
+    }
+
+    #[test]
+    fn test_compressed_keys_arrive_intact() {
+        let (address, server) = start_server(|conn| {
+            let log = log_lines(3 << 20);
+            for _ in 0..3 {
+                let (_, mut reader) = conn.receive().unwrap().unwrap();
+                let mut data = Vec::new();
+                reader.read_to_end(&mut data).unwrap();
+                assert!(data == log);
+            }
+            assert!(conn.receive().unwrap().is_none());
+            conn.close().unwrap();
+        });
+
+        let conn = dial(address);
+        let log = log_lines(3 << 20);
+        let writers: Vec<_> = [Compression::None, Compression::Lz4, Compression::Zstd]
+            .into_iter()
+            .map(|compression| {
+                let options = SendOptions::new().compression(compression);
+                conn.send_with(&format!("{:?}", compression), &options)
+                    .unwrap()
+            })
+            .collect();
+        thread::scope(|scope| {
+            for mut writer in writers {
+                let log = &log;
+                scope.spawn(move || {
+                    for chunk in log.chunks(100_000) {
+                        writer.write_all(chunk).unwrap();
+                    }
+                    writer.close().unwrap();
+                });
+            }
+        });
+        assert_eq!(
+            conn.compressions(),
+            Some(vec![Compression::Zstd, Compression::Lz4])
+        );
+        conn.close().unwrap();
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn test_announced_length_is_enforced() {
+        let mut client = State::new(&Config::new());
+        let mut server = State::new(&Config::new());
+        settle(&mut client, &mut server);
+
+        let options = SendOptions::new().length(10);
+        let long = client.open("long", &options).unwrap();
+        assert!(matches!(
+            client.poll_write(long, &[1; 15]),
+            Poll::Ready(Ok(10))
+        ));
+        let error = match client.poll_write(long, &[1]) {
+            Poll::Ready(Err(e)) => e,
+            poll => panic!("{:?}", poll),
+        };
+        assert_eq!(error.kind(), io::ErrorKind::InvalidInput);
+        client.end(long).unwrap();
+
+        let short = client.open("short", &options).unwrap();
+        write_to_state(&mut client, short, &[2; 5]);
+        let error = client.end(short).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidInput);
+
+        settle(&mut client, &mut server);
+        assert_eq!(read_from_state(&mut server, long), [1; 10]);
+        let mut buf = [0; 10];
+        assert!(matches!(
+            server.poll_read(short, &mut buf),
+            Poll::Ready(Ok(5))
+        ));
+        let error = match server.poll_read(short, &mut buf) {
+            Poll::Ready(Err(e)) => e,
+            poll => panic!("{:?}", poll),
+        };
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+
+        // a sender not keeping to it fails the key on the receiver too
+        server
+            .receive_frame(Frame::Open {
+                stream: 2,
+                key: "liar".to_string(),
+                metadata: Some(Metadata {
+                    length: Some(1),
+                    ..Metadata::default()
+                }),
+            })
+            .unwrap();
+        let payload = vec![3, 3];
+        let data = Frame::Data {
+            stream: 2,
+            crc: Some(crc32c::crc32c(&payload)),
+            payload,
+            compressed: None,
+        };
+        server.receive_frame(data).unwrap();
+        assert!(matches!(server.poll_read(2, &mut buf), Poll::Ready(Err(_))));
+    }
+
+    #[test]
+    fn test_keys_are_aborted_and_rejected() {
+        let mut client = State::new(&Config::new());
+        let mut server = State::new(&Config::new());
+        settle(&mut client, &mut server);
+
+        let aborted = client.open("aborted", &SendOptions::default()).unwrap();
+        write_to_state(&mut client, aborted, &[1; 10]);
+        settle(&mut client, &mut server);
+        write_to_state(&mut client, aborted, &[2; 10]);
+        client.abort_stream(aborted, "out of disk").unwrap();
+        let rejected = client.open("rejected", &SendOptions::default()).unwrap();
+        write_to_state(&mut client, rejected, &[3; 10]);
+        settle(&mut client, &mut server);
+
+        // what arrived before the ABORT is read, the rest never went out
+        let mut buf = [0; 100];
+        assert!(matches!(
+            server.poll_read(aborted, &mut buf),
+            Poll::Ready(Ok(10))
+        ));
+        let error = match server.poll_read(aborted, &mut buf) {
+            Poll::Ready(Err(e)) => e,
+            poll => panic!("{:?}", poll),
+        };
+        assert_eq!(error.kind(), io::ErrorKind::ConnectionAborted);
+        assert_eq!(error.to_string(), "the sender aborted the key: out of disk");
+        assert!(!server.reject(aborted, ""));
+
+        assert!(server.reject(rejected, "not wanted"));
+        settle(&mut client, &mut server);
+        let error = match client.poll_write(rejected, &[4]) {
+            Poll::Ready(Err(e)) => e,
+            poll => panic!("{:?}", poll),
+        };
+        assert_eq!(error.kind(), io::ErrorKind::ConnectionRefused);
+        assert_eq!(
+            error.to_string(),
+            "the receiver rejected the key: not wanted"
+        );
+        let error = client.end(rejected).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::ConnectionRefused);
+
+        // both sides forgot the keys, the connection carries on
+        assert!(client.sending.is_empty() && client.rejected.is_empty());
+        assert!(server.receiving.is_empty());
+        let next = client.open("next", &SendOptions::default()).unwrap();
+        write_to_state(&mut client, next, b"next");
+        client.end(next).unwrap();
+        settle(&mut client, &mut server);
+        assert_eq!(read_from_state(&mut server, next), b"next");
+    }
+
+    #[test]
+    fn test_cancelling_survives_dropped_connections() {
+        let mut client = State::new(&Config::new()).resumable(None);
+        let mut server = State::new(&Config::new()).resumable(Some(7));
+        settle(&mut client, &mut server);
+
+        let aborted = client.open("aborted", &SendOptions::default()).unwrap();
+        let rejected = client.open("rejected", &SendOptions::default()).unwrap();
+        write_to_state(&mut client, aborted, &[1; 10]);
+        write_to_state(&mut client, rejected, &[2; 10]);
+        settle(&mut client, &mut server);
+
+        // the ABORT and the REJECT go down with the connection
+        client.abort_stream(aborted, "").unwrap();
+        assert!(matches!(client.next_frame(), Some(Frame::Abort { .. })));
+        assert!(server.reject(rejected, ""));
+        assert!(matches!(server.next_frame(), Some(Frame::Reject { .. })));
+
+        client.reconnect();
+        server.reconnect();
+        settle(&mut client, &mut server);
+        let mut buf = [0; 100];
+        assert!(matches!(
+            server.poll_read(aborted, &mut buf),
+            Poll::Ready(Ok(10))
+        ));
+        assert!(matches!(
+            server.poll_read(aborted, &mut buf),
+            Poll::Ready(Err(e)) if e.kind() == io::ErrorKind::ConnectionAborted
+        ));
+        let error = client.end(rejected).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::ConnectionRefused);
+        // the ABORTs were acknowledged
+        assert!(client.sending.is_empty());
+    }
+
+    #[test]
+    fn test_close_ends_open_keys() {
+        let mut client = State::new(&Config::new());
+        let mut server = State::new(&Config::new());
+        settle(&mut client, &mut server);
+
+        let ended = client.open("ended", &SendOptions::default()).unwrap();
+        write_to_state(&mut client, ended, &[1; 10]);
+        client.end(ended).unwrap();
+        let open = client.open("open", &SendOptions::default()).unwrap();
+        write_to_state(&mut client, open, &[2; 10]);
+        let incoming = server.open("incoming", &SendOptions::default()).unwrap();
+        write_to_state(&mut server, incoming, &[3; 10]);
+
+        client.close();
+        let error = client.open("late", &SendOptions::default()).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::NotConnected);
+        assert!(matches!(
+            client.poll_write(open, &[2]),
+            Poll::Ready(Err(e)) if e.kind() == io::ErrorKind::NotConnected
+        ));
+        settle(&mut client, &mut server);
+        assert!(client.goaway_sent && server.peer_done);
+
+        // the keys arrive in full, before the end of the keys
+        assert!(matches!(server.poll_accept(), Poll::Ready(Ok(Some((_, _, s)))) if s == ended));
+        assert!(matches!(server.poll_accept(), Poll::Ready(Ok(Some((_, _, s)))) if s == open));
+        assert!(matches!(server.poll_accept(), Poll::Ready(Ok(None))));
+        assert_eq!(read_from_state(&mut server, ended), [1; 10]);
+        // ended by closing, with what was written
+        assert_eq!(read_from_state(&mut server, open), [2; 10]);
+        // opened as the client closed, rejected
+        let error = match server.poll_write(incoming, &[3]) {
+            Poll::Ready(Err(e)) => e,
+            poll => panic!("{:?}", poll),
+        };
+        assert_eq!(error.kind(), io::ErrorKind::ConnectionRefused);
+        assert!(matches!(client.poll_accept(), Poll::Ready(Ok(None))));
+    }
+
+    #[test]
+    fn test_reading_after_close_fails() {
+        let mut client = State::new(&Config::new());
+        let mut server = State::new(&Config::new());
+        settle(&mut client, &mut server);
+
+        let stream = client.open("key", &SendOptions::default()).unwrap();
+        write_to_state(&mut client, stream, &[1; 10]);
+        settle(&mut client, &mut server);
+        assert!(matches!(server.poll_accept(), Poll::Ready(Ok(Some(_)))));
+
+        server.close();
+        for _ in 0..2 {
+            let error = match server.poll_read(stream, &mut [0; 10]) {
+                Poll::Ready(Err(e)) => e,
+                poll => panic!("{:?}", poll),
+            };
+            assert_eq!(error.kind(), io::ErrorKind::NotConnected);
+            // the client aborts the rejected key, which the server forgets
+            settle(&mut client, &mut server);
+        }
+        assert!(server.receiving.is_empty());
+    }
+
+    #[test]
+    fn test_idle_connection_closes() {
+        let config = Config::new().idle_timeout(Duration::from_secs(60));
+        let mut client = State::new(&config);
+        let mut server = State::new(&Config::new());
+        settle(&mut client, &mut server);
+
+        let key = client.open("key", &SendOptions::default()).unwrap();
+        assert_eq!(client.wake_at(Instant::now()), None);
+        write_to_state(&mut client, key, b"key");
+        client.end(key).unwrap();
+        settle(&mut client, &mut server);
+        assert_eq!(read_from_state(&mut server, key), b"key");
+        server.reject(key, "");
+
+        // nothing open any more, the timer runs from the last frame
+        let idle_at = client.wake_at(Instant::now()).unwrap();
+        let before = idle_at - Duration::from_millis(1);
+        assert_eq!(client.next_frame_at(before), None);
+        assert_eq!(client.next_frame_at(idle_at), Some(Frame::GoAway));
+        assert_eq!(client.wake_at(idle_at), None);
+        assert!(matches!(client.poll_accept(), Poll::Ready(Ok(None))));
+    }
+
+    #[test]
+    fn test_metadata_arrives_before_the_data() {
+        let data = log_lines(1 << 20);
+        let length = data.len() as u64;
+        let (address, server) = start_server(move |conn| {
+            let (key, mut reader) = conn.receive().unwrap().unwrap();
+            assert_eq!(key, "described");
+            let metadata = reader.metadata();
+            assert_eq!(metadata.content_type.as_deref(), Some("text/plain"));
+            assert_eq!(metadata.header("host"), Some("web-1"));
+            assert_eq!(metadata.header("level"), Some("info"));
+            assert_eq!(metadata.header("missing"), None);
+            let mut received = Vec::with_capacity(metadata.length.unwrap() as usize);
+            reader.read_to_end(&mut received).unwrap();
+            assert_eq!(received.len() as u64, length);
+
+            let (key, reader) = conn.receive().unwrap().unwrap();
+            assert_eq!(key, "plain");
+            assert_eq!(reader.metadata(), &Metadata::default());
+            conn.close().unwrap();
+        });
+
+        let conn = dial(address);
+        let options = SendOptions::new()
+            .content_type("text/plain")
+            .length(length)
+            .header("host", "web-1")
+            .header("level", "info");
+        let mut writer = conn.send_with("described", &options).unwrap();
+        writer.write_all(&data).unwrap();
+        writer.close().unwrap();
+        conn.send("plain").unwrap().close().unwrap();
+
+        let huge = SendOptions::new().header("huge", &"x".repeat(MAX_METADATA));
+        let error = conn.send_with("huge", &huge).err().unwrap();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidInput);
+        conn.close().unwrap();
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn test_cancelled_keys_leave_the_connection_up() {
+        let (address, server) = start_server(|conn| {
+            let (key, mut reader) = conn.receive().unwrap().unwrap();
+            assert_eq!(key, "aborted");
+            let mut data = Vec::new();
+            let error = reader.read_to_end(&mut data).unwrap_err();
+            assert_eq!(error.kind(), io::ErrorKind::ConnectionAborted);
+            assert!(data.len() <= 3 * MAX_DATA);
+
+            let (key, reader) = conn.receive().unwrap().unwrap();
+            assert_eq!(key, "rejected");
+            reader.reject("no space left");
+            let (key, reader) = conn.receive().unwrap().unwrap();
+            assert_eq!(key, "dropped");
+            drop(reader);
+
+            let (key, mut reader) = conn.receive().unwrap().unwrap();
+            assert_eq!(key, "kept");
+            data.clear();
+            reader.read_to_end(&mut data).unwrap();
+            assert_eq!(data, b"kept");
+            conn.close().unwrap();
+        });
+
+        let conn = dial(address);
+        let mut writer = conn.send("aborted").unwrap();
+        writer.write_all(&[1; 3 * MAX_DATA]).unwrap();
+        writer.abort("the disk is full").unwrap();
+
+        // writing until the receiver's answer arrives
+        for (key, reason) in [("rejected", "no space left"), ("dropped", "")] {
+            let mut writer = conn.send(key).unwrap();
+            let error = loop {
+                if let Err(e) = writer.write_all(&[2; MAX_DATA]) {
+                    break e;
+                }
+            };
+            assert_eq!(error.kind(), io::ErrorKind::ConnectionRefused);
+            assert!(error.to_string().ends_with(reason));
+            assert_eq!(
+                writer.close().unwrap_err().kind(),
+                io::ErrorKind::ConnectionRefused
+            );
+        }
+
+        let mut writer = conn.send("kept").unwrap();
+        writer.write_all(b"kept").unwrap();
+        writer.close().unwrap();
+        assert!(conn.receive().unwrap().is_none());
+        conn.close().unwrap();
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn test_close_during_active_sends() {
+        const SIZE: usize = 4 * INITIAL_WINDOW as usize;
+
+        let (address, server) = start_server(|conn| {
+            let (key, mut reader) = conn.receive().unwrap().unwrap();
+            assert_eq!(key, "finished");
+            let mut data = Vec::new();
+            reader.read_to_end(&mut data).unwrap();
+            assert!(data.len() == SIZE && data.iter().all(|&b| b == 1));
+
+            // ends with what was written before the close
+            let (key, mut reader) = conn.receive().unwrap().unwrap();
+            assert_eq!(key, "unfinished");
+            data.clear();
+            reader.read_to_end(&mut data).unwrap();
+            assert!(data.iter().all(|&b| b == 2));
+            assert!(conn.receive().unwrap().is_none());
+            conn.close().unwrap();
+        });
+
+        let conn = dial(address);
+        let mut finished = conn.send("finished").unwrap();
+        let mut unfinished = conn.send("unfinished").unwrap();
+        let writing = thread::spawn(move || loop {
+            if let Err(e) = unfinished.write_all(&[2; MAX_DATA]) {
+                return e;
+            }
+        });
+        finished.write_all(&vec![1; SIZE]).unwrap();
+        finished.close().unwrap();
+
+        // waits for the server to read the rest of the finished key
+        conn.close().unwrap();
+        assert_eq!(writing.join().unwrap().kind(), io::ErrorKind::NotConnected);
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn test_close_while_the_peer_is_still_writing() {
+        let (closed, client_closed) = mpsc::channel();
+        let (address, server) = start_server(move |conn| {
+            let (_, mut reader) = conn.receive().unwrap().unwrap();
+            reader.read_exact(&mut [0; 1000]).unwrap();
+            conn.close().unwrap();
+            // cut off, nothing more of it arrives, even once the client
+            // answered the rejection
+            let error = reader.read_to_end(&mut Vec::new()).unwrap_err();
+            assert_eq!(error.kind(), io::ErrorKind::NotConnected);
+            client_closed.recv().unwrap();
+            // for the reader thread to get through to the client's ABORT
+            thread::sleep(Duration::from_millis(100));
+            let error = reader.read_to_end(&mut Vec::new()).unwrap_err();
+            assert_eq!(error.kind(), io::ErrorKind::NotConnected);
+        });
+
+        let conn = dial(address);
+        let mut writer = conn.send("endless").unwrap();
+        let error = loop {
+            if let Err(e) = writer.write_all(&[1; MAX_DATA]) {
+                break e;
+            }
+        };
+        assert_eq!(error.kind(), io::ErrorKind::ConnectionRefused);
+        assert_eq!(
+            error.to_string(),
+            "the receiver rejected the key: the connection closed"
+        );
+        drop(writer);
+        assert!(conn.receive().unwrap().is_none());
+        conn.close().unwrap();
+        closed.send(()).unwrap();
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn test_close_gives_up_on_a_peer_not_reading() {
+        const TIMEOUT: Duration = Duration::from_millis(200);
+
+        let (done, client_done) = mpsc::channel::<()>();
+        let (address, server) = start_server(move |conn| {
+            let (_, reader) = conn.receive().unwrap().unwrap();
+            let _ = client_done.recv();
+            drop(reader);
+        });
+
+        let stream = TcpStream::connect(address).unwrap();
+        let conn = Conn::with_config(stream, Config::new().close_timeout(TIMEOUT)).unwrap();
+        // half of it never fits the window
+        let mut writer = conn.send("unread").unwrap();
+        writer
+            .write_all(&vec![1; 2 * INITIAL_WINDOW as usize])
+            .unwrap();
+        let started = Instant::now();
+        let error = conn.close().unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::TimedOut);
+        assert!(started.elapsed() >= TIMEOUT);
+        let error = writer.write(&[1]).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::ConnectionAborted);
+        done.send(()).unwrap();
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn test_double_close() {
+        let (address, server) = start_server(|conn| {
+            let (_, mut reader) = conn.receive().unwrap().unwrap();
+            let mut data = Vec::new();
+            reader.read_to_end(&mut data).unwrap();
+            assert_eq!(data, b"once");
+            assert!(conn.receive().unwrap().is_none());
+            conn.close().unwrap();
+        });
+
+        let conn = Arc::new(dial(address));
+        let mut writer = conn.send("once").unwrap();
+        writer.write_all(b"once").unwrap();
+        writer.close().unwrap();
+        let closing: Vec<_> = (0..2)
+            .map(|_| {
+                let conn = conn.clone();
+                thread::spawn(move || conn.close())
+            })
+            .collect();
+        for closing in closing {
+            closing.join().unwrap().unwrap();
+        }
+        conn.close().unwrap();
+        assert!(conn.receive().unwrap().is_none());
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn test_abort_fails_the_keys_at_once() {
+        let (address, server) = start_server(|conn| {
+            let (_, mut reader) = conn.receive().unwrap().unwrap();
+            assert!(reader.read_to_end(&mut Vec::new()).is_err());
+        });
+
+        let conn = dial(address);
+        let mut writer = conn.send("aborted").unwrap();
+        writer.write_all(&[1; MAX_DATA]).unwrap();
+        writer.flush().unwrap();
+        conn.abort();
+        let error = writer.write_all(&[1]).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::ConnectionAborted);
+        server.join().unwrap();
+    }
+
+    /// One end of a connection in memory, for tests that need to know
+    /// exactly what went over it.
+    struct MemoryStream {
+        input: Arc<Pipe>,
+        output: Arc<Pipe>,
+    }
+
+    #[derive(Default)]
+    struct Pipe {
+        /// The bytes in flight and whether the writing end shut down.
+        buffer: Mutex<(VecDeque<u8>, bool)>,
+        readable: Condvar,
+    }
+
+    impl Pipe {
+        fn shut(&self) {
+            self.buffer.lock().unwrap().1 = true;
+            self.readable.notify_all();
+        }
+    }
+
+    impl MemoryStream {
+        fn pair() -> (MemoryStream, MemoryStream) {
+            let (a, b) = (Arc::new(Pipe::default()), Arc::new(Pipe::default()));
+            let one = MemoryStream {
+                input: a.clone(),
+                output: b.clone(),
+            };
+            (
+                one,
+                MemoryStream {
+                    input: b,
+                    output: a,
+                },
+            )
+        }
+    }
+
+    impl Read for MemoryStream {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            let mut buffer = self.input.buffer.lock().unwrap();
+            while buffer.0.is_empty() && !buffer.1 {
+                buffer = self.input.readable.wait(buffer).unwrap();
+            }
+            buffer.0.read(buf)
+        }
+    }
+
+    impl Write for MemoryStream {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            let mut buffer = self.output.buffer.lock().unwrap();
+            if buffer.1 {
+                return Err(io::ErrorKind::BrokenPipe.into());
+            }
+            buffer.0.extend(buf);
+            self.output.readable.notify_all();
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl Transport for MemoryStream {
+        fn try_clone(&self) -> io::Result<Self> {
+            Ok(MemoryStream {
+                input: self.input.clone(),
+                output: self.output.clone(),
+            })
+        }
+
+        fn shutdown(&self, how: Shutdown) -> io::Result<()> {
+            if how != Shutdown::Write {
+                self.input.shut();
+            }
+            if how != Shutdown::Read {
+                self.output.shut();
+            }
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_higher_priorities_go_first() {
+        let (client, mut server) = MemoryStream::pair();
+        let conn = Conn::with_transport(client, Config::new()).unwrap();
+        // all buffered before the handshake lets anything out
+        let keys = [
+            Priority::Low,
+            Priority::High,
+            Priority::Normal,
+            Priority::High,
+        ];
+        for (stream, priority) in keys.into_iter().enumerate() {
+            let options = SendOptions::new().priority(priority);
+            let mut writer = conn.send_with(&stream.to_string(), &options).unwrap();
+            writer.write_all(&[0; 2 * MAX_DATA]).unwrap();
+            writer.close().unwrap();
+        }
+
+        let hello = Frame::Hello {
+            features: 0,
+            session: 0,
+        };
+        hello.write_to(&mut server).unwrap();
+        let mut sent = Vec::new();
+        while sent.len() < 12 {
+            match Frame::read_from(&mut server).unwrap().unwrap() {
+                Frame::Data {
+                    stream, payload, ..
+                } => sent.push((stream, payload.len())),
+                Frame::End { stream, .. } => sent.push((stream, 0)),
+                _ => {}
+            }
+        }
+        // the high ones take turns, then the normal one, then the low one
+        #[rustfmt::skip]
+        assert_eq!(sent, [
+            (1, MAX_DATA), (3, MAX_DATA), (1, MAX_DATA), (3, MAX_DATA), (1, 0), (3, 0),
+            (2, MAX_DATA), (2, MAX_DATA), (2, 0),
+            (0, MAX_DATA), (0, MAX_DATA), (0, 0),
+        ]);
+        drop(conn);
+    }
+
+    #[test]
+    fn test_rate_limits_shape_the_streams() {
+        let start = Instant::now();
+        let config = Config::new().integrity(Integrity::NONE).rate_limit(1 << 20);
+        let mut state = State::new(&config);
+        let hello = state.next_frame_at(start).unwrap();
+        state.receive_frame(hello).unwrap();
+        // a fast key limited to 128 KiB/s, a slow one using what's left
+        for (stream, priority, rate_limit) in [
+            (0, Priority::High, Some(128 << 10)),
+            (1, Priority::Low, None),
+        ] {
+            state.sending.insert(
+                stream,
+                Sending {
+                    chunks: vec![vec![0; MAX_DATA]; 64].into(),
+                    buffered: 64 * MAX_DATA,
+                    credit: u32::MAX,
+                    priority,
+                    limiter: rate_limit.map(Limiter::new),
+                    ..Sending::default()
+                },
+            );
+        }
+
+        // in virtual time, always right when the limits allow more
+        let mut now = start;
+        let mut sent = [0; 2];
+        let mut times = [Vec::new(), Vec::new()];
+        while now < start + Duration::from_secs(2) {
+            match state.next_frame_at(now) {
+                Some(Frame::Data {
+                    stream, payload, ..
+                }) => {
+                    sent[stream as usize] += payload.len();
+                    times[stream as usize].push(now - start);
+                }
+                Some(frame) => panic!("{:?}", frame),
+                None => now = state.throttled_until(now).unwrap(),
+            }
+        }
+        // up to the burst and a frame beyond the rate
+        let slack = (1 << 20) / 20 + MAX_DATA;
+        assert!((2 << 20..=(2 << 20) + slack).contains(&(sent[0] + sent[1])));
+        let slack = (128 << 10) / 20 + MAX_DATA;
+        assert!((256 << 10..=(256 << 10) + slack).contains(&sent[0]));
+        // a frame every half second, not waiting for the slow key
+        let next = Duration::from_millis(500) - Limiter::BURST;
+        assert_eq!(times[0][..2], [Duration::ZERO, next]);
+        assert!(times[1].len() > 2 * times[0].len());
+    }
+
+    #[test]
+    fn test_rate_limit_holds_the_writer_back() {
+        let (address, server) = start_server(|conn| {
+            let (_, mut reader) = conn.receive().unwrap().unwrap();
+            io::copy(&mut reader, &mut io::sink()).unwrap();
+            conn.close().unwrap();
+        });
+
+        let stream = TcpStream::connect(address).unwrap();
+        let conn = Conn::with_config(stream, Config::new().rate_limit(1 << 20)).unwrap();
+        let start = Instant::now();
+        let mut writer = conn.send("limited").unwrap();
+        writer.write_all(&[0; 10 * MAX_DATA]).unwrap();
+        writer.close().unwrap();
+        conn.close().unwrap();
+        // the last frame waits for the nine before it, less the burst
+        assert!(start.elapsed() >= Duration::from_millis(9 * 62) - Limiter::BURST);
+        server.join().unwrap();
+    }
+
+    /// Relays connections to `upstream`, killing each once `budget` bytes
+    /// went through it. Counts the kills.
+    fn start_killing_proxy(upstream: SocketAddr, budget: usize) -> (SocketAddr, Arc<AtomicUsize>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let kills = Arc::new(AtomicUsize::new(0));
+        let counter = kills.clone();
+        thread::spawn(move || {
+            for client in listener.incoming() {
+                let client = client.unwrap();
+                let Ok(server) = TcpStream::connect(upstream) else {
+                    return;
+                };
+                let relayed = Arc::new(AtomicUsize::new(0));
+                let killed = Arc::new(AtomicBool::new(false));
+                let directions = [
+                    (client.try_clone().unwrap(), server.try_clone().unwrap()),
+                    (server, client),
+                ];
+                for (mut from, mut to) in directions {
+                    let (kills, relayed, killed) =
+                        (counter.clone(), relayed.clone(), killed.clone());
+                    thread::spawn(move || {
+                        let mut buf = vec![0; 16 << 10];
+                        while let Ok(n @ 1..) = from.read(&mut buf) {
+                            // mid-frame most likely
+                            if relayed.fetch_add(n, Ordering::Relaxed) + n > budget {
+                                if !killed.swap(true, Ordering::Relaxed) {
+                                    kills.fetch_add(1, Ordering::Relaxed);
+                                }
+                                let _ = from.shutdown(Shutdown::Both);
+                                let _ = to.shutdown(Shutdown::Both);
+                                return;
+                            }
+                            if to.write_all(&buf[..n]).is_err() {
+                                return;
+                            }
+                        }
+                        let _ = to.shutdown(Shutdown::Write);
+                    });
+                }
+            }
+        });
+        (address, kills)
+    }
+
+    /// Keys in both directions carry on over new connections while the
+    /// old ones keep getting killed.
+    #[test]
+    fn test_session_survives_dropped_connections() {
+        const SIZE: usize = 8 << 20;
+        const BUF_SIZE: usize = 1 << 16;
+
+        let listener = Listener::bind("127.0.0.1:0", Config::new()).unwrap();
+        let (proxy, kills) = start_killing_proxy(listener.local_addr(), 1 << 20);
+        let transfer = |conn: &Conn, send: &str, receive: &str| {
+            let mut writer = conn.send(send).unwrap();
+            let writing = thread::spawn(move || {
+                let checksum = write_random_data(&mut writer, SIZE, BUF_SIZE);
+                writer.close().unwrap();
+                checksum
+            });
+            let (key, mut reader) = conn.receive().unwrap().unwrap();
+            assert_eq!(key, receive);
+            let received = read_random_data(&mut reader);
+            (writing.join().unwrap(), received)
+        };
+
+        let server = thread::spawn(move || {
+            let conn = listener.accept().unwrap();
+            let checksums = transfer(&conn, "down", "up");
+            assert!(conn.receive().unwrap().is_none());
+            conn.close().unwrap();
+            checksums
+        });
+
+        let conn = Conn::dial(proxy, Config::new()).unwrap();
+        let (sent, received) = transfer(&conn, "up", "down");
+        assert!(conn.session().is_some());
+        conn.close().unwrap();
+        let (server_sent, server_received) = server.join().unwrap();
+        assert_eq!(sent, server_received);
+        assert_eq!(received, server_sent);
+        assert!(kills.load(Ordering::Relaxed) >= 4);
+    }
+
+    /// A client that never says HELLO holds up neither new connections
+    /// nor resumed ones.
+    #[test]
+    fn test_silent_client_blocks_no_accepts() {
+        let listener = Listener::bind("127.0.0.1:0", Config::new()).unwrap();
+        let (proxy, kills) = start_killing_proxy(listener.local_addr(), 1 << 20);
+        let _silent = TcpStream::connect(listener.local_addr()).unwrap();
+
+        let started = Instant::now();
+        let server = thread::spawn(move || {
+            let conn = listener.accept().unwrap();
+            let (_, mut reader) = conn.receive().unwrap().unwrap();
+            let mut data = Vec::new();
+            reader.read_to_end(&mut data).unwrap();
+            assert_eq!(data.len(), 4 << 20);
+            assert!(conn.receive().unwrap().is_none());
+            conn.close().unwrap();
+        });
+
+        let conn = Conn::dial(proxy, Config::new()).unwrap();
+        let mut writer = conn.send("key").unwrap();
+        writer.write_all(&vec![1; 4 << 20]).unwrap();
+        writer.close().unwrap();
+        conn.close().unwrap();
+        server.join().unwrap();
+        assert!(kills.load(Ordering::Relaxed) >= 1);
+        assert!(started.elapsed() < HANDSHAKE_TIMEOUT);
+    }
+
+    /// A session nobody resumes fails after the timeout, on both ends.
+    #[test]
+    fn test_session_gives_up() {
+        let config = Config::new().resume_timeout(Duration::from_millis(300));
+        let listener = Listener::bind("127.0.0.1:0", config.clone()).unwrap();
+        let (proxy, kills) = start_killing_proxy(listener.local_addr(), 1 << 20);
+
+        let server = thread::spawn(move || {
+            let conn = listener.accept().unwrap();
+            // the client can't come back
+            drop(listener);
+            let (_, mut reader) = conn.receive().unwrap().unwrap();
+            assert!(reader.read_to_end(&mut Vec::new()).is_err());
+        });
+
+        let conn = Conn::dial(proxy, config).unwrap();
+        let mut writer = conn.send("key").unwrap();
+        let started = Instant::now();
+        assert!(writer.write_all(&vec![1; 8 << 20]).is_err());
+        assert!(started.elapsed() >= Duration::from_millis(300));
+        assert!(conn.receive().is_err());
+        server.join().unwrap();
+        assert_eq!(kills.load(Ordering::Relaxed), 1);
+    }
+
+    /// A key nobody reads holds up neither other keys nor the reverse
+    /// direction.
+    #[test]
+    fn test_slow_reader_blocks_nothing() {
+        const SIZE: usize = 8 * INITIAL_WINDOW as usize;
+
+        let (address, server) = start_server(|conn| {
+            let (key, mut slow) = conn.receive().unwrap().unwrap();
+            assert_eq!(key, "slow");
+            let (key, mut fast) = conn.receive().unwrap().unwrap();
+            assert_eq!(key, "fast");
+
+            let mut data = Vec::new();
+            fast.read_to_end(&mut data).unwrap();
+            assert!(data.len() == SIZE && data.iter().all(|&b| b == 2));
+
+            let mut reply = conn.send("reply").unwrap();
+            reply.write_all(&vec![3; SIZE]).unwrap();
+            reply.close().unwrap();
+
+            data.clear();
+            slow.read_to_end(&mut data).unwrap();
+            assert!(data.len() == SIZE && data.iter().all(|&b| b == 1));
+            conn.close().unwrap();
+        });
+
+        let conn = dial(address);
+        let writers: Vec<_> = [("slow", 1), ("fast", 2)]
+            .into_iter()
+            .map(|(key, byte)| {
+                let mut writer = conn.send(key).unwrap();
+                thread::spawn(move || {
+                    writer.write_all(&vec![byte; SIZE]).unwrap();
+                    writer.close().unwrap();
+                })
+            })
+            .collect();
+
+        let (key, mut reply) = conn.receive().unwrap().unwrap();
+        assert_eq!(key, "reply");
+        let mut data = Vec::new();
+        reply.read_to_end(&mut data).unwrap();
+        assert!(data.len() == SIZE && data.iter().all(|&b| b == 3));
+
+        for writer in writers {
+            writer.join().unwrap();
+        }
+        assert!(conn.receive().unwrap().is_none());
+        conn.close().unwrap();
+        server.join().unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_runs_over_unix_sockets() {
+        use std::os::unix::net::UnixStream;
+
+        let (client, server) = UnixStream::pair().unwrap();
+        let server = thread::spawn(move || {
+            let conn = Conn::with_transport(server, Config::new()).unwrap();
+            let (key, mut reader) = conn.receive().unwrap().unwrap();
+            let mut writer = conn.send(&key).unwrap();
+            io::copy(&mut reader, &mut writer).unwrap();
+            writer.close().unwrap();
+            assert!(conn.receive().unwrap().is_none());
+            conn.close().unwrap();
+        });
+
+        let conn = Conn::with_transport(client, Config::new()).unwrap();
+        let mut writer = conn.send("echo").unwrap();
+        let sent = write_random_data(&mut writer, 4 << 20, 64 << 10);
+        writer.close().unwrap();
+        let (key, mut reader) = conn.receive().unwrap().unwrap();
+        assert_eq!(key, "echo");
+        assert_eq!(read_random_data(&mut reader), sent);
+        conn.close().unwrap();
+        server.join().unwrap();
+    }
+
+    /// Single connection, a little data both ways.
+    #[test]
+    fn test_case_0() {
+        const KEY: &str = "Bible";
+        const DATA: &str = "Then I heard the voice of the Lord saying, \u{201c}Whom shall I send? And who will go for us?\u{201d}
+And I said, \u{201c}Here am I. Send me!\u{201d}
+Isaiah 6:8";
+
+        let (address, server) = start_server(|conn| {
+            let (key, mut reader) = conn.receive().unwrap().unwrap();
+            assert_eq!(key, KEY);
+            let mut data = String::new();
+            reader.read_to_string(&mut data).unwrap();
+            assert_eq!(data, DATA);
+            drop(reader);
+
+            // never closed, closing the connection ends the key
+            let mut writer = conn.send(KEY).unwrap();
+            assert_eq!(writer.write(DATA.as_bytes()).unwrap(), DATA.len());
+            conn.close().unwrap();
+        });
+
+        let conn = dial(address);
+        let mut writer = conn.send(KEY).unwrap();
+        assert_eq!(writer.write(DATA.as_bytes()).unwrap(), DATA.len());
+        writer.close().unwrap();
+
+        let (key, mut reader) = conn.receive().unwrap().unwrap();
+        assert_eq!(key, KEY);
+        let mut data = String::new();
+        reader.read_to_string(&mut data).unwrap();
+        assert_eq!(data, DATA);
+        drop(reader);
+        conn.close().unwrap();
+        server.join().unwrap();
+    }
+
+    /// 500 MB in each direction, the server sending two keys back to back.
+    #[test]
+    fn test_case_1() {
+        const DATA_SIZE: usize = 500 << 20;
+        const BUF_SIZE: usize = 1 << 20;
+
+        let checksums = Arc::new(Mutex::new(HashMap::<String, String>::new()));
+        let (address, server) = start_server({
+            let checksums = checksums.clone();
+            move |conn| {
+                let (key, mut reader) = conn.receive().unwrap().unwrap();
+                let checksum = read_random_data(&mut reader);
+                drop(reader);
+                let expected = checksums.lock().unwrap().get(&key).cloned();
+                assert_eq!(Some(checksum), expected, "{}", key);
+
+                for key in [new_random_key(), new_random_key()] {
+                    let mut writer = conn.send(&key).unwrap();
+                    let checksum = write_random_data(&mut writer, DATA_SIZE, BUF_SIZE);
+                    checksums.lock().unwrap().insert(key, checksum);
+                    writer.close().unwrap();
+                }
+                conn.close().unwrap();
+            }
+        });
+
+        let conn = dial(address);
+        let key = new_random_key();
+        let mut writer = conn.send(&key).unwrap();
+        // recorded before the peer can finish reading
+        let checksum = write_random_data(&mut writer, DATA_SIZE, BUF_SIZE);
+        checksums.lock().unwrap().insert(key, checksum);
+        writer.close().unwrap();
+
+        let mut keys = 0;
+        while let Some((key, mut reader)) = conn.receive().unwrap() {
+            let checksum = read_random_data(&mut reader);
+            let expected = checksums.lock().unwrap().get(&key).cloned();
+            assert_eq!(Some(checksum), expected, "{}", key);
+            keys += 1;
+        }
+        assert_eq!(keys, 2);
+        conn.close().unwrap();
+        server.join().unwrap();
+    }
+}
@@ -0,0 +1,301 @@
+//! Which Direct3D device to create for capturing.
+//!
+//! The selection policy lives here, away from the Windows API, so it can be
+//! tested anywhere: [`DeviceBuilder::select`] runs the attempts in order
+//! with a caller supplied create function and reports what it settled on.
+//! `dxgi::create_device` plugs `D3D11CreateDevice` into it.
+
+use std::fmt;
+
+use serde::Serialize;
+
+use crate::error::{Error, Result};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DriverType {
+    Hardware,
+    /// The Windows Advanced Rasterization Platform, a fast software
+    /// rasterizer available everywhere.
+    Warp,
+    /// The reference rasterizer, slow but exact, needs the SDK layers.
+    Reference,
+}
+
+/// Direct3D feature levels, ordered from lowest to highest.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize)]
+pub enum FeatureLevel {
+    #[serde(rename = "9_1")]
+    Level9_1,
+    #[serde(rename = "10_0")]
+    Level10_0,
+    #[serde(rename = "10_1")]
+    Level10_1,
+    #[serde(rename = "11_0")]
+    Level11_0,
+}
+
+impl FeatureLevel {
+    /// Highest first, the order Direct3D expects them in.
+    pub const ALL: [FeatureLevel; 4] = [
+        FeatureLevel::Level11_0,
+        FeatureLevel::Level10_1,
+        FeatureLevel::Level10_0,
+        FeatureLevel::Level9_1,
+    ];
+}
+
+impl fmt::Display for FeatureLevel {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            FeatureLevel::Level9_1 => "9_1",
+            FeatureLevel::Level10_0 => "10_0",
+            FeatureLevel::Level10_1 => "10_1",
+            FeatureLevel::Level11_0 => "11_0",
+        })
+    }
+}
+
+/// One try at creating a device.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Attempt {
+    pub driver: DriverType,
+    /// Index of the DXGI adapter to create the device on, `None` for the
+    /// default one.
+    pub adapter: Option<u32>,
+    pub debug: bool,
+}
+
+/// What [`DeviceBuilder::select`] settled on, and why the attempts before
+/// it failed.
+#[derive(Debug)]
+pub struct Selection {
+    pub driver: DriverType,
+    pub feature_level: FeatureLevel,
+    pub adapter: Option<u32>,
+    /// Whether the debug layer is active, it is skipped when the SDK
+    /// layers aren't installed.
+    pub debug: bool,
+    pub failed: Vec<(Attempt, Error)>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DeviceBuilder {
+    driver: Option<DriverType>,
+    adapter: Option<u32>,
+    debug: bool,
+    min_feature_level: FeatureLevel,
+}
+
+impl Default for DeviceBuilder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl DeviceBuilder {
+    /// Hardware first, then WARP, then the reference rasterizer, on the
+    /// default adapter at any feature level.
+    pub fn new() -> Self {
+        Self {
+            driver: None,
+            adapter: None,
+            debug: false,
+            min_feature_level: FeatureLevel::Level9_1,
+        }
+    }
+
+    /// Only try this driver type.
+    pub fn driver(mut self, driver: DriverType) -> Self {
+        self.driver = Some(driver);
+        self
+    }
+
+    /// Create the device on a specific adapter, which rules out WARP and
+    /// the reference rasterizer as they bring their own.
+    pub fn adapter(mut self, index: u32) -> Self {
+        self.adapter = Some(index);
+        self
+    }
+
+    /// Enable the Direct3D debug layer if it is installed.
+    pub fn debug(mut self, debug: bool) -> Self {
+        self.debug = debug;
+        self
+    }
+
+    /// Treat devices below `level` as failures and move on.
+    pub fn min_feature_level(mut self, level: FeatureLevel) -> Self {
+        self.min_feature_level = level;
+        self
+    }
+
+    /// The feature levels to request, highest first.
+    pub fn feature_levels(&self) -> Vec<FeatureLevel> {
+        FeatureLevel::ALL
+            .into_iter()
+            .filter(|level| *level >= self.min_feature_level)
+            .collect()
+    }
+
+    /// The attempts in the order they are made.
+    pub fn attempts(&self) -> Result<Vec<Attempt>> {
+        let drivers = match (self.driver, self.adapter) {
+            (Some(DriverType::Hardware) | None, Some(_)) => vec![DriverType::Hardware],
+            (Some(driver), Some(_)) => {
+                return Err(Error::backend(format!(
+                    "{:?} can't be combined with an adapter",
+                    driver
+                )))
+            }
+            (Some(driver), None) => vec![driver],
+            (None, None) => vec![
+                DriverType::Hardware,
+                DriverType::Warp,
+                DriverType::Reference,
+            ],
+        };
+
+        let debug: &[bool] = if self.debug { &[true, false] } else { &[false] };
+        Ok(drivers
+            .into_iter()
+            .flat_map(|driver| {
+                debug.iter().map(move |&debug| Attempt {
+                    driver,
+                    adapter: self.adapter,
+                    debug,
+                })
+            })
+            .collect())
+    }
+
+    /// Make the attempts with `create` until one succeeds at a high enough
+    /// feature level. The last error is returned if none does.
+    pub fn select<D>(
+        &self,
+        mut create: impl FnMut(&Attempt, &[FeatureLevel]) -> Result<(D, FeatureLevel)>,
+    ) -> Result<(D, Selection)> {
+        let levels = self.feature_levels();
+        let mut failed = Vec::new();
+
+        for attempt in self.attempts()? {
+            match create(&attempt, &levels) {
+                Ok((device, level)) if level >= self.min_feature_level => {
+                    return Ok((
+                        device,
+                        Selection {
+                            driver: attempt.driver,
+                            feature_level: level,
+                            adapter: attempt.adapter,
+                            debug: attempt.debug,
+                            failed,
+                        },
+                    ))
+                }
+                Ok((_, level)) => failed.push((
+                    attempt,
+                    Error::backend(format!(
+                        "feature level {} is below {}",
+                        level, self.min_feature_level
+                    )),
+                )),
+                Err(e) => failed.push((attempt, e)),
+            }
+        }
+
+        Err(failed
+            .pop()
+            .map(|(_, e)| e)
+            .unwrap_or_else(|| Error::backend("no device could be created")))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_falls_back_to_warp() {
+        let (device, selection) = DeviceBuilder::new()
+            .select(|attempt, _| match attempt.driver {
+                DriverType::Hardware => Err(Error::backend("no GPU")),
+                driver => Ok((driver, FeatureLevel::Level11_0)),
+            })
+            .unwrap();
+
+        assert_eq!(device, DriverType::Warp);
+        assert_eq!(selection.driver, DriverType::Warp);
+        assert_eq!(selection.feature_level, FeatureLevel::Level11_0);
+        assert_eq!(selection.failed.len(), 1);
+        assert_eq!(selection.failed[0].0.driver, DriverType::Hardware);
+    }
+
+    #[test]
+    fn test_min_feature_level() {
+        let builder = DeviceBuilder::new().min_feature_level(FeatureLevel::Level10_1);
+        assert_eq!(
+            builder.feature_levels(),
+            [FeatureLevel::Level11_0, FeatureLevel::Level10_1]
+        );
+
+        let (_, selection) = builder
+            .select(|attempt, _| match attempt.driver {
+                DriverType::Hardware => Ok(((), FeatureLevel::Level10_0)),
+                _ => Ok(((), FeatureLevel::Level10_1)),
+            })
+            .unwrap();
+        assert_eq!(selection.driver, DriverType::Warp);
+        assert!(matches!(
+            &selection.failed[0].1,
+            Error::Backend(e) if e.to_string() == "feature level 10_0 is below 10_1"
+        ));
+    }
+
+    #[test]
+    fn test_forced_driver_and_debug_fallback() {
+        let builder = DeviceBuilder::new()
+            .driver(DriverType::Reference)
+            .debug(true);
+        assert_eq!(
+            builder.attempts().unwrap(),
+            [true, false].map(|debug| Attempt {
+                driver: DriverType::Reference,
+                adapter: None,
+                debug,
+            })
+        );
+
+        let (_, selection) = builder
+            .select(|attempt, _| {
+                if attempt.debug {
+                    Err(Error::backend("SDK layers missing"))
+                } else {
+                    Ok(((), FeatureLevel::Level9_1))
+                }
+            })
+            .unwrap();
+        assert_eq!(selection.driver, DriverType::Reference);
+        assert!(!selection.debug);
+
+        let e = builder.select(|_, _| Err::<((), _), _>(Error::AccessLost));
+        assert!(matches!(e, Err(Error::AccessLost)));
+    }
+
+    #[test]
+    fn test_adapter() {
+        let attempts = DeviceBuilder::new().adapter(1).attempts().unwrap();
+        assert_eq!(
+            attempts,
+            [Attempt {
+                driver: DriverType::Hardware,
+                adapter: Some(1),
+                debug: false,
+            }]
+        );
+        assert!(DeviceBuilder::new()
+            .adapter(1)
+            .driver(DriverType::Warp)
+            .attempts()
+            .is_err());
+    }
+}
@@ -0,0 +1,491 @@
+//! A small drawing layer for annotating frames: anti-aliased lines, arrows
+//! and ellipses, translucent highlights and bitmap text.
+
+use crate::frame::{Frame, Rect};
+
+/// Width of a glyph cell of the built-in font, before scaling.
+pub const GLYPH_WIDTH: u32 = 6;
+/// Height of a glyph cell of the built-in font, before scaling.
+pub const GLYPH_HEIGHT: u32 = 10;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Color {
+    pub r: u8,
+    pub g: u8,
+    pub b: u8,
+    /// 0 is fully transparent, 255 fully opaque.
+    pub a: u8,
+}
+
+impl Color {
+    pub const BLACK: Color = Color::rgb(0, 0, 0);
+    pub const WHITE: Color = Color::rgb(0xFF, 0xFF, 0xFF);
+    pub const RED: Color = Color::rgb(0xE5, 0x39, 0x35);
+    pub const YELLOW: Color = Color::rgb(0xFD, 0xD8, 0x35);
+
+    pub const fn rgb(r: u8, g: u8, b: u8) -> Self {
+        Self::rgba(r, g, b, 0xFF)
+    }
+
+    pub const fn rgba(r: u8, g: u8, b: u8, a: u8) -> Self {
+        Self { r, g, b, a }
+    }
+
+    pub const fn with_alpha(self, a: u8) -> Self {
+        Self { a, ..self }
+    }
+}
+
+impl Frame {
+    /// Blend `color` over the pixel, `coverage` (0..=1) scales its alpha.
+    /// Pixels outside the frame are ignored so shapes may be clipped freely.
+    /// An HDR frame is tone mapped to BGRA8 first.
+    pub fn blend_pixel(&mut self, x: i32, y: i32, color: Color, coverage: f32) {
+        if self.format.is_hdr() {
+            *self = self.to_sdr().into_owned();
+        }
+        if x < 0 || y < 0 || x >= self.width as i32 || y >= self.height as i32 {
+            return;
+        }
+
+        let alpha = color.a as f32 / 255.0 * coverage.clamp(0.0, 1.0);
+        if alpha <= 0.0 {
+            return;
+        }
+
+        let [b, g, r, a] = self.pixel(x as u32, y as u32);
+        let mix = |dst: u8, src: u8| (dst as f32 + (src as f32 - dst as f32) * alpha).round() as u8;
+        self.set_pixel(
+            x as u32,
+            y as u32,
+            [
+                mix(b, color.b),
+                mix(g, color.g),
+                mix(r, color.r),
+                a.max((alpha * 255.0).round() as u8),
+            ],
+        );
+    }
+
+    /// Fill `rect`, translucent colors make highlights.
+    pub fn fill_rect(&mut self, rect: Rect, color: Color) {
+        let Some(rect) = rect.intersect(&self.bounds()) else {
+            return;
+        };
+        for y in rect.y..rect.bottom() {
+            for x in rect.x..rect.right() {
+                self.blend_pixel(x, y, color, 1.0);
+            }
+        }
+    }
+
+    /// Outline `rect` with a border of `width` pixels drawn inside it.
+    pub fn draw_rect(&mut self, rect: Rect, width: u32, color: Color) {
+        let width = width.min(rect.width / 2).min(rect.height / 2).max(1);
+        let inner_height = rect.height.saturating_sub(2 * width);
+
+        self.fill_rect(Rect::new(rect.x, rect.y, rect.width, width), color);
+        self.fill_rect(
+            Rect::new(rect.x, rect.bottom() - width as i32, rect.width, width),
+            color,
+        );
+        self.fill_rect(
+            Rect::new(rect.x, rect.y + width as i32, width, inner_height),
+            color,
+        );
+        self.fill_rect(
+            Rect::new(
+                rect.right() - width as i32,
+                rect.y + width as i32,
+                width,
+                inner_height,
+            ),
+            color,
+        );
+    }
+
+    /// An anti-aliased line of `width` pixels between two points given in
+    /// pixel coordinates, `(0.5, 0.5)` being the center of the first pixel.
+    pub fn draw_line(&mut self, from: (f32, f32), to: (f32, f32), width: f32, color: Color) {
+        let half = width.max(1.0) / 2.0;
+        let (left, top, right, bottom) = (
+            (from.0.min(to.0) - half - 1.0).floor() as i32,
+            (from.1.min(to.1) - half - 1.0).floor() as i32,
+            (from.0.max(to.0) + half + 1.0).ceil() as i32,
+            (from.1.max(to.1) + half + 1.0).ceil() as i32,
+        );
+
+        for y in top.max(0)..bottom.min(self.height as i32) {
+            for x in left.max(0)..right.min(self.width as i32) {
+                let d = segment_distance((x as f32 + 0.5, y as f32 + 0.5), from, to);
+                self.blend_pixel(x, y, color, half + 0.5 - d);
+            }
+        }
+    }
+
+    /// A line with a head at `to`, sized after `width`.
+    pub fn draw_arrow(&mut self, from: (f32, f32), to: (f32, f32), width: f32, color: Color) {
+        self.draw_line(from, to, width, color);
+
+        let (dx, dy) = (from.0 - to.0, from.1 - to.1);
+        let len = (dx * dx + dy * dy).sqrt();
+        if len == 0.0 {
+            return;
+        }
+
+        let head = (width * 4.0).max(10.0).min(len);
+        let (ux, uy) = (dx / len, dy / len);
+        let (sin, cos) = 28f32.to_radians().sin_cos();
+        for sin in [sin, -sin] {
+            let tip = (
+                to.0 + head * (ux * cos - uy * sin),
+                to.1 + head * (ux * sin + uy * cos),
+            );
+            self.draw_line(to, tip, width, color);
+        }
+    }
+
+    /// An anti-aliased ellipse outline of `width` pixels.
+    pub fn draw_ellipse(
+        &mut self,
+        center: (f32, f32),
+        radii: (f32, f32),
+        width: f32,
+        color: Color,
+    ) {
+        let half = width.max(1.0) / 2.0;
+        self.for_each_ellipse_pixel(center, radii, half, |frame, x, y, d| {
+            frame.blend_pixel(x, y, color, half + 0.5 - d.abs());
+        });
+    }
+
+    pub fn fill_ellipse(&mut self, center: (f32, f32), radii: (f32, f32), color: Color) {
+        self.for_each_ellipse_pixel(center, radii, 0.0, |frame, x, y, d| {
+            frame.blend_pixel(x, y, color, 0.5 - d);
+        });
+    }
+
+    fn for_each_ellipse_pixel<F>(
+        &mut self,
+        center: (f32, f32),
+        radii: (f32, f32),
+        pad: f32,
+        mut f: F,
+    ) where
+        F: FnMut(&mut Frame, i32, i32, f32),
+    {
+        let (rx, ry) = (radii.0.max(0.5), radii.1.max(0.5));
+        let left = (center.0 - rx - pad - 1.0).floor().max(0.0) as i32;
+        let top = (center.1 - ry - pad - 1.0).floor().max(0.0) as i32;
+        let right = ((center.0 + rx + pad + 1.0).ceil() as i32).min(self.width as i32);
+        let bottom = ((center.1 + ry + pad + 1.0).ceil() as i32).min(self.height as i32);
+
+        for y in top..bottom {
+            for x in left..right {
+                let d = ellipse_distance(
+                    (x as f32 + 0.5 - center.0, y as f32 + 0.5 - center.1),
+                    rx,
+                    ry,
+                );
+                f(self, x, y, d);
+            }
+        }
+    }
+
+    /// Draw `text` with the built-in 6x10 font, each font pixel becoming a
+    /// `scale x scale` block. `\n` starts a new line, characters outside
+    /// printable ASCII are drawn as `?`. Returns the area covered.
+    pub fn draw_text(&mut self, x: i32, y: i32, text: &str, scale: u32, color: Color) -> Rect {
+        let scale = scale.max(1);
+        for (line_no, line) in text.lines().enumerate() {
+            let top = y + (line_no as u32 * GLYPH_HEIGHT * scale) as i32;
+            for (col, ch) in line.chars().enumerate() {
+                let left = x + (col as u32 * GLYPH_WIDTH * scale) as i32;
+                for (row, bits) in glyph(ch).iter().enumerate() {
+                    for bit in 0..GLYPH_WIDTH {
+                        if bits & (0x20 >> bit) == 0 {
+                            continue;
+                        }
+                        self.fill_rect(
+                            Rect::new(
+                                left + (bit * scale) as i32,
+                                top + (row as u32 * scale) as i32,
+                                scale,
+                                scale,
+                            ),
+                            color,
+                        );
+                    }
+                }
+            }
+        }
+
+        let (width, height) = text_size(text, scale);
+        Rect::new(x, y, width, height)
+    }
+
+    /// Stamp `text` in the bottom right corner on a translucent dark box.
+    pub fn watermark(&mut self, text: &str, scale: u32) {
+        let scale = scale.max(1);
+        let (width, height) = text_size(text, scale);
+        let margin = 4 * scale as i32;
+        let x = self.width as i32 - width as i32 - 2 * margin;
+        let y = self.height as i32 - height as i32 - 2 * margin;
+
+        self.fill_rect(
+            Rect::new(
+                x - margin,
+                y - margin,
+                width + 2 * margin as u32,
+                height + 2 * margin as u32,
+            ),
+            Color::BLACK.with_alpha(0x90),
+        );
+        self.draw_text(x, y, text, scale, Color::WHITE);
+    }
+}
+
+/// Pixel size of `text` drawn with [`Frame::draw_text`].
+pub fn text_size(text: &str, scale: u32) -> (u32, u32) {
+    let scale = scale.max(1);
+    let columns = text
+        .lines()
+        .map(|line| line.chars().count())
+        .max()
+        .unwrap_or(0) as u32;
+    let rows = text.lines().count() as u32;
+    (columns * GLYPH_WIDTH * scale, rows * GLYPH_HEIGHT * scale)
+}
+
+fn glyph(ch: char) -> &'static [u8; GLYPH_HEIGHT as usize] {
+    let index = match ch {
+        ' '..='~' => ch as usize - ' ' as usize,
+        _ => '?' as usize - ' ' as usize,
+    };
+    &FONT_6X10[index]
+}
+
+fn segment_distance(p: (f32, f32), a: (f32, f32), b: (f32, f32)) -> f32 {
+    let (abx, aby) = (b.0 - a.0, b.1 - a.1);
+    let (apx, apy) = (p.0 - a.0, p.1 - a.1);
+    let len2 = abx * abx + aby * aby;
+    let t = if len2 == 0.0 {
+        0.0
+    } else {
+        ((apx * abx + apy * aby) / len2).clamp(0.0, 1.0)
+    };
+    let (dx, dy) = (apx - abx * t, apy - aby * t);
+    (dx * dx + dy * dy).sqrt()
+}
+
+/// Approximate signed distance from `p`, relative to the center, to the
+/// ellipse outline; negative inside.
+fn ellipse_distance(p: (f32, f32), rx: f32, ry: f32) -> f32 {
+    let (nx, ny) = (p.0 / rx, p.1 / ry);
+    let k = (nx * nx + ny * ny).sqrt();
+    if k == 0.0 {
+        return -rx.min(ry);
+    }
+    // first order correction: divide the implicit function by its gradient
+    let grad = ((nx / rx).powi(2) + (ny / ry).powi(2)).sqrt() / k;
+    (k - 1.0) / grad
+}
+
+/// Printable ASCII from the public domain X11 misc-fixed 6x10 font, one byte
+/// per row with the leftmost pixel in bit 5.
+#[rustfmt::skip]
+const FONT_6X10: [[u8; GLYPH_HEIGHT as usize]; 95] = [
+    [0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00], // ' '
+    [0x00, 0x08, 0x08, 0x08, 0x08, 0x08, 0x00, 0x08, 0x00, 0x00], // '!'
+    [0x00, 0x14, 0x14, 0x14, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00], // '"'
+    [0x00, 0x14, 0x14, 0x3E, 0x14, 0x3E, 0x14, 0x14, 0x00, 0x00], // '#'
+    [0x00, 0x08, 0x1C, 0x28, 0x1C, 0x0A, 0x1C, 0x08, 0x00, 0x00], // '$'
+    [0x00, 0x12, 0x2A, 0x14, 0x08, 0x14, 0x2A, 0x24, 0x00, 0x00], // '%'
+    [0x00, 0x10, 0x28, 0x28, 0x10, 0x2A, 0x24, 0x1A, 0x00, 0x00], // '&'
+    [0x00, 0x08, 0x08, 0x08, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00], // '\''
+    [0x00, 0x04, 0x08, 0x10, 0x10, 0x10, 0x08, 0x04, 0x00, 0x00], // '('
+    [0x00, 0x10, 0x08, 0x04, 0x04, 0x04, 0x08, 0x10, 0x00, 0x00], // ')'
+    [0x00, 0x00, 0x22, 0x14, 0x3E, 0x14, 0x22, 0x00, 0x00, 0x00], // '*'
+    [0x00, 0x00, 0x08, 0x08, 0x3E, 0x08, 0x08, 0x00, 0x00, 0x00], // '+'
+    [0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x0C, 0x08, 0x10, 0x00], // ','
+    [0x00, 0x00, 0x00, 0x00, 0x3E, 0x00, 0x00, 0x00, 0x00, 0x00], // '-'
+    [0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x08, 0x1C, 0x08, 0x00], // '.'
+    [0x00, 0x02, 0x02, 0x04, 0x08, 0x10, 0x20, 0x20, 0x00, 0x00], // '/'
+    [0x00, 0x08, 0x14, 0x22, 0x22, 0x22, 0x14, 0x08, 0x00, 0x00], // '0'
+    [0x00, 0x08, 0x18, 0x28, 0x08, 0x08, 0x08, 0x3E, 0x00, 0x00], // '1'
+    [0x00, 0x1C, 0x22, 0x02, 0x0C, 0x10, 0x20, 0x3E, 0x00, 0x00], // '2'
+    [0x00, 0x3E, 0x02, 0x04, 0x0C, 0x02, 0x22, 0x1C, 0x00, 0x00], // '3'
+    [0x00, 0x04, 0x0C, 0x14, 0x24, 0x3E, 0x04, 0x04, 0x00, 0x00], // '4'
+    [0x00, 0x3E, 0x20, 0x2C, 0x32, 0x02, 0x22, 0x1C, 0x00, 0x00], // '5'
+    [0x00, 0x0C, 0x10, 0x20, 0x2C, 0x32, 0x22, 0x1C, 0x00, 0x00], // '6'
+    [0x00, 0x3E, 0x02, 0x04, 0x04, 0x08, 0x10, 0x10, 0x00, 0x00], // '7'
+    [0x00, 0x1C, 0x22, 0x22, 0x1C, 0x22, 0x22, 0x1C, 0x00, 0x00], // '8'
+    [0x00, 0x1C, 0x22, 0x26, 0x1A, 0x02, 0x04, 0x18, 0x00, 0x00], // '9'
+    [0x00, 0x00, 0x08, 0x1C, 0x08, 0x00, 0x08, 0x1C, 0x08, 0x00], // ':'
+    [0x00, 0x00, 0x08, 0x1C, 0x08, 0x00, 0x0C, 0x08, 0x10, 0x00], // ';'
+    [0x00, 0x02, 0x04, 0x08, 0x10, 0x08, 0x04, 0x02, 0x00, 0x00], // '<'
+    [0x00, 0x00, 0x00, 0x3E, 0x00, 0x3E, 0x00, 0x00, 0x00, 0x00], // '='
+    [0x00, 0x10, 0x08, 0x04, 0x02, 0x04, 0x08, 0x10, 0x00, 0x00], // '>'
+    [0x00, 0x1C, 0x22, 0x04, 0x08, 0x08, 0x00, 0x08, 0x00, 0x00], // '?'
+    [0x00, 0x1C, 0x22, 0x26, 0x2A, 0x2C, 0x20, 0x1C, 0x00, 0x00], // '@'
+    [0x00, 0x08, 0x14, 0x22, 0x22, 0x3E, 0x22, 0x22, 0x00, 0x00], // 'A'
+    [0x00, 0x3C, 0x12, 0x12, 0x1C, 0x12, 0x12, 0x3C, 0x00, 0x00], // 'B'
+    [0x00, 0x1C, 0x22, 0x20, 0x20, 0x20, 0x22, 0x1C, 0x00, 0x00], // 'C'
+    [0x00, 0x3C, 0x12, 0x12, 0x12, 0x12, 0x12, 0x3C, 0x00, 0x00], // 'D'
+    [0x00, 0x3E, 0x20, 0x20, 0x3C, 0x20, 0x20, 0x3E, 0x00, 0x00], // 'E'
+    [0x00, 0x3E, 0x20, 0x20, 0x3C, 0x20, 0x20, 0x20, 0x00, 0x00], // 'F'
+    [0x00, 0x1C, 0x22, 0x20, 0x20, 0x26, 0x22, 0x1C, 0x00, 0x00], // 'G'
+    [0x00, 0x22, 0x22, 0x22, 0x3E, 0x22, 0x22, 0x22, 0x00, 0x00], // 'H'
+    [0x00, 0x1C, 0x08, 0x08, 0x08, 0x08, 0x08, 0x1C, 0x00, 0x00], // 'I'
+    [0x00, 0x0E, 0x04, 0x04, 0x04, 0x04, 0x24, 0x18, 0x00, 0x00], // 'J'
+    [0x00, 0x22, 0x24, 0x28, 0x30, 0x28, 0x24, 0x22, 0x00, 0x00], // 'K'
+    [0x00, 0x20, 0x20, 0x20, 0x20, 0x20, 0x20, 0x3E, 0x00, 0x00], // 'L'
+    [0x00, 0x22, 0x22, 0x36, 0x2A, 0x22, 0x22, 0x22, 0x00, 0x00], // 'M'
+    [0x00, 0x22, 0x22, 0x32, 0x2A, 0x26, 0x22, 0x22, 0x00, 0x00], // 'N'
+    [0x00, 0x1C, 0x22, 0x22, 0x22, 0x22, 0x22, 0x1C, 0x00, 0x00], // 'O'
+    [0x00, 0x3C, 0x22, 0x22, 0x3C, 0x20, 0x20, 0x20, 0x00, 0x00], // 'P'
+    [0x00, 0x1C, 0x22, 0x22, 0x22, 0x22, 0x2A, 0x1C, 0x02, 0x00], // 'Q'
+    [0x00, 0x3C, 0x22, 0x22, 0x3C, 0x28, 0x24, 0x22, 0x00, 0x00], // 'R'
+    [0x00, 0x1C, 0x22, 0x20, 0x1C, 0x02, 0x22, 0x1C, 0x00, 0x00], // 'S'
+    [0x00, 0x3E, 0x08, 0x08, 0x08, 0x08, 0x08, 0x08, 0x00, 0x00], // 'T'
+    [0x00, 0x22, 0x22, 0x22, 0x22, 0x22, 0x22, 0x1C, 0x00, 0x00], // 'U'
+    [0x00, 0x22, 0x22, 0x22, 0x14, 0x14, 0x14, 0x08, 0x00, 0x00], // 'V'
+    [0x00, 0x22, 0x22, 0x22, 0x2A, 0x2A, 0x36, 0x22, 0x00, 0x00], // 'W'
+    [0x00, 0x22, 0x22, 0x14, 0x08, 0x14, 0x22, 0x22, 0x00, 0x00], // 'X'
+    [0x00, 0x22, 0x22, 0x14, 0x08, 0x08, 0x08, 0x08, 0x00, 0x00], // 'Y'
+    [0x00, 0x3E, 0x02, 0x04, 0x08, 0x10, 0x20, 0x3E, 0x00, 0x00], // 'Z'
+    [0x00, 0x1C, 0x10, 0x10, 0x10, 0x10, 0x10, 0x1C, 0x00, 0x00], // '['
+    [0x00, 0x20, 0x20, 0x10, 0x08, 0x04, 0x02, 0x02, 0x00, 0x00], // '\\'
+    [0x00, 0x1C, 0x04, 0x04, 0x04, 0x04, 0x04, 0x1C, 0x00, 0x00], // ']'
+    [0x00, 0x08, 0x14, 0x22, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00], // '^'
+    [0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x3E, 0x00], // '_'
+    [0x08, 0x04, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00], // '`'
+    [0x00, 0x00, 0x00, 0x1C, 0x02, 0x1E, 0x22, 0x1E, 0x00, 0x00], // 'a'
+    [0x00, 0x20, 0x20, 0x2C, 0x32, 0x22, 0x32, 0x2C, 0x00, 0x00], // 'b'
+    [0x00, 0x00, 0x00, 0x1C, 0x22, 0x20, 0x22, 0x1C, 0x00, 0x00], // 'c'
+    [0x00, 0x02, 0x02, 0x1A, 0x26, 0x22, 0x26, 0x1A, 0x00, 0x00], // 'd'
+    [0x00, 0x00, 0x00, 0x1C, 0x22, 0x3E, 0x20, 0x1C, 0x00, 0x00], // 'e'
+    [0x00, 0x0C, 0x12, 0x10, 0x3C, 0x10, 0x10, 0x10, 0x00, 0x00], // 'f'
+    [0x00, 0x00, 0x00, 0x1E, 0x22, 0x22, 0x1E, 0x02, 0x22, 0x1C], // 'g'
+    [0x00, 0x20, 0x20, 0x2C, 0x32, 0x22, 0x22, 0x22, 0x00, 0x00], // 'h'
+    [0x00, 0x08, 0x00, 0x18, 0x08, 0x08, 0x08, 0x1C, 0x00, 0x00], // 'i'
+    [0x00, 0x02, 0x00, 0x06, 0x02, 0x02, 0x02, 0x12, 0x12, 0x0C], // 'j'
+    [0x00, 0x20, 0x20, 0x22, 0x24, 0x38, 0x24, 0x22, 0x00, 0x00], // 'k'
+    [0x00, 0x18, 0x08, 0x08, 0x08, 0x08, 0x08, 0x1C, 0x00, 0x00], // 'l'
+    [0x00, 0x00, 0x00, 0x34, 0x2A, 0x2A, 0x2A, 0x22, 0x00, 0x00], // 'm'
+    [0x00, 0x00, 0x00, 0x2C, 0x32, 0x22, 0x22, 0x22, 0x00, 0x00], // 'n'
+    [0x00, 0x00, 0x00, 0x1C, 0x22, 0x22, 0x22, 0x1C, 0x00, 0x00], // 'o'
+    [0x00, 0x00, 0x00, 0x2C, 0x32, 0x22, 0x32, 0x2C, 0x20, 0x20], // 'p'
+    [0x00, 0x00, 0x00, 0x1A, 0x26, 0x22, 0x26, 0x1A, 0x02, 0x02], // 'q'
+    [0x00, 0x00, 0x00, 0x2C, 0x32, 0x20, 0x20, 0x20, 0x00, 0x00], // 'r'
+    [0x00, 0x00, 0x00, 0x1C, 0x20, 0x1C, 0x02, 0x3C, 0x00, 0x00], // 's'
+    [0x00, 0x10, 0x10, 0x3C, 0x10, 0x10, 0x12, 0x0C, 0x00, 0x00], // 't'
+    [0x00, 0x00, 0x00, 0x22, 0x22, 0x22, 0x26, 0x1A, 0x00, 0x00], // 'u'
+    [0x00, 0x00, 0x00, 0x22, 0x22, 0x14, 0x14, 0x08, 0x00, 0x00], // 'v'
+    [0x00, 0x00, 0x00, 0x22, 0x22, 0x2A, 0x2A, 0x14, 0x00, 0x00], // 'w'
+    [0x00, 0x00, 0x00, 0x22, 0x14, 0x08, 0x14, 0x22, 0x00, 0x00], // 'x'
+    [0x00, 0x00, 0x00, 0x22, 0x22, 0x26, 0x1A, 0x02, 0x22, 0x1C], // 'y'
+    [0x00, 0x00, 0x00, 0x3E, 0x04, 0x08, 0x10, 0x3E, 0x00, 0x00], // 'z'
+    [0x00, 0x06, 0x08, 0x04, 0x18, 0x04, 0x08, 0x06, 0x00, 0x00], // '{'
+    [0x00, 0x08, 0x08, 0x08, 0x08, 0x08, 0x08, 0x08, 0x00, 0x00], // '|'
+    [0x00, 0x18, 0x04, 0x08, 0x06, 0x08, 0x04, 0x18, 0x00, 0x00], // '}'
+    [0x00, 0x12, 0x2A, 0x24, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00], // '~'
+];
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::frame::PixelFormat;
+
+    #[test]
+    fn test_blend_pixel() {
+        let mut frame = Frame::new(2, 1);
+        frame.blend_pixel(0, 0, Color::rgba(0xFF, 0, 0, 0x80), 1.0);
+        assert_eq!(frame.pixel(0, 0), [0, 0, 0x80, 0xFF]);
+
+        frame.blend_pixel(1, 0, Color::WHITE, 0.0);
+        frame.blend_pixel(5, -1, Color::WHITE, 1.0);
+        assert_eq!(frame.pixel(1, 0), [0, 0, 0, 0xFF]);
+    }
+
+    #[test]
+    fn test_draw_on_hdr() {
+        let mut frame = Frame::from_raw(2, 1, PixelFormat::Rgba16Float, vec![0; 16]).unwrap();
+        frame.blend_pixel(0, 0, Color::RED, 1.0);
+        assert_eq!(frame.format, PixelFormat::Bgra8);
+        assert_eq!(frame.pixel(0, 0), [0x35, 0x39, 0xE5, 0xFF]);
+        assert_eq!(frame.pixel(1, 0), [0; 4]);
+    }
+
+    #[test]
+    fn test_line_is_anti_aliased() {
+        let mut frame = Frame::new(20, 20);
+        frame.draw_line((2.0, 2.0), (17.0, 9.0), 1.0, Color::WHITE);
+
+        let values: Vec<u8> = (0..20)
+            .flat_map(|y| (0..20).map(move |x| (x, y)))
+            .map(|(x, y)| frame.pixel(x, y)[0])
+            .collect();
+        assert!(values.contains(&0xFF));
+        assert!(values.iter().any(|&v| v > 0 && v < 0xFF));
+        assert_eq!(frame.pixel(2, 15)[0], 0);
+    }
+
+    #[test]
+    fn test_rect_and_highlight() {
+        let mut frame = Frame::new(10, 10);
+        frame.draw_rect(Rect::new(1, 1, 8, 8), 2, Color::WHITE);
+        assert_eq!(frame.pixel(1, 1)[0], 0xFF);
+        assert_eq!(frame.pixel(2, 5)[0], 0xFF);
+        assert_eq!(frame.pixel(3, 5)[0], 0);
+        assert_eq!(frame.pixel(8, 8)[0], 0xFF);
+
+        frame.fill_rect(Rect::new(3, 3, 4, 4), Color::WHITE.with_alpha(0x40));
+        assert_eq!(frame.pixel(4, 4)[0], 0x40);
+    }
+
+    #[test]
+    fn test_ellipse() {
+        let mut frame = Frame::new(21, 21);
+        frame.fill_ellipse((10.5, 10.5), (8.0, 4.0), Color::WHITE);
+        assert_eq!(frame.pixel(10, 10)[0], 0xFF);
+        assert_eq!(frame.pixel(17, 10)[0], 0xFF);
+        assert_eq!(frame.pixel(10, 16)[0], 0);
+
+        let mut frame = Frame::new(21, 21);
+        frame.draw_ellipse((10.5, 10.5), (8.0, 8.0), 1.0, Color::WHITE);
+        assert_eq!(frame.pixel(10, 10)[0], 0);
+        assert_eq!(frame.pixel(18, 10)[0], 0xFF);
+    }
+
+    #[test]
+    fn test_arrow_has_head() {
+        let mut frame = Frame::new(40, 40);
+        frame.draw_arrow((2.0, 20.0), (38.0, 20.0), 2.0, Color::WHITE);
+        assert!(frame.pixel(30, 16)[0] > 0);
+        assert!(frame.pixel(30, 24)[0] > 0);
+        assert_eq!(frame.pixel(10, 16)[0], 0);
+    }
+
+    #[test]
+    fn test_text() {
+        assert_eq!(text_size("ab\nc", 2), (24, 40));
+
+        let mut frame = Frame::new(12, 10);
+        let area = frame.draw_text(0, 0, "!é", 1, Color::WHITE);
+        assert_eq!(area, Rect::new(0, 0, 12, 10));
+        // the stem of '!'
+        assert_eq!(frame.pixel(2, 1)[0], 0xFF);
+        assert_eq!(frame.pixel(2, 6)[0], 0);
+        // unknown characters fall back to '?'
+        assert!(frame.row(1)[6 * 4..].contains(&0xFF));
+    }
+
+    #[test]
+    fn test_watermark_stays_inside() {
+        let mut frame = Frame::new(200, 40);
+        frame.watermark("host 2024-01-01", 1);
+        assert_eq!(frame.pixel(0, 0), [0, 0, 0, 0xFF]);
+        assert!(frame.row(30).contains(&0xFF));
+    }
+}
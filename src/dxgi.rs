@@ -1,95 +1,341 @@
+use std::cell::RefCell;
 use std::ffi::c_void;
-use std::fs::File;
-use std::io::Write;
-use std::ops::Not;
+use std::path::Path;
 use std::{ptr, slice};
 
-use windows::core::{Error, Interface};
+use windows::core::Interface;
+use windows::Win32::Foundation::E_ACCESSDENIED;
 use windows::Win32::Graphics::Direct3D::{
-    D3D_DRIVER_TYPE, D3D_DRIVER_TYPE_HARDWARE, D3D_DRIVER_TYPE_REFERENCE, D3D_DRIVER_TYPE_WARP,
-    D3D_FEATURE_LEVEL_10_0, D3D_FEATURE_LEVEL_10_1, D3D_FEATURE_LEVEL_11_0, D3D_FEATURE_LEVEL_9_1,
+    D3D_DRIVER_TYPE, D3D_DRIVER_TYPE_HARDWARE, D3D_DRIVER_TYPE_REFERENCE, D3D_DRIVER_TYPE_UNKNOWN,
+    D3D_DRIVER_TYPE_WARP, D3D_FEATURE_LEVEL, D3D_FEATURE_LEVEL_10_0, D3D_FEATURE_LEVEL_10_1,
+    D3D_FEATURE_LEVEL_11_0, D3D_FEATURE_LEVEL_9_1,
 };
 use windows::Win32::Graphics::Direct3D11::{
     D3D11CreateDevice, ID3D11Device, ID3D11DeviceContext, ID3D11Texture2D, D3D11_BIND_FLAG,
     D3D11_BIND_RENDER_TARGET, D3D11_CPU_ACCESS_READ, D3D11_CPU_ACCESS_WRITE,
-    D3D11_CREATE_DEVICE_FLAG, D3D11_RESOURCE_MISC_FLAG, D3D11_RESOURCE_MISC_GDI_COMPATIBLE,
-    D3D11_SDK_VERSION, D3D11_TEXTURE2D_DESC, D3D11_USAGE_DEFAULT, D3D11_USAGE_STAGING,
+    D3D11_CREATE_DEVICE_DEBUG, D3D11_CREATE_DEVICE_FLAG, D3D11_RESOURCE_MISC_FLAG,
+    D3D11_RESOURCE_MISC_GDI_COMPATIBLE, D3D11_SDK_VERSION, D3D11_TEXTURE2D_DESC,
+    D3D11_USAGE_DEFAULT, D3D11_USAGE_STAGING,
 };
 use windows::Win32::Graphics::Dxgi::Common::{
-    DXGI_CPU_ACCESS_NONE, DXGI_FORMAT_B8G8R8A8_UNORM, DXGI_SAMPLE_DESC,
+    DXGI_CPU_ACCESS_NONE, DXGI_FORMAT, DXGI_FORMAT_B8G8R8A8_UNORM, DXGI_FORMAT_R10G10B10A2_UNORM,
+    DXGI_FORMAT_R16G16B16A16_FLOAT, DXGI_SAMPLE_DESC,
 };
 use windows::Win32::Graphics::Dxgi::{
-    CreateDXGIFactory1, IDXGIAdapter1, IDXGIFactory1, IDXGIOutput1, IDXGIOutputDuplication,
-    IDXGIResource, IDXGISurface, IDXGISurface1, DXGI_MAPPED_RECT, DXGI_MAP_READ, DXGI_OUTDUPL_DESC,
+    CreateDXGIFactory1, IDXGIAdapter, IDXGIAdapter1, IDXGIFactory1, IDXGIOutput1, IDXGIOutput5,
+    IDXGIOutputDuplication, IDXGIResource, IDXGISurface, IDXGISurface1, DXGI_ERROR_ACCESS_LOST,
+    DXGI_ERROR_DEVICE_REMOVED, DXGI_ERROR_DEVICE_RESET, DXGI_ERROR_INVALID_CALL,
+    DXGI_ERROR_MODE_CHANGE_IN_PROGRESS, DXGI_ERROR_SESSION_DISCONNECTED, DXGI_ERROR_UNSUPPORTED,
+    DXGI_ERROR_WAIT_TIMEOUT, DXGI_MAPPED_RECT, DXGI_MAP_READ, DXGI_OUTDUPL_DESC,
     DXGI_OUTDUPL_FRAME_INFO, DXGI_OUTDUPL_POINTER_SHAPE_INFO,
     DXGI_OUTDUPL_POINTER_SHAPE_TYPE_COLOR, DXGI_OUTDUPL_POINTER_SHAPE_TYPE_MASKED_COLOR,
     DXGI_OUTDUPL_POINTER_SHAPE_TYPE_MONOCHROME, DXGI_OUTPUT_DESC, DXGI_RESOURCE_PRIORITY_MAXIMUM,
 };
-use windows::Win32::Graphics::Gdi::{
-    DeleteObject, BITMAPFILEHEADER, BITMAPINFOHEADER, BI_RGB, HBRUSH, HDC,
-};
+use windows::Win32::Graphics::Gdi::{DeleteObject, HBRUSH, HDC};
 use windows::Win32::UI::WindowsAndMessaging::{
     DrawIconEx, GetCursorInfo, GetIconInfo, GetSystemMetrics, CURSORINFO, CURSOR_SHOWING,
     DI_DEFAULTSIZE, DI_NORMAL, ICONINFO, SM_CXSCREEN, SM_CYSCREEN,
 };
 
-const DRIVER_TYPES: [D3D_DRIVER_TYPE; 3] = [
-    D3D_DRIVER_TYPE_HARDWARE,
-    D3D_DRIVER_TYPE_WARP,
-    D3D_DRIVER_TYPE_REFERENCE,
-];
+use crate::bmp::save_bmp;
+use crate::capture::Backend;
+use crate::device::{Attempt, DeviceBuilder, DriverType, FeatureLevel, Selection};
+use crate::error::Error;
+use crate::frame::{Frame, FrameInfo, PixelFormat, Rect};
+use crate::output::{Adapter, Output, Rotation};
+
+impl From<FeatureLevel> for D3D_FEATURE_LEVEL {
+    fn from(level: FeatureLevel) -> Self {
+        match level {
+            FeatureLevel::Level9_1 => D3D_FEATURE_LEVEL_9_1,
+            FeatureLevel::Level10_0 => D3D_FEATURE_LEVEL_10_0,
+            FeatureLevel::Level10_1 => D3D_FEATURE_LEVEL_10_1,
+            FeatureLevel::Level11_0 => D3D_FEATURE_LEVEL_11_0,
+        }
+    }
+}
 
-const FEATURE_LEVELS: [windows::Win32::Graphics::Direct3D::D3D_FEATURE_LEVEL; 4] = [
-    D3D_FEATURE_LEVEL_11_0,
-    D3D_FEATURE_LEVEL_10_1,
-    D3D_FEATURE_LEVEL_10_0,
-    D3D_FEATURE_LEVEL_9_1,
-];
+fn feature_level(level: D3D_FEATURE_LEVEL) -> Result<FeatureLevel, Error> {
+    FeatureLevel::ALL
+        .into_iter()
+        .find(|known| D3D_FEATURE_LEVEL::from(*known) == level)
+        .ok_or_else(|| Error::backend(format!("unknown feature level {:#x}", level.0)))
+}
+
+impl From<windows::core::Error> for Error {
+    fn from(e: windows::core::Error) -> Self {
+        match e.code() {
+            // all of them invalidate the duplication, which has to be recreated.
+            // E_ACCESSDENIED comes from DuplicateOutput while the secure desktop
+            // (UAC, lock screen) is shown, INVALID_CALL from AcquireNextFrame on
+            // a duplication that broke without telling us.
+            DXGI_ERROR_ACCESS_LOST
+            | DXGI_ERROR_INVALID_CALL
+            | E_ACCESSDENIED
+            | DXGI_ERROR_DEVICE_REMOVED
+            | DXGI_ERROR_DEVICE_RESET
+            | DXGI_ERROR_MODE_CHANGE_IN_PROGRESS
+            | DXGI_ERROR_SESSION_DISCONNECTED => Error::AccessLost,
+            DXGI_ERROR_WAIT_TIMEOUT => Error::Timeout,
+            DXGI_ERROR_UNSUPPORTED => Error::UnsupportedFormat(e.message()),
+            _ => Error::backend(e),
+        }
+    }
+}
 
 pub fn adapter1_by_id(id: u32) -> Result<IDXGIAdapter1, Error> {
-    unsafe { CreateDXGIFactory1::<IDXGIFactory1>().and_then(|e| e.EnumAdapters1(id)) }
+    Ok(unsafe { CreateDXGIFactory1::<IDXGIFactory1>().and_then(|e| e.EnumAdapters1(id)) }?)
 }
 
 pub fn dxgi_output1_by_id_and_adapter1(
     id: u32,
     adapter: &IDXGIAdapter1,
 ) -> Result<IDXGIOutput1, Error> {
-    unsafe { adapter.EnumOutputs(id).and_then(|e| e.cast()) }
+    Ok(unsafe { adapter.EnumOutputs(id).and_then(|e| e.cast()) }?)
 }
 
 pub fn dxgi_output_duplication_by_output1(
     dxgi_device: &ID3D11Device,
     dxgi_output1: &IDXGIOutput1,
 ) -> Result<IDXGIOutputDuplication, Error> {
-    unsafe { dxgi_output1.DuplicateOutput(dxgi_device) }
+    Ok(unsafe { dxgi_output1.DuplicateOutput(dxgi_device) }?)
 }
 
-pub fn dxgi_device_and_dxgi_device_context() -> Option<(ID3D11Device, ID3D11DeviceContext)> {
-    for driver_type in DRIVER_TYPES.iter() {
-        let mut device: Option<ID3D11Device> = None;
-        let mut immediate_context: Option<ID3D11DeviceContext> = None;
-        let mut feature_level = D3D_FEATURE_LEVEL_9_1;
-        let hr = unsafe {
-            D3D11CreateDevice(
-                None,
-                *driver_type,
-                None,
-                D3D11_CREATE_DEVICE_FLAG::default(),
-                Some(FEATURE_LEVELS.to_vec().as_ref()),
-                D3D11_SDK_VERSION,
-                Some(&mut device),
-                Some(&mut feature_level),
-                Some(&mut immediate_context),
-            )
+/// Formats asked for by [`dxgi_output_duplication_hdr`], in order of
+/// preference. Without them duplication converts HDR desktops to BGRA8,
+/// clipping everything above SDR white.
+const HDR_FORMATS: [DXGI_FORMAT; 3] = [
+    DXGI_FORMAT_R16G16B16A16_FLOAT,
+    DXGI_FORMAT_R10G10B10A2_UNORM,
+    DXGI_FORMAT_B8G8R8A8_UNORM,
+];
+
+/// Duplicate the output in its native format, which is HDR on HDR
+/// displays. Needs Windows 10 1703, older versions fall back to BGRA8.
+pub fn dxgi_output_duplication_hdr(
+    dxgi_device: &ID3D11Device,
+    dxgi_output1: &IDXGIOutput1,
+) -> Result<IDXGIOutputDuplication, Error> {
+    match dxgi_output1.cast::<IDXGIOutput5>() {
+        Ok(dxgi_output5) => {
+            Ok(unsafe { dxgi_output5.DuplicateOutput1(dxgi_device, 0, &HDR_FORMATS) }?)
+        }
+        Err(_) => dxgi_output_duplication_by_output1(dxgi_device, dxgi_output1),
+    }
+}
+
+pub fn pixel_format(format: DXGI_FORMAT) -> Result<PixelFormat, Error> {
+    match format {
+        DXGI_FORMAT_B8G8R8A8_UNORM => Ok(PixelFormat::Bgra8),
+        DXGI_FORMAT_R10G10B10A2_UNORM => Ok(PixelFormat::Rgb10A2),
+        DXGI_FORMAT_R16G16B16A16_FLOAT => Ok(PixelFormat::Rgba16Float),
+        other => Err(Error::UnsupportedFormat(format!("DXGI format {}", other.0))),
+    }
+}
+
+/// A D3D11 device with its immediate context, and how it was chosen.
+pub struct Device {
+    pub device: ID3D11Device,
+    pub context: ID3D11DeviceContext,
+    pub selection: Selection,
+}
+
+/// Create a device following the policy of `builder`.
+pub fn create_device(builder: &DeviceBuilder) -> Result<Device, Error> {
+    let ((device, context), selection) = builder.select(|attempt, levels| {
+        let levels: Vec<D3D_FEATURE_LEVEL> = levels.iter().map(|&level| level.into()).collect();
+        let (device, context, level) = create_device_with(attempt, &levels)?;
+        Ok(((device, context), feature_level(level)?))
+    })?;
+
+    Ok(Device {
+        device,
+        context,
+        selection,
+    })
+}
+
+fn create_device_with(
+    attempt: &Attempt,
+    levels: &[D3D_FEATURE_LEVEL],
+) -> Result<(ID3D11Device, ID3D11DeviceContext, D3D_FEATURE_LEVEL), Error> {
+    // a device on an explicit adapter has to be created with the unknown
+    // driver type
+    let (adapter, driver_type): (Option<IDXGIAdapter>, D3D_DRIVER_TYPE) =
+        match (attempt.adapter, attempt.driver) {
+            (Some(index), _) => (
+                Some(adapter1_by_id(index)?.cast()?),
+                D3D_DRIVER_TYPE_UNKNOWN,
+            ),
+            (None, DriverType::Hardware) => (None, D3D_DRIVER_TYPE_HARDWARE),
+            (None, DriverType::Warp) => (None, D3D_DRIVER_TYPE_WARP),
+            (None, DriverType::Reference) => (None, D3D_DRIVER_TYPE_REFERENCE),
+        };
+    let flags = if attempt.debug {
+        D3D11_CREATE_DEVICE_DEBUG
+    } else {
+        D3D11_CREATE_DEVICE_FLAG::default()
+    };
+
+    let mut device: Option<ID3D11Device> = None;
+    let mut immediate_context: Option<ID3D11DeviceContext> = None;
+    let mut feature_level = D3D_FEATURE_LEVEL_9_1;
+    unsafe {
+        D3D11CreateDevice(
+            adapter.as_ref(),
+            driver_type,
+            None,
+            flags,
+            Some(levels),
+            D3D11_SDK_VERSION,
+            Some(&mut device),
+            Some(&mut feature_level),
+            Some(&mut immediate_context),
+        )
+    }?;
+
+    match (device, immediate_context) {
+        (Some(device), Some(context)) => Ok((device, context, feature_level)),
+        _ => Err(Error::backend("D3D11CreateDevice returned no device")),
+    }
+}
+
+impl From<&DXGI_OUTDUPL_FRAME_INFO> for FrameInfo {
+    fn from(frame_info: &DXGI_OUTDUPL_FRAME_INFO) -> Self {
+        Self {
+            protected_content_masked_out: frame_info.ProtectedContentMaskedOut.as_bool(),
+            accumulated_frames: frame_info.AccumulatedFrames,
+            rects_coalesced: frame_info.RectsCoalesced.as_bool(),
+            cursor_visible: frame_info.PointerPosition.Visible.as_bool(),
+            last_present_time: frame_info.LastPresentTime,
+        }
+    }
+}
+
+/// Every adapter with its outputs, indexed like the ids [`adapter1_by_id`]
+/// and [`dxgi_output1_by_id_and_adapter1`] take.
+pub fn adapters() -> Result<Vec<Adapter>, Error> {
+    use windows::Win32::Graphics::Dxgi::{DXGI_ADAPTER_FLAG_SOFTWARE, DXGI_ERROR_NOT_FOUND};
+
+    let factory = unsafe { CreateDXGIFactory1::<IDXGIFactory1>() }?;
+    let mut adapters = Vec::new();
+    for index in 0.. {
+        let adapter = match unsafe { factory.EnumAdapters1(index) } {
+            Ok(adapter) => adapter,
+            Err(e) if e.code() == DXGI_ERROR_NOT_FOUND => break,
+            Err(e) => return Err(e.into()),
         };
-        if hr.is_ok() && device.is_some() && immediate_context.is_some() {
-            return Some((unsafe { device.unwrap_unchecked() }, unsafe {
-                immediate_context.unwrap_unchecked()
-            }));
+        let desc = unsafe { adapter.GetDesc1() }?;
+
+        let mut outputs = Vec::new();
+        for output_index in 0.. {
+            let output = match unsafe { adapter.EnumOutputs(output_index) } {
+                Ok(output) => output,
+                Err(e) if e.code() == DXGI_ERROR_NOT_FOUND => break,
+                Err(e) => return Err(e.into()),
+            };
+            outputs.push(output_info(output_index, &unsafe { output.GetDesc() }?));
         }
+
+        adapters.push(Adapter {
+            index,
+            name: from_wide(&desc.Description),
+            description: format!(
+                "vendor {:04x}, device {:04x}, {} MiB video memory",
+                desc.VendorId,
+                desc.DeviceId,
+                desc.DedicatedVideoMemory >> 20
+            ),
+            software: desc.Flags & DXGI_ADAPTER_FLAG_SOFTWARE.0 as u32 != 0,
+            outputs,
+        });
     }
 
-    None
+    Ok(adapters)
+}
+
+fn output_info(index: u32, desc: &DXGI_OUTPUT_DESC) -> Output {
+    use windows::core::PCWSTR;
+    use windows::Win32::Graphics::Dxgi::Common::{
+        DXGI_MODE_ROTATION_ROTATE180, DXGI_MODE_ROTATION_ROTATE270, DXGI_MODE_ROTATION_ROTATE90,
+    };
+    use windows::Win32::Graphics::Gdi::{
+        EnumDisplayDevicesW, EnumDisplaySettingsW, GetMonitorInfoW, DEVMODEW, DISPLAY_DEVICEW,
+        ENUM_CURRENT_SETTINGS, MONITORINFO,
+    };
+    use windows::Win32::UI::WindowsAndMessaging::MONITORINFOF_PRIMARY;
+
+    let device_name = PCWSTR(desc.DeviceName.as_ptr());
+    let rect = desc.DesktopCoordinates;
+    let desktop = Rect::new(
+        rect.left,
+        rect.top,
+        (rect.right - rect.left) as u32,
+        (rect.bottom - rect.top) as u32,
+    );
+
+    // 0 and 1 both mean the hardware default
+    let rotation = match desc.Rotation {
+        DXGI_MODE_ROTATION_ROTATE90 => Rotation::Rotate90,
+        DXGI_MODE_ROTATION_ROTATE180 => Rotation::Rotate180,
+        DXGI_MODE_ROTATION_ROTATE270 => Rotation::Rotate270,
+        _ => Rotation::Identity,
+    };
+
+    let mut mode = DEVMODEW {
+        dmSize: size_of::<DEVMODEW>() as u16,
+        ..Default::default()
+    };
+    let (mut width, mut height, refresh_rate) =
+        if unsafe { EnumDisplaySettingsW(device_name, ENUM_CURRENT_SETTINGS, &mut mode) }.as_bool()
+        {
+            let refresh_rate =
+                (mode.dmDisplayFrequency > 1).then_some(mode.dmDisplayFrequency as f64);
+            (mode.dmPelsWidth, mode.dmPelsHeight, refresh_rate)
+        } else {
+            (desktop.width, desktop.height, None)
+        };
+    // Windows reports the rotated size, outputs carry the mode's own
+    if matches!(rotation, Rotation::Rotate90 | Rotation::Rotate270) {
+        (width, height) = (height, width);
+    }
+
+    // the first display device below an output is its monitor
+    let mut monitor = DISPLAY_DEVICEW {
+        cb: size_of::<DISPLAY_DEVICEW>() as u32,
+        ..Default::default()
+    };
+    let description = if unsafe { EnumDisplayDevicesW(device_name, 0, &mut monitor, 0) }.as_bool() {
+        from_wide(&monitor.DeviceString)
+    } else {
+        String::new()
+    };
+
+    let mut monitor_info = MONITORINFO {
+        cbSize: size_of::<MONITORINFO>() as u32,
+        ..Default::default()
+    };
+    let primary = unsafe { GetMonitorInfoW(desc.Monitor, &mut monitor_info) }.as_bool()
+        && monitor_info.dwFlags & MONITORINFOF_PRIMARY != 0;
+
+    Output {
+        index,
+        name: from_wide(&desc.DeviceName),
+        description,
+        width,
+        height,
+        refresh_rate,
+        rotation,
+        desktop,
+        attached_to_desktop: desc.AttachedToDesktop.as_bool(),
+        primary,
+    }
+}
+
+/// A NUL terminated UTF-16 buffer as used in DXGI and GDI structs.
+fn from_wide(s: &[u16]) -> String {
+    let len = s.iter().position(|&c| c == 0).unwrap_or(s.len());
+    String::from_utf16_lossy(&s[..len])
 }
 
 #[derive(Debug)]
@@ -99,6 +345,9 @@ pub struct DuplicationContext {
     timeout_ms: u32,
     dxgi_output: IDXGIOutput1,
     dxgi_output_duplication: IDXGIOutputDuplication,
+    /// GDI compatible texture to draw the cursor on and the staging texture
+    /// to read it back, created on the first [`DuplicationContext::capture_into`].
+    cursor_textures: RefCell<Option<(Option<ID3D11Texture2D>, ID3D11Texture2D)>>,
 }
 
 impl DuplicationContext {
@@ -115,12 +364,13 @@ impl DuplicationContext {
             timeout_ms,
             dxgi_output: dxgi_output1,
             dxgi_output_duplication,
+            cursor_textures: RefCell::new(None),
         }
     }
 
     /// This is usually used to get the screen's position and size.
     pub fn dxgi_output_desc(&self) -> Result<DXGI_OUTPUT_DESC, Error> {
-        unsafe { self.dxgi_output.GetDesc() }
+        Ok(unsafe { self.dxgi_output.GetDesc() }?)
     }
 
     /// This is usually used to get the screen's pixel width/height and buffer size.
@@ -151,7 +401,7 @@ impl DuplicationContext {
 
                 Ok(texture2d)
             }
-            None => Err(Error::from_win32()),
+            None => Err(Error::backend("CreateTexture2D returned no texture")),
         }
     }
 
@@ -164,7 +414,7 @@ impl DuplicationContext {
         let d3d11_texture_desc = D3D11_TEXTURE2D_DESC {
             Width: dxgi_outdupl_desc.ModeDesc.Width,
             Height: dxgi_outdupl_desc.ModeDesc.Height,
-            Format: DXGI_FORMAT_B8G8R8A8_UNORM,
+            Format: dxgi_outdupl_desc.ModeDesc.Format,
             ArraySize: 1,
             BindFlags: 0,
             MiscFlags: 0,
@@ -241,18 +491,19 @@ impl DuplicationContext {
 
         match (hr, dxgi_resource) {
             (Ok(_), Some(resource)) => Ok((*resource).cast()?),
-            (Err(e), _) => Err(e),
-            (_, None) => Err(Error::from_win32()),
+            (Err(e), _) => Err(e.into()),
+            (_, None) => Err(Error::backend("AcquireNextFrame returned no resource")),
         }
     }
 
-    pub fn capture(&self) -> Result<(), Error> {
+    /// Save a frame with the cursor drawn on it as a BMP at `path`.
+    pub fn capture<P: AsRef<Path>>(&self, path: P) -> Result<(), Error> {
         let dxgi_outdupl_desc = self.dxgi_outdupl_desc();
         let mut dxgi_outdupl_frame_info = DXGI_OUTDUPL_FRAME_INFO::default();
         let mut dxgi_resource: Option<IDXGIResource> = None;
         let mut pointer_shape_info = DXGI_OUTDUPL_POINTER_SHAPE_INFO::default();
 
-        for i in 0..2 {
+        for _ in 0..2 {
             let mut pointer_shape_buffer = Vec::new();
             let (texture2d, dxgi_pointer_shape_info) = self.acquire_next_frame_with_cursor(
                 &mut dxgi_outdupl_frame_info,
@@ -268,7 +519,7 @@ impl DuplicationContext {
             let d3d11_texture_desc = D3D11_TEXTURE2D_DESC {
                 Width: dxgi_outdupl_desc.ModeDesc.Width,
                 Height: dxgi_outdupl_desc.ModeDesc.Height,
-                Format: DXGI_FORMAT_B8G8R8A8_UNORM,
+                Format: dxgi_outdupl_desc.ModeDesc.Format,
                 ArraySize: 1,
                 BindFlags: 0,
                 MiscFlags: 0,
@@ -337,35 +588,31 @@ impl DuplicationContext {
             }
 
             let dxgi_output_desc = self.dxgi_output_desc()?;
-            println!("{:?}", dxgi_output_desc);
-            println!("{:?}", pointer_shape_info);
-            println!("{:?}", dxgi_outdupl_frame_info);
 
-            if dxgi_outdupl_frame_info.PointerPosition.Visible.as_bool() {
-                let desktop_image_buffer = draw_mouse(
+            let desktop_coordinates = dxgi_output_desc.DesktopCoordinates;
+            let desktop_image_buffer = if dxgi_outdupl_frame_info.PointerPosition.Visible.as_bool()
+            {
+                draw_mouse(
                     pointer_shape_buffer,
                     dxgi_outdupl_frame_info,
                     pointer_shape_info,
                     dxgi_output_desc,
                     desktop_image_buffer,
-                );
-                rgba_to_bmp(
-                    "filename.bmp",
-                    &desktop_image_buffer[..],
-                    desktop_image_buffer.len() as u32,
-                    dxgi_outdupl_desc.ModeDesc.Width as i32,
-                    dxgi_outdupl_desc.ModeDesc.Height as i32,
-                );
-                continue;
-            }
-            rgba_to_bmp(
-                "filename.bmp",
-                &desktop_image_buffer[..],
-                desktop_image_buffer.len() as u32,
-                dxgi_outdupl_desc.ModeDesc.Width as i32,
-                dxgi_outdupl_desc.ModeDesc.Height as i32,
-            );
+                )?
+            } else {
+                desktop_image_buffer
+            };
 
+            if let Some(frame) = Frame::from_bgra(
+                dxgi_outdupl_desc.ModeDesc.Width,
+                dxgi_outdupl_desc.ModeDesc.Height,
+                desktop_image_buffer,
+            ) {
+                let frame = frame
+                    .with_origin(desktop_coordinates.left, desktop_coordinates.top)
+                    .with_info(FrameInfo::from(&dxgi_outdupl_frame_info));
+                save_bmp(path.as_ref(), &frame)?;
+            }
         }
 
         Ok(())
@@ -406,10 +653,6 @@ impl DuplicationContext {
                 )
             }?;
 
-            // println!("{:?}", size);
-            // println!("{:?}", pointer_shape_buffer.len());
-            // println!("HHHHHHHHHHHHHHHHH");
-
             if let Some(resource) = dxgi_resource {
                 return Ok(((*resource).cast()?, Some(pointer_shape_info)));
             }
@@ -419,7 +662,7 @@ impl DuplicationContext {
             }
         }
 
-        Err(Error::from_win32())
+        Err(Error::backend("AcquireNextFrame returned no resource"))
     }
 
     pub fn capture_desktop_image_with_cursor(
@@ -511,95 +754,266 @@ impl DuplicationContext {
         Ok(())
     }
 
-    pub fn capture_monitor(&self) -> Result<(), Error> {
+    /// Capture the output with the cursor drawn in.
+    pub fn capture_frame(&self) -> Result<Frame, Error> {
+        self.capture_into(Vec::new())
+    }
+
+    /// Capture the output with the cursor drawn in, storing the pixels in
+    /// `buffer`. The textures needed on the way are kept for the next call.
+    ///
+    /// HDR frames come in the duplication's format and without the cursor,
+    /// GDI can only draw on BGRA8 surfaces.
+    pub fn capture_into(&self, mut buffer: Vec<u8>) -> Result<Frame, Error> {
         let dxgi_outdupl_desc = self.dxgi_outdupl_desc();
+        let format = pixel_format(dxgi_outdupl_desc.ModeDesc.Format)?;
         let mut dxgi_outdupl_frame_info = DXGI_OUTDUPL_FRAME_INFO::default();
         let mut dxgi_resource: Option<IDXGIResource> = None;
+
+        let d3d11_texture2d =
+            self.acquire_next_frame(&mut dxgi_outdupl_frame_info, &mut dxgi_resource)?;
+        let copied = self.copy_with_cursor(&dxgi_outdupl_desc, &d3d11_texture2d, &mut buffer);
+        // the frame has to be released even if the copy failed
+        self.release_frame()?;
+        copied?;
+
+        let desktop_coordinates = self.dxgi_output_desc()?.DesktopCoordinates;
+        let frame = Frame::from_raw(
+            dxgi_outdupl_desc.ModeDesc.Width,
+            dxgi_outdupl_desc.ModeDesc.Height,
+            format,
+            buffer,
+        )
+        .ok_or_else(|| {
+            Error::InvalidGeometry("mapped surface smaller than the output".to_string())
+        })?
+        .with_origin(desktop_coordinates.left, desktop_coordinates.top)
+        .with_info(FrameInfo::from(&dxgi_outdupl_frame_info));
+
+        Ok(frame)
+    }
+
+    fn copy_with_cursor(
+        &self,
+        dxgi_outdupl_desc: &DXGI_OUTDUPL_DESC,
+        d3d11_texture2d: &ID3D11Texture2D,
+        buffer: &mut Vec<u8>,
+    ) -> Result<(), Error> {
+        let mut cursor_textures = self.cursor_textures.borrow_mut();
+        if cursor_textures.is_none() {
+            *cursor_textures = Some(self.create_cursor_textures(dxgi_outdupl_desc)?);
+        }
+        let (d3d11_texture_gdi, d3d11_texture_cpu) = cursor_textures
+            .as_ref()
+            .expect("cursor textures created above");
+
+        match d3d11_texture_gdi {
+            Some(d3d11_texture_gdi) => {
+                unsafe {
+                    self.d3d11_device_context
+                        .CopyResource(d3d11_texture_gdi, d3d11_texture2d)
+                };
+                let dxgi_surface1 = d3d11_texture_gdi.cast::<IDXGISurface1>()?;
+                let hdc = unsafe { dxgi_surface1.GetDC(false) }?;
+                let drawn = draw_mouse_with_dc(hdc);
+                unsafe { dxgi_surface1.ReleaseDC(None) }?;
+                drawn?;
+
+                unsafe {
+                    self.d3d11_device_context
+                        .CopyResource(d3d11_texture_cpu, d3d11_texture_gdi)
+                };
+            }
+            None => unsafe {
+                self.d3d11_device_context
+                    .CopyResource(d3d11_texture_cpu, d3d11_texture2d)
+            },
+        }
+        let dxgi_surface = d3d11_texture_cpu.cast::<IDXGISurface>()?;
         let mut locked_rect = DXGI_MAPPED_RECT::default();
+        unsafe { dxgi_surface.Map(&mut locked_rect, DXGI_MAP_READ) }?;
+
+        // rows are padded to the pitch, copy them while the surface is mapped
+        let line_bytes = dxgi_outdupl_desc.ModeDesc.Width as usize
+            * pixel_format(dxgi_outdupl_desc.ModeDesc.Format)?.bytes_per_pixel();
+        let height = dxgi_outdupl_desc.ModeDesc.Height as usize;
+        buffer.clear();
+        buffer.reserve(line_bytes * height);
+        for y in 0..height {
+            let row = unsafe {
+                slice::from_raw_parts(
+                    locked_rect.pBits.add(y * locked_rect.Pitch as usize),
+                    line_bytes,
+                )
+            };
+            buffer.extend_from_slice(row);
+        }
 
-        self.capture_desktop_image_with_cursor(
-            &mut dxgi_outdupl_frame_info,
-            &mut dxgi_resource,
-            &mut locked_rect,
-            &dxgi_outdupl_desc,
-        )?;
-
-        let img_buffer_size =
-            dxgi_outdupl_desc.ModeDesc.Width * dxgi_outdupl_desc.ModeDesc.Height * 4;
-        let img_data =
-            unsafe { slice::from_raw_parts(locked_rect.pBits, img_buffer_size as usize) };
-
-        rgba_to_bmp(
-            "screen.bmp",
-            img_data,
-            img_buffer_size,
-            dxgi_outdupl_desc.ModeDesc.Width as i32,
-            dxgi_outdupl_desc.ModeDesc.Height as i32,
-        );
+        unsafe { dxgi_surface.Unmap() }?;
+
+        Ok(())
+    }
+
+    /// The GDI texture is `None` for HDR formats, which GDI can't draw on.
+    fn create_cursor_textures(
+        &self,
+        dxgi_outdupl_desc: &DXGI_OUTDUPL_DESC,
+    ) -> Result<(Option<ID3D11Texture2D>, ID3D11Texture2D), Error> {
+        let mut d3d11_texture_desc = D3D11_TEXTURE2D_DESC {
+            Width: dxgi_outdupl_desc.ModeDesc.Width,
+            Height: dxgi_outdupl_desc.ModeDesc.Height,
+            Format: dxgi_outdupl_desc.ModeDesc.Format,
+            ArraySize: 1,
+            BindFlags: D3D11_BIND_RENDER_TARGET.0 as u32,
+            MiscFlags: D3D11_RESOURCE_MISC_GDI_COMPATIBLE.0 as u32,
+            SampleDesc: DXGI_SAMPLE_DESC {
+                Count: 1,
+                Quality: 0,
+            },
+            Usage: D3D11_USAGE_DEFAULT,
+            CPUAccessFlags: DXGI_CPU_ACCESS_NONE,
+            MipLevels: 1,
+        };
+        let d3d11_texture_gdi = match dxgi_outdupl_desc.ModeDesc.Format {
+            DXGI_FORMAT_B8G8R8A8_UNORM => Some(self.create_d3d11_texture2d(d3d11_texture_desc)?),
+            _ => None,
+        };
+
+        d3d11_texture_desc.BindFlags = D3D11_BIND_FLAG::default().0 as u32;
+        d3d11_texture_desc.MiscFlags = D3D11_RESOURCE_MISC_FLAG::default().0 as u32;
+        d3d11_texture_desc.CPUAccessFlags = D3D11_CPU_ACCESS_READ.0 as u32;
+        d3d11_texture_desc.Usage = D3D11_USAGE_STAGING;
+        let d3d11_texture_cpu = self.create_d3d11_texture2d(d3d11_texture_desc)?;
+
+        Ok((d3d11_texture_gdi, d3d11_texture_cpu))
+    }
+
+    pub fn capture_monitor(&self) -> Result<(), Error> {
+        let frame = self.capture_frame()?;
+        save_bmp("screen.bmp", &frame)?;
 
         Ok(())
     }
 
     fn release_frame(&self) -> Result<(), Error> {
-        unsafe { self.dxgi_output_duplication.ReleaseFrame() }
+        Ok(unsafe { self.dxgi_output_duplication.ReleaseFrame() }?)
     }
 }
 
-pub fn rgba_to_bmp(filename: &str, img_data: &[u8], img_buffer_size: u32, width: i32, height: i32) {
-    let bf_off_bits = (size_of::<BITMAPFILEHEADER>() + size_of::<BITMAPINFOHEADER>()) as u32;
+/// [`Backend`] duplicating one output of one adapter, recreating device and
+/// duplication on every [`Backend::open`].
+pub struct DxgiBackend {
+    adapter: u32,
+    output: u32,
+    timeout_ms: u32,
+    device: DeviceBuilder,
+    hdr: bool,
+    selection: Option<Selection>,
+    context: Option<DuplicationContext>,
+}
 
-    let bfh = BITMAPFILEHEADER {
-        bfType: 0x4D42,
-        bfOffBits: bf_off_bits,
-        bfSize: bf_off_bits + img_buffer_size,
-        bfReserved1: 0,
-        bfReserved2: 0,
-    };
+impl DxgiBackend {
+    pub fn new(adapter: u32, output: u32, timeout_ms: u32) -> Self {
+        Self {
+            adapter,
+            output,
+            timeout_ms,
+            device: DeviceBuilder::new(),
+            hdr: false,
+            selection: None,
+            context: None,
+        }
+    }
 
-    let bih = BITMAPINFOHEADER {
-        biSize: size_of::<BITMAPINFOHEADER>() as u32,
-        biWidth: width,
-        biHeight: -height,
-        biPlanes: 1,
-        biBitCount: 32,
-        biCompression: BI_RGB.0,
-        biSizeImage: img_buffer_size,
-        biXPelsPerMeter: 0,
-        biYPelsPerMeter: 0,
-        biClrUsed: 0,
-        biClrImportant: 0,
-    };
+    /// How to create the device, the output has to be on the device's
+    /// adapter for the duplication to succeed.
+    pub fn device(mut self, builder: DeviceBuilder) -> Self {
+        self.device = builder;
+        self
+    }
 
-    // let data = unsafe { std::slice::from_raw_parts(img_data, img_size as usize) };
+    /// Capture HDR outputs in their native format rather than clipped to
+    /// BGRA8, see [`crate::tonemap`] for bringing the frames back to SDR.
+    pub fn hdr(mut self, hdr: bool) -> Self {
+        self.hdr = hdr;
+        self
+    }
 
-    let mut file = File::create(filename).unwrap();
-    file.write_all(struct_to_bytes(&bfh)).unwrap();
-    file.write_all(struct_to_bytes(&bih)).unwrap();
-    file.write_all(img_data).unwrap();
+    /// The device chosen by the last successful [`Backend::open`].
+    pub fn selection(&self) -> Option<&Selection> {
+        self.selection.as_ref()
+    }
 }
 
-fn struct_to_bytes<T>(s: &T) -> &[u8] {
-    unsafe { std::slice::from_raw_parts((s as *const T) as *const u8, std::mem::size_of::<T>()) }
+impl Backend for DxgiBackend {
+    fn open(&mut self) -> Result<Rect, Error> {
+        // release the broken duplication first, an output can only be
+        // duplicated once per process
+        self.context = None;
+
+        let dxgi_adapter1 = adapter1_by_id(self.adapter)?;
+        let dxgi_output1 = dxgi_output1_by_id_and_adapter1(self.output, &dxgi_adapter1)?;
+        let device = create_device(&self.device)?;
+        let dxgi_output_duplication = if self.hdr {
+            dxgi_output_duplication_hdr(&device.device, &dxgi_output1)?
+        } else {
+            dxgi_output_duplication_by_output1(&device.device, &dxgi_output1)?
+        };
+        let context = DuplicationContext::new(
+            device.device,
+            device.context,
+            self.timeout_ms,
+            dxgi_output1,
+            dxgi_output_duplication,
+        );
+
+        // the mode size rather than the desktop rectangle, they differ for
+        // rotated outputs and frames have the mode size
+        let desktop_coordinates = context.dxgi_output_desc()?.DesktopCoordinates;
+        let mode = context.dxgi_outdupl_desc().ModeDesc;
+        self.context = Some(context);
+        self.selection = Some(device.selection);
+
+        Ok(Rect::new(
+            desktop_coordinates.left,
+            desktop_coordinates.top,
+            mode.Width,
+            mode.Height,
+        ))
+    }
+
+    fn next_frame(&mut self) -> Result<Frame, Error> {
+        self.next_frame_into(Vec::new())
+    }
+
+    fn next_frame_into(&mut self, buffer: Vec<u8>) -> Result<Frame, Error> {
+        match &self.context {
+            Some(context) => context.capture_into(buffer),
+            None => Err(Error::AccessLost),
+        }
+    }
+
+    fn adapters(&mut self) -> Result<Vec<Adapter>, Error> {
+        adapters()
+    }
 }
 
+/// Draw the current cursor onto a GDI compatible surface, nothing is drawn
+/// while the cursor is hidden.
 pub fn draw_mouse_with_dc(hdc: HDC) -> Result<(), Error> {
     let mut cursor_info = CURSORINFO::default();
     let mut icon_info = ICONINFO::default();
     cursor_info.cbSize = size_of::<CURSORINFO>() as u32;
 
-    if let Err(e) = unsafe { GetCursorInfo(&mut cursor_info) } {
-        println!("GetCursorInfo failed: {:?}", e);
-    }
+    unsafe { GetCursorInfo(&mut cursor_info) }?;
 
     if (cursor_info.flags.0 & CURSOR_SHOWING.0) == 0 {
-        println!("Cursor is not showing");
+        return Ok(());
     }
 
-    if let Err(e) = unsafe { GetIconInfo(cursor_info.hCursor, &mut icon_info) } {
-        println!("GetIconInfo failed: {:?}", e);
-    }
+    unsafe { GetIconInfo(cursor_info.hCursor, &mut icon_info) }?;
 
-    if let Err(e) = unsafe {
+    let drawn = unsafe {
         DrawIconEx(
             hdc,
             cursor_info.ptScreenPos.x,
@@ -611,13 +1025,17 @@ pub fn draw_mouse_with_dc(hdc: HDC) -> Result<(), Error> {
             HBRUSH::default(),
             DI_NORMAL | DI_DEFAULTSIZE,
         )
-    } {
-        println!("DrawIconEx failed: {:?}", e);
-        if unsafe { DeleteObject(icon_info.hbmColor) }.as_bool().not()
-            || unsafe { DeleteObject(icon_info.hbmMask) }.as_bool().not()
-        {
-            println!("DeleteObject failed");
-        }
+    };
+
+    // GetIconInfo hands us copies of the bitmaps which we have to free either way
+    let deleted = [icon_info.hbmColor, icon_info.hbmMask]
+        .into_iter()
+        .filter(|bitmap| !bitmap.is_invalid())
+        .all(|bitmap| unsafe { DeleteObject(bitmap) }.as_bool());
+
+    drawn?;
+    if !deleted {
+        return Err(Error::backend("DeleteObject failed for the cursor bitmaps"));
     }
 
     Ok(())
@@ -629,7 +1047,7 @@ pub fn draw_mouse(
     pointer_shape_info: DXGI_OUTDUPL_POINTER_SHAPE_INFO,
     dxgi_output_desc: DXGI_OUTPUT_DESC,
     buf: Vec<u8>,
-) -> Vec<u8> {
+) -> Result<Vec<u8>, Error> {
     let desktop_width = width();
     let desktop_height = height();
 
@@ -656,8 +1074,6 @@ pub fn draw_mouse(
     let mut cursor_height = pointer_shape_info.Height as i32;
     let cursor_width = pointer_shape_info.Width as i32;
 
-    println!("{} {}", cursor_width, cursor_height);
-
     let cursor_left = if frame_info.PointerPosition.Position.x < 0 {
         0
     } else {
@@ -713,7 +1129,7 @@ pub fn draw_mouse(
                 }
             }
 
-            return vec32_to_vec8(buf32);
+            Ok(vec32_to_vec8(buf32))
         }
         val if val == DXGI_OUTDUPL_POINTER_SHAPE_TYPE_COLOR.0 as u32 => {
             let cursor32 = vec8_to_vec32(pointer_shape_buffer);
@@ -721,11 +1137,6 @@ pub fn draw_mouse(
 
             for row in 0..cursor_height {
                 for col in 0..cursor_width {
-                    // println!("*************");
-                    // println!("{} {}", row, skip_x);
-                    // println!("{} {}", col, cursor_width);
-                    // println!("{} {}", cursor_width, cursor_height);
-
                     let cur_cursor_val = cursor32[(col
                         + skip_x
                         + (row + skip_y) * (pointer_shape_info.Pitch as i32 / 4))
@@ -740,7 +1151,7 @@ pub fn draw_mouse(
                 }
             }
 
-            return vec32_to_vec8(buf32);
+            Ok(vec32_to_vec8(buf32))
         }
         val if val == DXGI_OUTDUPL_POINTER_SHAPE_TYPE_MASKED_COLOR.0 as u32 => {
             let cursor32 = vec8_to_vec32(pointer_shape_buffer);
@@ -770,11 +1181,12 @@ pub fn draw_mouse(
                 }
             }
 
-            return vec32_to_vec8(buf32);
-        }
-        _ => {
-            return Vec::new();
+            Ok(vec32_to_vec8(buf32))
         }
+        other => Err(Error::UnsupportedFormat(format!(
+            "pointer shape type {}",
+            other
+        ))),
     }
 }
 
@@ -822,24 +1234,19 @@ mod tests {
     fn test_dxgi_screenshot() {
         let dxgi_adapter1 = adapter1_by_id(0).unwrap();
         let dxgi_output1 = dxgi_output1_by_id_and_adapter1(0, &dxgi_adapter1).unwrap();
-        let (d3d11_device, d3d11_device_context) = dxgi_device_and_dxgi_device_context().unwrap();
+        let device = create_device(&DeviceBuilder::new()).unwrap();
         let dxgi_output_duplication =
-            dxgi_output_duplication_by_output1(&d3d11_device, &dxgi_output1).unwrap();
+            dxgi_output_duplication_by_output1(&device.device, &dxgi_output1).unwrap();
         let duplication_context = DuplicationContext::new(
-            d3d11_device,
-            d3d11_device_context,
+            device.device,
+            device.context,
             1000,
             dxgi_output1,
             dxgi_output_duplication,
         );
 
-        thread::spawn(move || {
-            for i in 1.. {
-                // let _dxgi_mapped_rect = duplication_context.capture_desktop_image().unwrap();
-                duplication_context.capture_monitor().unwrap();
-
-                println!("frame: {}", i);
-            }
+        thread::spawn(move || loop {
+            duplication_context.capture_monitor().unwrap();
         });
 
         sleep(Duration::from_secs(1));
@@ -0,0 +1,89 @@
+use std::{fmt, io};
+
+pub type BoxError = Box<dyn std::error::Error + Send + Sync>;
+
+pub type Result<T, E = Error> = std::result::Result<T, E>;
+
+/// Everything that can go wrong while capturing, processing or writing frames.
+#[derive(Debug)]
+pub enum Error {
+    /// The capture source was invalidated, e.g. by a desktop switch, a UAC
+    /// prompt or a mode change, and has to be recreated.
+    AccessLost,
+    /// No new frame arrived within the timeout.
+    Timeout,
+    /// A pixel or cursor format this crate can't handle.
+    UnsupportedFormat(String),
+    /// Sizes, strides or rectangles that don't fit together.
+    InvalidGeometry(String),
+    Io(io::Error),
+    /// Any other failure of the platform capture API.
+    Backend(BoxError),
+}
+
+impl Error {
+    pub fn backend<E: Into<BoxError>>(error: E) -> Self {
+        Error::Backend(error.into())
+    }
+
+    /// Whether recreating the capture source may fix the error.
+    pub fn is_recoverable(&self) -> bool {
+        matches!(self, Error::AccessLost | Error::Timeout)
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::AccessLost => f.write_str("access to the capture source was lost"),
+            Error::Timeout => f.write_str("timed out waiting for a frame"),
+            Error::UnsupportedFormat(format) => write!(f, "unsupported format: {}", format),
+            Error::InvalidGeometry(reason) => write!(f, "invalid geometry: {}", reason),
+            Error::Io(_) => f.write_str("I/O error"),
+            Error::Backend(_) => f.write_str("capture backend error"),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Io(e) => Some(e),
+            Error::Backend(e) => Some(e.as_ref()),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Self {
+        Error::Io(e)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::error::Error as _;
+
+    #[test]
+    fn test_source_chain() {
+        let e = Error::from(io::Error::new(io::ErrorKind::NotFound, "screen.bmp"));
+        assert_eq!(e.to_string(), "I/O error");
+        assert_eq!(e.source().unwrap().to_string(), "screen.bmp");
+
+        let e = Error::backend("CreateTexture2D returned no texture");
+        assert_eq!(
+            e.source().unwrap().to_string(),
+            "CreateTexture2D returned no texture"
+        );
+        assert!(Error::Timeout.source().is_none());
+    }
+
+    #[test]
+    fn test_is_recoverable() {
+        assert!(Error::AccessLost.is_recoverable());
+        assert!(Error::Timeout.is_recoverable());
+        assert!(!Error::InvalidGeometry("0x0".to_string()).is_recoverable());
+    }
+}
@@ -0,0 +1,336 @@
+use serde::{Deserialize, Serialize};
+
+/// Bytes per pixel of a BGRA8 frame, the layout DXGI desktop duplication hands us.
+pub const BYTES_PER_PIXEL: usize = 4;
+
+/// An axis aligned rectangle in pixel coordinates.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+pub struct Rect {
+    pub x: i32,
+    pub y: i32,
+    pub width: u32,
+    pub height: u32,
+}
+
+impl Rect {
+    pub const fn new(x: i32, y: i32, width: u32, height: u32) -> Self {
+        Self {
+            x,
+            y,
+            width,
+            height,
+        }
+    }
+
+    /// One past the last column, saturating at `i32::MAX`.
+    pub fn right(&self) -> i32 {
+        self.x.saturating_add_unsigned(self.width)
+    }
+
+    /// One past the last row, saturating at `i32::MAX`.
+    pub fn bottom(&self) -> i32 {
+        self.y.saturating_add_unsigned(self.height)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.width == 0 || self.height == 0
+    }
+
+    /// Move the rectangle by `(dx, dy)`, e.g. from desktop to frame coordinates.
+    pub fn offset(&self, dx: i32, dy: i32) -> Self {
+        Self::new(
+            self.x.saturating_add(dx),
+            self.y.saturating_add(dy),
+            self.width,
+            self.height,
+        )
+    }
+
+    /// The overlapping part of both rectangles, `None` if they don't overlap.
+    pub fn intersect(&self, other: &Rect) -> Option<Rect> {
+        let left = self.x.max(other.x);
+        let top = self.y.max(other.y);
+        let right = self.right().min(other.right());
+        let bottom = self.bottom().min(other.bottom());
+
+        if right <= left || bottom <= top {
+            return None;
+        }
+
+        Some(Rect::new(
+            left,
+            top,
+            right.abs_diff(left),
+            bottom.abs_diff(top),
+        ))
+    }
+}
+
+/// How trustworthy a captured frame is, as reported by the capture backend.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct FrameInfo {
+    /// DRM protected content was blacked out by the OS.
+    pub protected_content_masked_out: bool,
+    /// Desktop updates folded into this frame, 0 means only the cursor moved
+    /// and the image is the previous one.
+    pub accumulated_frames: u32,
+    /// Dirty rectangles were merged by the OS, so update regions are coarse.
+    pub rects_coalesced: bool,
+    pub cursor_visible: bool,
+    /// QPC time of the last desktop present, 0 if the image wasn't updated.
+    pub last_present_time: i64,
+}
+
+impl FrameInfo {
+    /// Nothing was masked out and the image holds fresh desktop content.
+    pub fn is_complete(&self) -> bool {
+        !self.protected_content_masked_out && self.accumulated_frames > 0
+    }
+}
+
+/// What consumers such as the recorder do with frames that aren't
+/// [`FrameInfo::is_complete`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum IntegrityPolicy {
+    /// Use the frame as is.
+    #[default]
+    Keep,
+    /// Use the frame but mark it as incomplete.
+    Flag,
+    /// Drop the frame.
+    Skip,
+}
+
+impl IntegrityPolicy {
+    /// `None` when the frame should be dropped, otherwise whether it has to
+    /// be flagged.
+    pub fn admit(&self, info: &FrameInfo) -> Option<bool> {
+        match (self, info.is_complete()) {
+            (_, true) | (IntegrityPolicy::Keep, false) => Some(false),
+            (IntegrityPolicy::Flag, false) => Some(true),
+            (IntegrityPolicy::Skip, false) => None,
+        }
+    }
+}
+
+/// Pixel layout of a [`Frame`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Hash)]
+pub enum PixelFormat {
+    /// 8 bit sRGB, what everything but HDR outputs use.
+    #[default]
+    Bgra8,
+    /// 10 bit HDR10, PQ encoded BT.2020 primaries in a little endian `u32`
+    /// with red in the low bits and 2 bits of alpha on top.
+    Rgb10A2,
+    /// Half float scRGB, linear BT.709 primaries where 1.0 is 80 nits and
+    /// values beyond it are brighter than SDR white.
+    Rgba16Float,
+}
+
+impl PixelFormat {
+    pub const fn bytes_per_pixel(&self) -> usize {
+        match self {
+            PixelFormat::Bgra8 | PixelFormat::Rgb10A2 => 4,
+            PixelFormat::Rgba16Float => 8,
+        }
+    }
+
+    pub fn is_hdr(&self) -> bool {
+        *self != PixelFormat::Bgra8
+    }
+}
+
+/// A captured desktop image, rows top to bottom. Frames are BGRA8 unless
+/// captured from an HDR output, see [`Frame::to_sdr`] for converting those.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Frame {
+    pub width: u32,
+    pub height: u32,
+    pub format: PixelFormat,
+    /// Bytes between the start of two consecutive rows.
+    pub stride: usize,
+    /// Desktop coordinates of the top-left pixel, so desktop space regions
+    /// (window rectangles for example) can be mapped onto the frame.
+    pub origin: (i32, i32),
+    pub info: FrameInfo,
+    pub data: Vec<u8>,
+}
+
+impl Frame {
+    /// A black, fully opaque frame.
+    pub fn new(width: u32, height: u32) -> Self {
+        let stride = width as usize * BYTES_PER_PIXEL;
+        let mut data = vec![0u8; stride * height as usize];
+        data.chunks_exact_mut(BYTES_PER_PIXEL)
+            .for_each(|px| px[3] = 0xFF);
+
+        Self {
+            width,
+            height,
+            format: PixelFormat::Bgra8,
+            stride,
+            origin: (0, 0),
+            info: FrameInfo::default(),
+            data,
+        }
+    }
+
+    /// Wrap tightly packed BGRA8 pixels, `None` if `data` is too short.
+    pub fn from_bgra(width: u32, height: u32, data: Vec<u8>) -> Option<Self> {
+        Self::from_raw(width, height, PixelFormat::Bgra8, data)
+    }
+
+    /// Wrap tightly packed pixels of any format, `None` if `data` is too
+    /// short.
+    pub fn from_raw(width: u32, height: u32, format: PixelFormat, data: Vec<u8>) -> Option<Self> {
+        let stride = width as usize * format.bytes_per_pixel();
+        if data.len() < stride * height as usize {
+            return None;
+        }
+
+        Some(Self {
+            width,
+            height,
+            format,
+            stride,
+            origin: (0, 0),
+            info: FrameInfo::default(),
+            data,
+        })
+    }
+
+    pub fn with_origin(mut self, x: i32, y: i32) -> Self {
+        self.origin = (x, y);
+        self
+    }
+
+    pub fn with_info(mut self, info: FrameInfo) -> Self {
+        self.info = info;
+        self
+    }
+
+    /// The whole frame as a rectangle in frame coordinates.
+    pub fn bounds(&self) -> Rect {
+        Rect::new(0, 0, self.width, self.height)
+    }
+
+    /// The visible pixels of one row, without stride padding.
+    pub fn row(&self, y: u32) -> &[u8] {
+        let start = y as usize * self.stride;
+        &self.data[start..start + self.width as usize * self.format.bytes_per_pixel()]
+    }
+
+    pub fn row_mut(&mut self, y: u32) -> &mut [u8] {
+        let start = y as usize * self.stride;
+        let len = self.width as usize * self.format.bytes_per_pixel();
+        &mut self.data[start..start + len]
+    }
+
+    /// The BGRA8 pixel at `(x, y)`, for [`PixelFormat::Bgra8`] frames only.
+    pub fn pixel(&self, x: u32, y: u32) -> [u8; 4] {
+        let offset = y as usize * self.stride + x as usize * BYTES_PER_PIXEL;
+        let mut px = [0u8; 4];
+        px.copy_from_slice(&self.data[offset..offset + BYTES_PER_PIXEL]);
+        px
+    }
+
+    pub fn set_pixel(&mut self, x: u32, y: u32, bgra: [u8; 4]) {
+        let offset = y as usize * self.stride + x as usize * BYTES_PER_PIXEL;
+        self.data[offset..offset + BYTES_PER_PIXEL].copy_from_slice(&bgra);
+    }
+
+    /// The part of the frame covered by `rect`, given in desktop coordinates
+    /// like a window rectangle. `None` if they don't overlap.
+    pub fn crop(&self, rect: &Rect) -> Option<Frame> {
+        let area = rect
+            .offset(-self.origin.0, -self.origin.1)
+            .intersect(&self.bounds())?;
+
+        let bytes_per_pixel = self.format.bytes_per_pixel();
+        let line_bytes = area.width as usize * bytes_per_pixel;
+        let mut data = Vec::with_capacity(line_bytes * area.height as usize);
+        for y in area.y..area.bottom() {
+            let start = area.x as usize * bytes_per_pixel;
+            data.extend_from_slice(&self.row(y as u32)[start..start + line_bytes]);
+        }
+
+        Some(Frame {
+            width: area.width,
+            height: area.height,
+            format: self.format,
+            stride: line_bytes,
+            origin: (area.x + self.origin.0, area.y + self.origin.1),
+            info: self.info,
+            data,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rect_intersect() {
+        let a = Rect::new(0, 0, 10, 10);
+        assert_eq!(
+            a.intersect(&Rect::new(5, -5, 10, 10)),
+            Some(Rect::new(5, 0, 5, 5))
+        );
+        assert_eq!(a.intersect(&Rect::new(10, 0, 5, 5)), None);
+
+        let huge = Rect::new(-1, i32::MAX - 1, u32::MAX, u32::MAX);
+        assert_eq!((huge.right(), huge.bottom()), (i32::MAX, i32::MAX));
+        assert_eq!(huge.intersect(&a), None);
+        assert_eq!(
+            Rect::new(i32::MIN, 0, u32::MAX, 1).intersect(&a),
+            Some(Rect::new(0, 0, 10, 1))
+        );
+    }
+
+    #[test]
+    fn test_frame_pixels() {
+        let mut frame = Frame::new(3, 2);
+        assert_eq!(frame.pixel(2, 1), [0, 0, 0, 0xFF]);
+
+        frame.set_pixel(2, 1, [1, 2, 3, 4]);
+        assert_eq!(frame.pixel(2, 1), [1, 2, 3, 4]);
+        assert_eq!(&frame.row(1)[8..], &[1, 2, 3, 4]);
+        assert!(Frame::from_bgra(3, 2, vec![0; 23]).is_none());
+    }
+
+    #[test]
+    fn test_crop() {
+        let mut frame = Frame::new(4, 4).with_origin(100, 50);
+        frame.set_pixel(3, 2, [1, 2, 3, 4]);
+
+        let cropped = frame.crop(&Rect::new(102, 51, 10, 2)).unwrap();
+        assert_eq!((cropped.width, cropped.height), (2, 2));
+        assert_eq!(cropped.origin, (102, 51));
+        assert_eq!(cropped.pixel(1, 1), [1, 2, 3, 4]);
+        assert!(frame.crop(&Rect::new(0, 0, 100, 100)).is_none());
+    }
+
+    #[test]
+    fn test_integrity_policy() {
+        let fresh = FrameInfo {
+            accumulated_frames: 1,
+            ..Default::default()
+        };
+        let masked = FrameInfo {
+            protected_content_masked_out: true,
+            ..fresh
+        };
+        let stale = FrameInfo::default();
+
+        assert!(fresh.is_complete());
+        assert!(!masked.is_complete());
+        assert!(!stale.is_complete());
+
+        assert_eq!(IntegrityPolicy::Keep.admit(&masked), Some(false));
+        assert_eq!(IntegrityPolicy::Flag.admit(&fresh), Some(false));
+        assert_eq!(IntegrityPolicy::Flag.admit(&masked), Some(true));
+        assert_eq!(IntegrityPolicy::Skip.admit(&stale), None);
+        assert_eq!(IntegrityPolicy::Skip.admit(&fresh), Some(false));
+    }
+}
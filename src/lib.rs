@@ -0,0 +1,23 @@
+pub mod async_conn;
+pub mod bmp;
+pub mod capture;
+pub mod conn;
+pub mod device;
+pub mod draw;
+#[cfg(windows)]
+pub mod dxgi;
+pub mod error;
+pub mod frame;
+pub mod output;
+pub mod pool;
+pub mod record;
+pub mod redact;
+pub mod replay;
+pub mod serve;
+pub mod stream;
+pub mod synthetic;
+pub mod tls;
+pub mod tonemap;
+pub mod window;
+#[cfg(target_os = "linux")]
+pub mod x11;
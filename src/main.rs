@@ -1,3 +1,197 @@
-fn main() {
-    println!("Hello, world!");
+use std::env;
+use std::process::ExitCode;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use action_demo::bmp::save_bmp;
+use action_demo::capture::Pace;
+use action_demo::error::Error;
+use action_demo::frame::Frame;
+use action_demo::serve::Server;
+use action_demo::{output, redact};
+
+/// `;` separated redaction rules applied to everything the binary writes,
+/// e.g. `pixelate=16:0,0,400,40;fill:window=1234`.
+const REDACT_ENV: &str = "ACTION_DEMO_REDACT";
+
+/// Where `--serve` listens unless an address follows it.
+const SERVE_ADDRESS: &str = "127.0.0.1:8080";
+
+fn main() -> ExitCode {
+    let output = env::args()
+        .nth(1)
+        .unwrap_or_else(|| "screen.bmp".to_string());
+
+    if output == "--list-outputs" {
+        return match output::adapters() {
+            Ok(adapters) => {
+                println!("{}", serde_json::to_string_pretty(&adapters).unwrap());
+                ExitCode::SUCCESS
+            }
+            Err(e) => {
+                eprintln!("listing outputs failed: {}", report(&e));
+                ExitCode::FAILURE
+            }
+        };
+    }
+
+    if let Ok(rules) = env::var(REDACT_ENV) {
+        match rules.parse() {
+            Ok(redactor) => redact::install(redactor),
+            Err(e) => {
+                eprintln!("{}: {}", REDACT_ENV, e);
+                return ExitCode::FAILURE;
+            }
+        }
+    }
+
+    if output == "--serve" {
+        let address = env::args()
+            .nth(2)
+            .unwrap_or_else(|| SERVE_ADDRESS.to_string());
+        return match serve(&address) {
+            Ok(()) => ExitCode::SUCCESS,
+            Err(e) => {
+                eprintln!("serving on {} failed: {}", address, report(&e));
+                ExitCode::FAILURE
+            }
+        };
+    }
+
+    let mut frame = match capture() {
+        Ok(frame) => frame,
+        Err(e) => {
+            eprintln!("capture failed: {}", report(&e));
+            return ExitCode::FAILURE;
+        }
+    };
+
+    frame.watermark(&format!("{}  {}", hostname(), timestamp()), 2);
+
+    if let Err(e) = save_bmp(&output, &frame) {
+        eprintln!("writing {} failed: {}", output, e);
+        return ExitCode::FAILURE;
+    }
+
+    ExitCode::SUCCESS
+}
+
+#[cfg(windows)]
+fn capture() -> Result<Frame, Error> {
+    use action_demo::capture::{Backoff, Session};
+    use action_demo::dxgi::DxgiBackend;
+
+    // ride out a UAC prompt or mode switch that happens to be in progress
+    Session::new(DxgiBackend::new(0, 0, 1000))
+        .backoff(Backoff {
+            max_attempts: Some(8),
+            ..Backoff::default()
+        })
+        .next_frame()
+}
+
+#[cfg(target_os = "linux")]
+fn capture() -> Result<Frame, Error> {
+    use action_demo::capture::{Backoff, Session};
+    use action_demo::x11::X11Backend;
+
+    Session::new(X11Backend::new(None))
+        .backoff(Backoff {
+            max_attempts: Some(8),
+            ..Backoff::default()
+        })
+        .next_frame()
+}
+
+#[cfg(not(any(windows, target_os = "linux")))]
+fn capture() -> Result<Frame, Error> {
+    Err(Error::backend("no capture backend for this platform"))
+}
+
+/// Serve the stream and screenshots until the capture fails.
+fn serve(address: &str) -> Result<(), Error> {
+    let server = open_server(address)?;
+    eprintln!(
+        "serving http://{0}/stream.mjpeg, http://{0}/screenshot.png and http://{0}/screenshot.bmp",
+        server.local_addr()
+    );
+    server.wait()
+}
+
+#[cfg(windows)]
+fn open_server(address: &str) -> Result<Server, Error> {
+    use action_demo::capture::Session;
+    use action_demo::dxgi::DxgiBackend;
+
+    // the default backoff retries forever, riding out any desktop switch
+    Server::bind(
+        address,
+        || Session::new(DxgiBackend::new(0, 0, 1000)),
+        Pace::Fps(30),
+    )
+}
+
+#[cfg(target_os = "linux")]
+fn open_server(address: &str) -> Result<Server, Error> {
+    use action_demo::capture::Session;
+    use action_demo::x11::X11Backend;
+
+    Server::bind(
+        address,
+        || Session::new(X11Backend::new(None)),
+        Pace::Fps(30),
+    )
+}
+
+#[cfg(not(any(windows, target_os = "linux")))]
+fn open_server(_address: &str) -> Result<Server, Error> {
+    Err(Error::backend("no capture backend for this platform"))
+}
+
+/// `error: cause: cause ...` down the whole source chain.
+fn report(e: &dyn std::error::Error) -> String {
+    let mut message = e.to_string();
+    let mut source = e.source();
+    while let Some(cause) = source {
+        message.push_str(": ");
+        message.push_str(&cause.to_string());
+        source = cause.source();
+    }
+    message
+}
+
+fn hostname() -> String {
+    env::var("COMPUTERNAME")
+        .or_else(|_| env::var("HOSTNAME"))
+        .or_else(|_| std::fs::read_to_string("/etc/hostname").map(|name| name.trim().to_string()))
+        .unwrap_or_else(|_| "unknown".to_string())
+}
+
+/// The current UTC time as `YYYY-MM-DD HH:MM:SS UTC`.
+fn timestamp() -> String {
+    let secs = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0);
+    let (days, rem) = (secs / 86_400, secs % 86_400);
+
+    // civil from days, see http://howardhinnant.github.io/date_algorithms.html
+    let z = days as i64 + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1_460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + (month <= 2) as i64;
+
+    format!(
+        "{:04}-{:02}-{:02} {:02}:{:02}:{:02} UTC",
+        year,
+        month,
+        day,
+        rem / 3_600,
+        rem % 3_600 / 60,
+        rem % 60
+    )
 }
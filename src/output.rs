@@ -0,0 +1,126 @@
+//! Enumeration of graphics adapters and the outputs (monitors) attached to
+//! them, so callers can pick what to capture by name instead of guessing
+//! indices.
+
+use serde::{Deserialize, Serialize};
+
+use crate::error::Result;
+use crate::frame::Rect;
+
+/// Display rotation of an output, clockwise.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Rotation {
+    #[default]
+    Identity,
+    Rotate90,
+    Rotate180,
+    Rotate270,
+}
+
+/// A monitor, or an X RandR output.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Output {
+    /// Position in the adapter's enumeration order, the index the
+    /// platform functions take.
+    pub index: u32,
+    /// Device name, e.g. `\\.\DISPLAY1` or `HDMI-1`.
+    pub name: String,
+    /// Human readable name of the attached monitor, if known.
+    pub description: String,
+    /// Resolution of the current mode as the monitor sees it, so before
+    /// rotation.
+    pub width: u32,
+    pub height: u32,
+    /// In Hz, `None` if the platform doesn't tell.
+    pub refresh_rate: Option<f64>,
+    pub rotation: Rotation,
+    /// The area of the virtual desktop the output shows.
+    pub desktop: Rect,
+    pub attached_to_desktop: bool,
+    pub primary: bool,
+}
+
+/// A GPU, or the X screen.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Adapter {
+    pub index: u32,
+    pub name: String,
+    pub description: String,
+    /// Rendering in software, e.g. WARP.
+    pub software: bool,
+    pub outputs: Vec<Output>,
+}
+
+impl Adapter {
+    /// The primary output of all adapters, as `(adapter index, output)`.
+    pub fn primary(adapters: &[Adapter]) -> Option<(u32, &Output)> {
+        adapters.iter().find_map(|adapter| {
+            adapter
+                .outputs
+                .iter()
+                .find(|output| output.primary)
+                .map(|output| (adapter.index, output))
+        })
+    }
+}
+
+/// Every adapter of the platform's capture backend with its outputs.
+#[cfg(windows)]
+pub fn adapters() -> Result<Vec<Adapter>> {
+    crate::dxgi::adapters()
+}
+
+#[cfg(target_os = "linux")]
+pub fn adapters() -> Result<Vec<Adapter>> {
+    crate::x11::Display::shared()?.adapters()
+}
+
+#[cfg(not(any(windows, target_os = "linux")))]
+pub fn adapters() -> Result<Vec<Adapter>> {
+    Err(crate::error::Error::backend(
+        "no capture backend for this platform",
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn output(index: u32, primary: bool) -> Output {
+        Output {
+            index,
+            name: format!("DP-{}", index),
+            description: String::new(),
+            width: 1920,
+            height: 1080,
+            refresh_rate: Some(60.0),
+            rotation: Rotation::Rotate90,
+            desktop: Rect::new(1920 * index as i32, 0, 1080, 1920),
+            attached_to_desktop: true,
+            primary,
+        }
+    }
+
+    #[test]
+    fn test_json_round_trip() {
+        let adapters = vec![Adapter {
+            index: 0,
+            name: "GPU".to_string(),
+            description: "vendor 10de".to_string(),
+            software: false,
+            outputs: vec![output(0, false), output(1, true)],
+        }];
+
+        let json = serde_json::to_string(&adapters).unwrap();
+        assert!(json.contains(r#""rotation":"rotate90""#));
+        assert!(json.contains(r#""desktop":{"x":1920,"y":0,"width":1080,"height":1920}"#));
+        assert_eq!(
+            serde_json::from_str::<Vec<Adapter>>(&json).unwrap(),
+            adapters
+        );
+
+        let (adapter, primary) = Adapter::primary(&adapters).unwrap();
+        assert_eq!((adapter, primary.name.as_str()), (0, "DP-1"));
+    }
+}
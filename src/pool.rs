@@ -0,0 +1,137 @@
+//! Recycling of frame buffers, so continuous capture doesn't allocate a new
+//! multi-megabyte `Vec` for every frame.
+
+use std::ops::{Deref, DerefMut};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+
+use crate::frame::Frame;
+
+struct Shared {
+    free: Mutex<Vec<Vec<u8>>>,
+    capacity: usize,
+    allocations: AtomicUsize,
+}
+
+/// A cheaply cloneable pool of pixel buffers.
+#[derive(Clone)]
+pub struct FramePool {
+    shared: Arc<Shared>,
+}
+
+impl FramePool {
+    /// Keeps up to `capacity` idle buffers, more are freed when recycled.
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            shared: Arc::new(Shared {
+                free: Mutex::new(Vec::with_capacity(capacity)),
+                capacity,
+                allocations: AtomicUsize::new(0),
+            }),
+        }
+    }
+
+    /// An idle buffer, or a new empty one if there is none. Its length and
+    /// content are unspecified, backends resize it as needed.
+    pub fn take(&self) -> Vec<u8> {
+        if let Some(buffer) = self.shared.free.lock().unwrap().pop() {
+            return buffer;
+        }
+
+        self.shared.allocations.fetch_add(1, Ordering::Relaxed);
+        Vec::new()
+    }
+
+    pub fn recycle(&self, buffer: Vec<u8>) {
+        let mut free = self.shared.free.lock().unwrap();
+        if free.len() < self.shared.capacity && buffer.capacity() > 0 {
+            free.push(buffer);
+        }
+    }
+
+    /// Hand `frame` out so its buffer comes back once it is dropped.
+    pub fn wrap(&self, frame: Frame) -> PooledFrame {
+        PooledFrame {
+            frame: Some(frame),
+            pool: self.clone(),
+        }
+    }
+
+    /// Number of idle buffers.
+    pub fn idle(&self) -> usize {
+        self.shared.free.lock().unwrap().len()
+    }
+
+    /// Number of buffers [`take`](Self::take) had to create so far.
+    pub fn allocations(&self) -> usize {
+        self.shared.allocations.load(Ordering::Relaxed)
+    }
+}
+
+/// A [`Frame`] whose buffer returns to its [`FramePool`] when dropped.
+pub struct PooledFrame {
+    frame: Option<Frame>,
+    pool: FramePool,
+}
+
+impl PooledFrame {
+    /// Keep the frame for good, its buffer won't be recycled.
+    pub fn into_frame(mut self) -> Frame {
+        self.frame.take().unwrap()
+    }
+}
+
+impl Deref for PooledFrame {
+    type Target = Frame;
+
+    fn deref(&self) -> &Frame {
+        self.frame.as_ref().unwrap()
+    }
+}
+
+impl DerefMut for PooledFrame {
+    fn deref_mut(&mut self) -> &mut Frame {
+        self.frame.as_mut().unwrap()
+    }
+}
+
+impl Drop for PooledFrame {
+    fn drop(&mut self) {
+        if let Some(frame) = self.frame.take() {
+            self.pool.recycle(frame.data);
+        }
+    }
+}
+
+impl std::fmt::Debug for PooledFrame {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        self.deref().fmt(f)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_buffers_are_reused() {
+        let pool = FramePool::new(1);
+        let mut buffer = pool.take();
+        buffer.resize(16, 0);
+        let frame = Frame::from_bgra(2, 2, buffer).unwrap();
+
+        drop(pool.wrap(frame.clone()));
+        assert_eq!(pool.idle(), 1);
+        assert_eq!(pool.take().len(), 16);
+        assert_eq!(pool.allocations(), 1);
+
+        // over capacity, the second buffer is freed
+        pool.recycle(vec![0; 4]);
+        pool.recycle(vec![0; 4]);
+        assert_eq!(pool.idle(), 1);
+
+        let kept = pool.wrap(frame).into_frame();
+        assert_eq!(kept.width, 2);
+        assert_eq!(pool.idle(), 1);
+    }
+}
@@ -0,0 +1,750 @@
+//! Long running screen recording to disk, and seeking in recordings.
+//!
+//! A recording is a directory of numbered segments, each a pair of files:
+//!
+//! - `NNNNNN.seg` holds the frames. It starts with [`SEGMENT_MAGIC`] and
+//!   every frame is a record of a 29 byte header (flags `u8`, timestamp in
+//!   microseconds `u64`, width `u32`, height `u32`, origin `i32` `i32`,
+//!   payload length `u32`) and an LZ4 compressed payload. A keyframe's
+//!   payload is the packed BGRA8 image, a delta's is a rectangle count
+//!   `u32`, the rectangles as `x y width height` `u32`s and then their
+//!   pixels XORed with the previous frame's, row by row.
+//! - `NNNNNN.idx` starts with [`INDEX_MAGIC`] followed by 17 byte entries
+//!   (timestamp `u64`, record offset `u64`, flags `u8`), one per record.
+//!
+//! All integers are little endian. Segments start with a keyframe so each
+//! can be decoded, and deleted, on its own.
+
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Read, Seek, SeekFrom, Write};
+use std::ops::Deref;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::capture::Event;
+use crate::error::{Error, Result};
+use crate::frame::{Frame, IntegrityPolicy, Rect, BYTES_PER_PIXEL};
+use crate::redact;
+
+pub const SEGMENT_MAGIC: &[u8; 8] = b"ADSEG\0\0\x01";
+pub const INDEX_MAGIC: &[u8; 8] = b"ADIDX\0\0\x01";
+
+const KEYFRAME: u8 = 1;
+const FLAGGED: u8 = 2;
+const RECORD_HEADER_LEN: usize = 29;
+const ENTRY_LEN: usize = 17;
+/// Side of the squares frames are compared in for dirty rectangles.
+const TILE: u32 = 32;
+
+/// Writes frames into a rotating set of segments.
+///
+/// Nothing is created on disk before the first frame.
+pub struct Recorder {
+    dir: PathBuf,
+    segment_duration: Duration,
+    max_segments: Option<usize>,
+    integrity: IntegrityPolicy,
+    encoder: Encoder,
+    segment: Option<SegmentWriter>,
+    next_segment: Option<u32>,
+    last_timestamp: Duration,
+}
+
+impl Recorder {
+    /// Record into `dir`, after any segments already in it.
+    pub fn new<P: Into<PathBuf>>(dir: P) -> Self {
+        Self {
+            dir: dir.into(),
+            segment_duration: Duration::from_secs(60),
+            max_segments: None,
+            integrity: IntegrityPolicy::default(),
+            encoder: Encoder::new(Duration::from_secs(5)),
+            segment: None,
+            next_segment: None,
+            last_timestamp: Duration::ZERO,
+        }
+    }
+
+    /// Start a new segment after this long, one minute by default.
+    pub fn segment_duration(mut self, duration: Duration) -> Self {
+        self.segment_duration = duration;
+        self
+    }
+
+    /// Write a keyframe at least this often, five seconds by default. It
+    /// bounds how many deltas a seek has to decode.
+    pub fn keyframe_interval(mut self, interval: Duration) -> Self {
+        self.encoder.keyframe_interval = interval;
+        self
+    }
+
+    /// Delete the oldest segments beyond `count`.
+    pub fn max_segments(mut self, count: usize) -> Self {
+        self.max_segments = Some(count.max(1));
+        self
+    }
+
+    pub fn integrity(mut self, policy: IntegrityPolicy) -> Self {
+        self.integrity = policy;
+        self
+    }
+
+    /// Record `frame` as captured at `at`, e.g. the time since the UNIX
+    /// epoch. Timestamps going backwards are clamped to the last one.
+    pub fn push(&mut self, frame: &Frame, at: Duration) -> Result<()> {
+        let Some(flagged) = self.integrity.admit(&frame.info) else {
+            return Ok(());
+        };
+        // a wall clock stepping back mustn't break seeking
+        let at = at.max(self.last_timestamp);
+        self.last_timestamp = at;
+
+        let rotate = self
+            .segment
+            .as_ref()
+            .is_none_or(|segment| at - segment.started >= self.segment_duration);
+        if rotate {
+            self.rotate(at)?;
+        }
+
+        let record = self.encoder.encode(&redact::prepare(frame), at, flagged);
+        self.segment
+            .as_mut()
+            .expect("segment opened above")
+            .append(&record)
+    }
+
+    /// [`push`](Self::push) with the current wall clock time.
+    pub fn push_now(&mut self, frame: &Frame) -> Result<()> {
+        self.push(frame, since_epoch())
+    }
+
+    /// Record the frames of a [`Session`](crate::capture::Session) or of
+    /// [`spawn`](crate::capture::spawn) until they end or fail.
+    pub fn record<I, F>(&mut self, events: I) -> Result<()>
+    where
+        I: IntoIterator<Item = Result<Event<F>>>,
+        F: Deref<Target = Frame>,
+    {
+        for event in events {
+            if let Event::Frame(frame) = event? {
+                self.push_now(&frame)?;
+            }
+        }
+        self.flush()
+    }
+
+    pub fn flush(&mut self) -> Result<()> {
+        match self.segment.as_mut() {
+            Some(segment) => segment.flush(),
+            None => Ok(()),
+        }
+    }
+
+    fn rotate(&mut self, at: Duration) -> Result<()> {
+        self.flush()?;
+        self.segment = None;
+        self.encoder.reset();
+
+        let number = match self.next_segment {
+            Some(number) => number,
+            None => next_segment(&self.dir)?,
+        };
+        self.next_segment = Some(number + 1);
+        self.segment = Some(SegmentWriter::create(&self.dir, number, at)?);
+
+        if let Some(max) = self.max_segments {
+            let numbers = segments(&self.dir)?;
+            for old in &numbers[..numbers.len().saturating_sub(max)] {
+                fs::remove_file(segment_path(&self.dir, *old, "seg"))?;
+                fs::remove_file(segment_path(&self.dir, *old, "idx"))?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Drop for Recorder {
+    fn drop(&mut self) {
+        let _ = self.flush();
+    }
+}
+
+/// One encoded frame, a record as stored in a segment.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct Encoded {
+    pub timestamp: Duration,
+    pub flags: u8,
+    pub bytes: Vec<u8>,
+}
+
+impl Encoded {
+    pub fn is_keyframe(&self) -> bool {
+        self.flags & KEYFRAME != 0
+    }
+}
+
+/// Turns frames into keyframes, or deltas against the previous frame.
+pub(crate) struct Encoder {
+    keyframe_interval: Duration,
+    previous: Option<Frame>,
+    last_keyframe: Duration,
+}
+
+impl Encoder {
+    pub fn new(keyframe_interval: Duration) -> Self {
+        Self {
+            keyframe_interval,
+            previous: None,
+            last_keyframe: Duration::ZERO,
+        }
+    }
+
+    /// Make the next frame a keyframe.
+    pub fn reset(&mut self) {
+        self.previous = None;
+    }
+
+    /// Bytes held for the previous frame.
+    pub fn memory(&self) -> usize {
+        self.previous.as_ref().map_or(0, |frame| frame.data.len())
+    }
+
+    /// Encode a BGRA8 frame, one that went through [`redact::prepare`].
+    pub fn encode(&mut self, frame: &Frame, at: Duration, flagged: bool) -> Encoded {
+        let previous = self.previous.as_ref().filter(|previous| {
+            (previous.width, previous.height, previous.origin)
+                == (frame.width, frame.height, frame.origin)
+        });
+        let (flags, raw) = match previous {
+            Some(previous) if at.saturating_sub(self.last_keyframe) < self.keyframe_interval => {
+                (0, encode_delta(previous, frame))
+            }
+            _ => {
+                self.last_keyframe = at;
+                (KEYFRAME, encode_keyframe(frame))
+            }
+        };
+        let flags = flags | if flagged { FLAGGED } else { 0 };
+        let payload = lz4_flex::compress_prepend_size(&raw);
+
+        let mut bytes = Vec::with_capacity(RECORD_HEADER_LEN + payload.len());
+        bytes.push(flags);
+        bytes.extend_from_slice(&(at.as_micros() as u64).to_le_bytes());
+        bytes.extend_from_slice(&frame.width.to_le_bytes());
+        bytes.extend_from_slice(&frame.height.to_le_bytes());
+        bytes.extend_from_slice(&frame.origin.0.to_le_bytes());
+        bytes.extend_from_slice(&frame.origin.1.to_le_bytes());
+        bytes.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+        bytes.extend_from_slice(&payload);
+
+        self.previous = Some(packed(frame));
+        Encoded {
+            timestamp: at,
+            flags,
+            bytes,
+        }
+    }
+}
+
+/// The files of one segment being written.
+pub(crate) struct SegmentWriter {
+    started: Duration,
+    data: File,
+    index: BufWriter<File>,
+    offset: u64,
+}
+
+impl SegmentWriter {
+    /// Create segment `number` in `dir`, and `dir` if needed.
+    pub fn create(dir: &Path, number: u32, started: Duration) -> Result<Self> {
+        fs::create_dir_all(dir)?;
+        let mut data = File::create(segment_path(dir, number, "seg"))?;
+        data.write_all(SEGMENT_MAGIC)?;
+        let mut index = BufWriter::new(File::create(segment_path(dir, number, "idx"))?);
+        index.write_all(INDEX_MAGIC)?;
+
+        Ok(Self {
+            started,
+            data,
+            index,
+            offset: SEGMENT_MAGIC.len() as u64,
+        })
+    }
+
+    /// Segments have to start with a keyframe.
+    pub fn append(&mut self, record: &Encoded) -> Result<()> {
+        // the record is written in one go before its index entry, so an
+        // interrupted recording never indexes a missing record
+        self.data.write_all(&record.bytes)?;
+        self.index
+            .write_all(&(record.timestamp.as_micros() as u64).to_le_bytes())?;
+        self.index.write_all(&self.offset.to_le_bytes())?;
+        self.index.write_all(&[record.flags])?;
+        self.offset += record.bytes.len() as u64;
+        Ok(())
+    }
+
+    pub fn flush(&mut self) -> Result<()> {
+        Ok(self.index.flush()?)
+    }
+}
+
+/// One recorded frame.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Entry {
+    pub timestamp: Duration,
+    pub segment: u32,
+    offset: u64,
+    pub keyframe: bool,
+    /// Recorded under [`IntegrityPolicy::Flag`] although incomplete.
+    pub flagged: bool,
+}
+
+/// Reconstructs frames of a recording, at any time. Frames come back with a
+/// default [`FrameInfo`](crate::frame::FrameInfo), only whether they were
+/// complete is recorded, in [`Entry::flagged`].
+pub struct Reader {
+    dir: PathBuf,
+    entries: Vec<Entry>,
+    /// The entry [`next_frame`](Self::next_frame) decodes.
+    position: usize,
+    current: Option<Frame>,
+    file: Option<(u32, File)>,
+}
+
+impl Reader {
+    /// Load the index of the recording in `dir`. Entries of a recording
+    /// still being written appear once the recorder flushed them.
+    pub fn open<P: Into<PathBuf>>(dir: P) -> Result<Self> {
+        let dir = dir.into();
+        let mut entries = Vec::new();
+        for number in segments(&dir)? {
+            let index = fs::read(segment_path(&dir, number, "idx"))?;
+            if index.get(..INDEX_MAGIC.len()) != Some(INDEX_MAGIC) {
+                return Err(Error::UnsupportedFormat(format!(
+                    "segment {} has no index header",
+                    number
+                )));
+            }
+            // a partial entry at the end is a write cut short
+            entries.extend(
+                index[INDEX_MAGIC.len()..]
+                    .chunks_exact(ENTRY_LEN)
+                    .map(|entry| Entry {
+                        timestamp: Duration::from_micros(u64_at(entry, 0)),
+                        segment: number,
+                        offset: u64_at(entry, 8),
+                        keyframe: entry[16] & KEYFRAME != 0,
+                        flagged: entry[16] & FLAGGED != 0,
+                    }),
+            );
+        }
+
+        Ok(Self {
+            dir,
+            entries,
+            position: 0,
+            current: None,
+            file: None,
+        })
+    }
+
+    pub fn entries(&self) -> &[Entry] {
+        &self.entries
+    }
+
+    /// Timestamps of the first and the last frame.
+    pub fn range(&self) -> Option<(Duration, Duration)> {
+        Some((
+            self.entries.first()?.timestamp,
+            self.entries.last()?.timestamp,
+        ))
+    }
+
+    /// The frame shown at `at`: the last one recorded at or before it, or
+    /// the first one for times before the recording. Decodes from the
+    /// closest keyframe unless `at` is just ahead of the last frame read.
+    pub fn seek(&mut self, at: Duration) -> Result<&Frame> {
+        let target = self
+            .entries
+            .partition_point(|entry| entry.timestamp <= at)
+            .saturating_sub(1);
+        let Some(entry) = self.entries.get(target) else {
+            return Err(Error::backend("empty recording"));
+        };
+        let keyframe = self.entries[..=target]
+            .iter()
+            .rposition(|e| e.keyframe && e.segment == entry.segment)
+            .ok_or_else(|| corrupt("segment doesn't start with a keyframe"))?;
+
+        let resume = self.current.is_some() && self.position > keyframe && self.position <= target;
+        if !resume {
+            self.position = keyframe;
+            self.current = None;
+        }
+        while self.position <= target {
+            self.decode_next()?;
+        }
+        Ok(self.current.as_ref().expect("decoded above"))
+    }
+
+    /// The frame after the last one read, `None` at the end.
+    pub fn next_frame(&mut self) -> Result<Option<(Entry, &Frame)>> {
+        let Some(&entry) = self.entries.get(self.position) else {
+            return Ok(None);
+        };
+        self.decode_next()?;
+        Ok(self.current.as_ref().map(|frame| (entry, frame)))
+    }
+
+    fn decode_next(&mut self) -> Result<()> {
+        let entry = self.entries[self.position];
+        let file = match &mut self.file {
+            Some((number, file)) if *number == entry.segment => file,
+            file => {
+                let opened = File::open(segment_path(&self.dir, entry.segment, "seg"))?;
+                &mut file.insert((entry.segment, opened)).1
+            }
+        };
+
+        file.seek(SeekFrom::Start(entry.offset))?;
+        let mut header = [0u8; RECORD_HEADER_LEN];
+        file.read_exact(&mut header)?;
+        let (width, height) = (u32_at(&header, 9), u32_at(&header, 13));
+        let origin = (u32_at(&header, 17) as i32, u32_at(&header, 21) as i32);
+        let mut payload = vec![0u8; u32_at(&header, 25) as usize];
+        file.read_exact(&mut payload)?;
+        let raw =
+            lz4_flex::decompress_size_prepended(&payload).map_err(|e| corrupt(&e.to_string()))?;
+
+        let frame = if header[0] & KEYFRAME != 0 {
+            Frame::from_bgra(width, height, raw)
+                .ok_or_else(|| corrupt("keyframe too short"))?
+                .with_origin(origin.0, origin.1)
+        } else {
+            let mut frame = self
+                .current
+                .take()
+                .filter(|frame| (frame.width, frame.height) == (width, height))
+                .ok_or_else(|| corrupt("delta without a matching keyframe"))?;
+            apply_delta(&mut frame, &raw)?;
+            frame
+        };
+
+        self.current = Some(frame);
+        self.position += 1;
+        Ok(())
+    }
+}
+
+pub(crate) fn since_epoch() -> Duration {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+}
+
+/// The number after the last segment in `dir`, 0 for a new recording.
+pub(crate) fn next_segment(dir: &Path) -> Result<u32> {
+    match segments(dir) {
+        Ok(numbers) => Ok(numbers.last().map_or(0, |last| last + 1)),
+        Err(Error::Io(e)) if e.kind() == io::ErrorKind::NotFound => Ok(0),
+        Err(e) => Err(e),
+    }
+}
+
+fn segment_path(dir: &Path, number: u32, extension: &str) -> PathBuf {
+    dir.join(format!("{:06}.{}", number, extension))
+}
+
+/// Numbers of the segments in `dir`, ascending.
+fn segments(dir: &Path) -> Result<Vec<u32>> {
+    let mut numbers = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.extension().is_some_and(|extension| extension == "seg") {
+            if let Some(number) = path
+                .file_stem()
+                .and_then(|stem| stem.to_str())
+                .and_then(|stem| stem.parse().ok())
+            {
+                numbers.push(number);
+            }
+        }
+    }
+    numbers.sort_unstable();
+    Ok(numbers)
+}
+
+fn corrupt(message: &str) -> Error {
+    Error::Io(io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("corrupt recording: {}", message),
+    ))
+}
+
+fn u32_at(bytes: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(bytes[offset..offset + 4].try_into().unwrap())
+}
+
+fn u64_at(bytes: &[u8], offset: usize) -> u64 {
+    u64::from_le_bytes(bytes[offset..offset + 8].try_into().unwrap())
+}
+
+/// A copy of `frame` without row padding.
+fn packed(frame: &Frame) -> Frame {
+    Frame::from_bgra(frame.width, frame.height, encode_keyframe(frame))
+        .expect("packed rows cover the frame")
+        .with_origin(frame.origin.0, frame.origin.1)
+}
+
+fn encode_keyframe(frame: &Frame) -> Vec<u8> {
+    let mut raw =
+        Vec::with_capacity(frame.width as usize * frame.height as usize * BYTES_PER_PIXEL);
+    for y in 0..frame.height {
+        raw.extend_from_slice(frame.row(y));
+    }
+    raw
+}
+
+/// Rectangles of `frame` that differ from `previous`, runs of changed tiles
+/// within a row of tiles merged into one.
+fn dirty_rects(previous: &Frame, frame: &Frame) -> Vec<Rect> {
+    let mut rects = Vec::new();
+    for top in (0..frame.height).step_by(TILE as usize) {
+        let height = TILE.min(frame.height - top);
+        let mut run: Option<Rect> = None;
+        for left in (0..frame.width).step_by(TILE as usize) {
+            let width = TILE.min(frame.width - left);
+            let bytes = left as usize * BYTES_PER_PIXEL..(left + width) as usize * BYTES_PER_PIXEL;
+            let changed = (top..top + height)
+                .any(|y| previous.row(y)[bytes.clone()] != frame.row(y)[bytes.clone()]);
+
+            run = match (run, changed) {
+                (Some(rect), true) => Some(Rect::new(rect.x, rect.y, rect.width + width, height)),
+                (None, true) => Some(Rect::new(left as i32, top as i32, width, height)),
+                (Some(rect), false) => {
+                    rects.push(rect);
+                    None
+                }
+                (None, false) => None,
+            };
+        }
+        rects.extend(run);
+    }
+    rects
+}
+
+fn encode_delta(previous: &Frame, frame: &Frame) -> Vec<u8> {
+    let rects = dirty_rects(previous, frame);
+    let mut raw = Vec::new();
+    raw.extend_from_slice(&(rects.len() as u32).to_le_bytes());
+    for rect in &rects {
+        for value in [rect.x as u32, rect.y as u32, rect.width, rect.height] {
+            raw.extend_from_slice(&value.to_le_bytes());
+        }
+    }
+    // XORed with the previous frame, unchanged pixels in dirty tiles become
+    // zeros that compress to nothing
+    for rect in &rects {
+        let bytes = rect.x as usize * BYTES_PER_PIXEL..rect.right() as usize * BYTES_PER_PIXEL;
+        for y in rect.y..rect.bottom() {
+            let current = &frame.row(y as u32)[bytes.clone()];
+            let previous = &previous.row(y as u32)[bytes.clone()];
+            raw.extend(current.iter().zip(previous).map(|(a, b)| a ^ b));
+        }
+    }
+    raw
+}
+
+fn apply_delta(frame: &mut Frame, raw: &[u8]) -> Result<()> {
+    let count = *raw
+        .first_chunk::<4>()
+        .ok_or_else(|| corrupt("empty delta"))?;
+    let count = u32::from_le_bytes(count) as usize;
+    let mut pixels = raw
+        .get(4 + count * 16..)
+        .ok_or_else(|| corrupt("delta rectangles cut short"))?;
+
+    for i in 0..count {
+        let at = 4 + i * 16;
+        let rect = Rect::new(
+            u32_at(raw, at) as i32,
+            u32_at(raw, at + 4) as i32,
+            u32_at(raw, at + 8),
+            u32_at(raw, at + 12),
+        );
+        if rect.intersect(&frame.bounds()) != Some(rect) {
+            return Err(corrupt("delta rectangle outside the frame"));
+        }
+
+        let line_bytes = rect.width as usize * BYTES_PER_PIXEL;
+        let start = rect.x as usize * BYTES_PER_PIXEL;
+        for y in rect.y..rect.bottom() {
+            let (line, rest) = pixels
+                .split_at_checked(line_bytes)
+                .ok_or_else(|| corrupt("delta pixels cut short"))?;
+            frame.row_mut(y as u32)[start..start + line_bytes]
+                .iter_mut()
+                .zip(line)
+                .for_each(|(px, delta)| *px ^= delta);
+            pixels = rest;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::capture::Backend;
+    use crate::frame::FrameInfo;
+    use crate::synthetic::SyntheticBackend;
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!(
+            "action-demo-record-{}-{}",
+            name,
+            std::process::id()
+        ));
+        let _ = fs::remove_dir_all(&dir);
+        dir
+    }
+
+    fn frames(count: usize) -> Vec<Frame> {
+        let mut backend = SyntheticBackend::new(80, 40);
+        (0..count).map(|_| backend.next_frame().unwrap()).collect()
+    }
+
+    fn ms(ms: u64) -> Duration {
+        Duration::from_millis(ms)
+    }
+
+    /// The frame as it is recorded, without its info.
+    fn recorded(frame: &Frame) -> Frame {
+        frame.clone().with_info(FrameInfo::default())
+    }
+
+    #[test]
+    fn test_seek() {
+        let dir = temp_dir("seek");
+        let frames = frames(50);
+        let mut recorder = Recorder::new(&dir)
+            .segment_duration(ms(2000))
+            .keyframe_interval(ms(500));
+        for (i, frame) in frames.iter().enumerate() {
+            recorder.push(frame, ms(1000 + i as u64 * 100)).unwrap();
+        }
+        drop(recorder);
+
+        let mut reader = Reader::open(&dir).unwrap();
+        assert_eq!(reader.entries().len(), 50);
+        assert_eq!(reader.range(), Some((ms(1000), ms(5900))));
+        assert_eq!(segments(&dir).unwrap(), [0, 1, 2]);
+        assert!(reader.entries()[1..5].iter().all(|entry| !entry.keyframe));
+        assert!(reader.entries()[20].keyframe);
+
+        // backwards, forwards from a keyframe, just ahead, before and after
+        for (at, expected) in [
+            (3450, 24),
+            (1250, 2),
+            (1300, 3),
+            (1700, 7),
+            (5000, 40),
+            (0, 0),
+            (99_000, 49),
+        ] {
+            assert_eq!(
+                *reader.seek(ms(at)).unwrap(),
+                recorded(&frames[expected]),
+                "at {}",
+                at
+            );
+        }
+
+        assert!(reader.next_frame().unwrap().is_none());
+        reader.seek(ms(5850)).unwrap();
+        let (entry, frame) = reader.next_frame().unwrap().unwrap();
+        assert_eq!(entry.timestamp, ms(5900));
+        assert_eq!(*frame, recorded(&frames[49]));
+        assert!(reader.next_frame().unwrap().is_none());
+
+        reader.seek(ms(1000)).unwrap();
+        for expected in &frames[1..30] {
+            assert_eq!(*reader.next_frame().unwrap().unwrap().1, recorded(expected));
+        }
+
+        // deltas of a one pixel bar are far smaller than keyframes
+        let entries = reader.entries();
+        let keyframe = entries[1].offset - entries[0].offset;
+        let delta = entries[2].offset - entries[1].offset;
+        assert!(delta * 20 < keyframe, "{} and {} bytes", keyframe, delta);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_keyframe_on_resize_and_retention() {
+        let dir = temp_dir("retention");
+        let mut recorder = Recorder::new(&dir)
+            .segment_duration(ms(1000))
+            .max_segments(2);
+        let small = Frame::new(4, 4);
+        let large = Frame::new(8, 8).with_origin(4, 0);
+        for i in 0..40 {
+            let frame = if i % 10 < 5 { &small } else { &large };
+            recorder.push(frame, ms(i * 100)).unwrap();
+        }
+        recorder.flush().unwrap();
+
+        // appending to a recording continues the numbering
+        let mut recorder = Recorder::new(&dir).max_segments(2);
+        recorder.push(&small, ms(4000)).unwrap();
+        drop(recorder);
+
+        let mut reader = Reader::open(&dir).unwrap();
+        assert_eq!(segments(&dir).unwrap(), [3, 4]);
+        assert_eq!(reader.range(), Some((ms(3000), ms(4000))));
+        assert!(reader.entries()[5].keyframe);
+        assert_eq!(*reader.seek(ms(3600)).unwrap(), large);
+        assert_eq!(*reader.seek(ms(4000)).unwrap(), small);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_integrity_and_corruption() {
+        let dir = temp_dir("integrity");
+        let fresh = Frame::new(4, 4).with_info(FrameInfo {
+            accumulated_frames: 1,
+            ..Default::default()
+        });
+        let stale = Frame::new(4, 4);
+
+        let mut recorder = Recorder::new(&dir).integrity(IntegrityPolicy::Skip);
+        recorder.push(&fresh, ms(0)).unwrap();
+        recorder.push(&stale, ms(100)).unwrap();
+        let mut recorder = recorder.integrity(IntegrityPolicy::Flag);
+        recorder.push(&stale, ms(200)).unwrap();
+        // the clock stepping back
+        recorder.push(&fresh, ms(50)).unwrap();
+        drop(recorder);
+
+        let reader = Reader::open(&dir).unwrap();
+        let entries = reader.entries();
+        assert_eq!(entries.len(), 3);
+        assert_eq!(entries[1].timestamp, ms(200));
+        assert!(entries[1].flagged && !entries[0].flagged);
+        assert_eq!(entries[2].timestamp, ms(200));
+
+        let path = segment_path(&dir, 0, "seg");
+        let mut data = fs::read(&path).unwrap();
+        let payload = SEGMENT_MAGIC.len() + RECORD_HEADER_LEN;
+        data.truncate(payload + 2);
+        fs::write(&path, data).unwrap();
+        let e = Reader::open(&dir).unwrap().seek(ms(0)).unwrap_err();
+        assert!(matches!(e, Error::Io(_)), "{:?}", e);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
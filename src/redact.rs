@@ -0,0 +1,431 @@
+//! Redaction of sensitive screen regions before a frame is encoded or sent.
+//!
+//! A [`Redactor`] is a list of rules, each pairing a [`Region`] with the
+//! [`Style`] used to hide it. Installing one with [`install`] makes it
+//! mandatory: every encoder and sink in this crate runs frames through
+//! [`prepare`], so nothing leaves the process unredacted.
+
+use std::borrow::Cow;
+use std::fmt;
+use std::str::FromStr;
+use std::sync::{Arc, RwLock};
+
+use crate::frame::{Frame, Rect, BYTES_PER_PIXEL};
+use crate::window::{self, WindowId};
+
+static MANDATORY: RwLock<Option<Arc<Redactor>>> = RwLock::new(None);
+
+/// Computes rectangles to redact, in frame coordinates, for each frame.
+pub type RegionCallback = Arc<dyn Fn(&Frame) -> Vec<Rect> + Send + Sync>;
+
+/// How a redacted region is hidden.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Style {
+    /// Paint the region with a solid BGRA color.
+    Fill([u8; 4]),
+    /// Replace every `n x n` block with its average color.
+    Pixelate(u32),
+    /// Box blur with the given radius, repeated a few times so it
+    /// approaches a gaussian.
+    Blur(u32),
+}
+
+/// Where a rule applies.
+#[derive(Clone)]
+pub enum Region {
+    /// A rectangle in frame coordinates.
+    Rect(Rect),
+    /// Whatever the window currently covers; skipped when the window is gone.
+    Window(WindowId),
+    /// Rectangles in frame coordinates computed per frame, e.g. from OCR or
+    /// an accessibility tree.
+    Callback(RegionCallback),
+}
+
+impl fmt::Debug for Region {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Region::Rect(rect) => f.debug_tuple("Rect").field(rect).finish(),
+            Region::Window(id) => f.debug_tuple("Window").field(id).finish(),
+            Region::Callback(_) => f.write_str("Callback(..)"),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Rule {
+    pub region: Region,
+    pub style: Style,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Redactor {
+    rules: Vec<Rule>,
+}
+
+impl Redactor {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn rule(mut self, region: Region, style: Style) -> Self {
+        self.rules.push(Rule { region, style });
+        self
+    }
+
+    pub fn rules(&self) -> &[Rule] {
+        &self.rules
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.rules.is_empty()
+    }
+
+    /// Redact every rule's region of `frame` in place. HDR frames are tone
+    /// mapped to BGRA8 first, the styles work on 8 bit pixels.
+    pub fn apply(&self, frame: &mut Frame) {
+        if frame.format.is_hdr() {
+            *frame = frame.to_sdr().into_owned();
+        }
+        for rule in &self.rules {
+            for rect in resolve(&rule.region, frame) {
+                let Some(rect) = rect.intersect(&frame.bounds()) else {
+                    continue;
+                };
+                match rule.style {
+                    Style::Fill(bgra) => fill(frame, rect, bgra),
+                    Style::Pixelate(block) => pixelate(frame, rect, block.max(1)),
+                    Style::Blur(radius) => blur(frame, rect, radius),
+                }
+            }
+        }
+    }
+}
+
+fn resolve(region: &Region, frame: &Frame) -> Vec<Rect> {
+    match region {
+        Region::Rect(rect) => vec![*rect],
+        Region::Window(id) => window::window_rect(*id)
+            .map(|rect| rect.offset(-frame.origin.0, -frame.origin.1))
+            .into_iter()
+            .collect(),
+        Region::Callback(callback) => callback(frame),
+    }
+}
+
+/// Make `redactor` mandatory for every output of this process.
+pub fn install(redactor: Redactor) {
+    *MANDATORY.write().unwrap() = Some(Arc::new(redactor));
+}
+
+pub fn uninstall() {
+    *MANDATORY.write().unwrap() = None;
+}
+
+/// The redactor installed with [`install`], if any.
+pub fn mandatory() -> Option<Arc<Redactor>> {
+    MANDATORY.read().unwrap().clone()
+}
+
+/// The frame as it may leave the process: borrowed untouched when no
+/// redactor is installed, otherwise a redacted copy. HDR frames are tone
+/// mapped to BGRA8 first, as the encoders and the redaction styles work
+/// on 8 bit pixels.
+pub fn prepare(frame: &Frame) -> Cow<'_, Frame> {
+    prepare_with(mandatory().as_deref(), frame)
+}
+
+pub fn prepare_with<'a>(redactor: Option<&Redactor>, frame: &'a Frame) -> Cow<'a, Frame> {
+    let frame = frame.to_sdr();
+
+    match redactor {
+        Some(redactor) if !redactor.is_empty() => {
+            let mut frame = frame.into_owned();
+            redactor.apply(&mut frame);
+            Cow::Owned(frame)
+        }
+        _ => frame,
+    }
+}
+
+fn fill(frame: &mut Frame, rect: Rect, bgra: [u8; 4]) {
+    for y in rect.y..rect.bottom() {
+        let row = frame.row_mut(y as u32);
+        row[rect.x as usize * BYTES_PER_PIXEL..rect.right() as usize * BYTES_PER_PIXEL]
+            .chunks_exact_mut(BYTES_PER_PIXEL)
+            .for_each(|px| px.copy_from_slice(&bgra));
+    }
+}
+
+fn pixelate(frame: &mut Frame, rect: Rect, block: u32) {
+    // blocks past the rect's size all cover the whole rect
+    let block = block.min(rect.width.max(rect.height));
+    let mut top = Some(rect.y);
+    while let Some(y) = top.filter(|&y| y < rect.bottom()) {
+        let mut left = Some(rect.x);
+        while let Some(x) = left.filter(|&x| x < rect.right()) {
+            if let Some(cell) = Rect::new(x, y, block, block).intersect(&rect) {
+                let mut sum = [0u64; 4];
+                for y in cell.y..cell.bottom() {
+                    for x in cell.x..cell.right() {
+                        let px = frame.pixel(x as u32, y as u32);
+                        sum.iter_mut().zip(px).for_each(|(s, c)| *s += c as u64);
+                    }
+                }
+                let count = cell.width as u64 * cell.height as u64;
+                fill(frame, cell, sum.map(|s| (s / count) as u8));
+            }
+            left = x.checked_add_unsigned(block);
+        }
+        top = y.checked_add_unsigned(block);
+    }
+}
+
+fn blur(frame: &mut Frame, rect: Rect, radius: u32) {
+    if radius == 0 {
+        return;
+    }
+    for _ in 0..3 {
+        for y in rect.y..rect.bottom() {
+            let line: Vec<[u8; 4]> = (rect.x..rect.right())
+                .map(|x| frame.pixel(x as u32, y as u32))
+                .collect();
+            for (i, px) in box_blur(&line, radius).into_iter().enumerate() {
+                frame.set_pixel((rect.x + i as i32) as u32, y as u32, px);
+            }
+        }
+        for x in rect.x..rect.right() {
+            let line: Vec<[u8; 4]> = (rect.y..rect.bottom())
+                .map(|y| frame.pixel(x as u32, y as u32))
+                .collect();
+            for (i, px) in box_blur(&line, radius).into_iter().enumerate() {
+                frame.set_pixel(x as u32, (rect.y + i as i32) as u32, px);
+            }
+        }
+    }
+}
+
+/// One dimensional moving average, clamping the window at both ends.
+fn box_blur(line: &[[u8; 4]], radius: u32) -> Vec<[u8; 4]> {
+    let radius = radius as usize;
+    let mut prefix = vec![[0u32; 4]; line.len() + 1];
+    for (i, px) in line.iter().enumerate() {
+        for c in 0..4 {
+            prefix[i + 1][c] = prefix[i][c] + px[c] as u32;
+        }
+    }
+
+    (0..line.len())
+        .map(|i| {
+            let lo = i.saturating_sub(radius);
+            let hi = (i + radius + 1).min(line.len());
+            let count = (hi - lo) as u32;
+            std::array::from_fn(|c| ((prefix[hi][c] - prefix[lo][c]) / count) as u8)
+        })
+        .collect()
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ParseRuleError(String);
+
+impl fmt::Display for ParseRuleError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "invalid redaction rule: {}", self.0)
+    }
+}
+
+impl std::error::Error for ParseRuleError {}
+
+/// Parses `<style>:<region>` where style is `fill`, `fill=#RRGGBB`,
+/// `pixelate=<block>` or `blur=<radius>`, and region is `<x>,<y>,<w>,<h>` or
+/// `window=<handle>`.
+impl FromStr for Rule {
+    type Err = ParseRuleError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let err = || ParseRuleError(s.to_string());
+        let (style, region) = s.trim().split_once(':').ok_or_else(err)?;
+
+        let style = match style.split_once('=') {
+            None if style == "fill" => Style::Fill([0, 0, 0, 0xFF]),
+            Some(("fill", color)) => {
+                let rgb = color
+                    .strip_prefix('#')
+                    .filter(|hex| hex.len() == 6)
+                    .and_then(|hex| u32::from_str_radix(hex, 16).ok())
+                    .ok_or_else(err)?;
+                Style::Fill([rgb as u8, (rgb >> 8) as u8, (rgb >> 16) as u8, 0xFF])
+            }
+            Some(("pixelate", block)) => Style::Pixelate(block.parse().map_err(|_| err())?),
+            Some(("blur", radius)) => Style::Blur(radius.parse().map_err(|_| err())?),
+            _ => return Err(err()),
+        };
+
+        let region = match region.strip_prefix("window=") {
+            Some(handle) => Region::Window(WindowId(handle.parse().map_err(|_| err())?)),
+            None => {
+                let parts = region
+                    .split(',')
+                    .map(|part| part.trim().parse::<i64>())
+                    .collect::<Result<Vec<_>, _>>()
+                    .map_err(|_| err())?;
+                let [x, y, w, h] = parts[..] else {
+                    return Err(err());
+                };
+                Region::Rect(Rect::new(
+                    x.try_into().map_err(|_| err())?,
+                    y.try_into().map_err(|_| err())?,
+                    w.try_into().map_err(|_| err())?,
+                    h.try_into().map_err(|_| err())?,
+                ))
+            }
+        };
+
+        Ok(Rule { region, style })
+    }
+}
+
+/// Parses `;` separated [`Rule`]s.
+impl FromStr for Redactor {
+    type Err = ParseRuleError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let rules = s
+            .split(';')
+            .filter(|rule| !rule.trim().is_empty())
+            .map(str::parse)
+            .collect::<Result<_, _>>()?;
+        Ok(Self { rules })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::frame::PixelFormat;
+
+    fn gradient(width: u32, height: u32) -> Frame {
+        let mut frame = Frame::new(width, height);
+        for y in 0..height {
+            for x in 0..width {
+                frame.set_pixel(x, y, [(x * 16) as u8, (y * 16) as u8, 0x80, 0xFF]);
+            }
+        }
+        frame
+    }
+
+    #[test]
+    fn test_fill() {
+        let mut frame = gradient(8, 8);
+        let original = frame.clone();
+        Redactor::new()
+            .rule(
+                Region::Rect(Rect::new(6, 6, 10, 10)),
+                Style::Fill([1, 2, 3, 4]),
+            )
+            .apply(&mut frame);
+
+        assert_eq!(frame.pixel(7, 7), [1, 2, 3, 4]);
+        assert_eq!(frame.pixel(6, 6), [1, 2, 3, 4]);
+        assert_eq!(frame.pixel(5, 6), original.pixel(5, 6));
+    }
+
+    #[test]
+    fn test_pixelate() {
+        let mut frame = gradient(8, 8);
+        Redactor::new()
+            .rule(Region::Rect(frame.bounds()), Style::Pixelate(4))
+            .apply(&mut frame);
+
+        // x in 0..4 averages (0 + 16 + 32 + 48) / 4
+        assert_eq!(frame.pixel(0, 0), [24, 24, 0x80, 0xFF]);
+        assert_eq!(frame.pixel(3, 3), [24, 24, 0x80, 0xFF]);
+        assert_eq!(frame.pixel(4, 0), [88, 24, 0x80, 0xFF]);
+
+        // one block for all of it, however large
+        for block in [8, 1 << 31, u32::MAX] {
+            let mut frame = gradient(8, 8);
+            Redactor::new()
+                .rule(Region::Rect(frame.bounds()), Style::Pixelate(block))
+                .apply(&mut frame);
+            assert_eq!(frame.pixel(0, 0), [56, 56, 0x80, 0xFF]);
+            assert_eq!(frame.pixel(7, 7), [56, 56, 0x80, 0xFF]);
+        }
+    }
+
+    #[test]
+    fn test_blur_stays_inside_rect() {
+        let mut frame = Frame::new(9, 9);
+        frame.set_pixel(4, 4, [0xFF, 0xFF, 0xFF, 0xFF]);
+        let rect = Rect::new(2, 2, 5, 5);
+        Redactor::new()
+            .rule(Region::Rect(rect), Style::Blur(2))
+            .apply(&mut frame);
+
+        assert!(frame.pixel(4, 4)[0] < 0xFF);
+        assert!(frame.pixel(3, 4)[0] > 0);
+        assert_eq!(frame.pixel(1, 4), [0, 0, 0, 0xFF]);
+    }
+
+    #[test]
+    fn test_callback_region() {
+        let mut frame = gradient(4, 4);
+        let callback: RegionCallback =
+            Arc::new(|frame: &Frame| vec![Rect::new(0, 0, frame.width, 1)]);
+        Redactor::new()
+            .rule(Region::Callback(callback), Style::Fill([0; 4]))
+            .apply(&mut frame);
+
+        assert!(frame.row(0).iter().all(|&b| b == 0));
+        assert_ne!(frame.pixel(0, 1), [0; 4]);
+    }
+
+    #[test]
+    fn test_prepare_with() {
+        let frame = gradient(4, 4);
+        assert!(matches!(prepare_with(None, &frame), Cow::Borrowed(_)));
+
+        let redactor: Redactor = "fill=#102030:0,0,4,4".parse().unwrap();
+        let prepared = prepare_with(Some(&redactor), &frame);
+        assert_eq!(prepared.pixel(3, 3), [0x30, 0x20, 0x10, 0xFF]);
+        assert_eq!(frame, gradient(4, 4));
+
+        // half float zeros, black and transparent after tone-mapping
+        let hdr = Frame::from_raw(2, 2, PixelFormat::Rgba16Float, vec![0; 32]).unwrap();
+        let prepared = prepare_with(None, &hdr);
+        assert_eq!(prepared.format, PixelFormat::Bgra8);
+        assert_eq!(prepared.pixel(1, 1), [0; 4]);
+
+        let mut hdr = hdr;
+        redactor.apply(&mut hdr);
+        assert_eq!(hdr.format, PixelFormat::Bgra8);
+        assert_eq!(hdr.pixel(1, 1), [0x30, 0x20, 0x10, 0xFF]);
+    }
+
+    #[test]
+    fn test_parse() {
+        let redactor: Redactor = "pixelate=16:0,0,100,20; blur=8:window=42;".parse().unwrap();
+        assert_eq!(redactor.rules().len(), 2);
+        assert_eq!(redactor.rules()[0].style, Style::Pixelate(16));
+        assert!(matches!(
+            redactor.rules()[1].region,
+            Region::Window(WindowId(42))
+        ));
+
+        assert!("fill:1,2,3".parse::<Rule>().is_err());
+        assert!("smudge:1,2,3,4".parse::<Rule>().is_err());
+        assert!("fill=#12:1,2,3,4".parse::<Rule>().is_err());
+        assert!("fill:1,2,-3,4".parse::<Rule>().is_err());
+        assert!("fill:2147483648,0,1,1".parse::<Rule>().is_err());
+        assert!("fill:0,0,4294967296,1".parse::<Rule>().is_err());
+        let rule: Rule = "fill:-2147483648,0,4294967295,1".parse().unwrap();
+        assert!(matches!(
+            rule.region,
+            Region::Rect(Rect {
+                x: i32::MIN,
+                width: u32::MAX,
+                ..
+            })
+        ));
+    }
+}
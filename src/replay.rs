@@ -0,0 +1,383 @@
+//! "Instant replay": the last seconds of capture kept in memory, to be saved
+//! after something interesting happened.
+//!
+//! Frames are held encoded like in a [`record`](crate::record) segment and
+//! a dump is written as one, so [`Reader`](crate::record::Reader) plays it.
+
+use std::collections::VecDeque;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+use crate::capture::{self, Backend, Event, Pace, Session};
+use crate::error::{Error, Result};
+use crate::frame::{Frame, IntegrityPolicy};
+use crate::record::{self, Encoded, Encoder, SegmentWriter};
+use crate::redact;
+
+/// Decides from a frame whether to dump the replay, e.g. on an error dialog.
+pub type Trigger = Box<dyn FnMut(&Frame) -> bool + Send>;
+
+/// The most recent frames, compressed, within a time window and a memory
+/// budget.
+///
+/// Frames are evicted a keyframe interval at a time so the buffer always
+/// starts with a keyframe. The interval being recorded is never evicted,
+/// so memory can exceed the budget by one interval's worth.
+pub struct ReplayBuffer {
+    window: Duration,
+    budget: usize,
+    integrity: IntegrityPolicy,
+    encoder: Encoder,
+    records: VecDeque<Encoded>,
+    bytes: usize,
+    last_timestamp: Duration,
+    trigger: Option<(PathBuf, Trigger)>,
+    last_dump: Option<Duration>,
+}
+
+impl ReplayBuffer {
+    /// Keep `window` worth of frames in at most `budget` bytes.
+    pub fn new(window: Duration, budget: usize) -> Self {
+        Self {
+            window,
+            budget,
+            integrity: IntegrityPolicy::default(),
+            encoder: Encoder::new(Duration::from_secs(1)),
+            records: VecDeque::new(),
+            bytes: 0,
+            last_timestamp: Duration::ZERO,
+            trigger: None,
+            last_dump: None,
+        }
+    }
+
+    /// One second by default. Shorter intervals evict more precisely but
+    /// compress worse.
+    pub fn keyframe_interval(mut self, interval: Duration) -> Self {
+        self.encoder = Encoder::new(interval);
+        self
+    }
+
+    pub fn integrity(mut self, policy: IntegrityPolicy) -> Self {
+        self.integrity = policy;
+        self
+    }
+
+    /// Dump into `dir` when `trigger` returns `true` for a frame, at most
+    /// once per window so dumps don't overlap.
+    pub fn trigger<P, T>(mut self, dir: P, trigger: T) -> Self
+    where
+        P: Into<PathBuf>,
+        T: FnMut(&Frame) -> bool + Send + 'static,
+    {
+        self.trigger = Some((dir.into(), Box::new(trigger)));
+        self
+    }
+
+    /// Add `frame` captured at `at`. Returns whether the trigger fired and
+    /// the buffer was dumped.
+    pub fn push(&mut self, frame: &Frame, at: Duration) -> Result<bool> {
+        let Some(flagged) = self.integrity.admit(&frame.info) else {
+            return Ok(false);
+        };
+        let at = at.max(self.last_timestamp);
+        self.last_timestamp = at;
+
+        let record = self.encoder.encode(&redact::prepare(frame), at, flagged);
+        self.bytes += record.bytes.len();
+        self.records.push_back(record);
+        self.evict(at);
+
+        let cooled_down = self
+            .last_dump
+            .is_none_or(|last| at.saturating_sub(last) >= self.window);
+        let dir = match self.trigger.as_mut() {
+            Some((dir, trigger)) if cooled_down => {
+                if !trigger(frame) {
+                    return Ok(false);
+                }
+                dir.clone()
+            }
+            _ => return Ok(false),
+        };
+        self.dump(dir)?;
+        self.last_dump = Some(at);
+        Ok(true)
+    }
+
+    /// [`push`](Self::push) with the current wall clock time.
+    pub fn push_now(&mut self, frame: &Frame) -> Result<bool> {
+        self.push(frame, record::since_epoch())
+    }
+
+    /// Write the buffered frames as a new segment of the recording in
+    /// `dir`, returning the segment's number.
+    pub fn dump<P: AsRef<Path>>(&self, dir: P) -> Result<u32> {
+        write_segment(dir.as_ref(), self.records.iter())
+    }
+
+    pub fn len(&self) -> usize {
+        self.records.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.records.is_empty()
+    }
+
+    /// Bytes held, the compressed frames and the last frame the next delta
+    /// is computed against.
+    pub fn memory(&self) -> usize {
+        self.bytes + self.encoder.memory()
+    }
+
+    /// Timestamps of the oldest and the newest frame.
+    pub fn range(&self) -> Option<(Duration, Duration)> {
+        Some((
+            self.records.front()?.timestamp,
+            self.records.back()?.timestamp,
+        ))
+    }
+
+    /// Drop the oldest keyframe interval while the next one still covers
+    /// the window, or while over budget.
+    fn evict(&mut self, now: Duration) {
+        while let Some(next) = self
+            .records
+            .iter()
+            .skip(1)
+            .position(Encoded::is_keyframe)
+            .map(|i| i + 1)
+        {
+            let covered = now.saturating_sub(self.records[next].timestamp) >= self.window;
+            if !covered && self.bytes <= self.budget {
+                break;
+            }
+            for record in self.records.drain(..next) {
+                self.bytes -= record.bytes.len();
+            }
+        }
+    }
+}
+
+fn write_segment<'a>(dir: &Path, records: impl Iterator<Item = &'a Encoded>) -> Result<u32> {
+    let mut records = records.peekable();
+    let Some(first) = records.peek() else {
+        return Err(Error::backend("the replay buffer is empty"));
+    };
+
+    let number = record::next_segment(dir)?;
+    let mut segment = SegmentWriter::create(dir, number, first.timestamp)?;
+    for record in records {
+        segment.append(record)?;
+    }
+    segment.flush()?;
+    Ok(number)
+}
+
+/// A [`ReplayBuffer`] fed by a capture thread.
+///
+/// Dropping it stops the thread.
+pub struct InstantReplay {
+    buffer: Arc<Mutex<ReplayBuffer>>,
+    stop: Arc<AtomicBool>,
+    thread: Option<JoinHandle<Result<()>>>,
+}
+
+impl InstantReplay {
+    /// Capture into `buffer` on a new thread. `open` builds the session on
+    /// that thread, so backends don't have to be `Send`. Timeouts are
+    /// skipped, other errors and failed trigger dumps end the capture.
+    pub fn spawn<B, F>(open: F, pace: Pace, buffer: ReplayBuffer) -> Self
+    where
+        B: Backend,
+        F: FnOnce() -> Session<B> + Send + 'static,
+    {
+        let buffer = Arc::new(Mutex::new(buffer));
+        let stop = Arc::new(AtomicBool::new(false));
+        let (fed, stopped) = (buffer.clone(), stop.clone());
+
+        let thread = thread::spawn(move || {
+            let mut result = Ok(());
+            capture::run(open(), pace, &stopped, |event| {
+                let pushed = match event {
+                    Ok(Event::Geometry(_)) => Ok(false),
+                    Ok(Event::Frame(frame)) => fed.lock().unwrap().push_now(&frame),
+                    Err(e) => Err(e),
+                };
+                result = pushed.map(|_| ());
+                result.is_ok()
+            });
+            result
+        });
+
+        Self {
+            buffer,
+            stop,
+            thread: Some(thread),
+        }
+    }
+
+    /// Write what is buffered as a new segment of the recording in `dir`,
+    /// without holding up the capture while writing.
+    pub fn dump<P: AsRef<Path>>(&self, dir: P) -> Result<u32> {
+        let records: Vec<Encoded> = self
+            .buffer
+            .lock()
+            .unwrap()
+            .records
+            .iter()
+            .cloned()
+            .collect();
+        write_segment(dir.as_ref(), records.iter())
+    }
+
+    pub fn buffer(&self) -> &Mutex<ReplayBuffer> {
+        &self.buffer
+    }
+
+    /// Stop capturing, returning the error that ended the capture early.
+    pub fn stop(mut self) -> Result<()> {
+        self.stop.store(true, Ordering::Relaxed);
+        match self.thread.take().map(JoinHandle::join) {
+            Some(Ok(result)) => result,
+            Some(Err(_)) => Err(Error::backend("the replay capture thread panicked")),
+            None => Ok(()),
+        }
+    }
+}
+
+impl Drop for InstantReplay {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::Relaxed);
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    use crate::frame::FrameInfo;
+    use crate::record::Reader;
+    use crate::synthetic::SyntheticBackend;
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!(
+            "action-demo-replay-{}-{}",
+            name,
+            std::process::id()
+        ));
+        let _ = fs::remove_dir_all(&dir);
+        dir
+    }
+
+    fn ms(ms: u64) -> Duration {
+        Duration::from_millis(ms)
+    }
+
+    #[test]
+    fn test_window_and_dump() {
+        let dir = temp_dir("window");
+        let mut backend = SyntheticBackend::new(64, 32);
+        let mut buffer = ReplayBuffer::new(ms(2000), usize::MAX).keyframe_interval(ms(500));
+        let mut last = Frame::new(0, 0);
+        for i in 0..100 {
+            last = backend.next_frame().unwrap();
+            buffer.push(&last, ms(i * 100)).unwrap();
+        }
+
+        // keyframes at every 500 ms, the oldest interval covering 7.9 s - 2 s
+        assert_eq!(buffer.range(), Some((ms(7500), ms(9900))));
+        assert_eq!(buffer.len(), 25);
+
+        assert_eq!(buffer.dump(&dir).unwrap(), 0);
+        assert_eq!(buffer.dump(&dir).unwrap(), 1);
+        let mut reader = Reader::open(&dir).unwrap();
+        assert_eq!(reader.entries().len(), 50);
+        assert_eq!(reader.range(), Some((ms(7500), ms(9900))));
+        let replayed = reader.seek(ms(9900)).unwrap();
+        assert_eq!(replayed.data, last.data);
+
+        assert!(ReplayBuffer::new(ms(1), 1).dump(&dir).is_err());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_budget() {
+        let mut backend = SyntheticBackend::new(64, 32);
+        let mut buffer = ReplayBuffer::new(Duration::MAX, 4096).keyframe_interval(ms(300));
+        for i in 0..60 {
+            let frame = backend.next_frame().unwrap();
+            buffer.push(&frame, ms(i * 100)).unwrap();
+            // the interval being written may push it over
+            assert!(buffer.len() <= 6, "{} frames", buffer.len());
+        }
+        assert!(buffer.records[0].is_keyframe());
+        assert!(buffer.memory() >= buffer.bytes + 64 * 32 * 4);
+    }
+
+    #[test]
+    fn test_trigger() {
+        let dir = temp_dir("trigger");
+        let fresh = FrameInfo {
+            accumulated_frames: 1,
+            ..Default::default()
+        };
+        let mut buffer = ReplayBuffer::new(ms(1000), usize::MAX)
+            .integrity(IntegrityPolicy::Skip)
+            .trigger(&dir, |frame: &Frame| {
+                frame.pixel(0, 0) == [0, 0, 0xFF, 0xFF]
+            });
+
+        let black = Frame::new(4, 4).with_info(fresh);
+        let mut red = black.clone();
+        red.set_pixel(0, 0, [0, 0, 0xFF, 0xFF]);
+
+        assert!(!buffer.push(&black, ms(0)).unwrap());
+        // incomplete frames are neither kept nor looked at
+        assert!(!buffer
+            .push(&red.clone().with_info(FrameInfo::default()), ms(50))
+            .unwrap());
+        assert!(buffer.push(&red, ms(100)).unwrap());
+        assert!(!buffer.push(&red, ms(600)).unwrap());
+        assert!(buffer.push(&red, ms(1100)).unwrap());
+
+        let reader = Reader::open(&dir).unwrap();
+        let timestamps: Vec<_> = reader
+            .entries()
+            .iter()
+            .map(|entry| entry.timestamp)
+            .collect();
+        assert_eq!(
+            timestamps,
+            [ms(0), ms(100), ms(0), ms(100), ms(600), ms(1100)]
+        );
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_instant_replay() {
+        let dir = temp_dir("instant");
+        let replay = InstantReplay::spawn(
+            || Session::new(SyntheticBackend::new(32, 16).interval(ms(2))),
+            Pace::Max,
+            ReplayBuffer::new(Duration::from_secs(60), usize::MAX),
+        );
+        while replay.buffer().lock().unwrap().len() < 5 {
+            thread::sleep(ms(5));
+        }
+
+        replay.dump(&dir).unwrap();
+        replay.stop().unwrap();
+        assert!(Reader::open(&dir).unwrap().entries().len() >= 5);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,532 @@
+//! A tiny HTTP server showing the capture live in a browser.
+//!
+//! * `GET /` and `GET /stream.mjpeg`: a `multipart/x-mixed-replace` stream
+//!   with one JPEG part per captured frame
+//! * `GET /screenshot.png`, `GET /screenshot.bmp`: the latest frame
+//!
+//! Every connection gets its own thread and `Connection: close`; this is for
+//! a handful of viewers, not for the internet. All images go through
+//! [`redact::prepare`], so the mandatory redaction applies here too.
+
+use std::io::{self, BufRead, BufReader, Write};
+use std::net::{Ipv4Addr, Ipv6Addr, Shutdown, SocketAddr, TcpListener, TcpStream, ToSocketAddrs};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Condvar, Mutex, OnceLock};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+use crate::bmp::write_bmp;
+use crate::capture::{self, Backend, Event, Pace, Session};
+use crate::error::{Error, Result};
+use crate::frame::{Frame, BYTES_PER_PIXEL};
+use crate::pool::PooledFrame;
+use crate::redact;
+
+const BOUNDARY: &str = "action-demo-frame";
+
+/// How long a screenshot request waits for the first frame.
+const FIRST_FRAME_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// How long a client has to send its request.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// How often threads blocked on a new frame check whether to stop.
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// The newest frame, its JPEG encoded once for all stream viewers.
+struct Latest {
+    sequence: u64,
+    frame: PooledFrame,
+    jpeg: OnceLock<Option<Arc<Vec<u8>>>>,
+}
+
+#[derive(Default)]
+struct State {
+    latest: Option<Arc<Latest>>,
+    /// Set once the capture thread ended, with the error that ended it.
+    finished: Option<String>,
+}
+
+struct Shared {
+    state: Mutex<State>,
+    changed: Condvar,
+    stop: AtomicBool,
+    quality: u8,
+}
+
+impl Shared {
+    /// Blocks until a frame newer than `after` arrived, `None` once the
+    /// capture ended, the server stops or `timeout` passed.
+    fn wait_newer(&self, after: u64, timeout: Option<Duration>) -> Option<Arc<Latest>> {
+        let deadline = timeout.map(|timeout| Instant::now() + timeout);
+        let mut state = self.state.lock().unwrap();
+        loop {
+            if let Some(latest) = state.latest.as_ref().filter(|l| l.sequence > after) {
+                return Some(latest.clone());
+            }
+            if state.finished.is_some() || self.stop.load(Ordering::Relaxed) {
+                return None;
+            }
+            let wait = match deadline {
+                Some(deadline) => deadline
+                    .checked_duration_since(Instant::now())
+                    .filter(|left| !left.is_zero())?
+                    .min(POLL_INTERVAL),
+                None => POLL_INTERVAL,
+            };
+            state = self.changed.wait_timeout(state, wait).unwrap().0;
+        }
+    }
+}
+
+/// Serves the frames of a capture session running on its own thread.
+///
+/// Dropping it stops the capture and the listener; open streams end with
+/// their next frame.
+pub struct Server {
+    shared: Arc<Shared>,
+    address: SocketAddr,
+    capture: Option<JoinHandle<()>>,
+    listener: Option<JoinHandle<()>>,
+}
+
+impl Server {
+    /// Listen on `address` and capture with the session built by `open` on
+    /// the capture thread, see [`capture::spawn`].
+    pub fn bind<A, B, F>(address: A, open: F, pace: Pace) -> Result<Self>
+    where
+        A: ToSocketAddrs,
+        B: Backend,
+        F: FnOnce() -> Session<B> + Send + 'static,
+    {
+        Self::bind_with_quality(address, open, pace, 80)
+    }
+
+    /// Like [`bind`](Self::bind) with a JPEG quality between 1 and 100.
+    pub fn bind_with_quality<A, B, F>(address: A, open: F, pace: Pace, quality: u8) -> Result<Self>
+    where
+        A: ToSocketAddrs,
+        B: Backend,
+        F: FnOnce() -> Session<B> + Send + 'static,
+    {
+        let listener = TcpListener::bind(address)?;
+        let address = listener.local_addr()?;
+        let shared = Arc::new(Shared {
+            state: Mutex::new(State::default()),
+            changed: Condvar::new(),
+            stop: AtomicBool::new(false),
+            quality: quality.clamp(1, 100),
+        });
+
+        let fed = shared.clone();
+        let capture = thread::spawn(move || {
+            let mut sequence = 0;
+            let mut finished = "capture stopped".to_string();
+            capture::run(open(), pace, &fed.stop, |event| match event {
+                Ok(Event::Geometry(_)) => true,
+                Ok(Event::Frame(frame)) => {
+                    sequence += 1;
+                    fed.state.lock().unwrap().latest = Some(Arc::new(Latest {
+                        sequence,
+                        frame,
+                        jpeg: OnceLock::new(),
+                    }));
+                    fed.changed.notify_all();
+                    true
+                }
+                Err(e) => {
+                    finished = e.to_string();
+                    false
+                }
+            });
+            fed.state.lock().unwrap().finished = Some(finished);
+            fed.changed.notify_all();
+        });
+
+        let served = shared.clone();
+        let listener = thread::spawn(move || {
+            for stream in listener.incoming() {
+                if served.stop.load(Ordering::Relaxed) {
+                    break;
+                }
+                let Ok(stream) = stream else { continue };
+                let shared = served.clone();
+                thread::spawn(move || {
+                    let _ = handle(&shared, stream);
+                });
+            }
+        });
+
+        Ok(Self {
+            shared,
+            address,
+            capture: Some(capture),
+            listener: Some(listener),
+        })
+    }
+
+    /// The address actually bound, useful after binding port 0.
+    pub fn local_addr(&self) -> SocketAddr {
+        self.address
+    }
+
+    /// Blocks until the capture ended, returning why it did.
+    pub fn wait(mut self) -> Result<()> {
+        if let Some(capture) = self.capture.take() {
+            let _ = capture.join();
+        }
+        match self.shared.state.lock().unwrap().finished.take() {
+            Some(reason) if !self.shared.stop.load(Ordering::Relaxed) => {
+                Err(Error::backend(reason))
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+impl Drop for Server {
+    fn drop(&mut self) {
+        self.shared.stop.store(true, Ordering::Relaxed);
+        self.shared.changed.notify_all();
+        // unblocks the accept loop, an unspecified address can't be dialed
+        // everywhere
+        let mut wake = self.address;
+        if wake.ip().is_unspecified() {
+            wake.set_ip(match wake {
+                SocketAddr::V4(_) => Ipv4Addr::LOCALHOST.into(),
+                SocketAddr::V6(_) => Ipv6Addr::LOCALHOST.into(),
+            });
+        }
+        let _ = TcpStream::connect(wake);
+        for thread in [self.capture.take(), self.listener.take()]
+            .into_iter()
+            .flatten()
+        {
+            let _ = thread.join();
+        }
+    }
+}
+
+fn handle(shared: &Shared, mut stream: TcpStream) -> io::Result<()> {
+    // a client sending nothing would hold on to the thread forever
+    stream.set_read_timeout(Some(REQUEST_TIMEOUT))?;
+    let mut request_line = String::new();
+    {
+        let mut reader = BufReader::new(&stream);
+        reader.read_line(&mut request_line)?;
+        // the headers don't matter, but must be read before closing or the
+        // peer may see a reset instead of the response
+        let mut header = String::new();
+        while reader.read_line(&mut header)? > 2 {
+            header.clear();
+        }
+    }
+    stream.set_read_timeout(None)?;
+
+    let mut parts = request_line.split_whitespace();
+    let (method, target) = (parts.next().unwrap_or(""), parts.next().unwrap_or(""));
+    let path = target.split('?').next().unwrap_or("");
+
+    if method != "GET" {
+        return respond(
+            &mut stream,
+            "405 Method Not Allowed",
+            "text/plain",
+            b"GET only\n",
+        );
+    }
+
+    match path {
+        "/" | "/stream.mjpeg" => stream_mjpeg(shared, &mut stream),
+        "/screenshot.png" => screenshot(shared, &mut stream, "image/png", encode_png),
+        "/screenshot.bmp" => screenshot(shared, &mut stream, "image/bmp", encode_bmp),
+        _ => respond(&mut stream, "404 Not Found", "text/plain", b"not found\n"),
+    }?;
+    stream.shutdown(Shutdown::Write)
+}
+
+fn respond(
+    stream: &mut TcpStream,
+    status: &str,
+    content_type: &str,
+    body: &[u8],
+) -> io::Result<()> {
+    write!(
+        stream,
+        "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\n\
+         Cache-Control: no-store\r\nConnection: close\r\n\r\n",
+        status,
+        content_type,
+        body.len()
+    )?;
+    stream.write_all(body)
+}
+
+fn screenshot(
+    shared: &Shared,
+    stream: &mut TcpStream,
+    content_type: &str,
+    encode: fn(&Frame) -> Result<Vec<u8>>,
+) -> io::Result<()> {
+    let Some(latest) = shared.wait_newer(0, Some(FIRST_FRAME_TIMEOUT)) else {
+        return respond(
+            stream,
+            "503 Service Unavailable",
+            "text/plain",
+            b"no frame\n",
+        );
+    };
+    match encode(&latest.frame) {
+        Ok(image) => respond(stream, "200 OK", content_type, &image),
+        Err(e) => respond(
+            stream,
+            "500 Internal Server Error",
+            "text/plain",
+            format!("{}\n", e).as_bytes(),
+        ),
+    }
+}
+
+fn stream_mjpeg(shared: &Shared, stream: &mut TcpStream) -> io::Result<()> {
+    write!(
+        stream,
+        "HTTP/1.1 200 OK\r\nContent-Type: multipart/x-mixed-replace; boundary={}\r\n\
+         Cache-Control: no-store\r\nConnection: close\r\n\r\n",
+        BOUNDARY
+    )?;
+
+    let mut sequence = 0;
+    while let Some(latest) = shared.wait_newer(sequence, None) {
+        sequence = latest.sequence;
+        let jpeg = latest.jpeg.get_or_init(|| {
+            encode_jpeg(&latest.frame, shared.quality)
+                .ok()
+                .map(Arc::new)
+        });
+        let Some(jpeg) = jpeg else { continue };
+
+        write!(
+            stream,
+            "--{}\r\nContent-Type: image/jpeg\r\nContent-Length: {}\r\n\r\n",
+            BOUNDARY,
+            jpeg.len()
+        )?;
+        stream.write_all(jpeg)?;
+        stream.write_all(b"\r\n")?;
+        stream.flush()?;
+    }
+    write!(stream, "--{}--\r\n", BOUNDARY)
+}
+
+/// The visible pixels of a redacted, SDR copy of `frame`, tightly packed.
+fn packed_bgra(frame: &Frame) -> (u32, u32, Vec<u8>) {
+    let frame = redact::prepare(frame);
+    let mut data =
+        Vec::with_capacity(frame.width as usize * frame.height as usize * BYTES_PER_PIXEL);
+    for y in 0..frame.height {
+        data.extend_from_slice(frame.row(y));
+    }
+    (frame.width, frame.height, data)
+}
+
+fn encode_jpeg(frame: &Frame, quality: u8) -> Result<Vec<u8>> {
+    let (width, height, data) = packed_bgra(frame);
+    let (Ok(w), Ok(h)) = (u16::try_from(width), u16::try_from(height)) else {
+        return Err(Error::InvalidGeometry(format!(
+            "{}x{} exceeds the JPEG limit",
+            width, height
+        )));
+    };
+
+    let mut jpeg = Vec::new();
+    jpeg_encoder::Encoder::new(&mut jpeg, quality)
+        .encode(&data, w, h, jpeg_encoder::ColorType::Bgra)
+        .map_err(Error::backend)?;
+    Ok(jpeg)
+}
+
+fn encode_png(frame: &Frame) -> Result<Vec<u8>> {
+    let (width, height, data) = packed_bgra(frame);
+    let rgb: Vec<u8> = data
+        .chunks_exact(BYTES_PER_PIXEL)
+        .flat_map(|px| [px[2], px[1], px[0]])
+        .collect();
+
+    let mut png = Vec::new();
+    let mut encoder = png::Encoder::new(&mut png, width, height);
+    encoder.set_color(png::ColorType::Rgb);
+    encoder.set_depth(png::BitDepth::Eight);
+    encoder
+        .write_header()
+        .and_then(|mut writer| writer.write_image_data(&rgb))
+        .map_err(Error::backend)?;
+    Ok(png)
+}
+
+fn encode_bmp(frame: &Frame) -> Result<Vec<u8>> {
+    let mut bmp = Vec::new();
+    write_bmp(&mut bmp, frame)?;
+    Ok(bmp)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Read;
+
+    use crate::synthetic::SyntheticBackend;
+
+    fn server() -> Server {
+        Server::bind(
+            "127.0.0.1:0",
+            || Session::new(SyntheticBackend::new(64, 48).interval(Duration::from_millis(5))),
+            Pace::Max,
+        )
+        .unwrap()
+    }
+
+    /// Sends a GET and returns the connection positioned after the headers,
+    /// with the status line and the headers.
+    fn get(server: &Server, path: &str) -> (BufReader<TcpStream>, String, Vec<(String, String)>) {
+        let mut stream = TcpStream::connect(server.local_addr()).unwrap();
+        stream
+            .set_read_timeout(Some(Duration::from_secs(10)))
+            .unwrap();
+        write!(stream, "GET {} HTTP/1.1\r\nHost: localhost\r\n\r\n", path).unwrap();
+
+        let mut reader = BufReader::new(stream);
+        let mut status = String::new();
+        reader.read_line(&mut status).unwrap();
+        let headers = read_headers(&mut reader);
+        (reader, status.trim_end().to_string(), headers)
+    }
+
+    fn read_headers(reader: &mut impl BufRead) -> Vec<(String, String)> {
+        let mut headers = Vec::new();
+        loop {
+            let mut line = String::new();
+            reader.read_line(&mut line).unwrap();
+            let line = line.trim_end();
+            if line.is_empty() {
+                return headers;
+            }
+            let (name, value) = line.split_once(':').unwrap();
+            headers.push((name.to_ascii_lowercase(), value.trim().to_string()));
+        }
+    }
+
+    fn header<'a>(headers: &'a [(String, String)], name: &str) -> &'a str {
+        headers
+            .iter()
+            .find(|(n, _)| n == name)
+            .map(|(_, v)| v.as_str())
+            .unwrap_or_else(|| panic!("no {} header", name))
+    }
+
+    fn body(path: &str) -> (String, Vec<u8>) {
+        let server = server();
+        let (mut reader, status, headers) = get(&server, path);
+        assert_eq!(status, "HTTP/1.1 200 OK");
+        let mut body = Vec::new();
+        reader.read_to_end(&mut body).unwrap();
+        assert_eq!(header(&headers, "content-length"), body.len().to_string());
+        (header(&headers, "content-type").to_string(), body)
+    }
+
+    #[test]
+    fn test_screenshots() {
+        let (content_type, png) = body("/screenshot.png");
+        assert_eq!(content_type, "image/png");
+        assert_eq!(&png[..8], b"\x89PNG\r\n\x1a\n");
+        let decoder = png::Decoder::new(&png[..]);
+        let info = decoder.read_info().unwrap().info().clone();
+        assert_eq!((info.width, info.height), (64, 48));
+
+        let (content_type, bmp) = body("/screenshot.bmp");
+        assert_eq!(content_type, "image/bmp");
+        assert_eq!(&bmp[..2], b"BM");
+        assert_eq!(bmp.len(), 54 + 64 * 48 * 4);
+    }
+
+    #[test]
+    fn test_mjpeg_stream() {
+        let server = server();
+        let (mut reader, status, headers) = get(&server, "/stream.mjpeg");
+        assert_eq!(status, "HTTP/1.1 200 OK");
+        assert_eq!(
+            header(&headers, "content-type"),
+            format!("multipart/x-mixed-replace; boundary={}", BOUNDARY)
+        );
+
+        let mut parts = Vec::new();
+        for _ in 0..3 {
+            let mut boundary = String::new();
+            reader.read_line(&mut boundary).unwrap();
+            assert_eq!(boundary.trim_end(), format!("--{}", BOUNDARY));
+
+            let headers = read_headers(&mut reader);
+            assert_eq!(header(&headers, "content-type"), "image/jpeg");
+            let mut jpeg = vec![0; header(&headers, "content-length").parse().unwrap()];
+            reader.read_exact(&mut jpeg).unwrap();
+            assert_eq!(&jpeg[..2], [0xFF, 0xD8]);
+            assert_eq!(&jpeg[jpeg.len() - 2..], [0xFF, 0xD9]);
+
+            let mut end = [0; 2];
+            reader.read_exact(&mut end).unwrap();
+            assert_eq!(&end, b"\r\n");
+            parts.push(jpeg);
+        }
+        // the bar moves, so consecutive frames differ
+        assert!(parts.windows(2).any(|pair| pair[0] != pair[1]));
+    }
+
+    #[test]
+    fn test_errors() {
+        let server = server();
+        let (_, status, _) = get(&server, "/nope");
+        assert_eq!(status, "HTTP/1.1 404 Not Found");
+
+        let mut stream = TcpStream::connect(server.local_addr()).unwrap();
+        stream.write_all(b"POST / HTTP/1.1\r\n\r\n").unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        assert!(response.starts_with("HTTP/1.1 405 "));
+
+        // a backend that can't open ends the capture, and the server says so
+        let broken = Server::bind(
+            "127.0.0.1:0",
+            || Session::new(SyntheticBackend::new(0, 0)),
+            Pace::Max,
+        )
+        .unwrap();
+        let (_, status, _) = get(&broken, "/screenshot.png");
+        assert_eq!(status, "HTTP/1.1 503 Service Unavailable");
+        assert!(broken.wait().is_err());
+    }
+
+    #[test]
+    fn test_silent_client_is_dropped() {
+        let server = server();
+        let mut stream = TcpStream::connect(server.local_addr()).unwrap();
+        stream.set_read_timeout(Some(REQUEST_TIMEOUT * 2)).unwrap();
+        let started = Instant::now();
+        let mut response = Vec::new();
+        // closed rather than timed out on our side
+        stream.read_to_end(&mut response).unwrap();
+        assert!(response.is_empty());
+        assert!(started.elapsed() >= REQUEST_TIMEOUT);
+    }
+
+    #[test]
+    fn test_stop_listening_on_all_interfaces() {
+        let server = Server::bind(
+            "0.0.0.0:0",
+            || Session::new(SyntheticBackend::new(64, 48)),
+            Pace::Max,
+        )
+        .unwrap();
+        let address = server.local_addr();
+        drop(server);
+        assert!(TcpStream::connect(("127.0.0.1", address.port())).is_err());
+    }
+}
@@ -0,0 +1,152 @@
+//! Async capture for tokio based services.
+//!
+//! Backends block while waiting for the next frame, e.g. DXGI in
+//! `AcquireNextFrame`, so [`FrameStream`] keeps the [`Session`] on a
+//! dedicated thread and only the hand-off of finished frames is async.
+
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::thread;
+
+use futures_core::Stream;
+use tokio::sync::mpsc;
+
+use crate::capture::{self, Backend, Event, Pace, Session};
+use crate::error::Result;
+use crate::pool::PooledFrame;
+
+/// Frames of a session running on its own thread.
+///
+/// Geometry changes aren't reported separately, every frame carries its
+/// size and origin. Dropping the stream or calling [`cancel`](Self::cancel)
+/// stops the capture thread after the frame it is waiting for; neither
+/// blocks the runtime.
+pub struct FrameStream {
+    receiver: mpsc::Receiver<Result<PooledFrame>>,
+    stop: Arc<AtomicBool>,
+}
+
+impl FrameStream {
+    /// Start capturing. `open` builds the session on the capture thread, so
+    /// backends don't have to be `Send`. At most `depth` frames are buffered
+    /// for a slow consumer, then the capture thread waits.
+    pub fn spawn<B, F>(open: F, pace: Pace, depth: usize) -> Self
+    where
+        B: Backend,
+        F: FnOnce() -> Session<B> + Send + 'static,
+    {
+        let (sender, receiver) = mpsc::channel(depth.max(1));
+        let stop = Arc::new(AtomicBool::new(false));
+        let stopped = stop.clone();
+
+        thread::spawn(move || {
+            capture::run(open(), pace, &stopped, |event| match event {
+                Ok(Event::Geometry(_)) => true,
+                Ok(Event::Frame(frame)) => sender.blocking_send(Ok(frame)).is_ok(),
+                Err(e) => sender.blocking_send(Err(e)).is_ok(),
+            });
+        });
+
+        Self { receiver, stop }
+    }
+
+    /// The next frame, `None` once the capture ended after an error or
+    /// [`cancel`](Self::cancel).
+    pub async fn next_frame(&mut self) -> Option<Result<PooledFrame>> {
+        self.receiver.recv().await
+    }
+
+    /// Stop capturing. Frames already buffered can still be received.
+    pub fn cancel(&self) {
+        self.stop.store(true, Ordering::Relaxed);
+    }
+}
+
+impl Stream for FrameStream {
+    type Item = Result<PooledFrame>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        self.receiver.poll_recv(cx)
+    }
+}
+
+impl Drop for FrameStream {
+    fn drop(&mut self) {
+        self.cancel();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::error::Error;
+    use crate::frame::{Frame, Rect};
+    use crate::synthetic::SyntheticBackend;
+    use std::future::poll_fn;
+    use std::time::Duration;
+
+    #[tokio::test]
+    async fn test_stream_frames() {
+        let mut frames =
+            FrameStream::spawn(|| Session::new(SyntheticBackend::new(16, 8)), Pace::Max, 2);
+
+        for n in 0..3 {
+            let frame = poll_fn(|cx| Pin::new(&mut frames).poll_next(cx))
+                .await
+                .unwrap()
+                .unwrap();
+            assert_eq!((frame.width, frame.height), (16, 8));
+            assert_eq!(frame.pixel(n, 0), [0xFF; 4]);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_cancel_ends_stream() {
+        let mut frames = FrameStream::spawn(
+            || {
+                let backend = SyntheticBackend::new(4, 4).interval(Duration::from_millis(5));
+                Session::new(backend)
+            },
+            Pace::Max,
+            1,
+        );
+
+        assert!(frames.next_frame().await.unwrap().is_ok());
+        frames.cancel();
+
+        let drained = tokio::time::timeout(Duration::from_secs(5), async {
+            let mut remaining = 0;
+            while frames.next_frame().await.is_some() {
+                remaining += 1;
+            }
+            remaining
+        })
+        .await
+        .expect("capture thread didn't stop");
+        assert!(drained <= 2);
+    }
+
+    #[tokio::test]
+    async fn test_errors_end_stream() {
+        struct Broken;
+
+        impl Backend for Broken {
+            fn open(&mut self) -> Result<Rect> {
+                Ok(Rect::new(0, 0, 1, 1))
+            }
+
+            fn next_frame(&mut self) -> Result<Frame> {
+                Err(Error::backend("device removed"))
+            }
+        }
+
+        let mut frames = FrameStream::spawn(|| Session::new(Broken), Pace::Max, 1);
+        assert!(matches!(
+            frames.next_frame().await,
+            Some(Err(Error::Backend(_)))
+        ));
+        assert!(frames.next_frame().await.is_none());
+    }
+}
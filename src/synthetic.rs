@@ -0,0 +1,138 @@
+//! A [`Backend`] drawing a test pattern, for tests and for running the
+//! capture pipeline on machines without a supported capture API.
+
+use std::thread;
+use std::time::Duration;
+
+use crate::capture::Backend;
+use crate::error::{Error, Result};
+use crate::frame::{Frame, FrameInfo, Rect, BYTES_PER_PIXEL};
+use crate::output::{Adapter, Output, Rotation};
+
+/// Produces frames with a color gradient and a white bar moving one pixel
+/// to the right per frame.
+#[derive(Debug, Clone)]
+pub struct SyntheticBackend {
+    width: u32,
+    height: u32,
+    interval: Duration,
+    produced: u64,
+}
+
+impl SyntheticBackend {
+    pub fn new(width: u32, height: u32) -> Self {
+        Self {
+            width,
+            height,
+            interval: Duration::ZERO,
+            produced: 0,
+        }
+    }
+
+    /// Wait this long for every frame, like a display refreshing.
+    pub fn interval(mut self, interval: Duration) -> Self {
+        self.interval = interval;
+        self
+    }
+
+    /// Number of frames produced so far.
+    pub fn produced(&self) -> u64 {
+        self.produced
+    }
+}
+
+impl Backend for SyntheticBackend {
+    fn open(&mut self) -> Result<Rect> {
+        if self.width == 0 || self.height == 0 {
+            return Err(Error::InvalidGeometry(format!(
+                "{}x{} output",
+                self.width, self.height
+            )));
+        }
+        Ok(Rect::new(0, 0, self.width, self.height))
+    }
+
+    fn next_frame(&mut self) -> Result<Frame> {
+        self.next_frame_into(Vec::new())
+    }
+
+    fn next_frame_into(&mut self, mut buffer: Vec<u8>) -> Result<Frame> {
+        thread::sleep(self.interval);
+
+        let (width, height) = (self.width as usize, self.height as usize);
+        let bar = (self.produced % self.width as u64) as usize;
+        buffer.clear();
+        buffer.reserve(width * height * BYTES_PER_PIXEL);
+        for y in 0..height {
+            for x in 0..width {
+                let px = if x == bar {
+                    [0xFF; 4]
+                } else {
+                    [
+                        (x * 255 / width) as u8,
+                        (y * 255 / height) as u8,
+                        0x80,
+                        0xFF,
+                    ]
+                };
+                buffer.extend_from_slice(&px);
+            }
+        }
+
+        self.produced += 1;
+        let info = FrameInfo {
+            accumulated_frames: 1,
+            last_present_time: self.produced as i64,
+            ..Default::default()
+        };
+
+        Frame::from_bgra(self.width, self.height, buffer)
+            .map(|frame| frame.with_info(info))
+            .ok_or_else(|| Error::InvalidGeometry("frame buffer too short".to_string()))
+    }
+
+    fn adapters(&mut self) -> Result<Vec<Adapter>> {
+        Ok(vec![Adapter {
+            index: 0,
+            name: "synthetic".to_string(),
+            description: "test pattern generator".to_string(),
+            software: true,
+            outputs: vec![Output {
+                index: 0,
+                name: "synthetic-0".to_string(),
+                description: String::new(),
+                width: self.width,
+                height: self.height,
+                refresh_rate: (!self.interval.is_zero()).then(|| 1.0 / self.interval.as_secs_f64()),
+                rotation: Rotation::Identity,
+                desktop: Rect::new(0, 0, self.width, self.height),
+                attached_to_desktop: true,
+                primary: true,
+            }],
+        }])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_moving_bar() {
+        let mut backend = SyntheticBackend::new(8, 2);
+        assert_eq!(backend.open().unwrap(), Rect::new(0, 0, 8, 2));
+
+        let first = backend.next_frame().unwrap();
+        let second = backend.next_frame_into(first.data.clone()).unwrap();
+        assert_eq!(first.pixel(0, 1), [0xFF; 4]);
+        assert_eq!(second.pixel(1, 1), [0xFF; 4]);
+        assert_ne!(second.pixel(0, 1), [0xFF; 4]);
+        assert!(second.info.is_complete());
+        assert_eq!(backend.produced(), 2);
+
+        let adapters = backend.adapters().unwrap();
+        assert_eq!(adapters[0].outputs[0].desktop, Rect::new(0, 0, 8, 2));
+
+        assert!(SyntheticBackend::new(0, 2).open().is_err());
+    }
+}
@@ -0,0 +1,346 @@
+//! TLS for [`Conn`](crate::conn::Conn) with rustls.
+//!
+//! [`server_config`] and [`client_config`] set up mutual authentication:
+//! both sides show a certificate and trust only those issued by the CA
+//! given, a client without one doesn't get in. Any other rustls
+//! configuration works with [`Conn::connect_tls`](crate::conn::Conn::connect_tls)
+//! and [`Conn::accept_tls`](crate::conn::Conn::accept_tls) too.
+
+use std::io::{self, Read, Write};
+use std::net::{Shutdown, TcpStream};
+use std::ops::Range;
+use std::sync::{Arc, Mutex};
+
+use rustls::pki_types::pem::PemObject;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer, ServerName};
+use rustls::server::WebPkiClientVerifier;
+use rustls::{ClientConfig, ClientConnection, RootCertStore, ServerConfig, ServerConnection};
+
+use crate::conn::Transport;
+
+/// A server presenting the certificate `chain` with `key` that accepts
+/// clients with certificates issued by `ca`, all PEM encoded.
+pub fn server_config(ca: &[u8], chain: &[u8], key: &[u8]) -> io::Result<Arc<ServerConfig>> {
+    let verifier = WebPkiClientVerifier::builder(Arc::new(roots(ca)?))
+        .build()
+        .map_err(invalid)?;
+    let config = ServerConfig::builder()
+        .with_client_cert_verifier(verifier)
+        .with_single_cert(certificates(chain)?, private_key(key)?)
+        .map_err(invalid)?;
+    Ok(Arc::new(config))
+}
+
+/// A client presenting the certificate `chain` with `key` that accepts
+/// servers with certificates issued by `ca`, all PEM encoded.
+pub fn client_config(ca: &[u8], chain: &[u8], key: &[u8]) -> io::Result<Arc<ClientConfig>> {
+    let config = ClientConfig::builder()
+        .with_root_certificates(roots(ca)?)
+        .with_client_auth_cert(certificates(chain)?, private_key(key)?)
+        .map_err(invalid)?;
+    Ok(Arc::new(config))
+}
+
+fn certificates(pem: &[u8]) -> io::Result<Vec<CertificateDer<'static>>> {
+    let certificates = CertificateDer::pem_slice_iter(pem)
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(invalid)?;
+    if certificates.is_empty() {
+        return Err(invalid("no certificate in the PEM"));
+    }
+    Ok(certificates)
+}
+
+fn private_key(pem: &[u8]) -> io::Result<PrivateKeyDer<'static>> {
+    PrivateKeyDer::from_pem_slice(pem).map_err(invalid)
+}
+
+fn roots(pem: &[u8]) -> io::Result<RootCertStore> {
+    let mut roots = RootCertStore::empty();
+    for certificate in certificates(pem)? {
+        roots.add(certificate).map_err(invalid)?;
+    }
+    Ok(roots)
+}
+
+/// A TLS session over TCP. Unlike a rustls `StreamOwned` it can be
+/// [cloned](Transport::try_clone), one handle reading while another
+/// writes.
+pub struct TlsStream {
+    socket: TcpStream,
+    session: Arc<Session>,
+    /// Records read but not handed to rustls yet.
+    incoming: Vec<u8>,
+    pending: Range<usize>,
+}
+
+struct Session {
+    tls: Mutex<rustls::Connection>,
+    /// Locked before `tls` when both are, so records go out in the order
+    /// they were sealed.
+    output: Mutex<TcpStream>,
+}
+
+impl TlsStream {
+    /// Shake hands as the client, checking the server's certificate is for
+    /// `server_name`.
+    pub fn connect(
+        socket: TcpStream,
+        server_name: &str,
+        config: Arc<ClientConfig>,
+    ) -> io::Result<Self> {
+        let server_name = ServerName::try_from(server_name.to_string()).map_err(invalid)?;
+        let tls = ClientConnection::new(config, server_name).map_err(invalid)?;
+        Self::handshake(socket, tls.into())
+    }
+
+    /// Shake hands as the server.
+    pub fn accept(socket: TcpStream, config: Arc<ServerConfig>) -> io::Result<Self> {
+        let tls = ServerConnection::new(config).map_err(invalid)?;
+        Self::handshake(socket, tls.into())
+    }
+
+    fn handshake(mut socket: TcpStream, mut tls: rustls::Connection) -> io::Result<Self> {
+        while tls.is_handshaking() {
+            tls.complete_io(&mut socket)?;
+        }
+        // session tickets, for example
+        while tls.wants_write() {
+            tls.write_tls(&mut socket)?;
+        }
+        let session = Session {
+            tls: Mutex::new(tls),
+            output: Mutex::new(socket.try_clone()?),
+        };
+        Ok(Self {
+            socket,
+            session: Arc::new(session),
+            incoming: vec![0; 16 << 10],
+            pending: 0..0,
+        })
+    }
+
+    /// Write what rustls sealed, holding `output`.
+    fn send(&self, output: &mut TcpStream) -> io::Result<()> {
+        let mut records = Vec::new();
+        {
+            let mut tls = self.session.tls.lock().unwrap();
+            while tls.wants_write() {
+                tls.write_tls(&mut records)?;
+            }
+        }
+        output.write_all(&records)
+    }
+}
+
+impl Read for TlsStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            let mut tls = self.session.tls.lock().unwrap();
+            match tls.reader().read(buf) {
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => {}
+                // 0 after a close_notify, UnexpectedEof without one
+                result => return result,
+            }
+
+            if self.pending.is_empty() {
+                // the other thread may write meanwhile
+                drop(tls);
+                let n = self.socket.read(&mut self.incoming)?;
+                self.pending = 0..n;
+                tls = self.session.tls.lock().unwrap();
+            }
+            // only with the plaintext read so rustls' buffer doesn't fill
+            let mut records = &self.incoming[self.pending.clone()];
+            let n = tls.read_tls(&mut records)?;
+            self.pending.start += n;
+            let processed = tls.process_new_packets();
+            // a reply, or the alert for what went wrong
+            let reply = tls.wants_write();
+            drop(tls);
+            let sent = if reply {
+                self.send(&mut self.session.output.lock().unwrap())
+            } else {
+                Ok(())
+            };
+            processed.map_err(invalid)?;
+            sent?;
+        }
+    }
+}
+
+impl Write for TlsStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut output = self.session.output.lock().unwrap();
+        let n = self.session.tls.lock().unwrap().writer().write(buf)?;
+        self.send(&mut output)?;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        let mut output = self.session.output.lock().unwrap();
+        self.send(&mut output)?;
+        output.flush()
+    }
+}
+
+impl Transport for TlsStream {
+    fn try_clone(&self) -> io::Result<Self> {
+        Ok(Self {
+            socket: self.socket.try_clone()?,
+            session: self.session.clone(),
+            incoming: vec![0; self.incoming.len()],
+            pending: 0..0,
+        })
+    }
+
+    fn shutdown(&self, how: Shutdown) -> io::Result<()> {
+        // the writer may be stuck holding the output, only a clean end of
+        // the writing waits for it
+        if how == Shutdown::Write {
+            let mut output = self.session.output.lock().unwrap();
+            self.session.tls.lock().unwrap().send_close_notify();
+            self.send(&mut output)?;
+        }
+        self.socket.shutdown(how)
+    }
+}
+
+fn invalid<E: Into<Box<dyn std::error::Error + Send + Sync>>>(error: E) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, error)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::TcpListener;
+    use std::thread::{self, JoinHandle};
+
+    use rcgen::{
+        BasicConstraints, Certificate, CertificateParams, ExtendedKeyUsagePurpose, IsCa, KeyPair,
+    };
+
+    use crate::conn::tests::Random;
+    use crate::conn::{Config, Conn};
+
+    /// A CA made up for a test.
+    struct Ca {
+        certificate: Certificate,
+        key: KeyPair,
+    }
+
+    impl Ca {
+        fn new() -> Self {
+            let key = KeyPair::generate().unwrap();
+            let mut params = CertificateParams::new(Vec::new()).unwrap();
+            params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+            let certificate = params.self_signed(&key).unwrap();
+            Ca { certificate, key }
+        }
+
+        fn pem(&self) -> Vec<u8> {
+            self.certificate.pem().into_bytes()
+        }
+
+        /// A certificate for `name` and its key.
+        fn issue(&self, name: &str, usage: ExtendedKeyUsagePurpose) -> (Vec<u8>, Vec<u8>) {
+            let key = KeyPair::generate().unwrap();
+            let mut params = CertificateParams::new(vec![name.to_string()]).unwrap();
+            params.extended_key_usages = vec![usage];
+            let certificate = params
+                .signed_by(&key, &self.certificate, &self.key)
+                .unwrap();
+            (
+                certificate.pem().into_bytes(),
+                key.serialize_pem().into_bytes(),
+            )
+        }
+
+        fn server_config(&self) -> Arc<ServerConfig> {
+            let (chain, key) = self.issue("localhost", ExtendedKeyUsagePurpose::ServerAuth);
+            server_config(&self.pem(), &chain, &key).unwrap()
+        }
+
+        /// A client trusting `self`, with a certificate issued by `issuer`.
+        fn client_config(&self, issuer: &Ca) -> Arc<ClientConfig> {
+            let (chain, key) = issuer.issue("client", ExtendedKeyUsagePurpose::ClientAuth);
+            client_config(&self.pem(), &chain, &key).unwrap()
+        }
+    }
+
+    /// Accepts one connection over TLS and runs `handle` on the result.
+    fn start_server<F>(config: Arc<ServerConfig>, handle: F) -> (u16, JoinHandle<()>)
+    where
+        F: FnOnce(io::Result<Conn>) + Send + 'static,
+    {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let server = thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            handle(Conn::accept_tls(stream, config, Config::new()));
+        });
+        (port, server)
+    }
+
+    fn connect(port: u16, config: Arc<ClientConfig>) -> io::Result<Conn> {
+        let stream = TcpStream::connect(("127.0.0.1", port))?;
+        Conn::connect_tls(stream, "localhost", config, Config::new())
+    }
+
+    #[test]
+    fn test_keys_go_both_ways_over_mutual_tls() {
+        let ca = Ca::new();
+        let (port, server) = start_server(ca.server_config(), |conn| {
+            let conn = conn.unwrap();
+            let (key, mut reader) = conn.receive().unwrap().unwrap();
+            assert_eq!(key, "up");
+            let mut writer = conn.send("down").unwrap();
+            io::copy(&mut reader, &mut writer).unwrap();
+            writer.close().unwrap();
+            assert!(conn.receive().unwrap().is_none());
+            conn.close().unwrap();
+        });
+
+        let conn = connect(port, ca.client_config(&ca)).unwrap();
+        let mut data = vec![0; 3 << 20];
+        Random::new().fill(&mut data);
+        let mut writer = conn.send("up").unwrap();
+        writer.write_all(&data).unwrap();
+        writer.close().unwrap();
+
+        let (key, mut reader) = conn.receive().unwrap().unwrap();
+        assert_eq!(key, "down");
+        let mut echoed = Vec::new();
+        reader.read_to_end(&mut echoed).unwrap();
+        assert!(echoed == data);
+        conn.close().unwrap();
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn test_untrusted_client_is_rejected() {
+        let ca = Ca::new();
+        let (port, server) = start_server(ca.server_config(), |conn| {
+            let error = conn.err().unwrap();
+            assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+        });
+
+        // the handshake may complete on the client before the server
+        // checked its certificate
+        let result = connect(port, ca.client_config(&Ca::new())).and_then(|conn| conn.receive());
+        assert!(result.is_err());
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn test_untrusted_server_is_rejected() {
+        let (port, server) = start_server(Ca::new().server_config(), |conn| {
+            assert!(conn.is_err());
+        });
+
+        let ca = Ca::new();
+        let error = connect(port, ca.client_config(&ca)).err().unwrap();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+        server.join().unwrap();
+    }
+}
@@ -0,0 +1,312 @@
+//! Conversion of HDR frames to 8 bit sRGB for encoders and image formats
+//! that can't take more.
+//!
+//! Pixels are decoded to linear BT.709 light relative to SDR white, then
+//! compressed into `0..=1` by the [`ToneMap`] operator and sRGB encoded.
+
+use std::borrow::Cow;
+
+use crate::frame::{Frame, PixelFormat, BYTES_PER_PIXEL};
+
+/// Nits of scRGB 1.0, the SDR white Windows composes HDR desktops with by
+/// default.
+pub const SCRGB_WHITE_NITS: f32 = 80.0;
+
+/// How light brighter than SDR white is brought into range.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ToneMap {
+    /// Cut everything above white off, exact for SDR content.
+    Clip,
+    /// `x / (1 + x)`, keeps highlight detail but dims midtones.
+    #[default]
+    Reinhard,
+    /// Narkowicz's fit of the ACES filmic curve, more contrast than
+    /// Reinhard.
+    Aces,
+}
+
+impl ToneMap {
+    /// Map linear light, 1.0 being SDR white, into `0..=1`.
+    pub fn apply(&self, x: f32) -> f32 {
+        let x = x.max(0.0);
+        let mapped = match self {
+            ToneMap::Clip => x,
+            ToneMap::Reinhard => x / (1.0 + x),
+            ToneMap::Aces => (x * (2.51 * x + 0.03)) / (x * (2.43 * x + 0.59) + 0.14),
+        };
+        mapped.min(1.0)
+    }
+}
+
+/// Turns frames of any [`PixelFormat`] into BGRA8 ones.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ToneMapper {
+    operator: ToneMap,
+    white_nits: f32,
+}
+
+impl Default for ToneMapper {
+    fn default() -> Self {
+        Self::new(ToneMap::default())
+    }
+}
+
+impl ToneMapper {
+    pub fn new(operator: ToneMap) -> Self {
+        Self {
+            operator,
+            white_nits: SCRGB_WHITE_NITS,
+        }
+    }
+
+    /// Luminance that ends up as SDR white before tone-mapping, the "SDR
+    /// content brightness" of the display. Defaults to
+    /// [`SCRGB_WHITE_NITS`].
+    pub fn white_nits(mut self, nits: f32) -> Self {
+        self.white_nits = nits;
+        self
+    }
+
+    /// A BGRA8 copy of `frame`.
+    pub fn map(&self, frame: &Frame) -> Frame {
+        self.map_into(frame, Vec::new())
+    }
+
+    /// Like [`map`](Self::map), storing the pixels in `buffer`.
+    pub fn map_into(&self, frame: &Frame, mut buffer: Vec<u8>) -> Frame {
+        buffer.clear();
+        buffer.reserve(frame.width as usize * frame.height as usize * BYTES_PER_PIXEL);
+
+        let bytes_per_pixel = frame.format.bytes_per_pixel();
+        for y in 0..frame.height {
+            let row = frame.row(y);
+            if frame.format == PixelFormat::Bgra8 {
+                buffer.extend_from_slice(row);
+                continue;
+            }
+            for px in row.chunks_exact(bytes_per_pixel) {
+                let [r, g, b, a] = self.linear(frame.format, px);
+                buffer.extend_from_slice(&[
+                    srgb_encode(self.operator.apply(b)),
+                    srgb_encode(self.operator.apply(g)),
+                    srgb_encode(self.operator.apply(r)),
+                    (a.clamp(0.0, 1.0) * 255.0).round() as u8,
+                ]);
+            }
+        }
+
+        Frame::from_bgra(frame.width, frame.height, buffer)
+            .expect("a BGRA8 pixel written for every source pixel")
+            .with_origin(frame.origin.0, frame.origin.1)
+            .with_info(frame.info)
+    }
+
+    /// Linear BT.709 RGB relative to white, and alpha.
+    fn linear(&self, format: PixelFormat, px: &[u8]) -> [f32; 4] {
+        match format {
+            PixelFormat::Bgra8 => [
+                srgb_decode(px[2]),
+                srgb_decode(px[1]),
+                srgb_decode(px[0]),
+                px[3] as f32 / 255.0,
+            ],
+            PixelFormat::Rgb10A2 => {
+                let v = u32::from_le_bytes([px[0], px[1], px[2], px[3]]);
+                let channel = |shift: u32| pq_eotf(((v >> shift) & 0x3FF) as f32 / 1023.0);
+                let [r, g, b] = bt2020_to_bt709([channel(0), channel(10), channel(20)]);
+                let scale = 1.0 / self.white_nits;
+                [r * scale, g * scale, b * scale, (v >> 30) as f32 / 3.0]
+            }
+            PixelFormat::Rgba16Float => {
+                let channel = |i: usize| f16_to_f32(u16::from_le_bytes([px[i], px[i + 1]]));
+                let scale = SCRGB_WHITE_NITS / self.white_nits;
+                [
+                    channel(0) * scale,
+                    channel(2) * scale,
+                    channel(4) * scale,
+                    channel(6),
+                ]
+            }
+        }
+    }
+}
+
+impl Frame {
+    /// The frame as BGRA8, borrowed if it is already and tone mapped with
+    /// the default [`ToneMapper`] otherwise.
+    pub fn to_sdr(&self) -> Cow<'_, Frame> {
+        if self.format.is_hdr() {
+            Cow::Owned(ToneMapper::default().map(self))
+        } else {
+            Cow::Borrowed(self)
+        }
+    }
+}
+
+/// Absolute luminance in nits of a PQ (SMPTE ST 2084) encoded value.
+fn pq_eotf(e: f32) -> f32 {
+    const M1: f32 = 2610.0 / 16384.0;
+    const M2: f32 = 2523.0 / 4096.0 * 128.0;
+    const C1: f32 = 3424.0 / 4096.0;
+    const C2: f32 = 2413.0 / 4096.0 * 32.0;
+    const C3: f32 = 2392.0 / 4096.0 * 32.0;
+
+    let p = e.clamp(0.0, 1.0).powf(1.0 / M2);
+    10000.0 * ((p - C1).max(0.0) / (C2 - C3 * p)).powf(1.0 / M1)
+}
+
+fn bt2020_to_bt709([r, g, b]: [f32; 3]) -> [f32; 3] {
+    [
+        1.6605 * r - 0.5876 * g - 0.0728 * b,
+        -0.1246 * r + 1.1329 * g - 0.0083 * b,
+        -0.0182 * r - 0.1006 * g + 1.1187 * b,
+    ]
+}
+
+fn srgb_encode(x: f32) -> u8 {
+    let v = if x <= 0.003_130_8 {
+        12.92 * x
+    } else {
+        1.055 * x.powf(1.0 / 2.4) - 0.055
+    };
+    (v.clamp(0.0, 1.0) * 255.0).round() as u8
+}
+
+fn srgb_decode(v: u8) -> f32 {
+    let v = v as f32 / 255.0;
+    if v <= 0.040_45 {
+        v / 12.92
+    } else {
+        ((v + 0.055) / 1.055).powf(2.4)
+    }
+}
+
+/// IEEE 754 binary16 to `f32`, exact for every value.
+fn f16_to_f32(h: u16) -> f32 {
+    let sign = if h & 0x8000 != 0 { -1.0 } else { 1.0 };
+    let exponent = ((h >> 10) & 0x1F) as i32;
+    let mantissa = (h & 0x3FF) as f32;
+
+    sign * match exponent {
+        0 => mantissa * 2f32.powi(-24),
+        0x1F if mantissa == 0.0 => f32::INFINITY,
+        0x1F => f32::NAN,
+        _ => (1.0 + mantissa / 1024.0) * 2f32.powi(exponent - 15),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn f16_bytes(values: [u16; 4]) -> Vec<u8> {
+        values.iter().flat_map(|v| v.to_le_bytes()).collect()
+    }
+
+    /// Inverse of [`pq_eotf`], for encoding test pixels.
+    fn pq_encode(nits: f32) -> u32 {
+        const M1: f32 = 2610.0 / 16384.0;
+        const M2: f32 = 2523.0 / 4096.0 * 128.0;
+        const C1: f32 = 3424.0 / 4096.0;
+        const C2: f32 = 2413.0 / 4096.0 * 32.0;
+        const C3: f32 = 2392.0 / 4096.0 * 32.0;
+
+        let y = (nits / 10000.0).powf(M1);
+        let e = ((C1 + C2 * y) / (1.0 + C3 * y)).powf(M2);
+        (e * 1023.0).round() as u32
+    }
+
+    #[test]
+    fn test_f16_to_f32() {
+        assert_eq!(f16_to_f32(0x3C00), 1.0);
+        assert_eq!(f16_to_f32(0x3800), 0.5);
+        assert_eq!(f16_to_f32(0x4400), 4.0);
+        assert_eq!(f16_to_f32(0xC000), -2.0);
+        assert_eq!(f16_to_f32(0x0001), 2f32.powi(-24));
+        assert_eq!(f16_to_f32(0x7C00), f32::INFINITY);
+        assert!(f16_to_f32(0x7E00).is_nan());
+    }
+
+    #[test]
+    fn test_operators() {
+        for op in [ToneMap::Clip, ToneMap::Reinhard, ToneMap::Aces] {
+            assert_eq!(op.apply(0.0), 0.0);
+            assert_eq!(op.apply(-1.0), 0.0);
+            assert!(op.apply(1000.0) <= 1.0);
+            assert!(op.apply(0.5) < op.apply(2.0));
+        }
+        assert_eq!(ToneMap::Clip.apply(4.0), 1.0);
+        assert_eq!(ToneMap::Reinhard.apply(4.0), 0.8);
+        assert!(ToneMap::Aces.apply(4.0) > ToneMap::Reinhard.apply(4.0));
+    }
+
+    #[test]
+    fn test_scrgb() {
+        // white, half, 4x brighter than white, out of gamut negative
+        let data = [
+            f16_bytes([0x3C00, 0x3C00, 0x3C00, 0x3C00]),
+            f16_bytes([0x3800, 0x3800, 0x3800, 0x3C00]),
+            f16_bytes([0x4400, 0x0000, 0x0000, 0x3C00]),
+            f16_bytes([0xBC00, 0x3C00, 0x0000, 0x3800]),
+        ]
+        .concat();
+        let frame = Frame::from_raw(4, 1, PixelFormat::Rgba16Float, data)
+            .unwrap()
+            .with_origin(10, 20);
+
+        let clipped = ToneMapper::new(ToneMap::Clip).map(&frame);
+        assert_eq!(clipped.format, PixelFormat::Bgra8);
+        assert_eq!(clipped.origin, (10, 20));
+        assert_eq!(clipped.pixel(0, 0), [255, 255, 255, 255]);
+        assert_eq!(clipped.pixel(1, 0), [188, 188, 188, 255]);
+        assert_eq!(clipped.pixel(2, 0), [0, 0, 255, 255]);
+        assert_eq!(clipped.pixel(3, 0), [0, 255, 0, 128]);
+
+        // 0.8 linear
+        let reinhard = ToneMapper::new(ToneMap::Reinhard).map(&frame);
+        assert_eq!(reinhard.pixel(2, 0), [0, 0, 231, 255]);
+
+        // a brighter SDR white dims everything
+        let dimmed = ToneMapper::new(ToneMap::Clip).white_nits(160.0).map(&frame);
+        assert_eq!(dimmed.pixel(0, 0), [188, 188, 188, 255]);
+    }
+
+    #[test]
+    fn test_hdr10() {
+        let pixel =
+            |r: u32, g: u32, b: u32, a: u32| (r | g << 10 | b << 20 | a << 30).to_le_bytes();
+        let white = pq_encode(SCRGB_WHITE_NITS);
+        let data = [
+            pixel(white, white, white, 3),
+            pixel(0, 0, 0, 0),
+            pixel(1023, 1023, 1023, 3),
+        ]
+        .concat();
+        let frame = Frame::from_raw(3, 1, PixelFormat::Rgb10A2, data).unwrap();
+        assert!(frame.format.is_hdr());
+
+        let mapped = ToneMapper::new(ToneMap::Clip).map(&frame);
+        // 10 bit quantisation of PQ is a bit off at 80 nits
+        for channel in &mapped.pixel(0, 0)[..3] {
+            assert!(channel.abs_diff(255) <= 2, "{:?}", mapped.pixel(0, 0));
+        }
+        assert_eq!(mapped.pixel(1, 0), [0, 0, 0, 0]);
+        assert_eq!(mapped.pixel(2, 0), [255, 255, 255, 255]);
+
+        // 10000 nits stays below white with a curve
+        let aces = ToneMapper::new(ToneMap::Aces).map(&frame);
+        assert_eq!(aces.pixel(2, 0), [255, 255, 255, 255]);
+        let reinhard = ToneMapper::new(ToneMap::Reinhard).map(&frame);
+        assert!(reinhard.pixel(2, 0)[0] < 255);
+        assert!(reinhard.pixel(0, 0)[0] < mapped.pixel(0, 0)[0]);
+    }
+
+    #[test]
+    fn test_sdr_passes_through() {
+        let mut frame = Frame::new(2, 2);
+        frame.set_pixel(1, 1, [1, 2, 3, 4]);
+
+        assert_eq!(ToneMapper::default().map(&frame), frame);
+        assert_eq!(srgb_encode(srgb_decode(200)), 200);
+    }
+}
@@ -0,0 +1,359 @@
+use std::fmt;
+
+use crate::capture::Backend;
+use crate::error::{Error, Result};
+use crate::frame::{Frame, Rect};
+use crate::output::{self, Adapter};
+
+/// A native top level window handle, `HWND` on Windows, the XID on X11.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct WindowId(pub isize);
+
+/// A top level window as listed by [`list_windows`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct WindowInfo {
+    pub id: WindowId,
+    pub title: String,
+    /// The owning process, if the platform (or on X11 the client) tells.
+    pub pid: Option<u32>,
+    /// In desktop coordinates.
+    pub rect: Rect,
+}
+
+/// Which window to capture.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum WindowTarget {
+    Id(WindowId),
+    /// The first window whose title contains this, ignoring case.
+    Title(String),
+    /// The first window of this process.
+    Process(u32),
+}
+
+impl WindowTarget {
+    pub fn matches(&self, window: &WindowInfo) -> bool {
+        match self {
+            WindowTarget::Id(id) => window.id == *id,
+            WindowTarget::Title(title) => {
+                window.title.to_lowercase().contains(&title.to_lowercase())
+            }
+            WindowTarget::Process(pid) => window.pid == Some(*pid),
+        }
+    }
+}
+
+impl fmt::Display for WindowTarget {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            WindowTarget::Id(id) => write!(f, "window {}", id.0),
+            WindowTarget::Title(title) => write!(f, "window titled {:?}", title),
+            WindowTarget::Process(pid) => write!(f, "window of process {}", pid),
+        }
+    }
+}
+
+/// The first window matching `target`.
+pub fn find_window(target: &WindowTarget) -> Result<WindowInfo> {
+    list_windows()?
+        .into_iter()
+        .find(|window| target.matches(window))
+        .ok_or_else(|| Error::backend(format!("no {}", target)))
+}
+
+/// The window's rectangle in desktop coordinates, `None` if the window is gone
+/// or the platform can't tell.
+#[cfg(windows)]
+pub fn window_rect(id: WindowId) -> Option<Rect> {
+    use windows::Win32::Foundation::{HWND, RECT};
+    use windows::Win32::UI::WindowsAndMessaging::GetWindowRect;
+
+    let mut rect = RECT::default();
+    unsafe { GetWindowRect(HWND(id.0 as *mut _), &mut rect) }.ok()?;
+
+    Some(Rect::new(
+        rect.left,
+        rect.top,
+        (rect.right - rect.left).max(0) as u32,
+        (rect.bottom - rect.top).max(0) as u32,
+    ))
+}
+
+#[cfg(target_os = "linux")]
+pub fn window_rect(id: WindowId) -> Option<Rect> {
+    crate::x11::Display::shared()
+        .and_then(|display| display.window_rect(id.0 as u32))
+        .ok()
+        .flatten()
+}
+
+#[cfg(not(any(windows, target_os = "linux")))]
+pub fn window_rect(_id: WindowId) -> Option<Rect> {
+    None
+}
+
+/// Visible top level windows.
+#[cfg(windows)]
+pub fn list_windows() -> Result<Vec<WindowInfo>> {
+    use windows::Win32::Foundation::{BOOL, HWND, LPARAM};
+    use windows::Win32::UI::WindowsAndMessaging::{
+        EnumWindows, GetWindowTextW, GetWindowThreadProcessId, IsWindowVisible,
+    };
+
+    unsafe extern "system" fn collect(hwnd: HWND, lparam: LPARAM) -> BOOL {
+        let handles = &mut *(lparam.0 as *mut Vec<HWND>);
+        if IsWindowVisible(hwnd).as_bool() {
+            handles.push(hwnd);
+        }
+        BOOL::from(true)
+    }
+
+    let mut handles: Vec<HWND> = Vec::new();
+    unsafe { EnumWindows(Some(collect), LPARAM(&mut handles as *mut _ as isize)) }
+        .map_err(Error::backend)?;
+
+    Ok(handles
+        .into_iter()
+        .filter_map(|hwnd| {
+            let id = WindowId(hwnd.0 as isize);
+            let rect = window_rect(id)?;
+
+            let mut title = [0u16; 512];
+            let len = unsafe { GetWindowTextW(hwnd, &mut title) }.max(0) as usize;
+            let mut pid = 0;
+            unsafe { GetWindowThreadProcessId(hwnd, Some(&mut pid)) };
+
+            Some(WindowInfo {
+                id,
+                title: String::from_utf16_lossy(&title[..len]),
+                pid: (pid != 0).then_some(pid),
+                rect,
+            })
+        })
+        .collect())
+}
+
+#[cfg(target_os = "linux")]
+pub fn list_windows() -> Result<Vec<WindowInfo>> {
+    crate::x11::Display::shared()?.list_windows()
+}
+
+#[cfg(not(any(windows, target_os = "linux")))]
+pub fn list_windows() -> Result<Vec<WindowInfo>> {
+    Err(Error::backend(
+        "listing windows isn't supported on this platform",
+    ))
+}
+
+/// Capture the window's own contents, `buffer` is reused for the pixels.
+///
+/// On Windows this uses `PrintWindow`, which renders windows even while
+/// they are covered by others.
+#[cfg(windows)]
+pub fn capture_window(id: WindowId, mut buffer: Vec<u8>) -> Result<Frame> {
+    use windows::Win32::Foundation::HWND;
+    use windows::Win32::Graphics::Gdi::{
+        CreateCompatibleBitmap, CreateCompatibleDC, DeleteDC, DeleteObject, GetDC, GetDIBits,
+        ReleaseDC, SelectObject, BITMAPINFO, BITMAPINFOHEADER, BI_RGB, DIB_RGB_COLORS,
+    };
+    use windows::Win32::Storage::Xps::{PrintWindow, PRINT_WINDOW_FLAGS};
+
+    use crate::frame::FrameInfo;
+
+    // not in the metadata yet, renders DirectComposition content as well
+    const PW_RENDERFULLCONTENT: PRINT_WINDOW_FLAGS = PRINT_WINDOW_FLAGS(2);
+
+    let rect = window_rect(id).ok_or(Error::AccessLost)?;
+    if rect.is_empty() {
+        return Err(Error::InvalidGeometry("minimized window".to_string()));
+    }
+
+    let hwnd = HWND(id.0 as *mut _);
+    let (width, height) = (rect.width as i32, rect.height as i32);
+    buffer.resize(rect.width as usize * rect.height as usize * 4, 0);
+
+    let copied = unsafe {
+        let screen_dc = GetDC(HWND::default());
+        let memory_dc = CreateCompatibleDC(screen_dc);
+        let bitmap = CreateCompatibleBitmap(screen_dc, width, height);
+        let previous = SelectObject(memory_dc, bitmap);
+
+        let printed = PrintWindow(hwnd, memory_dc, PW_RENDERFULLCONTENT).as_bool();
+        let mut bitmap_info = BITMAPINFO {
+            bmiHeader: BITMAPINFOHEADER {
+                biSize: size_of::<BITMAPINFOHEADER>() as u32,
+                biWidth: width,
+                // negative for top-down rows
+                biHeight: -height,
+                biPlanes: 1,
+                biBitCount: 32,
+                biCompression: BI_RGB.0,
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let lines = printed.then(|| {
+            GetDIBits(
+                memory_dc,
+                bitmap,
+                0,
+                height as u32,
+                Some(buffer.as_mut_ptr().cast()),
+                &mut bitmap_info,
+                DIB_RGB_COLORS,
+            )
+        });
+
+        SelectObject(memory_dc, previous);
+        let _ = DeleteObject(bitmap);
+        let _ = DeleteDC(memory_dc);
+        ReleaseDC(HWND::default(), screen_dc);
+
+        lines == Some(height)
+    };
+    if !copied {
+        return Err(Error::backend(format!("PrintWindow failed for {:?}", id)));
+    }
+
+    // GDI leaves the alpha channel undefined
+    buffer.chunks_exact_mut(4).for_each(|px| px[3] = 0xFF);
+
+    // PrintWindow renders the window anew every time
+    let info = FrameInfo {
+        accumulated_frames: 1,
+        ..Default::default()
+    };
+    Frame::from_bgra(rect.width, rect.height, buffer)
+        .map(|frame| frame.with_origin(rect.x, rect.y).with_info(info))
+        .ok_or_else(|| Error::InvalidGeometry("window bitmap too small".to_string()))
+}
+
+#[cfg(target_os = "linux")]
+pub fn capture_window(id: WindowId, buffer: Vec<u8>) -> Result<Frame> {
+    crate::x11::Display::shared()?.capture_window(id.0 as u32, buffer)
+}
+
+#[cfg(not(any(windows, target_os = "linux")))]
+pub fn capture_window(_id: WindowId, _buffer: Vec<u8>) -> Result<Frame> {
+    Err(Error::backend(
+        "window capture isn't supported on this platform",
+    ))
+}
+
+/// [`Backend`] capturing a single window.
+///
+/// When the window can't be captured directly, e.g. it reaches beyond the
+/// screen on X11, it is cropped out of frames of the fallback output
+/// backend. A closed window is reported as [`Error::AccessLost`], so a
+/// [`Session`](crate::capture::Session) looks it up again by its target:
+/// title and process targets are retried with its
+/// [`Backoff`](crate::capture::Backoff) until a matching window shows up,
+/// the session ends when a window id is gone.
+pub struct WindowBackend {
+    target: WindowTarget,
+    window: Option<WindowId>,
+    fallback: Option<Box<dyn Backend>>,
+    captured: i64,
+}
+
+impl WindowBackend {
+    pub fn new(target: WindowTarget) -> Self {
+        Self {
+            target,
+            window: None,
+            fallback: None,
+            captured: 0,
+        }
+    }
+
+    /// Crop from the frames of `output` when direct capture fails.
+    pub fn fallback<B: Backend + 'static>(mut self, output: B) -> Self {
+        self.fallback = Some(Box::new(output));
+        self
+    }
+
+    pub fn window(&self) -> Option<WindowId> {
+        self.window
+    }
+}
+
+impl Backend for WindowBackend {
+    fn open(&mut self) -> Result<Rect> {
+        self.window = None;
+        let window = match self.target {
+            WindowTarget::Id(id) => WindowInfo {
+                id,
+                title: String::new(),
+                pid: None,
+                rect: window_rect(id)
+                    .ok_or_else(|| Error::backend(format!("no {}", self.target)))?,
+            },
+            // another window may match later, e.g. the application restarted
+            _ => list_windows()?
+                .into_iter()
+                .find(|window| self.target.matches(window))
+                .ok_or(Error::AccessLost)?,
+        };
+
+        if let Some(fallback) = self.fallback.as_mut() {
+            fallback.open()?;
+        }
+        self.window = Some(window.id);
+        Ok(window.rect)
+    }
+
+    fn next_frame(&mut self) -> Result<Frame> {
+        self.next_frame_into(Vec::new())
+    }
+
+    fn next_frame_into(&mut self, buffer: Vec<u8>) -> Result<Frame> {
+        let id = self.window.ok_or(Error::AccessLost)?;
+        let rect = window_rect(id).ok_or(Error::AccessLost)?;
+
+        match (capture_window(id, buffer), self.fallback.as_mut()) {
+            (Ok(mut frame), _) => {
+                // neither PrintWindow nor GetImage report presents, count
+                // captures so pacing on change still sees progress
+                self.captured += 1;
+                frame.info.last_present_time = self.captured;
+                Ok(frame)
+            }
+            (Err(Error::AccessLost), _) => Err(Error::AccessLost),
+            (Err(_), Some(fallback)) => fallback.next_frame()?.crop(&rect).ok_or_else(|| {
+                Error::InvalidGeometry(format!("{} is off the output", self.target))
+            }),
+            (Err(e), None) => Err(e),
+        }
+    }
+
+    fn adapters(&mut self) -> Result<Vec<Adapter>> {
+        match self.fallback.as_mut() {
+            Some(fallback) => fallback.adapters(),
+            None => output::adapters(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_target_matches() {
+        let window = WindowInfo {
+            id: WindowId(7),
+            title: "Untitled - Notepad".to_string(),
+            pid: Some(42),
+            rect: Rect::new(0, 0, 10, 10),
+        };
+
+        assert!(WindowTarget::Id(WindowId(7)).matches(&window));
+        assert!(WindowTarget::Title("notepad".to_string()).matches(&window));
+        assert!(!WindowTarget::Title("Paint".to_string()).matches(&window));
+        assert!(WindowTarget::Process(42).matches(&window));
+        assert!(!WindowTarget::Process(43).matches(&window));
+        assert_eq!(
+            WindowTarget::Title("a".to_string()).to_string(),
+            "window titled \"a\""
+        );
+    }
+}
@@ -0,0 +1,613 @@
+//! Capture on X11 through core protocol `GetImage`, which also works on a
+//! headless Xvfb server.
+
+use std::sync::{Arc, Mutex};
+
+use x11rb::connection::{Connection, RequestConnection};
+use x11rb::protocol::randr::{self, ConnectionExt as _, ModeInfo};
+use x11rb::protocol::xproto::{
+    Atom, AtomEnum, ConnectionExt as _, Drawable, ImageFormat, ImageOrder, MapState, Window,
+};
+use x11rb::rust_connection::RustConnection;
+
+use crate::capture::Backend;
+use crate::error::{Error, Result};
+use crate::frame::{Frame, FrameInfo, Rect, BYTES_PER_PIXEL};
+use crate::output::{Adapter, Output, Rotation};
+use crate::window::{WindowId, WindowInfo};
+
+static DISPLAY: Mutex<Option<Arc<Display>>> = Mutex::new(None);
+
+/// A connection to the X server named by `$DISPLAY` and its default screen.
+pub struct Display {
+    conn: RustConnection,
+    screen: usize,
+}
+
+impl Display {
+    pub fn connect(name: Option<&str>) -> Result<Self> {
+        let (conn, screen) = x11rb::connect(name).map_err(Error::backend)?;
+        Ok(Self { conn, screen })
+    }
+
+    /// The connection shared by the free functions of [`crate::window`],
+    /// made on first use and again after it broke.
+    pub fn shared() -> Result<Arc<Self>> {
+        let mut shared = DISPLAY.lock().unwrap();
+        if let Some(display) = shared.as_ref() {
+            // a cheap round trip to find out whether the server is still there
+            let alive = display
+                .conn
+                .get_input_focus()
+                .ok()
+                .and_then(|c| c.reply().ok());
+            if alive.is_some() {
+                return Ok(display.clone());
+            }
+        }
+
+        let display = Arc::new(Self::connect(None)?);
+        *shared = Some(display.clone());
+        Ok(display)
+    }
+
+    pub fn root(&self) -> Window {
+        self.conn.setup().roots[self.screen].root
+    }
+
+    /// The whole screen in desktop coordinates.
+    pub fn screen_rect(&self) -> Rect {
+        let screen = &self.conn.setup().roots[self.screen];
+        Rect::new(
+            0,
+            0,
+            screen.width_in_pixels as u32,
+            screen.height_in_pixels as u32,
+        )
+    }
+
+    /// Top level windows, from the window manager's `_NET_CLIENT_LIST` or
+    /// the mapped children of the root window without one.
+    pub fn list_windows(&self) -> Result<Vec<WindowInfo>> {
+        let client_list = self.atom(b"_NET_CLIENT_LIST")?;
+        let mut ids: Vec<Window> = self
+            .property(self.root(), client_list, AtomEnum::WINDOW.into())?
+            .and_then(|reply| reply.value32().map(|ids| ids.collect()))
+            .unwrap_or_default();
+
+        if ids.is_empty() {
+            let tree = self
+                .conn
+                .query_tree(self.root())
+                .map_err(Error::backend)?
+                .reply()
+                .map_err(Error::backend)?;
+            for child in tree.children {
+                let attributes = self
+                    .conn
+                    .get_window_attributes(child)
+                    .map_err(Error::backend)?
+                    .reply()
+                    .map_err(Error::backend)?;
+                if attributes.map_state == MapState::VIEWABLE && !attributes.override_redirect {
+                    ids.push(child);
+                }
+            }
+        }
+
+        ids.into_iter()
+            .filter_map(|window| self.window_info(window).transpose())
+            .collect()
+    }
+
+    /// `None` if the window doesn't exist (anymore).
+    pub fn window_info(&self, window: Window) -> Result<Option<WindowInfo>> {
+        let Some(rect) = self.window_rect(window)? else {
+            return Ok(None);
+        };
+
+        Ok(Some(WindowInfo {
+            id: WindowId(window as isize),
+            title: self.title(window)?,
+            pid: self.pid(window)?,
+            rect,
+        }))
+    }
+
+    /// The window's rectangle in root coordinates, `None` if it doesn't exist.
+    pub fn window_rect(&self, window: Window) -> Result<Option<Rect>> {
+        let Ok(geometry) = self
+            .conn
+            .get_geometry(window)
+            .map_err(Error::backend)?
+            .reply()
+        else {
+            return Ok(None);
+        };
+        let Ok(position) = self
+            .conn
+            .translate_coordinates(window, self.root(), 0, 0)
+            .map_err(Error::backend)?
+            .reply()
+        else {
+            return Ok(None);
+        };
+
+        Ok(Some(Rect::new(
+            position.dst_x as i32,
+            position.dst_y as i32,
+            geometry.width as u32,
+            geometry.height as u32,
+        )))
+    }
+
+    /// The contents of a mapped window.
+    ///
+    /// Without a compositor, parts covered by other windows come back with
+    /// whatever is on top of them, and windows reaching beyond the screen
+    /// can't be read at all.
+    pub fn capture_window(&self, window: Window, buffer: Vec<u8>) -> Result<Frame> {
+        let attributes = self
+            .conn
+            .get_window_attributes(window)
+            .map_err(Error::backend)?
+            .reply()
+            .map_err(|_| Error::AccessLost)?;
+        if attributes.map_state != MapState::VIEWABLE {
+            return Err(Error::backend("window isn't viewable"));
+        }
+
+        let rect = self.window_rect(window)?.ok_or(Error::AccessLost)?;
+        let frame = self.capture(window, rect.width, rect.height, buffer)?;
+        Ok(frame.with_origin(rect.x, rect.y))
+    }
+
+    /// Read `width x height` pixels from the top-left of `drawable`.
+    pub fn capture(
+        &self,
+        drawable: Drawable,
+        width: u32,
+        height: u32,
+        mut buffer: Vec<u8>,
+    ) -> Result<Frame> {
+        let setup = self.conn.setup();
+        if setup.image_byte_order != ImageOrder::LSB_FIRST {
+            return Err(Error::UnsupportedFormat("MSB first images".to_string()));
+        }
+
+        let image = self
+            .conn
+            .get_image(
+                ImageFormat::Z_PIXMAP,
+                drawable,
+                0,
+                0,
+                width as u16,
+                height as u16,
+                !0,
+            )
+            .map_err(Error::backend)?
+            .reply()
+            .map_err(Error::backend)?;
+
+        // 24 and 32 bit TrueColor visuals are stored as BGRX, anything else
+        // would need a palette or bit fiddling
+        let bits_per_pixel = setup
+            .pixmap_formats
+            .iter()
+            .find(|format| format.depth == image.depth)
+            .map(|format| format.bits_per_pixel);
+        if bits_per_pixel != Some(32) || image.depth < 24 {
+            return Err(Error::UnsupportedFormat(format!(
+                "depth {} at {:?} bits per pixel",
+                image.depth, bits_per_pixel
+            )));
+        }
+
+        buffer.clear();
+        buffer.extend_from_slice(&image.data);
+        buffer
+            .chunks_exact_mut(BYTES_PER_PIXEL)
+            .for_each(|px| px[3] = 0xFF);
+
+        let info = FrameInfo {
+            accumulated_frames: 1,
+            ..Default::default()
+        };
+        Frame::from_bgra(width, height, buffer)
+            .map(|frame| frame.with_info(info))
+            .ok_or_else(|| Error::InvalidGeometry(format!("short image for {}x{}", width, height)))
+    }
+
+    /// The screen as a single adapter with one output per connected RandR
+    /// output, or one output covering the screen if RandR isn't available.
+    pub fn adapters(&self) -> Result<Vec<Adapter>> {
+        let outputs = match self.randr_outputs()? {
+            Some(outputs) if !outputs.is_empty() => outputs,
+            _ => vec![Output {
+                index: 0,
+                name: format!("screen-{}", self.screen),
+                description: String::new(),
+                width: self.screen_rect().width,
+                height: self.screen_rect().height,
+                refresh_rate: None,
+                rotation: Rotation::Identity,
+                desktop: self.screen_rect(),
+                attached_to_desktop: true,
+                primary: true,
+            }],
+        };
+
+        Ok(vec![Adapter {
+            index: self.screen as u32,
+            name: format!("screen {}", self.screen),
+            description: String::from_utf8_lossy(&self.conn.setup().vendor).into_owned(),
+            software: false,
+            outputs,
+        }])
+    }
+
+    fn randr_outputs(&self) -> Result<Option<Vec<Output>>> {
+        let present = self
+            .conn
+            .extension_information(randr::X11_EXTENSION_NAME)
+            .map_err(Error::backend)?
+            .is_some();
+        if !present {
+            return Ok(None);
+        }
+
+        // GetScreenResourcesCurrent and GetOutputPrimary need 1.3
+        let version = self
+            .conn
+            .randr_query_version(1, 3)
+            .map_err(Error::backend)?
+            .reply()
+            .map_err(Error::backend)?;
+        if (version.major_version, version.minor_version) < (1, 3) {
+            return Ok(None);
+        }
+
+        let root = self.root();
+        let resources = self
+            .conn
+            .randr_get_screen_resources_current(root)
+            .map_err(Error::backend)?
+            .reply()
+            .map_err(Error::backend)?;
+        let primary = self
+            .conn
+            .randr_get_output_primary(root)
+            .map_err(Error::backend)?
+            .reply()
+            .map_err(Error::backend)?
+            .output;
+
+        let mut outputs = Vec::new();
+        for (index, &output) in resources.outputs.iter().enumerate() {
+            let info = self
+                .conn
+                .randr_get_output_info(output, resources.config_timestamp)
+                .map_err(Error::backend)?
+                .reply()
+                .map_err(Error::backend)?;
+            if info.connection != randr::Connection::CONNECTED {
+                continue;
+            }
+
+            let crtc = match info.crtc {
+                0 => None,
+                crtc => Some(
+                    self.conn
+                        .randr_get_crtc_info(crtc, resources.config_timestamp)
+                        .map_err(Error::backend)?
+                        .reply()
+                        .map_err(Error::backend)?,
+                ),
+            };
+            let mode = crtc
+                .as_ref()
+                .and_then(|crtc| resources.modes.iter().find(|mode| mode.id == crtc.mode));
+
+            outputs.push(Output {
+                index: index as u32,
+                name: String::from_utf8_lossy(&info.name).into_owned(),
+                description: String::new(),
+                width: mode.map_or(0, |mode| mode.width as u32),
+                height: mode.map_or(0, |mode| mode.height as u32),
+                refresh_rate: mode.and_then(refresh_rate),
+                rotation: crtc
+                    .as_ref()
+                    .map_or(Rotation::Identity, |crtc| rotation(crtc.rotation)),
+                desktop: crtc.as_ref().map_or(Rect::default(), |crtc| {
+                    Rect::new(
+                        crtc.x as i32,
+                        crtc.y as i32,
+                        crtc.width as u32,
+                        crtc.height as u32,
+                    )
+                }),
+                attached_to_desktop: crtc.is_some(),
+                primary: output == primary,
+            });
+        }
+
+        Ok(Some(outputs))
+    }
+
+    fn atom(&self, name: &[u8]) -> Result<Atom> {
+        Ok(self
+            .conn
+            .intern_atom(false, name)
+            .map_err(Error::backend)?
+            .reply()
+            .map_err(Error::backend)?
+            .atom)
+    }
+
+    fn property(
+        &self,
+        window: Window,
+        property: Atom,
+        kind: Atom,
+    ) -> Result<Option<x11rb::protocol::xproto::GetPropertyReply>> {
+        let reply = self
+            .conn
+            .get_property(false, window, property, kind, 0, u32::MAX)
+            .map_err(Error::backend)?
+            .reply()
+            .map_err(Error::backend)?;
+        Ok((reply.format != 0).then_some(reply))
+    }
+
+    fn title(&self, window: Window) -> Result<String> {
+        let utf8_string = self.atom(b"UTF8_STRING")?;
+        let net_wm_name = self.atom(b"_NET_WM_NAME")?;
+        let reply = match self.property(window, net_wm_name, utf8_string)? {
+            Some(reply) => Some(reply),
+            None => self.property(window, AtomEnum::WM_NAME.into(), AtomEnum::ANY.into())?,
+        };
+
+        Ok(reply
+            .map(|reply| String::from_utf8_lossy(&reply.value).into_owned())
+            .unwrap_or_default())
+    }
+
+    fn pid(&self, window: Window) -> Result<Option<u32>> {
+        let net_wm_pid = self.atom(b"_NET_WM_PID")?;
+        Ok(self
+            .property(window, net_wm_pid, AtomEnum::CARDINAL.into())?
+            .and_then(|reply| reply.value32().and_then(|mut values| values.next())))
+    }
+}
+
+/// Vertical refresh in Hz from the mode timings.
+fn refresh_rate(mode: &ModeInfo) -> Option<f64> {
+    let mut lines = mode.vtotal as f64;
+    if mode.mode_flags.contains(randr::ModeFlag::DOUBLE_SCAN) {
+        lines *= 2.0;
+    }
+    if mode.mode_flags.contains(randr::ModeFlag::INTERLACE) {
+        lines /= 2.0;
+    }
+
+    let pixels = mode.htotal as f64 * lines;
+    (pixels > 0.0).then(|| mode.dot_clock as f64 / pixels)
+}
+
+fn rotation(rotation: randr::Rotation) -> Rotation {
+    if rotation.contains(randr::Rotation::ROTATE90) {
+        Rotation::Rotate90
+    } else if rotation.contains(randr::Rotation::ROTATE180) {
+        Rotation::Rotate180
+    } else if rotation.contains(randr::Rotation::ROTATE270) {
+        Rotation::Rotate270
+    } else {
+        Rotation::Identity
+    }
+}
+
+/// [`Backend`] reading the whole X screen.
+pub struct X11Backend {
+    display_name: Option<String>,
+    display: Option<Display>,
+    captured: i64,
+}
+
+impl X11Backend {
+    /// Capture the screen of `display_name`, `$DISPLAY` if `None`.
+    pub fn new(display_name: Option<&str>) -> Self {
+        Self {
+            display_name: display_name.map(str::to_string),
+            display: None,
+            captured: 0,
+        }
+    }
+}
+
+impl Backend for X11Backend {
+    fn open(&mut self) -> Result<Rect> {
+        let display = Display::connect(self.display_name.as_deref())?;
+        let rect = display.screen_rect();
+        self.display = Some(display);
+        Ok(rect)
+    }
+
+    fn next_frame(&mut self) -> Result<Frame> {
+        self.next_frame_into(Vec::new())
+    }
+
+    fn next_frame_into(&mut self, buffer: Vec<u8>) -> Result<Frame> {
+        let display = self.display.as_ref().ok_or(Error::AccessLost)?;
+        let rect = display.screen_rect();
+        let mut frame = display.capture(display.root(), rect.width, rect.height, buffer)?;
+
+        // X has no notion of presents, count captures so pacing on change
+        // still sees progress
+        self.captured += 1;
+        frame.info.last_present_time = self.captured;
+        Ok(frame)
+    }
+
+    fn adapters(&mut self) -> Result<Vec<Adapter>> {
+        match self.display.as_ref() {
+            Some(display) => display.adapters(),
+            None => Display::connect(self.display_name.as_deref())?.adapters(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::capture::{Event, Session};
+    use crate::window::{WindowBackend, WindowTarget};
+    use x11rb::protocol::xproto::{CreateWindowAux, PropMode, WindowClass};
+    use x11rb::wrapper::ConnectionExt as _;
+
+    /// These tests need an X server, e.g. `xvfb-run cargo test`.
+    fn display() -> Option<Display> {
+        if std::env::var_os("DISPLAY").is_none() {
+            eprintln!("DISPLAY not set, skipping X11 test");
+            return None;
+        }
+        Some(Display::connect(None).expect("connecting to $DISPLAY"))
+    }
+
+    /// Map a solid colored window and wait until it's on screen.
+    fn create_window(display: &Display, title: &str, rect: Rect, pixel: u32) -> Window {
+        let conn = &display.conn;
+        let window = conn.generate_id().unwrap();
+        conn.create_window(
+            x11rb::COPY_DEPTH_FROM_PARENT,
+            window,
+            display.root(),
+            rect.x as i16,
+            rect.y as i16,
+            rect.width as u16,
+            rect.height as u16,
+            0,
+            WindowClass::INPUT_OUTPUT,
+            x11rb::COPY_FROM_PARENT,
+            &CreateWindowAux::new().background_pixel(pixel),
+        )
+        .unwrap();
+        conn.change_property8(
+            PropMode::REPLACE,
+            window,
+            AtomEnum::WM_NAME,
+            AtomEnum::STRING,
+            title.as_bytes(),
+        )
+        .unwrap();
+        let net_wm_pid = display.atom(b"_NET_WM_PID").unwrap();
+        conn.change_property32(
+            PropMode::REPLACE,
+            window,
+            net_wm_pid,
+            AtomEnum::CARDINAL,
+            &[std::process::id()],
+        )
+        .unwrap();
+        conn.map_window(window).unwrap();
+        conn.sync().unwrap();
+
+        for _ in 0..100 {
+            let attributes = conn.get_window_attributes(window).unwrap().reply().unwrap();
+            if attributes.map_state == MapState::VIEWABLE {
+                break;
+            }
+            std::thread::sleep(std::time::Duration::from_millis(10));
+        }
+        // give the server a moment to paint the background
+        std::thread::sleep(std::time::Duration::from_millis(50));
+        window
+    }
+
+    #[test]
+    fn test_refresh_rate() {
+        // the CVT 1920x1080 timings
+        let mode = ModeInfo {
+            width: 1920,
+            height: 1080,
+            dot_clock: 173_000_000,
+            htotal: 2576,
+            vtotal: 1120,
+            ..Default::default()
+        };
+        let hz = refresh_rate(&mode).unwrap();
+        assert!((hz - 59.96).abs() < 0.01, "{}", hz);
+        assert_eq!(refresh_rate(&ModeInfo::default()), None);
+        assert_eq!(
+            rotation(randr::Rotation::ROTATE270 | randr::Rotation::REFLECT_X),
+            Rotation::Rotate270
+        );
+    }
+
+    #[test]
+    fn test_adapters() {
+        let Some(display) = display() else { return };
+        let adapters = display.adapters().unwrap();
+
+        assert_eq!(adapters.len(), 1);
+        assert!(!adapters[0].outputs.is_empty());
+        let screen = display.screen_rect();
+        for output in &adapters[0].outputs {
+            assert!(output.desktop.intersect(&screen).is_some() || !output.attached_to_desktop);
+        }
+    }
+
+    #[test]
+    fn test_capture_window() {
+        let Some(display) = display() else { return };
+        let rect = Rect::new(20, 30, 40, 20);
+        let window = create_window(&display, "action-demo red", rect, 0xFF0000);
+
+        let windows = display.list_windows().unwrap();
+        let info = windows
+            .iter()
+            .find(|info| info.id == WindowId(window as isize))
+            .expect("window not listed");
+        assert_eq!(info.title, "action-demo red");
+        assert_eq!(info.pid, Some(std::process::id()));
+        assert_eq!((info.rect.width, info.rect.height), (40, 20));
+
+        let frame = display.capture_window(window, Vec::new()).unwrap();
+        assert_eq!((frame.width, frame.height), (40, 20));
+        assert_eq!(frame.pixel(5, 5), [0x00, 0x00, 0xFF, 0xFF]);
+
+        let screen = X11Backend::new(None).next_frame();
+        assert!(matches!(screen, Err(Error::AccessLost)));
+    }
+
+    #[test]
+    fn test_window_backend_by_title() {
+        let Some(display) = display() else { return };
+        let rect = Rect::new(5, 5, 16, 16);
+        create_window(&display, "action-demo green", rect, 0x00FF00);
+
+        let backend = WindowBackend::new(WindowTarget::Title("demo GREEN".to_string()))
+            .fallback(X11Backend::new(None));
+        let mut session = Session::new(backend);
+
+        assert!(matches!(session.next_event().unwrap(), Event::Geometry(_)));
+        let frame = session.next_frame().unwrap();
+        assert_eq!((frame.width, frame.height), (16, 16));
+        assert_eq!(frame.pixel(8, 8), [0x00, 0xFF, 0x00, 0xFF]);
+        assert!(frame.info.is_complete());
+        let next = session.next_frame().unwrap();
+        assert!(next.info.last_present_time > frame.info.last_present_time);
+    }
+
+    #[test]
+    fn test_window_backend_without_the_window() {
+        if display().is_none() {
+            return;
+        }
+        let mut backend = WindowBackend::new(WindowTarget::Title("no such window".to_string()));
+        assert!(matches!(backend.open(), Err(Error::AccessLost)));
+
+        let mut backend = WindowBackend::new(WindowTarget::Id(WindowId(0)));
+        assert!(matches!(backend.open(), Err(Error::Backend(_))));
+    }
+}